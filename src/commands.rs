@@ -29,10 +29,10 @@ pub enum Command {
     Ignore,
     /// Show this help text.
     Help,
-    /// Show this help text.
-    Start,
-    /// List available models.
-    Models,
+    /// Show this help text, or continue a group conversation via a `/dm` deep-link payload.
+    Start { payload: Option<String> },
+    /// Browse the models, or search them by id and name.
+    Models { query: Option<String> },
     /// Get/set the model (use `none` to clear).
     Model(CommandArg),
     /// Get/set the API key (use `none` to clear).
@@ -41,6 +41,234 @@ pub enum Command {
     SystemPrompt(CommandArg),
     /// List or update chat authorization.
     Approve(ApproveArg),
+    /// Admin only: show the runtime config or override a setting until reset.
+    Config(ConfigArg),
+    /// Send a canned message exercising every MarkdownV2 construct.
+    MdTest,
+    /// Show, set or clear the function/tool definitions.
+    Tools(ToolsArg),
+    /// Supply the output of the pending tool call(s).
+    ToolResult(CommandArg),
+    /// Show the latest request log entries of a chat.
+    Log(LogArg),
+    /// Show the chat id, authorization, model, key, system prompt and history size.
+    Whoami,
+    /// Cancel the answer being generated for the chat.
+    Stop,
+    /// Admin only: list the admin chats.
+    Admins,
+    /// (Groups, Telegram admins only) show or toggle deleting command messages.
+    DeleteCommands(ToggleArg),
+    /// List the chat's feature toggles and whether the current model supports them.
+    Features,
+    /// Leave the first-run onboarding.
+    Skip,
+    /// Send the next message without the stored history (the history itself is kept).
+    ClearContext,
+    /// Delete the conversation history and start over; settings are kept.
+    Reset,
+    /// Delete only the last turns of the history.
+    Forget(ForgetArg),
+    /// Show the last turns of the stored history with their age.
+    History(HistoryArg),
+    /// Re-run the last prompt, optionally with an extra instruction.
+    Regenerate(CommandArg),
+    /// Show or toggle ephemeral (non-persisted) history.
+    Ephemeral(EphemeralArg),
+    /// Export or import the chat settings as JSON.
+    Settings(SettingsArg),
+    /// Show or toggle voice replies.
+    Voice(ToggleArg),
+    /// Show or toggle the response cache.
+    Cache(ToggleArg),
+    /// Show or toggle the web search plugin.
+    WebSearch(ToggleArg),
+    /// Get/set live-edit streaming (use `none` for the operator default).
+    Stream(StreamArg),
+    /// Estimate the token count of the given text or the replied-to message.
+    Tokens { text: Option<String> },
+    /// Show or toggle sending the model's reasoning before answers.
+    ShowThinking(ToggleArg),
+    /// Show or toggle stripping reasoning the model writes before its final answer.
+    CleanThinking(ToggleArg),
+    /// Get/set the chat's UTC offset (use `none` to reset to UTC).
+    Timezone(CommandArg),
+    /// Archive history past the configured age right away.
+    Archive(ArchiveArg),
+    /// Show the chat's lifetime token usage and cost, and the last request's.
+    Usage,
+    /// Export per-day usage and cost as a CSV document.
+    UsageCsv(UsageCsvArg),
+    /// Send the chat's whole stored history as a Markdown or JSON document.
+    Export(ExportArg),
+    /// Admin only: send the text to every authorized chat (empty shows the usage).
+    Broadcast(String),
+    /// Get/set whether answers reply to the question (use `none` for the default).
+    ReplyMode(CommandArg),
+    /// Show the sampling parameters or apply a creative/balanced/precise preset.
+    Mode(CommandArg),
+    /// Get/set the reasoning effort (use `none` for the model default).
+    Reasoning(CommandArg),
+    /// Get/set one sampling parameter (`/temperature`, `/top_p`; `none` for the default).
+    Sampling {
+        param: crate::conversation::SamplingParam,
+        arg: SamplingArg,
+    },
+    /// List, add, remove or reorder named system prompt sections.
+    Section(SectionArg),
+    /// Show the full system context sent with requests, in order.
+    EffectivePrompt,
+    /// List, add or cancel recurring prompts.
+    Schedule(ScheduleArg),
+    /// In a group: get a link to continue the conversation in a private chat.
+    Dm,
+    /// In a group: summarize the last stored messages.
+    Tldr(TldrArg),
+}
+
+#[derive(Debug)]
+pub enum UsageCsvArg {
+    Range {
+        from: Option<chrono::NaiveDate>,
+        to: Option<chrono::NaiveDate>,
+    },
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum ExportArg {
+    Format(crate::export::ExportFormat),
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum ArchiveArg {
+    Run,
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum SectionArg {
+    List,
+    Add {
+        name: String,
+        text: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Move to a 1-based position.
+    Move {
+        name: String,
+        position: usize,
+    },
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum ScheduleArg {
+    List,
+    Add {
+        recurrence: crate::schedule::Recurrence,
+        time: chrono::NaiveTime,
+        prompt: String,
+    },
+    Cancel {
+        id: i64,
+    },
+    Invalid,
+}
+
+/// Argument of `/stream`; `Set(None)` returns to the operator default.
+#[derive(Debug)]
+pub enum StreamArg {
+    Show,
+    Set(Option<bool>),
+    Invalid,
+}
+
+/// Argument of the plain `[on|off]` switches.
+#[derive(Debug)]
+pub enum ToggleArg {
+    Show,
+    On,
+    Off,
+    Invalid,
+}
+
+impl ToggleArg {
+    fn from_text(text: Option<&str>) -> Self {
+        match text.map(|args| args.to_ascii_lowercase()).as_deref() {
+            None => ToggleArg::Show,
+            Some("on") => ToggleArg::On,
+            Some("off") => ToggleArg::Off,
+            Some(_) => ToggleArg::Invalid,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsArg {
+    Export { reveal_key: bool },
+    Import(String),
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum EphemeralArg {
+    Show,
+    On { purge: bool },
+    Off,
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum ForgetArg {
+    Invalid,
+    Turns(usize),
+}
+
+#[derive(Debug)]
+pub enum HistoryArg {
+    Invalid,
+    Show { turns: usize },
+}
+
+#[derive(Debug)]
+pub enum TldrArg {
+    Invalid,
+    Summarize { messages: usize },
+}
+
+#[derive(Debug)]
+pub enum LogArg {
+    Invalid,
+    Show { chat_id: i64, limit: usize },
+}
+
+#[derive(Debug)]
+pub enum ToolsArg {
+    Show,
+    Clear,
+    Set(String),
+    Invalid,
+}
+
+/// Value of `/temperature` or `/top_p`, range-checked when parsed.
+#[derive(Debug)]
+pub enum SamplingArg {
+    Show,
+    Clear,
+    Set(f64),
+    Invalid,
+}
+
+#[derive(Debug)]
+pub enum ConfigArg {
+    Show,
+    Set { name: String, value: String },
+    Reset { name: String },
+    Invalid,
 }
 
 #[derive(Debug)]
@@ -50,6 +278,14 @@ pub enum ApproveArg {
     ApproveChat { chat_id: i64, is_authorized: bool },
 }
 
+/// Whether `text` is a `/stop`, for any bot mention; cheap enough to check on every update.
+pub fn is_stop(text: &str) -> bool {
+    text.split_whitespace()
+        .next()
+        .and_then(|command| command.split('@').next())
+        .is_some_and(|command| command.eq_ignore_ascii_case("/stop"))
+}
+
 pub fn parse_command(text: &str, bot_username: &str) -> Result<Command, String> {
     let trimmed = text.trim();
     if !trimmed.starts_with('/') {
@@ -85,23 +321,349 @@ pub fn parse_command(text: &str, bot_username: &str) -> Result<Command, String>
                 Err("Unknown command".to_string())
             }
         }
-        "start" => {
+        "start" => Ok(Command::Start {
+            payload: args_part.map(|payload| payload.trim().to_string()),
+        }),
+        "stream" => Ok(Command::Stream(match ToggleArg::from_text(args_part) {
+            ToggleArg::Show => StreamArg::Show,
+            ToggleArg::On => StreamArg::Set(Some(true)),
+            ToggleArg::Off => StreamArg::Set(Some(false)),
+            ToggleArg::Invalid
+                if args_part.is_some_and(|args| args.eq_ignore_ascii_case("none")) =>
+            {
+                StreamArg::Set(None)
+            }
+            ToggleArg::Invalid => StreamArg::Invalid,
+        })),
+        "tokens" => Ok(Command::Tokens {
+            text: args_part
+                .map(str::trim)
+                .filter(|text| !text.is_empty())
+                .map(str::to_string),
+        }),
+        "dm" => {
             if args_part.is_none() {
-                Ok(Command::Start)
+                Ok(Command::Dm)
             } else {
                 Err("Unknown command".to_string())
             }
         }
-        "models" => {
+        "tldr" => {
+            const DEFAULT_MESSAGES: usize = 50;
+            const MAX_MESSAGES: usize = 300;
+
+            let arg = match args_part.map(|args| args.trim().parse::<usize>()) {
+                None => TldrArg::Summarize {
+                    messages: DEFAULT_MESSAGES,
+                },
+                Some(Ok(messages)) if (1..=MAX_MESSAGES).contains(&messages) => {
+                    TldrArg::Summarize { messages }
+                }
+                Some(_) => TldrArg::Invalid,
+            };
+            Ok(Command::Tldr(arg))
+        }
+        "models" => Ok(Command::Models {
+            query: args_part.map(str::to_string),
+        }),
+        "mdtest" => {
             if args_part.is_none() {
-                Ok(Command::Models)
+                Ok(Command::MdTest)
             } else {
                 Err("Unknown command".to_string())
             }
         }
+        "tools" => {
+            let Some(args) = args_part else {
+                return Ok(Command::Tools(ToolsArg::Show));
+            };
+            if args.eq_ignore_ascii_case("none") || args.eq_ignore_ascii_case("clear") {
+                return Ok(Command::Tools(ToolsArg::Clear));
+            }
+            match args.split_once(char::is_whitespace) {
+                Some((sub, json)) if sub.eq_ignore_ascii_case("set") => {
+                    Ok(Command::Tools(ToolsArg::Set(json.trim().to_string())))
+                }
+                _ => Ok(Command::Tools(ToolsArg::Invalid)),
+            }
+        }
+        "log" => {
+            const DEFAULT_LIMIT: usize = 10;
+            const MAX_LIMIT: usize = 50;
+
+            let args = args_part
+                .map(|args| args.split_whitespace().collect::<Vec<&str>>())
+                .unwrap_or_default();
+            let chat_id = match args.first().map(|id| id.parse::<i64>()) {
+                Some(Ok(chat_id)) => chat_id,
+                _ => return Ok(Command::Log(LogArg::Invalid)),
+            };
+            let limit = match args.get(1).map(|n| n.parse::<usize>()) {
+                None => DEFAULT_LIMIT,
+                Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) && args.len() == 2 => limit,
+                _ => return Ok(Command::Log(LogArg::Invalid)),
+            };
+            Ok(Command::Log(LogArg::Show { chat_id, limit }))
+        }
+        "whoami" => {
+            if args_part.is_none() {
+                Ok(Command::Whoami)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "stop" => {
+            if args_part.is_none() {
+                Ok(Command::Stop)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "delete_commands" => Ok(Command::DeleteCommands(ToggleArg::from_text(args_part))),
+        "features" => {
+            if args_part.is_none() {
+                Ok(Command::Features)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "admins" => {
+            if args_part.is_none() {
+                Ok(Command::Admins)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "skip" => {
+            if args_part.is_none() {
+                Ok(Command::Skip)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "clear_context" => {
+            if args_part.is_none() {
+                Ok(Command::ClearContext)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "reset" => {
+            if args_part.is_none() {
+                Ok(Command::Reset)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "forget" => {
+            let arg = match args_part.map(|args| args.trim().parse::<usize>()) {
+                None => ForgetArg::Turns(1),
+                Some(Ok(turns)) if turns > 0 => ForgetArg::Turns(turns),
+                Some(_) => ForgetArg::Invalid,
+            };
+            Ok(Command::Forget(arg))
+        }
+        "history" => {
+            const DEFAULT_TURNS: usize = 5;
+            const MAX_TURNS: usize = 20;
+
+            let arg = match args_part.map(|args| args.trim().parse::<usize>()) {
+                None => HistoryArg::Show {
+                    turns: DEFAULT_TURNS,
+                },
+                Some(Ok(turns)) if (1..=MAX_TURNS).contains(&turns) => HistoryArg::Show { turns },
+                Some(_) => HistoryArg::Invalid,
+            };
+            Ok(Command::History(arg))
+        }
+        "ephemeral" => {
+            let args = args_part
+                .map(|args| args.to_ascii_lowercase())
+                .unwrap_or_default();
+            let arg = match args.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [] => EphemeralArg::Show,
+                ["on"] => EphemeralArg::On { purge: false },
+                ["on", "purge"] => EphemeralArg::On { purge: true },
+                ["off"] => EphemeralArg::Off,
+                _ => EphemeralArg::Invalid,
+            };
+            Ok(Command::Ephemeral(arg))
+        }
+        "settings" => {
+            let args = args_part.unwrap_or_default();
+            let (sub, rest) = match args.split_once(char::is_whitespace) {
+                Some((sub, rest)) => (sub, rest.trim()),
+                None => (args, ""),
+            };
+            let arg = match (sub.to_ascii_lowercase().as_str(), rest) {
+                ("export", "") => SettingsArg::Export { reveal_key: false },
+                ("export", reveal) if reveal.eq_ignore_ascii_case("reveal") => {
+                    SettingsArg::Export { reveal_key: true }
+                }
+                ("import", json) if !json.is_empty() => SettingsArg::Import(json.to_string()),
+                _ => SettingsArg::Invalid,
+            };
+            Ok(Command::Settings(arg))
+        }
+        "voice" => Ok(Command::Voice(ToggleArg::from_text(args_part))),
+        "cache" => Ok(Command::Cache(ToggleArg::from_text(args_part))),
+        "websearch" => Ok(Command::WebSearch(ToggleArg::from_text(args_part))),
+        "showthinking" => Ok(Command::ShowThinking(ToggleArg::from_text(args_part))),
+        "clean_thinking" => Ok(Command::CleanThinking(ToggleArg::from_text(args_part))),
+        "archive" => match args_part {
+            Some(args) if args.eq_ignore_ascii_case("run") => Ok(Command::Archive(ArchiveArg::Run)),
+            _ => Ok(Command::Archive(ArchiveArg::Invalid)),
+        },
+        "usage" => {
+            if args_part.is_none() {
+                Ok(Command::Usage)
+            } else {
+                Err("Unknown command".to_string())
+            }
+        }
+        "usage_csv" => {
+            let args = args_part
+                .map(|args| args.split_whitespace().collect::<Vec<&str>>())
+                .unwrap_or_default();
+            if args.len() > 2 {
+                return Ok(Command::UsageCsv(UsageCsvArg::Invalid));
+            }
+            let dates = args
+                .iter()
+                .map(|arg| chrono::NaiveDate::parse_from_str(arg, "%Y-%m-%d"))
+                .collect::<Result<Vec<_>, _>>();
+            let arg = match dates.as_deref() {
+                Ok([]) => UsageCsvArg::Range {
+                    from: None,
+                    to: None,
+                },
+                Ok([from]) => UsageCsvArg::Range {
+                    from: Some(*from),
+                    to: None,
+                },
+                Ok([from, to]) => UsageCsvArg::Range {
+                    from: Some(*from),
+                    to: Some(*to),
+                },
+                _ => UsageCsvArg::Invalid,
+            };
+            Ok(Command::UsageCsv(arg))
+        }
+        "broadcast" => Ok(Command::Broadcast(
+            args_part.unwrap_or_default().trim().to_string(),
+        )),
+        "export" => {
+            let arg = match args_part {
+                None => ExportArg::Format(crate::export::ExportFormat::Markdown),
+                Some(format) => crate::export::ExportFormat::parse(format)
+                    .map_or(ExportArg::Invalid, ExportArg::Format),
+            };
+            Ok(Command::Export(arg))
+        }
+        "reply_mode" => Ok(Command::ReplyMode(CommandArg::from_text(args_part))),
+        "mode" => Ok(Command::Mode(CommandArg::from_text(args_part))),
+        "reasoning" => Ok(Command::Reasoning(CommandArg::from_text(args_part))),
+        name @ ("temperature" | "top_p") => {
+            let param = if name == "temperature" {
+                crate::conversation::SamplingParam::Temperature
+            } else {
+                crate::conversation::SamplingParam::TopP
+            };
+            let arg = match CommandArg::from_text(args_part) {
+                CommandArg::Empty => SamplingArg::Show,
+                CommandArg::None => SamplingArg::Clear,
+                CommandArg::Text(text) => param
+                    .parse_value(&text)
+                    .map_or(SamplingArg::Invalid, SamplingArg::Set),
+            };
+            Ok(Command::Sampling { param, arg })
+        }
+        "effective_prompt" => Ok(Command::EffectivePrompt),
+        "section" => {
+            let Some(args) = args_part else {
+                return Ok(Command::Section(SectionArg::List));
+            };
+            let (action, rest) = args
+                .split_once(char::is_whitespace)
+                .map(|(action, rest)| (action, rest.trim_start()))
+                .unwrap_or((args, ""));
+            let (name, rest) = rest
+                .split_once(char::is_whitespace)
+                .map(|(name, rest)| (name, rest.trim()))
+                .unwrap_or((rest, ""));
+            let name = name.to_string();
+            let arg = match action.to_ascii_lowercase().as_str() {
+                "add" if !name.is_empty() && !rest.is_empty() => SectionArg::Add {
+                    name,
+                    text: rest.to_string(),
+                },
+                "remove" if !name.is_empty() && rest.is_empty() => SectionArg::Remove { name },
+                "move" => match rest.parse() {
+                    Ok(position) if !name.is_empty() && position > 0 => {
+                        SectionArg::Move { name, position }
+                    }
+                    _ => SectionArg::Invalid,
+                },
+                _ => SectionArg::Invalid,
+            };
+            Ok(Command::Section(arg))
+        }
+        "schedule" => {
+            let Some(args) = args_part else {
+                return Ok(Command::Schedule(ScheduleArg::List));
+            };
+            let mut parts = args.splitn(3, char::is_whitespace);
+            let first = parts.next().unwrap_or_default();
+            let second = parts.next().map(str::trim).unwrap_or_default();
+            let rest = parts.next().map(str::trim).unwrap_or_default();
+            let arg = match first.to_ascii_lowercase().as_str() {
+                "list" if second.is_empty() => ScheduleArg::List,
+                "cancel" if rest.is_empty() => match second.parse() {
+                    Ok(id) => ScheduleArg::Cancel { id },
+                    Err(_) => ScheduleArg::Invalid,
+                },
+                _ => match (
+                    crate::schedule::Recurrence::parse(first),
+                    crate::schedule::parse_time(second),
+                    crate::schedule::unquote(rest),
+                ) {
+                    (Some(recurrence), Some(time), prompt) if !prompt.is_empty() => {
+                        ScheduleArg::Add {
+                            recurrence,
+                            time,
+                            prompt: prompt.to_string(),
+                        }
+                    }
+                    _ => ScheduleArg::Invalid,
+                },
+            };
+            Ok(Command::Schedule(arg))
+        }
+        "timezone" => Ok(Command::Timezone(CommandArg::from_text(args_part))),
+        "regenerate" | "retry" => Ok(Command::Regenerate(CommandArg::from_text(args_part))),
+        "tool_result" => Ok(Command::ToolResult(CommandArg::from_text(args_part))),
         "model" => Ok(Command::Model(CommandArg::from_text(args_part))),
         "key" => Ok(Command::Key(CommandArg::from_text(args_part))),
         "system_prompt" => Ok(Command::SystemPrompt(CommandArg::from_text(args_part))),
+        "config" => {
+            let Some(args) = args_part else {
+                return Ok(Command::Config(ConfigArg::Show));
+            };
+            let mut parts = args.splitn(3, char::is_whitespace);
+            let action = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let name = parts.next().unwrap_or_default().to_string();
+            let rest = parts.next();
+            let arg = match (action.as_str(), rest) {
+                _ if name.is_empty() => ConfigArg::Invalid,
+                ("set", value) => ConfigArg::Set {
+                    name,
+                    value: value.unwrap_or_default().to_string(),
+                },
+                ("reset", None) => ConfigArg::Reset { name },
+                _ => ConfigArg::Invalid,
+            };
+            Ok(Command::Config(arg))
+        }
         "approve" => {
             if args_part.is_none() {
                 return Ok(Command::Approve(ApproveArg::Empty));
@@ -0,0 +1,1023 @@
+use crate::openrouter_api;
+use crate::panic_handler::fatal_panic;
+use crate::postprocess::{StripRule, ThinkingFilter};
+use crate::telegram;
+use crate::tts::TtsConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Base system prompt sent first in every chat unless `SYSTEM_PROMPT(_FILE)` replaces it.
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a Telegram bot. In group chats you may see many messages, but only treat the latest message that explicitly mentions @{bot_name} (or replies to you) as the user's prompt; ignore the rest. Respond in plain text only (no Markdown).";
+
+/// What happens to history rows older than `HISTORY_MAX_AGE_DAYS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveMode {
+    /// Move them to the `history_archive` table.
+    Archive,
+    /// Delete them for good.
+    Delete,
+}
+
+/// What happens to a single message too large for the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedInput {
+    /// Refuse it and tell the user how far over the limit it is.
+    Reject,
+    /// Send only the part that fits and tell the user it was cut.
+    Truncate,
+}
+
+/// How unauthorized chats are answered (`UNAUTHORIZED_REPLY`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnauthorizedReply {
+    /// Tell them on every message.
+    Always,
+    /// Tell them once; later messages are ignored until their authorization changes.
+    Once,
+    /// Never answer; admins are still asked for approval.
+    Never,
+}
+
+/// Operator settings read from the environment once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Chats treated as authorized regardless of the database (`AUTHORIZED_CHATS`).
+    pub authorized_chats: BTreeSet<i64>,
+    /// Chats made admins (and authorized) in the database at startup (`ADMIN_CHATS`, or its
+    /// alias `ADMIN_CHAT_IDS`); they stay admins while listed.
+    pub admin_chats: BTreeSet<i64>,
+    /// Chats authorized in the database when first loaded (`AUTO_AUTHORIZE_CHAT_IDS`).
+    pub auto_authorize_chats: BTreeSet<i64>,
+    /// Authorize every chat the bot sees for the first time (`AUTO_AUTHORIZE_ALL`).
+    pub auto_authorize_all: bool,
+    /// Run the guided setup (language, model) on a chat's first `/start`.
+    pub onboarding: bool,
+    /// Greeting sent before the first onboarding question (`\n` escapes allowed).
+    pub onboarding_welcome: String,
+    /// Ask the model for a short title after the first exchange of a conversation.
+    pub auto_title: bool,
+    /// Tell authorized private chats that stickers, photos, polls etc. aren't understood,
+    /// at most once per `MEDIA_DECLINE_INTERVAL`.
+    pub media_decline: bool,
+    /// Record every LLM call in the `request_log` table.
+    pub request_log: bool,
+    /// Most LLM requests a group may make per hour.
+    pub group_llm_limit: usize,
+    /// Requests per minute a non-admin chat may make, refilled continuously (`None` = off).
+    pub chat_rate_limit: Option<u32>,
+    /// Operator-provided key used by authorized chats that haven't set their own.
+    pub fallback_openrouter_key: Option<String>,
+    /// Max requests per chat per day on the fallback key (`None` = unlimited).
+    pub fallback_key_daily_limit: Option<u32>,
+    /// Models tried in order when the chat's model fails with anything but an auth error.
+    pub fallback_models: Vec<String>,
+    /// OpenRouter API root, without a trailing slash.
+    pub openrouter_base_url: String,
+    /// Context length assumed for the default model while it's missing from the model list.
+    pub default_model_context_length: u64,
+    /// Max completion tokens assumed for the default model while it's missing from the list.
+    pub default_model_max_completion_tokens: u64,
+    /// Delay between model list fetch attempts while no list is available.
+    pub model_refresh_retry_delay: Duration,
+    /// Startup fetch attempts before starting with an empty list (0 = don't wait at all).
+    pub model_refresh_max_attempts: u32,
+    /// Delay between background refreshes once a list is available.
+    pub model_refresh_interval: Duration,
+    /// Extra attempts for a Telegram send that failed at the network level.
+    pub telegram_send_retries: u32,
+    /// Wait before the first send retry; doubled for each further one.
+    pub telegram_send_retry_delay: Duration,
+    /// Text prepended to every assistant reply (`\n` escapes allowed).
+    pub reply_prefix: String,
+    /// Text appended to every assistant reply (`\n` escapes allowed).
+    pub reply_suffix: String,
+    /// Appended to each piece of a word too long for one message when it is cut (`\n` escapes
+    /// allowed); empty means cut silently.
+    pub split_marker: String,
+    /// Most messages one answer is split into; the rest is sent as a text file (None = no cap).
+    pub max_reply_chunks: Option<usize>,
+    /// History rows older than this leave the context (`None` = keep forever).
+    pub history_max_age: Option<Duration>,
+    pub history_archive_mode: ArchiveMode,
+    /// Turns summarized at a time once the history outgrows the model's context
+    /// (`None` = the oldest turns are dropped).
+    pub history_summarize_turns: Option<usize>,
+    pub oversized_input: OversizedInput,
+    pub unauthorized_reply: UnauthorizedReply,
+    /// Cleanup applied to answers before they are sent and stored (empty = send as returned).
+    pub response_strip_rules: Vec<StripRule>,
+    /// Markers that end leaked reasoning, for chats with `/clean_thinking on`.
+    pub thinking_filter: ThinkingFilter,
+    /// Entries kept by the response cache used by chats with `/cache on`.
+    pub response_cache_size: usize,
+    /// How long a cached completion may be reused.
+    pub response_cache_ttl: Duration,
+    /// JSON file mapping model-id prefixes to default system prompts.
+    pub model_prompts_file: Option<String>,
+    /// JSON file mapping model ids to context lengths that replace the advertised ones.
+    pub context_overrides_file: Option<String>,
+    /// Where the last fetched model list is kept, so a restart during an OpenRouter outage
+    /// still starts with models.
+    pub model_cache_file: Option<String>,
+    /// Speech synthesis for `/voice`; only available when `TTS_API_KEY` is set.
+    pub tts: Option<TtsConfig>,
+    /// Streaming default for private chats that haven't chosen with `/stream`.
+    pub stream_default_private: bool,
+    /// Streaming default for groups, where live edits are noisier.
+    pub stream_default_group: bool,
+    /// Base system prompt template (`SYSTEM_PROMPT`, `\n` escapes allowed); `None` = default.
+    pub system_prompt: Option<String>,
+    /// File holding the base system prompt template, read once at startup.
+    pub system_prompt_file: Option<String>,
+    /// Reaction put on a prompt whose request failed (`None` = no reaction, just the text).
+    pub error_reaction_emoji: Option<String>,
+    /// Shown when `/stop` cancels an answer, under whatever part of it had arrived.
+    pub stopped_message: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// The environment with `/config` overrides on top; the overrides must be validated.
+    pub fn from_env_with(overrides: &BTreeMap<String, String>) -> Self {
+        Self::from_lookup(|name| {
+            overrides
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+        })
+    }
+
+    /// Build the config from an arbitrary variable lookup (used by tests).
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            authorized_chats: parse_chat_ids(&lookup, "AUTHORIZED_CHATS"),
+            admin_chats: parse_chat_ids(&lookup, "ADMIN_CHATS")
+                .into_iter()
+                .chain(parse_chat_ids(&lookup, "ADMIN_CHAT_IDS"))
+                .collect(),
+            auto_authorize_chats: parse_chat_ids(&lookup, "AUTO_AUTHORIZE_CHAT_IDS"),
+            auto_authorize_all: parse_bool(&lookup, "AUTO_AUTHORIZE_ALL", false),
+            onboarding: parse_bool(&lookup, "ONBOARDING", false),
+            onboarding_welcome: Some(parse_text(&lookup, "ONBOARDING_WELCOME"))
+                .filter(|text| !text.trim().is_empty())
+                .unwrap_or_else(|| "Welcome! Two quick questions to set things up.".to_string()),
+            auto_title: parse_bool(&lookup, "AUTO_TITLE", false),
+            media_decline: parse_bool(&lookup, "MEDIA_DECLINE", false),
+            request_log: parse_bool(&lookup, "REQUEST_LOG", false),
+            group_llm_limit: parse_number(&lookup, "GROUP_LLM_LIMIT", 10).max(1),
+            chat_rate_limit: Some(parse_number(&lookup, "CHAT_RATE_LIMIT", 20))
+                .filter(|&limit| limit > 0),
+            fallback_openrouter_key: lookup("FALLBACK_OPENROUTER_KEY")
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty()),
+            fallback_key_daily_limit: Some(parse_number(&lookup, "FALLBACK_KEY_DAILY_LIMIT", 0))
+                .filter(|&limit| limit > 0),
+            fallback_models: parse_model_ids(&lookup, "FALLBACK_MODELS"),
+            openrouter_base_url: lookup("OPENROUTER_BASE_URL")
+                .map(|url| url.trim().trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty())
+                .unwrap_or_else(|| openrouter_api::DEFAULT_BASE_URL.to_string()),
+            default_model_context_length: parse_number(
+                &lookup,
+                "DEFAULT_MODEL_CONTEXT_LENGTH",
+                32_768,
+            ),
+            default_model_max_completion_tokens: parse_number(
+                &lookup,
+                "DEFAULT_MODEL_MAX_COMPLETION_TOKENS",
+                4_096,
+            ),
+            model_refresh_retry_delay: Duration::from_secs(parse_number(
+                &lookup,
+                "MODEL_REFRESH_RETRY_SECS",
+                30,
+            )),
+            model_refresh_max_attempts: parse_number(&lookup, "MODEL_REFRESH_MAX_ATTEMPTS", 10),
+            model_refresh_interval: Duration::from_secs(parse_number(
+                &lookup,
+                "MODEL_REFRESH_INTERVAL_SECS",
+                10 * 60,
+            )),
+            telegram_send_retries: parse_number(&lookup, "TELEGRAM_SEND_RETRIES", 2),
+            telegram_send_retry_delay: Duration::from_millis(parse_number(
+                &lookup,
+                "TELEGRAM_SEND_RETRY_DELAY_MS",
+                500,
+            )),
+            reply_prefix: parse_text(&lookup, "REPLY_PREFIX"),
+            reply_suffix: parse_text(&lookup, "REPLY_SUFFIX"),
+            split_marker: parse_split_marker(&lookup),
+            max_reply_chunks: Some(parse_number(&lookup, "MAX_REPLY_CHUNKS", 0))
+                .filter(|&chunks| chunks > 0),
+            history_max_age: Some(parse_number::<u64>(&lookup, "HISTORY_MAX_AGE_DAYS", 0))
+                .filter(|&days| days > 0)
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            history_archive_mode: parse_archive_mode(&lookup),
+            history_summarize_turns: Some(parse_number(&lookup, "HISTORY_SUMMARIZE_TURNS", 0))
+                .filter(|&turns| turns > 0),
+            oversized_input: parse_oversized_input(&lookup),
+            unauthorized_reply: parse_unauthorized_reply(&lookup),
+            response_strip_rules: parse_strip_rules(&lookup),
+            thinking_filter: parse_thinking_filter(&lookup),
+            response_cache_size: parse_number(&lookup, "RESPONSE_CACHE_SIZE", 256).max(1),
+            response_cache_ttl: Duration::from_secs(parse_number(
+                &lookup,
+                "RESPONSE_CACHE_TTL_SECS",
+                60 * 60,
+            )),
+            model_prompts_file: lookup("MODEL_PROMPTS_FILE")
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty()),
+            context_overrides_file: lookup("MODEL_CONTEXT_OVERRIDES_FILE")
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty()),
+            model_cache_file: lookup("MODEL_CACHE_FILE")
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty()),
+            tts: parse_tts(&lookup),
+            stream_default_private: parse_bool(&lookup, "STREAM_DEFAULT_PRIVATE", false),
+            stream_default_group: parse_bool(&lookup, "STREAM_DEFAULT_GROUP", false),
+            system_prompt: Some(parse_text(&lookup, "SYSTEM_PROMPT"))
+                .filter(|prompt| !prompt.trim().is_empty()),
+            system_prompt_file: lookup("SYSTEM_PROMPT_FILE")
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty()),
+            // Set but empty turns the reaction off.
+            error_reaction_emoji: match lookup("ERROR_REACTION_EMOJI") {
+                None => Some("👎".to_string()),
+                Some(emoji) => Some(emoji.trim().to_string()).filter(|emoji| !emoji.is_empty()),
+            },
+            stopped_message: Some(parse_text(&lookup, "STOPPED_MESSAGE"))
+                .filter(|message| !message.trim().is_empty())
+                .unwrap_or_else(|| "⏹ Stopped.".to_string()),
+        }
+    }
+
+    /// Env grants only ever add access; a chat listed here can't be denied via the database.
+    pub fn grants_authorization(&self, chat_id: i64) -> bool {
+        self.authorized_chats.contains(&chat_id) || self.admin_chats.contains(&chat_id)
+    }
+
+    pub fn grants_admin(&self, chat_id: i64) -> bool {
+        self.admin_chats.contains(&chat_id)
+    }
+
+    /// The base system prompt with `{bot_name}` replaced by the bot's username; a prompt file
+    /// that can't be read, or one set next to `SYSTEM_PROMPT`, is a configuration error.
+    pub fn base_system_prompt(&self, bot_username: &str) -> String {
+        let template = match (&self.system_prompt_file, &self.system_prompt) {
+            (Some(_), Some(_)) => {
+                fatal_panic("set either SYSTEM_PROMPT or SYSTEM_PROMPT_FILE, not both")
+            }
+            (Some(path), None) => {
+                let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+                    fatal_panic(format!("failed to read system prompt file {path}: {err}"))
+                });
+                if text.trim().is_empty() {
+                    fatal_panic(format!("system prompt file {path} is empty"));
+                }
+                log::info!("loaded base system prompt from {path}");
+                text.trim().to_string()
+            }
+            (None, Some(prompt)) => prompt.clone(),
+            (None, None) => DEFAULT_SYSTEM_PROMPT.to_string(),
+        };
+        template.replace("{bot_name}", bot_username)
+    }
+
+    /// Streaming default for a chat without an explicit `/stream` choice.
+    pub fn stream_default(&self, is_group: bool) -> bool {
+        if is_group {
+            self.stream_default_group
+        } else {
+            self.stream_default_private
+        }
+    }
+}
+
+/// The config in effect. Readers take a snapshot; `/config set` swaps in a rebuilt one.
+#[derive(Debug)]
+pub struct ConfigStore {
+    current: RwLock<Arc<Config>>,
+}
+
+impl ConfigStore {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().expect("config store lock poisoned"))
+    }
+
+    pub fn store(&self, config: Config) {
+        let config = Arc::new(config);
+        let _previous = std::mem::replace(
+            &mut *self.current.write().expect("config store lock poisoned"),
+            config,
+        );
+    }
+}
+
+/// How a value for a runtime setting is checked before it is stored.
+#[derive(Debug, Clone, Copy)]
+pub enum SettingKind {
+    Bool,
+    /// Whole number within the inclusive range.
+    Number {
+        min: u64,
+        max: u64,
+    },
+    /// Free-form text (`\n` escapes allowed).
+    Text,
+    /// Free-form text of at most this many characters once `\n` escapes are applied.
+    BoundedText {
+        max_chars: usize,
+    },
+    /// One of the listed words.
+    Choice(&'static [&'static str]),
+    /// Rule names as in `RESPONSE_STRIP_RULES`.
+    StripRules,
+}
+
+/// A setting `/config set` may change without a restart, by its environment variable name.
+/// Only settings read on every use are listed; the rest are fixed at startup.
+#[derive(Debug)]
+pub struct RuntimeSetting {
+    pub name: &'static str,
+    pub kind: SettingKind,
+    /// The effective value, as shown by `/config`.
+    pub current: fn(&Config) -> String,
+}
+
+pub const RUNTIME_SETTINGS: [RuntimeSetting; 17] = [
+    RuntimeSetting {
+        name: "ONBOARDING",
+        kind: SettingKind::Bool,
+        current: |config| config.onboarding.to_string(),
+    },
+    RuntimeSetting {
+        name: "AUTO_TITLE",
+        kind: SettingKind::Bool,
+        current: |config| config.auto_title.to_string(),
+    },
+    RuntimeSetting {
+        name: "MEDIA_DECLINE",
+        kind: SettingKind::Bool,
+        current: |config| config.media_decline.to_string(),
+    },
+    RuntimeSetting {
+        name: "REQUEST_LOG",
+        kind: SettingKind::Bool,
+        current: |config| config.request_log.to_string(),
+    },
+    RuntimeSetting {
+        name: "GROUP_LLM_LIMIT",
+        kind: SettingKind::Number { min: 1, max: 1000 },
+        current: |config| config.group_llm_limit.to_string(),
+    },
+    RuntimeSetting {
+        name: "CHAT_RATE_LIMIT",
+        kind: SettingKind::Number { min: 0, max: 1000 },
+        current: |config| {
+            config
+                .chat_rate_limit
+                .map_or_else(|| "0 (off)".to_string(), |limit| limit.to_string())
+        },
+    },
+    RuntimeSetting {
+        name: "FALLBACK_KEY_DAILY_LIMIT",
+        kind: SettingKind::Number {
+            min: 0,
+            max: 1_000_000,
+        },
+        current: |config| {
+            config
+                .fallback_key_daily_limit
+                .map_or_else(|| "0 (unlimited)".to_string(), |limit| limit.to_string())
+        },
+    },
+    RuntimeSetting {
+        name: "FALLBACK_MODELS",
+        kind: SettingKind::Text,
+        current: |config| {
+            if config.fallback_models.is_empty() {
+                "none".to_string()
+            } else {
+                config.fallback_models.join(",")
+            }
+        },
+    },
+    RuntimeSetting {
+        name: "MAX_REPLY_CHUNKS",
+        kind: SettingKind::Number { min: 0, max: 100 },
+        current: |config| {
+            config
+                .max_reply_chunks
+                .map_or_else(|| "0 (no cap)".to_string(), |chunks| chunks.to_string())
+        },
+    },
+    RuntimeSetting {
+        name: "REPLY_PREFIX",
+        kind: SettingKind::Text,
+        current: |config| format!("{:?}", config.reply_prefix),
+    },
+    RuntimeSetting {
+        name: "REPLY_SUFFIX",
+        kind: SettingKind::Text,
+        current: |config| format!("{:?}", config.reply_suffix),
+    },
+    RuntimeSetting {
+        name: "SPLIT_MARKER",
+        kind: SettingKind::BoundedText {
+            max_chars: telegram::MAX_SPLIT_MARKER_CHARS,
+        },
+        current: |config| format!("{:?}", config.split_marker),
+    },
+    RuntimeSetting {
+        name: "UNAUTHORIZED_REPLY",
+        kind: SettingKind::Choice(&["once", "always", "never"]),
+        current: |config| format!("{:?}", config.unauthorized_reply).to_ascii_lowercase(),
+    },
+    RuntimeSetting {
+        name: "OVERSIZED_INPUT",
+        kind: SettingKind::Choice(&["reject", "truncate"]),
+        current: |config| format!("{:?}", config.oversized_input).to_ascii_lowercase(),
+    },
+    RuntimeSetting {
+        name: "RESPONSE_STRIP_RULES",
+        kind: SettingKind::StripRules,
+        current: |config| {
+            if config.response_strip_rules.is_empty() {
+                "none".to_string()
+            } else {
+                let names: Vec<_> = config
+                    .response_strip_rules
+                    .iter()
+                    .map(|rule| rule.name())
+                    .collect();
+                names.join(",")
+            }
+        },
+    },
+    RuntimeSetting {
+        name: "STREAM_DEFAULT_PRIVATE",
+        kind: SettingKind::Bool,
+        current: |config| config.stream_default_private.to_string(),
+    },
+    RuntimeSetting {
+        name: "STREAM_DEFAULT_GROUP",
+        kind: SettingKind::Bool,
+        current: |config| config.stream_default_group.to_string(),
+    },
+];
+
+impl RuntimeSetting {
+    /// Look a setting up by name, ignoring case.
+    pub fn find(name: &str) -> Option<&'static RuntimeSetting> {
+        RUNTIME_SETTINGS
+            .iter()
+            .find(|setting| setting.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The value to store, normalized, or why it can't be used.
+    pub fn validate(&self, value: &str) -> Result<String, String> {
+        let trimmed = value.trim();
+        match self.kind {
+            SettingKind::Bool => match trimmed.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok("true".to_string()),
+                "0" | "false" | "no" | "off" => Ok("false".to_string()),
+                _ => Err(format!("{} must be on or off", self.name)),
+            },
+            SettingKind::Number { min, max } => match trimmed.parse::<u64>() {
+                Ok(number) if (min..=max).contains(&number) => Ok(number.to_string()),
+                _ => Err(format!(
+                    "{} must be a whole number from {min} to {max}",
+                    self.name
+                )),
+            },
+            SettingKind::Text => Ok(value.to_string()),
+            SettingKind::BoundedText { max_chars } => {
+                if value.replace("\\n", "\n").chars().count() <= max_chars {
+                    Ok(value.to_string())
+                } else {
+                    Err(format!(
+                        "{} must be at most {max_chars} characters",
+                        self.name
+                    ))
+                }
+            }
+            SettingKind::Choice(choices) => {
+                let choice = trimmed.to_ascii_lowercase();
+                if choices.contains(&choice.as_str()) {
+                    Ok(choice)
+                } else {
+                    Err(format!(
+                        "{} must be one of {}",
+                        self.name,
+                        choices.join(", ")
+                    ))
+                }
+            }
+            SettingKind::StripRules => {
+                let mut names = Vec::new();
+                for name in trimmed
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                {
+                    let name = name.to_ascii_lowercase();
+                    if name != "none" && name != "all" && StripRule::parse(&name).is_none() {
+                        let known: Vec<_> = StripRule::ALL.iter().map(|rule| rule.name()).collect();
+                        return Err(format!(
+                            "unknown rule {name} (expected none, all or any of {})",
+                            known.join(", ")
+                        ));
+                    }
+                    if name != "none" {
+                        names.push(name);
+                    }
+                }
+                Ok(names.join(","))
+            }
+        }
+    }
+}
+
+/// Stored `/config` overrides that still name a runtime setting and pass validation; the
+/// rest are skipped with a warning so a stale row can't stop the bot from starting.
+pub fn checked_overrides(stored: Vec<(String, String)>) -> BTreeMap<String, String> {
+    let mut overrides = BTreeMap::new();
+    for (name, value) in stored {
+        let Some(setting) = RuntimeSetting::find(&name) else {
+            log::warn!("ignoring stored override for {name}: not a runtime setting");
+            continue;
+        };
+        match setting.validate(&value) {
+            Ok(value) => {
+                overrides.insert(setting.name.to_string(), value);
+            }
+            Err(err) => log::warn!("ignoring stored override for {name}: {err}"),
+        }
+    }
+    overrides
+}
+
+/// Parse a boolean flag; unset or empty means `default`, anything unrecognized is fatal.
+fn parse_bool(lookup: &impl Fn(&str) -> Option<String>, name: &str, default: bool) -> bool {
+    let Some(value) = lookup(name) else {
+        return default;
+    };
+
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" => default,
+        "1" | "true" | "yes" | "on" => true,
+        "0" | "false" | "no" | "off" => false,
+        other => fatal_panic(format!("invalid boolean value for {name}: {other}")),
+    }
+}
+
+/// Comma- or space-separated chat ids; anything that isn't an integer is fatal.
+fn parse_chat_ids(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> BTreeSet<i64> {
+    lookup(name)
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse().unwrap_or_else(|err| {
+                fatal_panic(format!("invalid chat id in {name}: {id} ({err})"))
+            })
+        })
+        .collect()
+}
+
+/// Comma- or space-separated model ids, in the given order and without repeats.
+fn parse_model_ids(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for id in lookup(name)
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+    {
+        if !ids.iter().any(|known| known == id) {
+            ids.push(id.to_string());
+        }
+    }
+    ids
+}
+
+fn parse_archive_mode(lookup: &impl Fn(&str) -> Option<String>) -> ArchiveMode {
+    let value = lookup("HISTORY_ARCHIVE_MODE").unwrap_or_default();
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "archive" => ArchiveMode::Archive,
+        "delete" => ArchiveMode::Delete,
+        other => fatal_panic(format!(
+            "invalid value for HISTORY_ARCHIVE_MODE: {other} (expected archive or delete)"
+        )),
+    }
+}
+
+fn parse_oversized_input(lookup: &impl Fn(&str) -> Option<String>) -> OversizedInput {
+    let value = lookup("OVERSIZED_INPUT").unwrap_or_default();
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "reject" => OversizedInput::Reject,
+        "truncate" => OversizedInput::Truncate,
+        other => fatal_panic(format!(
+            "invalid value for OVERSIZED_INPUT: {other} (expected reject or truncate)"
+        )),
+    }
+}
+
+fn parse_unauthorized_reply(lookup: &impl Fn(&str) -> Option<String>) -> UnauthorizedReply {
+    let value = lookup("UNAUTHORIZED_REPLY").unwrap_or_default();
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "once" => UnauthorizedReply::Once,
+        "always" => UnauthorizedReply::Always,
+        "never" => UnauthorizedReply::Never,
+        other => fatal_panic(format!(
+            "invalid value for UNAUTHORIZED_REPLY: {other} (expected once, always or never)"
+        )),
+    }
+}
+
+/// Comma- or space-separated rule names, or `all`.
+fn parse_strip_rules(lookup: &impl Fn(&str) -> Option<String>) -> Vec<StripRule> {
+    let value = lookup("RESPONSE_STRIP_RULES").unwrap_or_default();
+    let mut rules = Vec::new();
+    for name in value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|name| !name.is_empty())
+    {
+        let name = name.to_ascii_lowercase();
+        if name == "all" {
+            rules.extend(StripRule::ALL);
+            continue;
+        }
+        let Some(rule) = StripRule::parse(&name) else {
+            let known: Vec<_> = StripRule::ALL.iter().map(|rule| rule.name()).collect();
+            fatal_panic(format!(
+                "invalid rule in RESPONSE_STRIP_RULES: {name} (expected all or any of {})",
+                known.join(", ")
+            ));
+        };
+        rules.push(rule);
+    }
+    rules.sort();
+    rules.dedup();
+    rules
+}
+
+/// JSON array of regexes; unset or blank keeps the default markers.
+fn parse_thinking_filter(lookup: &impl Fn(&str) -> Option<String>) -> ThinkingFilter {
+    match lookup("CLEAN_THINKING_PATTERNS") {
+        Some(json) if !json.trim().is_empty() => ThinkingFilter::parse(&json)
+            .unwrap_or_else(|err| fatal_panic(format!("invalid CLEAN_THINKING_PATTERNS: {err}"))),
+        _ => ThinkingFilter::default(),
+    }
+}
+
+fn parse_tts(lookup: &impl Fn(&str) -> Option<String>) -> Option<TtsConfig> {
+    let api_key = lookup("TTS_API_KEY")
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())?;
+    let text_or = |name: &str, default: &str| {
+        lookup(name)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| default.to_string())
+    };
+
+    let max_chars = parse_number(&lookup, "TTS_MAX_CHARS", 4096);
+    if max_chars == 0 {
+        fatal_panic("TTS_MAX_CHARS must be greater than 0");
+    }
+
+    Some(TtsConfig {
+        api_url: text_or("TTS_API_URL", "https://api.openai.com/v1/audio/speech"),
+        api_key,
+        model: text_or("TTS_MODEL", "gpt-4o-mini-tts"),
+        voice: text_or("TTS_VOICE", "alloy"),
+        max_chars,
+    })
+}
+
+/// Free-form text; unset means empty and a literal `\n` becomes a newline so multi-line
+/// values fit on one `.env` line.
+fn parse_text(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> String {
+    lookup(name)
+        .map(|value| value.replace("\\n", "\n"))
+        .unwrap_or_default()
+}
+
+/// `SPLIT_MARKER`, fatal when too long for the splitter to leave room for text next to it.
+fn parse_split_marker(lookup: &impl Fn(&str) -> Option<String>) -> String {
+    let marker = parse_text(lookup, "SPLIT_MARKER");
+    let max_chars = telegram::MAX_SPLIT_MARKER_CHARS;
+    if marker.chars().count() > max_chars {
+        fatal_panic(format!(
+            "SPLIT_MARKER is too long: {} characters (at most {max_chars})",
+            marker.chars().count()
+        ));
+    }
+    marker
+}
+
+/// Parse a numeric setting; unset or empty means `default`, anything unparsable is fatal.
+fn parse_number<T>(lookup: &impl Fn(&str) -> Option<String>, name: &str, default: T) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Some(value) = lookup(name) else {
+        return default;
+    };
+
+    let value = value.trim();
+    if value.is_empty() {
+        return default;
+    }
+
+    value
+        .parse()
+        .unwrap_or_else(|err| fatal_panic(format!("invalid value for {name}: {value} ({err})")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn defaults_when_unset() {
+        let config = Config::from_lookup(lookup(&[]));
+        assert!(config.authorized_chats.is_empty());
+        assert!(config.admin_chats.is_empty());
+        assert!(config.auto_authorize_chats.is_empty());
+        assert!(!config.auto_authorize_all);
+        assert!(!config.onboarding);
+        assert!(!config.onboarding_welcome.is_empty());
+        assert!(!config.auto_title);
+        assert!(!config.request_log);
+        assert_eq!(config.fallback_openrouter_key, None);
+        assert_eq!(config.fallback_key_daily_limit, None);
+        assert!(config.fallback_models.is_empty());
+        assert_eq!(config.default_model_context_length, 32_768);
+        assert_eq!(config.default_model_max_completion_tokens, 4_096);
+        assert_eq!(config.model_refresh_retry_delay, Duration::from_secs(30));
+        assert_eq!(config.model_refresh_max_attempts, 10);
+        assert_eq!(config.model_refresh_interval, Duration::from_secs(600));
+        assert_eq!(config.model_cache_file, None);
+        assert_eq!(config.reply_prefix, "");
+        assert_eq!(config.reply_suffix, "");
+        assert_eq!(config.split_marker, "");
+        assert_eq!(config.telegram_send_retries, 2);
+        assert_eq!(config.telegram_send_retry_delay, Duration::from_millis(500));
+        assert!(config.tts.is_none());
+        assert_eq!(config.history_max_age, None);
+        assert_eq!(config.history_archive_mode, ArchiveMode::Archive);
+        assert_eq!(config.history_summarize_turns, None);
+        assert_eq!(config.oversized_input, OversizedInput::Reject);
+        assert!(config.response_strip_rules.is_empty());
+        assert!(!config.media_decline);
+        assert_eq!(config.unauthorized_reply, UnauthorizedReply::Once);
+        assert_eq!(config.openrouter_base_url, "https://openrouter.ai/api/v1");
+        assert!(!config.stream_default(false));
+        assert!(!config.stream_default(true));
+        assert_eq!(config.error_reaction_emoji.as_deref(), Some("👎"));
+        assert_eq!(config.stopped_message, "⏹ Stopped.");
+    }
+
+    #[test]
+    fn fills_the_bot_name_into_the_base_system_prompt() {
+        let config = Config::from_lookup(lookup(&[]));
+        let prompt = config.base_system_prompt("gpt_helper_bot");
+        assert!(prompt.contains("mentions @gpt_helper_bot (or replies to you)"));
+        assert!(!prompt.contains("{bot_name}"));
+
+        let config = Config::from_lookup(lookup(&[(
+            "SYSTEM_PROMPT",
+            "You are @{bot_name}.\\nBe brief.",
+        )]));
+        assert_eq!(
+            config.base_system_prompt("gpt_helper_bot"),
+            "You are @gpt_helper_bot.\nBe brief."
+        );
+    }
+
+    #[test]
+    fn picks_stream_default_by_chat_kind() {
+        let config = Config::from_lookup(lookup(&[
+            ("STREAM_DEFAULT_PRIVATE", "on"),
+            ("STREAM_DEFAULT_GROUP", "off"),
+        ]));
+        assert!(config.stream_default(false));
+        assert!(!config.stream_default(true));
+    }
+
+    #[test]
+    fn parses_history_archival_settings() {
+        let config = Config::from_lookup(lookup(&[
+            ("HISTORY_MAX_AGE_DAYS", "30"),
+            ("HISTORY_ARCHIVE_MODE", "Delete"),
+            ("HISTORY_SUMMARIZE_TURNS", "6"),
+        ]));
+        assert_eq!(
+            config.history_max_age,
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(config.history_archive_mode, ArchiveMode::Delete);
+        assert_eq!(config.history_summarize_turns, Some(6));
+    }
+
+    #[test]
+    fn parses_env_chat_grants() {
+        let config = Config::from_lookup(lookup(&[
+            ("AUTHORIZED_CHATS", "123, -100456 789"),
+            ("ADMIN_CHATS", "42"),
+            ("ADMIN_CHAT_IDS", "43"),
+        ]));
+        assert_eq!(config.authorized_chats, BTreeSet::from([123, -100456, 789]));
+        assert!(config.grants_authorization(-100456));
+        assert!(config.grants_authorization(42));
+        assert!(config.grants_admin(42));
+        assert!(config.grants_admin(43));
+        assert!(!config.grants_admin(123));
+        assert!(!config.grants_authorization(7));
+    }
+
+    #[test]
+    fn parses_oversized_input_mode() {
+        let config = Config::from_lookup(lookup(&[("OVERSIZED_INPUT", "Truncate")]));
+        assert_eq!(config.oversized_input, OversizedInput::Truncate);
+    }
+
+    #[test]
+    fn parses_unauthorized_reply_policy() {
+        let config = Config::from_lookup(lookup(&[("UNAUTHORIZED_REPLY", "Never")]));
+        assert_eq!(config.unauthorized_reply, UnauthorizedReply::Never);
+        let config = Config::from_lookup(lookup(&[("UNAUTHORIZED_REPLY", "always")]));
+        assert_eq!(config.unauthorized_reply, UnauthorizedReply::Always);
+    }
+
+    #[test]
+    fn parses_response_strip_rules() {
+        let config = Config::from_lookup(lookup(&[(
+            "RESPONSE_STRIP_RULES",
+            "quotes, Special_Tokens quotes",
+        )]));
+        assert_eq!(
+            config.response_strip_rules,
+            [StripRule::SpecialTokens, StripRule::Quotes]
+        );
+
+        let config = Config::from_lookup(lookup(&[("RESPONSE_STRIP_RULES", "all")]));
+        assert_eq!(config.response_strip_rules, StripRule::ALL);
+    }
+
+    #[test]
+    fn parses_clean_thinking_patterns() {
+        let config = Config::from_lookup(lookup(&[]));
+        assert_eq!(config.thinking_filter.strip("Hmm.\nFinal answer: 4"), "4");
+
+        let config = Config::from_lookup(lookup(&[(
+            "CLEAN_THINKING_PATTERNS",
+            r#"["(?m)^ANSWER:"]"#,
+        )]));
+        assert_eq!(config.thinking_filter.strip("Hmm.\nANSWER: 4"), "4");
+        assert_eq!(
+            config.thinking_filter.strip("Hmm.\nFinal answer: 4"),
+            "Hmm.\nFinal answer: 4"
+        );
+    }
+
+    #[test]
+    fn runtime_overrides_are_validated_and_win() {
+        let limit = RuntimeSetting::find("group_llm_limit").expect("known setting");
+        assert_eq!(limit.validate(" 25 ").as_deref(), Ok("25"));
+        assert!(limit.validate("0").is_err());
+        assert!(limit.validate("ten").is_err());
+
+        let title = RuntimeSetting::find("AUTO_TITLE").expect("known setting");
+        assert_eq!(title.validate("On").as_deref(), Ok("true"));
+        assert!(title.validate("maybe").is_err());
+
+        let rules = RuntimeSetting::find("RESPONSE_STRIP_RULES").expect("known setting");
+        assert_eq!(rules.validate("Quotes, all").as_deref(), Ok("quotes,all"));
+        assert_eq!(rules.validate("none").as_deref(), Ok(""));
+        assert!(rules.validate("emoji").is_err());
+
+        let marker = RuntimeSetting::find("SPLIT_MARKER").expect("known setting");
+        assert_eq!(marker.validate(" …").as_deref(), Ok(" …"));
+        assert!(
+            marker
+                .validate(&"x".repeat(telegram::MAX_SPLIT_MARKER_CHARS + 1))
+                .is_err()
+        );
+        assert!(RuntimeSetting::find("OPENROUTER_BASE_URL").is_none());
+
+        let overrides = checked_overrides(vec![
+            ("GROUP_LLM_LIMIT".to_string(), "3".to_string()),
+            ("UNAUTHORIZED_REPLY".to_string(), "sometimes".to_string()),
+            ("TTS_API_KEY".to_string(), "sk-tts".to_string()),
+        ]);
+        assert_eq!(overrides.len(), 1);
+        let env = lookup(&[("GROUP_LLM_LIMIT", "50"), ("UNAUTHORIZED_REPLY", "never")]);
+        let config = Config::from_lookup(|name| overrides.get(name).cloned().or_else(|| env(name)));
+        assert_eq!(config.group_llm_limit, 3);
+        assert_eq!(config.chat_rate_limit, Some(20));
+        let off = Config::from_lookup(lookup(&[("CHAT_RATE_LIMIT", "0")]));
+        assert_eq!(off.chat_rate_limit, None);
+        assert_eq!(config.unauthorized_reply, UnauthorizedReply::Never);
+        for setting in &RUNTIME_SETTINGS {
+            assert!(!(setting.current)(&config).is_empty(), "{}", setting.name);
+        }
+    }
+
+    #[test]
+    fn parses_tts_settings() {
+        let tts = Config::from_lookup(lookup(&[("TTS_API_KEY", "sk-tts"), ("TTS_VOICE", "nova")]))
+            .tts
+            .expect("TTS is configured");
+        assert_eq!(tts.api_key, "sk-tts");
+        assert_eq!(tts.voice, "nova");
+        assert_eq!(tts.model, "gpt-4o-mini-tts");
+        assert_eq!(tts.max_chars, 4096);
+
+        assert!(
+            Config::from_lookup(lookup(&[("TTS_API_KEY", " ")]))
+                .tts
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn parses_reply_decorations() {
+        let config = Config::from_lookup(lookup(&[
+            ("REPLY_PREFIX", "🤖 "),
+            ("REPLY_SUFFIX", "\\n\\n_AI-generated, may be wrong._"),
+        ]));
+        assert_eq!(config.reply_prefix, "🤖 ");
+        assert_eq!(config.reply_suffix, "\n\n_AI-generated, may be wrong._");
+    }
+
+    #[test]
+    fn parses_model_refresh_settings() {
+        let config = Config::from_lookup(lookup(&[
+            ("MODEL_REFRESH_RETRY_SECS", "5"),
+            ("MODEL_REFRESH_MAX_ATTEMPTS", "0"),
+            ("MODEL_REFRESH_INTERVAL_SECS", " 120 "),
+        ]));
+        assert_eq!(config.model_refresh_retry_delay, Duration::from_secs(5));
+        assert_eq!(config.model_refresh_max_attempts, 0);
+        assert_eq!(config.model_refresh_interval, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn parses_fallback_key_settings() {
+        let config = Config::from_lookup(lookup(&[
+            ("FALLBACK_OPENROUTER_KEY", " sk-or-shared "),
+            ("FALLBACK_KEY_DAILY_LIMIT", "20"),
+        ]));
+        assert_eq!(
+            config.fallback_openrouter_key.as_deref(),
+            Some("sk-or-shared")
+        );
+        assert_eq!(config.fallback_key_daily_limit, Some(20));
+
+        let unlimited = Config::from_lookup(lookup(&[("FALLBACK_KEY_DAILY_LIMIT", "0")]));
+        assert_eq!(unlimited.fallback_key_daily_limit, None);
+    }
+
+    #[test]
+    fn parses_fallback_models_in_order() {
+        let config = Config::from_lookup(lookup(&[(
+            "FALLBACK_MODELS",
+            " openai/gpt-4o-mini, x-ai/grok-4 openai/gpt-4o-mini,",
+        )]));
+        assert_eq!(
+            config.fallback_models,
+            vec!["openai/gpt-4o-mini".to_string(), "x-ai/grok-4".to_string()]
+        );
+    }
+
+    #[test]
+    fn parses_boolean_flags() {
+        assert!(Config::from_lookup(lookup(&[("AUTO_TITLE", "on")])).auto_title);
+        assert!(Config::from_lookup(lookup(&[("AUTO_TITLE", "TRUE")])).auto_title);
+        assert!(!Config::from_lookup(lookup(&[("AUTO_TITLE", "0")])).auto_title);
+        assert!(!Config::from_lookup(lookup(&[("AUTO_TITLE", " ")])).auto_title);
+    }
+}
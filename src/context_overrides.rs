@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::panic_handler::fatal_panic;
+
+/// Context lengths that replace what the model list advertises, keyed by exact model id,
+/// for models whose metadata is wrong or whose provider truncates earlier.
+#[derive(Debug, Default)]
+pub struct ContextOverrides {
+    entries: HashMap<String, u64>,
+}
+
+impl ContextOverrides {
+    /// Load the operator's JSON file; a missing or malformed file is a configuration error.
+    pub fn load(path: &str) -> Self {
+        let json = std::fs::read_to_string(path).unwrap_or_else(|err| {
+            fatal_panic(format!(
+                "failed to read context overrides file {path}: {err}"
+            ))
+        });
+        let overrides = Self::parse(&json).unwrap_or_else(|err| {
+            fatal_panic(format!("invalid context overrides file {path}: {err}"))
+        });
+        log::info!(
+            "loaded {} context length override(s) from {}",
+            overrides.entries.len(),
+            path
+        );
+        overrides
+    }
+
+    /// Parse `{"<model id>": <context length>, ...}`.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        let Value::Object(map) = value else {
+            return Err("expected a JSON object mapping model ids to context lengths".to_string());
+        };
+
+        let mut entries = HashMap::with_capacity(map.len());
+        for (model_id, length) in map {
+            let Some(length) = length.as_u64().filter(|length| *length > 0) else {
+                return Err(format!(
+                    "context length for `{model_id}` must be a positive integer"
+                ));
+            };
+            entries.insert(model_id, length);
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// The overridden context length of `model_id`, if the operator set one.
+    pub fn for_model(&self, model_id: &str) -> Option<u64> {
+        self.entries.get(model_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_exact_model_ids() {
+        let overrides =
+            ContextOverrides::parse(r#"{"openai/gpt-4o": 64000, "x-ai/grok-4": 100000}"#)
+                .expect("valid overrides");
+
+        assert_eq!(overrides.for_model("openai/gpt-4o"), Some(64_000));
+        assert_eq!(overrides.for_model("openai/gpt-4o-mini"), None);
+        assert_eq!(ContextOverrides::default().for_model("x-ai/grok-4"), None);
+
+        assert!(ContextOverrides::parse("[]").is_err());
+        assert!(ContextOverrides::parse(r#"{"openai/gpt-4o": "64k"}"#).is_err());
+        assert!(ContextOverrides::parse(r#"{"openai/gpt-4o": 0}"#).is_err());
+    }
+}
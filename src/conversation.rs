@@ -1,5 +1,9 @@
-use std::{collections::VecDeque, fmt::Display};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+};
 
+use crate::onboarding::OnboardingStep;
 use crate::openrouter_api;
 
 #[derive(Debug)]
@@ -8,13 +12,423 @@ pub struct Conversation {
     pub history: VecDeque<Message>,
     pub is_authorized: bool,
     pub is_admin: bool,
-    pub openrouter_api_key: Option<String>,
+    /// The chat was already told it isn't authorized (`UNAUTHORIZED_REPLY=once`).
+    pub unauthorized_notified: bool,
+    /// API keys by provider name; requests use the one for `openrouter_api::PROVIDER`.
+    pub provider_keys: BTreeMap<String, String>,
     pub model_id: Option<String>,
     pub system_prompt: Option<Message>,
+    /// Named system prompt sections (`/section`), sent after the system prompt in this order.
+    pub prompt_sections: Vec<PromptSection>,
     pub user_name: Option<String>,
+    /// Function/tool definitions sent with every request (validated JSON array).
+    pub tools: Option<serde_json::Value>,
+    /// Tool calls the model requested that still await `/tool_result` (in memory only).
+    pub pending_tool_calls: Option<PendingToolCalls>,
+    /// One-shot `/clear_context`: the next request omits the history (in memory only).
+    pub skip_context_once: bool,
+    /// `/regenerate`s of the current prompt in a row (in memory only); each one raises the
+    /// temperature a little, a new prompt resets it.
+    pub regenerations: u32,
+    /// The Telegram message behind the newest turn (in memory only); editing it replaces
+    /// the turn.
+    pub last_prompt: Option<LastPrompt>,
+    /// Short human-readable title generated after the first exchange.
+    pub title: Option<String>,
+    /// When set, new messages stay in memory only and are never written to `history`.
+    pub ephemeral: bool,
+    /// Also send each answer as a synthesized voice message.
+    pub voice: bool,
+    /// Let the model search the web (OpenRouter's `web` plugin) for its answers.
+    pub web_search: bool,
+    /// Reuse cached answers for identical deterministic requests.
+    pub cache: bool,
+    /// Send the model's reasoning as a separate message before the answer (never stored).
+    pub show_thinking: bool,
+    /// Drop reasoning the model wrote into the answer itself, up to a marker such as
+    /// "Final answer:" (see `CLEAN_THINKING_PATTERNS`).
+    pub clean_thinking: bool,
+    /// Explicit `/stream` choice; `None` falls back to the operator default for the chat kind.
+    pub stream: Option<bool>,
+    /// In groups, delete members' command messages once handled (`/key` is always deleted).
+    pub delete_commands: bool,
+    /// Progress of the first-run onboarding (`None` = never started).
+    pub onboarding_step: Option<OnboardingStep>,
+    /// Cleared when the bot can no longer post in the chat (kicked, blocked or muted).
+    pub is_active: bool,
+    /// Fixed offset from UTC used for the chat's daily windows (quotas reset at local midnight).
+    pub utc_offset: chrono::FixedOffset,
+    /// Whether answers are sent as replies to the triggering message.
+    pub reply_mode: ReplyMode,
+    /// Sampling parameters sent with every request; unset ones use the provider default.
+    pub sampling: SamplingParams,
+    /// `/reasoning` effort for models that support it; `None` leaves it to the model.
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// The model the history was loaded for (in memory only), to notice when requests
+    /// resolve to another one.
+    pub loaded_model: Option<LoadedModel>,
 }
 
-#[derive(Debug, Clone, Default)]
+/// The model a chat's history was loaded for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedModel {
+    pub id: String,
+    pub token_budget: u64,
+}
+
+impl LoadedModel {
+    pub fn of(model: &openrouter_api::ModelSummary) -> Self {
+        Self {
+            id: model.id.clone(),
+            token_budget: model.token_budget(),
+        }
+    }
+
+    /// Requests now go to `current` although the chat didn't pick it: the default model
+    /// changed, or the pinned one (`pinned`) left the model list.
+    pub fn drifted(&self, pinned: Option<&str>, current: &openrouter_api::ModelSummary) -> bool {
+        self.id != current.id && pinned != Some(current.id.as_str())
+    }
+
+    /// `current` has room for more history than was loaded; a smaller budget only needs
+    /// pruning, which every request does anyway.
+    pub fn needs_reload(&self, current: &openrouter_api::ModelSummary) -> bool {
+        current.token_budget() > self.token_budget
+    }
+}
+
+/// How hard a reasoning model thinks before answering: more effort is slower but better.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "low" => Some(ReasoningEffort::Low),
+            "medium" => Some(ReasoningEffort::Medium),
+            "high" => Some(ReasoningEffort::High),
+            _ => None,
+        }
+    }
+
+    /// Value of the `reasoning.effort` request field, also the stored value.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+}
+
+impl Display for ReasoningEffort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How answers relate to the message that triggered them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ReplyMode {
+    /// Threaded in groups, plain in private chats.
+    #[default]
+    Auto,
+    /// Always reply to the triggering message.
+    Thread,
+    /// Never reply to the triggering message.
+    Inline,
+}
+
+impl ReplyMode {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(ReplyMode::Auto),
+            "thread" => Some(ReplyMode::Thread),
+            "inline" => Some(ReplyMode::Inline),
+            _ => None,
+        }
+    }
+
+    /// Stored value; `Auto` is stored as NULL so the default can change later.
+    pub fn to_db(self) -> Option<&'static str> {
+        match self {
+            ReplyMode::Auto => None,
+            ReplyMode::Thread => Some("thread"),
+            ReplyMode::Inline => Some("inline"),
+        }
+    }
+
+    pub fn threads(self, is_group: bool) -> bool {
+        match self {
+            ReplyMode::Auto => is_group,
+            ReplyMode::Thread => true,
+            ReplyMode::Inline => false,
+        }
+    }
+}
+
+impl Display for ReplyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyMode::Auto => write!(f, "auto"),
+            ReplyMode::Thread => write!(f, "thread"),
+            ReplyMode::Inline => write!(f, "inline"),
+        }
+    }
+}
+
+/// Per-chat sampling parameters, each stored in its own `chats` column.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct SamplingParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+}
+
+impl Display for SamplingParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = |value: Option<f64>| value.map_or("default".to_string(), |v| v.to_string());
+        write!(
+            f,
+            "temperature {}, top_p {}, frequency_penalty {}, presence_penalty {}",
+            value(self.temperature),
+            value(self.top_p),
+            value(self.frequency_penalty),
+            value(self.presence_penalty)
+        )
+    }
+}
+
+/// One named part of the system context, e.g. `policy` or `persona`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptSection {
+    pub name: String,
+    pub text: String,
+}
+
+/// Longest accepted section name.
+pub const MAX_SECTION_NAME_CHARS: usize = 32;
+
+impl PromptSection {
+    /// Names are short identifiers so they are easy to type in `/section move`.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.chars().count() <= MAX_SECTION_NAME_CHARS
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    }
+}
+
+/// Replace the text of section `name`, or append it as the last section. Returns whether it
+/// was new.
+pub fn upsert_section(sections: &mut Vec<PromptSection>, name: &str, text: &str) -> bool {
+    match sections.iter_mut().find(|section| section.name == name) {
+        Some(section) => {
+            section.text = text.to_string();
+            false
+        }
+        None => {
+            sections.push(PromptSection {
+                name: name.to_string(),
+                text: text.to_string(),
+            });
+            true
+        }
+    }
+}
+
+/// Move section `name` to the 1-based `position` (clamped to the list). Returns false when
+/// there is no such section.
+pub fn move_section(sections: &mut Vec<PromptSection>, name: &str, position: usize) -> bool {
+    let Some(index) = sections.iter().position(|section| section.name == name) else {
+        return false;
+    };
+    let section = sections.remove(index);
+    let target = position.saturating_sub(1).min(sections.len());
+    sections.insert(target, section);
+    true
+}
+
+/// The sections as one system message, each under a `### name` heading; `None` without any.
+pub fn assemble_sections(sections: &[PromptSection]) -> Option<Message> {
+    if sections.is_empty() {
+        return None;
+    }
+
+    let text = sections
+        .iter()
+        .map(|section| format!("### {}\n{}", section.name, section.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    Some(Message {
+        role: MessageRole::System,
+        text,
+    })
+}
+
+/// Temperature assumed for the ramp when the chat hasn't set one.
+const RAMP_BASE_TEMPERATURE: f64 = 0.7;
+/// Temperature added per `/regenerate` of the same prompt.
+const RAMP_STEP: f64 = 0.15;
+/// The ramp never goes above this (an explicitly higher temperature is kept as is).
+const RAMP_MAX_TEMPERATURE: f64 = 1.3;
+
+impl SamplingParams {
+    /// Parameters for the `regenerations`-th retry of a prompt: the temperature is nudged up
+    /// so repeated retries don't keep producing the same answer. `None` when nothing changes.
+    pub fn ramped(self, regenerations: u32) -> Option<SamplingParams> {
+        if regenerations == 0 {
+            return None;
+        }
+
+        let base = self.temperature.unwrap_or(RAMP_BASE_TEMPERATURE);
+        let ramped = (base + RAMP_STEP * f64::from(regenerations)).min(RAMP_MAX_TEMPERATURE);
+        if ramped <= base {
+            return None;
+        }
+
+        Some(SamplingParams {
+            // Rounded so the footer and request logs show e.g. 1 rather than 0.9999999999999999.
+            temperature: Some((ramped * 100.0).round() / 100.0),
+            ..self
+        })
+    }
+}
+
+/// Sampling parameters with a command of their own, e.g. `/temperature 0.4`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SamplingParam {
+    Temperature,
+    TopP,
+}
+
+impl SamplingParam {
+    pub fn name(self) -> &'static str {
+        match self {
+            SamplingParam::Temperature => "temperature",
+            SamplingParam::TopP => "top_p",
+        }
+    }
+
+    fn range(self) -> std::ops::RangeInclusive<f64> {
+        match self {
+            SamplingParam::Temperature => 0.0..=2.0,
+            SamplingParam::TopP => 0.0..=1.0,
+        }
+    }
+
+    /// A value within the parameter's range; anything else (including NaN) is rejected.
+    pub fn parse_value(self, text: &str) -> Option<f64> {
+        let value: f64 = text.trim().parse().ok()?;
+        self.range().contains(&value).then_some(value)
+    }
+
+    pub fn usage(self) -> String {
+        let range = self.range();
+        format!(
+            "Usage: /{} [{:.1}-{:.1}|none]",
+            self.name(),
+            range.start(),
+            range.end()
+        )
+    }
+
+    pub fn get(self, params: &SamplingParams) -> Option<f64> {
+        match self {
+            SamplingParam::Temperature => params.temperature,
+            SamplingParam::TopP => params.top_p,
+        }
+    }
+
+    pub fn set(self, params: &mut SamplingParams, value: Option<f64>) {
+        match self {
+            SamplingParam::Temperature => params.temperature = value,
+            SamplingParam::TopP => params.top_p = value,
+        }
+    }
+}
+
+/// `/mode` presets: bundles of sampling parameters for users who don't want to tune each one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SamplingMode {
+    Creative,
+    Balanced,
+    Precise,
+}
+
+impl SamplingMode {
+    pub const ALL: [SamplingMode; 3] = [
+        SamplingMode::Creative,
+        SamplingMode::Balanced,
+        SamplingMode::Precise,
+    ];
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "creative" => Some(SamplingMode::Creative),
+            "balanced" => Some(SamplingMode::Balanced),
+            "precise" => Some(SamplingMode::Precise),
+            _ => None,
+        }
+    }
+
+    pub fn params(self) -> SamplingParams {
+        let (temperature, top_p, frequency_penalty, presence_penalty) = match self {
+            SamplingMode::Creative => (1.0, 0.95, 0.2, 0.5),
+            SamplingMode::Balanced => (0.7, 1.0, 0.0, 0.0),
+            SamplingMode::Precise => (0.2, 0.9, 0.0, 0.0),
+        };
+        SamplingParams {
+            temperature: Some(temperature),
+            top_p: Some(top_p),
+            frequency_penalty: Some(frequency_penalty),
+            presence_penalty: Some(presence_penalty),
+        }
+    }
+
+    /// The preset the parameters currently match, if any (none after individual overrides).
+    pub fn matching(params: SamplingParams) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.params() == params)
+    }
+}
+
+impl Display for SamplingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SamplingMode::Creative => write!(f, "creative"),
+            SamplingMode::Balanced => write!(f, "balanced"),
+            SamplingMode::Precise => write!(f, "precise"),
+        }
+    }
+}
+
+/// A prompt as it was sent, to recognize its turn when the message is edited.
+#[derive(Debug, Clone)]
+pub struct LastPrompt {
+    pub message_id: i32,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingToolCalls {
+    /// The user prompt that triggered the tool calls; persisted once the model answers.
+    pub user_message: Message,
+    /// Calls already answered in earlier rounds, replayed with every follow-up request.
+    pub completed: Vec<(openrouter_api::ToolCall, String)>,
+    /// Calls still waiting for an output.
+    pub tool_calls: Vec<openrouter_api::ToolCall>,
+}
+
+/// Start of the system message that replaces summarized turns (`HISTORY_SUMMARIZE_TURNS`).
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Message {
     pub role: MessageRole,
     pub text: String,
@@ -27,9 +441,54 @@ pub enum MessageRole {
     System = 0,
     User = 1,
     Assistant = 2,
+    /// A tool call the model made and the output the client supplied, stored as JSON (see
+    /// `openrouter_api::tool_turn`) between the prompt and the answer that used it.
+    Tool = 3,
+}
+
+/// Index at which the last `turns` turns of a history with these roles (oldest first) start:
+/// right after the answer that ended the turn before them. A turn ends with an assistant
+/// message; messages after the last answer belong to the last turn. A leading summary
+/// (`HISTORY_SUMMARIZE_TURNS`) stands for older turns and is never part of the range. `None`
+/// when the history has fewer turns.
+pub fn last_turns_start(roles: &[MessageRole], turns: usize) -> Option<usize> {
+    let answers: Vec<usize> = roles
+        .iter()
+        .enumerate()
+        .filter(|(_, role)| **role == MessageRole::Assistant)
+        .map(|(idx, _)| idx)
+        .collect();
+    if turns == 0 || answers.len() < turns {
+        return None;
+    }
+    Some(answers.len().checked_sub(turns + 1).map_or_else(
+        || {
+            roles
+                .iter()
+                .take_while(|role| **role == MessageRole::System)
+                .count()
+        },
+        |before| answers[before] + 1,
+    ))
 }
 
 impl Conversation {
+    /// The chat's own key for the provider requests currently go to.
+    pub fn api_key(&self) -> Option<&str> {
+        self.provider_keys
+            .get(openrouter_api::PROVIDER)
+            .map(String::as_str)
+    }
+
+    pub fn set_api_key(&mut self, key: Option<String>) {
+        match key {
+            Some(key) => self
+                .provider_keys
+                .insert(openrouter_api::PROVIDER.to_string(), key),
+            None => self.provider_keys.remove(openrouter_api::PROVIDER),
+        };
+    }
+
     pub fn add_messages<I>(&mut self, messages: I)
     where
         I: IntoIterator<Item = Message>,
@@ -39,6 +498,105 @@ impl Conversation {
         }
     }
 
+    /// Features the chat has turned on that `capabilities` says the model can't honor.
+    pub fn unsupported_features(
+        &self,
+        capabilities: &openrouter_api::ModelCapabilities,
+    ) -> Vec<&'static str> {
+        let mut unsupported = Vec::new();
+        if self.tools.is_some() && !capabilities.tools {
+            unsupported.push("tools (/tools)");
+        }
+        if self.show_thinking && !capabilities.reasoning {
+            unsupported.push("reasoning display (/showthinking)");
+        }
+        if self.reasoning_effort.is_some() && !capabilities.reasoning {
+            unsupported.push("reasoning effort (/reasoning)");
+        }
+        unsupported
+    }
+
+    /// Remove and return the trailing user/assistant pair, if the history ends with one.
+    pub fn pop_last_turn(&mut self) -> Option<(Message, Message)> {
+        let len = self.history.len();
+        if len < 2
+            || self.history[len - 1].role != MessageRole::Assistant
+            || self.history[len - 2].role != MessageRole::User
+        {
+            return None;
+        }
+
+        let assistant = self
+            .history
+            .pop_back()
+            .expect("history has an assistant message");
+        let user = self.history.pop_back().expect("history has a user message");
+        Some((user, assistant))
+    }
+
+    /// Like [`Self::pop_last_turn`], but only when that turn answered `message_id` as it was
+    /// before the edit; older or unanswered prompts leave the history alone.
+    pub fn pop_edited_turn(&mut self, message_id: i32) -> Option<(Message, Message)> {
+        let last_prompt = self.last_prompt.as_ref()?;
+        let len = self.history.len();
+        if last_prompt.message_id != message_id
+            || len < 2
+            || self.history[len - 2].text != last_prompt.text
+        {
+            return None;
+        }
+        self.pop_last_turn()
+    }
+
+    /// Drop the last `turns` turns (see [`last_turns_start`]) and return how many messages
+    /// that removed; `None`, removing nothing, when the history has fewer turns.
+    pub fn forget_last_turns(&mut self, turns: usize) -> Option<usize> {
+        let roles: Vec<MessageRole> = self.history.iter().map(|m| m.role).collect();
+        let start = last_turns_start(&roles, turns)?;
+        let removed = self.history.len() - start;
+        self.history.truncate(start);
+        Some(removed)
+    }
+
+    /// Whether the history is too long for `token_budget`, so pruning would drop messages.
+    pub fn exceeds_token_budget(&self, token_budget: u64) -> bool {
+        let history_tokens: u64 = self
+            .history
+            .iter()
+            .map(|m| openrouter_api::estimate_message_tokens(&m.text))
+            .sum();
+        openrouter_api::PER_PROMPT_OVERHEAD + history_tokens > token_budget
+    }
+
+    /// How many of the oldest messages a summary of up to `turns` turns replaces: an earlier
+    /// summary at the front and everything up to the `turns`-th answer after it. Zero when
+    /// no answer is there to summarize.
+    pub fn summarizable_prefix(&self, turns: usize) -> usize {
+        self.history
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.role == MessageRole::Assistant)
+            .take(turns)
+            .last()
+            .map_or(0, |(idx, _)| idx + 1)
+    }
+
+    /// Replace the oldest `count` messages with one system message holding `summary`, and
+    /// return that message.
+    pub fn replace_with_summary(&mut self, count: usize, summary: &str) -> Message {
+        assert!(
+            count <= self.history.len(),
+            "summary replaces more messages than the history has"
+        );
+        let message = Message {
+            role: MessageRole::System,
+            text: format!("{SUMMARY_PREFIX}{}", summary.trim()),
+        };
+        self.history.drain(..count);
+        self.history.push_front(message.clone());
+        message
+    }
+
     pub fn prune_to_token_budget(&mut self, token_budget: u64) {
         // If no budget remains, drop all stored history so the request can proceed.
         if token_budget == 0 {
@@ -46,15 +604,25 @@ impl Conversation {
             return;
         }
 
+        // Each message is estimated once; dropping one just takes its share off the total.
+        let mut message_tokens: VecDeque<u64> = self
+            .history
+            .iter()
+            .map(|m| openrouter_api::estimate_message_tokens(&m.text))
+            .collect();
         let mut estimated_tokens =
-            openrouter_api::estimate_tokens(self.history.iter().map(|m| m.text.as_str()));
+            openrouter_api::PER_PROMPT_OVERHEAD + message_tokens.iter().sum::<u64>();
 
         while estimated_tokens > token_budget {
-            if self.history.pop_front().is_none() {
+            // A summary stands in for everything before it, so the turns after it go first.
+            let idx =
+                usize::from(self.history.len() > 1 && self.history[0].role == MessageRole::System);
+            if self.history.remove(idx).is_none() {
                 break;
             }
-            estimated_tokens =
-                openrouter_api::estimate_tokens(self.history.iter().map(|m| m.text.as_str()));
+            estimated_tokens -= message_tokens
+                .remove(idx)
+                .expect("one estimate per history message");
         }
     }
 }
@@ -65,6 +633,7 @@ impl Display for MessageRole {
             MessageRole::System => write!(f, "system"),
             MessageRole::User => write!(f, "user"),
             MessageRole::Assistant => write!(f, "assistant"),
+            MessageRole::Tool => write!(f, "tool"),
         }
     }
 }
@@ -77,7 +646,137 @@ impl TryFrom<u8> for MessageRole {
             0 => Ok(MessageRole::System),
             1 => Ok(MessageRole::User),
             2 => Ok(MessageRole::Assistant),
+            3 => Ok(MessageRole::Tool),
             _ => Err(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str, context_length: u64) -> openrouter_api::ModelSummary {
+        openrouter_api::ModelSummary {
+            id: id.to_string(),
+            name: id.to_string(),
+            context_length,
+            max_completion_tokens: 1_000,
+            capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+            pricing: None,
+        }
+    }
+
+    #[test]
+    fn detects_default_model_drift() {
+        let loaded = LoadedModel::of(&model("old/default", 8_000));
+
+        // The operator's default moved to a bigger model: drift, and room for more history.
+        let new_default = model("new/default", 128_000);
+        assert!(loaded.drifted(None, &new_default));
+        assert!(loaded.needs_reload(&new_default));
+
+        // The chat picked the new model itself.
+        assert!(!loaded.drifted(Some("new/default"), &new_default));
+
+        // The pinned model left the list, so requests fall back to a smaller default.
+        let small_default = model("small/default", 4_000);
+        assert!(loaded.drifted(Some("old/default"), &small_default));
+        assert!(!loaded.needs_reload(&small_default));
+
+        // Same model, but a refreshed list advertises a larger context.
+        let grown = model("old/default", 16_000);
+        assert!(!loaded.drifted(None, &grown));
+        assert!(loaded.needs_reload(&grown));
+    }
+
+    #[test]
+    fn finds_where_the_last_turns_start() {
+        use MessageRole::{Assistant, System, Tool, User};
+        let roles = [
+            User, Assistant, User, Tool, Assistant, User, Assistant, User,
+        ];
+
+        // The unanswered prompt at the end goes with the last turn.
+        assert_eq!(last_turns_start(&roles, 1), Some(5));
+        assert_eq!(last_turns_start(&roles, 2), Some(2));
+        assert_eq!(last_turns_start(&roles, 3), Some(0));
+        assert_eq!(last_turns_start(&roles, 4), None);
+        assert_eq!(last_turns_start(&roles, 0), None);
+        assert_eq!(last_turns_start(&[User], 1), None);
+
+        // Forgetting every turn still keeps the summary of the older ones.
+        assert_eq!(last_turns_start(&[System, User, Assistant], 1), Some(1));
+    }
+
+    #[test]
+    fn orders_sections_as_edited() {
+        let mut sections = Vec::new();
+        assert!(upsert_section(&mut sections, "policy", "Be safe."));
+        assert!(upsert_section(
+            &mut sections,
+            "persona",
+            "You are a pirate."
+        ));
+        assert!(upsert_section(&mut sections, "format", "Use short lines."));
+        assert!(!upsert_section(&mut sections, "policy", "Be kind."));
+
+        assert!(move_section(&mut sections, "format", 1));
+        assert!(move_section(&mut sections, "policy", 99));
+        assert!(!move_section(&mut sections, "tools", 1));
+        let names: Vec<&str> = sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["format", "persona", "policy"]);
+
+        let assembled = assemble_sections(&sections).expect("sections assembled");
+        assert_eq!(assembled.role, MessageRole::System);
+        assert_eq!(
+            assembled.text,
+            "### format\nUse short lines.\n\n### persona\nYou are a pirate.\n\n### policy\nBe kind."
+        );
+        assert!(assemble_sections(&[]).is_none());
+
+        assert!(PromptSection::is_valid_name("tone_2"));
+        assert!(!PromptSection::is_valid_name("two words"));
+        assert!(!PromptSection::is_valid_name(""));
+    }
+
+    #[test]
+    fn ramps_temperature_per_regeneration_up_to_the_cap() {
+        let precise = SamplingMode::Precise.params();
+        assert_eq!(precise.ramped(0), None);
+        assert_eq!(precise.ramped(1).and_then(|p| p.temperature), Some(0.35));
+        assert_eq!(precise.ramped(1).and_then(|p| p.top_p), precise.top_p);
+        assert_eq!(
+            precise.ramped(100).and_then(|p| p.temperature),
+            Some(RAMP_MAX_TEMPERATURE)
+        );
+
+        let unset = SamplingParams::default();
+        assert_eq!(unset.ramped(2).and_then(|p| p.temperature), Some(1.0));
+
+        let hot = SamplingParams {
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+        assert_eq!(hot.ramped(3), None);
+    }
+
+    #[test]
+    fn sampling_params_are_range_checked() {
+        let temperature = SamplingParam::Temperature;
+        assert_eq!(temperature.parse_value(" 1.2 "), Some(1.2));
+        assert_eq!(temperature.parse_value("2"), Some(2.0));
+        assert_eq!(temperature.parse_value("2.01"), None);
+        assert_eq!(temperature.parse_value("-0.1"), None);
+        assert_eq!(temperature.parse_value("NaN"), None);
+        assert_eq!(temperature.parse_value("warm"), None);
+        assert_eq!(SamplingParam::TopP.parse_value("1.5"), None);
+        assert_eq!(SamplingParam::TopP.usage(), "Usage: /top_p [0.0-1.0|none]");
+
+        let mut params = SamplingMode::Balanced.params();
+        SamplingParam::TopP.set(&mut params, Some(0.5));
+        assert_eq!(SamplingParam::TopP.get(&params), Some(0.5));
+        assert_eq!(params.temperature, Some(0.7));
+        assert_eq!(SamplingMode::matching(params), None);
+    }
+}
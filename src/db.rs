@@ -1,15 +1,24 @@
 use crate::conversation::{self, Conversation, Message, MessageRole};
+use crate::onboarding::OnboardingStep;
 use crate::openrouter_api;
 use crate::panic_handler::fatal_panic;
+use crate::schedule::{self, Recurrence, Schedule};
+use crate::timezone;
 use teloxide::types::ChatId;
 use tokio_rusqlite::Connection;
-use tokio_rusqlite::rusqlite::{Connection as SyncConnection, Error as SqliteError, params};
+use tokio_rusqlite::rusqlite::{
+    Connection as SyncConnection, Error as SqliteError, OptionalExtension, ToSql, params,
+};
 
-const SCHEMA_VERSION: i32 = 1;
+const SCHEMA_VERSION: i32 = 28;
 
 pub async fn init_db() -> Connection {
     let db_path = std::env::var("SQLITE_PATH").unwrap_or_else(|_| "data/db.sqlite".to_string());
+    open_db(&db_path).await
+}
 
+/// Open (creating if needed) and migrate the database at `db_path`; `:memory:` works too.
+pub async fn open_db(db_path: &str) -> Connection {
     // Ensure parent directory exists
     if let Some(parent) = std::path::Path::new(&db_path).parent()
         && !parent.as_os_str().is_empty()
@@ -17,7 +26,7 @@ pub async fn init_db() -> Connection {
         std::fs::create_dir_all(parent).expect("failed to create parent directory");
     }
 
-    let conn = Connection::open(&db_path)
+    let conn = Connection::open(db_path)
         .await
         .expect("failed to open database");
 
@@ -29,20 +38,7 @@ pub async fn init_db() -> Connection {
             _ => log::warn!("DB_ENCRYPTION_KEY not set; database will be unencrypted"),
         }
 
-        // Initialize database schema if needed and validate version.
-        let version = get_schema_version(conn);
-        if version == 0 {
-            init_schema(conn);
-            set_schema_version(conn, SCHEMA_VERSION);
-            log::info!("Initialized database schema version {}", SCHEMA_VERSION);
-        } else if version == SCHEMA_VERSION {
-            log::info!("Database schema version {} detected", version);
-        } else {
-            fatal_panic(format!(
-                "Unsupported database schema version {} (expected {})",
-                version, SCHEMA_VERSION
-            ));
-        }
+        prepare_schema(conn);
 
         Ok::<(), SqliteError>(())
     })
@@ -52,6 +48,41 @@ pub async fn init_db() -> Connection {
     conn
 }
 
+/// Initialize the schema if needed, then migrate it up to the current version one step at a
+/// time. Each step commits together with its `user_version`, so a step that fails (or a crash)
+/// leaves the database at the previous version and the next start retries just that step.
+fn prepare_schema(conn: &SyncConnection) {
+    let mut version = get_schema_version(conn);
+    if version == 0 {
+        version = 1;
+        in_schema_transaction(conn, version, || init_schema(conn));
+        log::info!("Initialized database schema version {}", version);
+    } else if version > SCHEMA_VERSION {
+        fatal_panic(format!(
+            "Unsupported database schema version {} (expected at most {})",
+            version, SCHEMA_VERSION
+        ));
+    } else {
+        log::info!("Database schema version {} detected", version);
+    }
+
+    while version < SCHEMA_VERSION {
+        in_schema_transaction(conn, version + 1, || migrate_schema(conn, version));
+        version += 1;
+        log::info!("Migrated database schema to version {}", version);
+    }
+}
+
+/// Run `step` and set the schema version to `version` in one transaction.
+fn in_schema_transaction(conn: &SyncConnection, version: i32, step: impl FnOnce()) {
+    let tx = conn
+        .unchecked_transaction()
+        .expect("failed to start schema transaction");
+    step();
+    set_schema_version(&tx, version);
+    tx.commit().expect("failed to commit schema transaction");
+}
+
 fn init_schema(conn: &SyncConnection) {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS history (
@@ -79,6 +110,307 @@ fn init_schema(conn: &SyncConnection) {
     .expect("failed to create chats table");
 }
 
+/// Apply the single migration step from `from_version` to `from_version + 1`.
+fn migrate_schema(conn: &SyncConnection, from_version: i32) {
+    match from_version {
+        1 => {
+            conn.execute("ALTER TABLE chats ADD COLUMN tools TEXT;", [])
+                .expect("failed to add tools column");
+        }
+        2 => {
+            conn.execute("ALTER TABLE chats ADD COLUMN title TEXT;", [])
+                .expect("failed to add title column");
+        }
+        3 => {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS request_log (
+                    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+                    chat_id             INTEGER NOT NULL,
+                    created_at          INTEGER NOT NULL,
+                    model_id            TEXT NOT NULL,
+                    prompt_tokens       INTEGER NOT NULL,
+                    completion_tokens   INTEGER NOT NULL,
+                    cost                REAL NOT NULL,
+                    latency_ms          INTEGER NOT NULL,
+                    error               TEXT
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create request_log table");
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS request_log_chat_id ON request_log (chat_id, id);",
+                [],
+            )
+            .expect("failed to create request_log index");
+        }
+        4 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN ephemeral INTEGER NOT NULL DEFAULT 0 CHECK (ephemeral IN (0, 1));",
+                [],
+            )
+            .expect("failed to add ephemeral column");
+        }
+        5 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN voice INTEGER NOT NULL DEFAULT 0 CHECK (voice IN (0, 1));",
+                [],
+            )
+            .expect("failed to add voice column");
+        }
+        6 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN utc_offset_minutes INTEGER NOT NULL DEFAULT 0;",
+                [],
+            )
+            .expect("failed to add utc_offset_minutes column");
+        }
+        7 => {
+            // Rows written before timestamps existed count as written now, so archival
+            // doesn't sweep up the whole existing history on the first run.
+            conn.execute(
+                "ALTER TABLE history ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+                [],
+            )
+            .expect("failed to add history created_at column");
+            conn.execute("UPDATE history SET created_at = unixepoch();", [])
+                .expect("failed to backfill history created_at");
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS history_archive (
+                    id          INTEGER PRIMARY KEY NOT NULL,
+                    chat_id     INTEGER NOT NULL,
+                    role        INTEGER NOT NULL,
+                    text        TEXT NOT NULL,
+                    created_at  INTEGER NOT NULL,
+                    archived_at INTEGER NOT NULL
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create history_archive table");
+        }
+        8 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN cache INTEGER NOT NULL DEFAULT 0 CHECK (cache IN (0, 1));",
+                [],
+            )
+            .expect("failed to add cache column");
+        }
+        9 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN is_active INTEGER NOT NULL DEFAULT 1 CHECK (is_active IN (0, 1));",
+                [],
+            )
+            .expect("failed to add is_active column");
+        }
+        10 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN reply_mode TEXT CHECK (reply_mode IN ('thread', 'inline'));",
+                [],
+            )
+            .expect("failed to add reply_mode column");
+        }
+        11 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN show_thinking INTEGER NOT NULL DEFAULT 0 CHECK (show_thinking IN (0, 1));",
+                [],
+            )
+            .expect("failed to add show_thinking column");
+        }
+        12 => {
+            // NULL means the chat never chose, so the operator default for its kind applies.
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN stream INTEGER CHECK (stream IN (0, 1));",
+                [],
+            )
+            .expect("failed to add stream column");
+        }
+        13 => {
+            // Keys move to one row per provider so switching providers keeps the others.
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS provider_keys (
+                    chat_id     INTEGER NOT NULL,
+                    provider    TEXT NOT NULL,
+                    key         TEXT NOT NULL,
+                    PRIMARY KEY (chat_id, provider)
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create provider_keys table");
+            conn.execute(
+                "INSERT INTO provider_keys (chat_id, provider, key)
+                 SELECT chat_id, 'openrouter', openrouter_api_key FROM chats
+                 WHERE openrouter_api_key IS NOT NULL AND openrouter_api_key != '';",
+                [],
+            )
+            .expect("failed to copy OpenRouter keys to provider_keys");
+            conn.execute("ALTER TABLE chats DROP COLUMN openrouter_api_key;", [])
+                .expect("failed to drop openrouter_api_key column");
+        }
+        14 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN delete_commands INTEGER NOT NULL DEFAULT 0 CHECK (delete_commands IN (0, 1));",
+                [],
+            )
+            .expect("failed to add delete_commands column");
+        }
+        15 => {
+            // NULL means onboarding never started.
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN onboarding_step TEXT CHECK (onboarding_step IN ('language', 'model', 'done'));",
+                [],
+            )
+            .expect("failed to add onboarding_step column");
+        }
+        16 => {
+            // NULL leaves the parameter to the provider default.
+            for column in [
+                "temperature",
+                "top_p",
+                "frequency_penalty",
+                "presence_penalty",
+            ] {
+                conn.execute(&format!("ALTER TABLE chats ADD COLUMN {column} REAL;"), [])
+                    .unwrap_or_else(|err| panic!("failed to add {column} column: {err}"));
+            }
+        }
+        17 => {
+            conn.execute(
+                "CREATE TABLE prompt_sections (
+                    chat_id     INTEGER NOT NULL,
+                    name        TEXT NOT NULL,
+                    position    INTEGER NOT NULL,
+                    text        TEXT NOT NULL,
+                    PRIMARY KEY (chat_id, name)
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create prompt_sections table");
+        }
+        18 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN unauthorized_notified INTEGER NOT NULL DEFAULT 0 CHECK (unauthorized_notified IN (0, 1));",
+                [],
+            )
+            .expect("failed to add unauthorized_notified column");
+        }
+        19 => {
+            // `time_of_day` is local `HH:MM` in the chat's offset; `next_run` is unix seconds.
+            conn.execute(
+                "CREATE TABLE schedules (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    chat_id     INTEGER NOT NULL,
+                    recurrence  TEXT NOT NULL,
+                    time_of_day TEXT NOT NULL,
+                    prompt      TEXT NOT NULL,
+                    next_run    INTEGER NOT NULL
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create schedules table");
+            conn.execute(
+                "CREATE INDEX schedules_next_run ON schedules (next_run);",
+                [],
+            )
+            .expect("failed to create schedules index");
+        }
+        20 => {
+            // Who wrote a group message, for `/tldr`; NULL for answers and private chats.
+            conn.execute("ALTER TABLE history ADD COLUMN sender_name TEXT;", [])
+                .expect("failed to add sender_name column");
+        }
+        21 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN clean_thinking INTEGER NOT NULL DEFAULT 0 CHECK (clean_thinking IN (0, 1));",
+                [],
+            )
+            .expect("failed to add clean_thinking column");
+        }
+        22 => {
+            // `/config set` overrides, by environment variable name; they win over the env.
+            conn.execute(
+                "CREATE TABLE config (
+                    name TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create config table");
+        }
+        23 => {
+            // Lifetime totals per chat for `/usage`, kept whether or not REQUEST_LOG is on.
+            conn.execute(
+                "CREATE TABLE usage (
+                    chat_id INTEGER PRIMARY KEY,
+                    requests INTEGER NOT NULL,
+                    prompt_tokens INTEGER NOT NULL,
+                    completion_tokens INTEGER NOT NULL,
+                    total_tokens INTEGER NOT NULL,
+                    cost REAL NOT NULL,
+                    last_model_id TEXT NOT NULL,
+                    last_prompt_tokens INTEGER NOT NULL,
+                    last_completion_tokens INTEGER NOT NULL,
+                    last_total_tokens INTEGER NOT NULL,
+                    last_cost REAL NOT NULL
+                ) STRICT;",
+                [],
+            )
+            .expect("failed to create usage table");
+        }
+        24 => {
+            // Each message's token estimate, so loading history sums stored counts instead of
+            // re-estimating the whole window for every message it adds.
+            conn.execute(
+                "ALTER TABLE history ADD COLUMN tokens INTEGER NOT NULL DEFAULT 0;",
+                [],
+            )
+            .expect("failed to add tokens column");
+
+            let rows: Vec<(i64, String)> = {
+                let mut stmt = conn
+                    .prepare("SELECT id, text FROM history")
+                    .expect("failed to prepare history scan");
+                stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .expect("failed to scan history")
+                    .map(|row| row.expect("failed to read history row"))
+                    .collect()
+            };
+            for (id, text) in rows {
+                conn.execute(
+                    "UPDATE history SET tokens = ?2 WHERE id = ?1",
+                    params![id, openrouter_api::estimate_message_tokens(&text) as i64],
+                )
+                .expect("failed to backfill message tokens");
+            }
+        }
+        25 => {
+            // On by default: every request used to include the web plugin.
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN web_search INTEGER NOT NULL DEFAULT 1 CHECK (web_search IN (0, 1));",
+                [],
+            )
+            .expect("failed to add web_search column");
+        }
+        26 => {
+            conn.execute(
+                "ALTER TABLE chats ADD COLUMN reasoning_effort TEXT CHECK (reasoning_effort IN ('low', 'medium', 'high'));",
+                [],
+            )
+            .expect("failed to add reasoning_effort column");
+        }
+        27 => {
+            // Archived group messages keep their author, like `history` rows do.
+            conn.execute(
+                "ALTER TABLE history_archive ADD COLUMN sender_name TEXT;",
+                [],
+            )
+            .expect("failed to add history_archive sender_name column");
+        }
+        _ => fatal_panic(format!(
+            "no migration defined from schema version {}",
+            from_version
+        )),
+    }
+}
+
 fn get_schema_version(conn: &SyncConnection) -> i32 {
     conn.query_row("PRAGMA user_version", [], |row| row.get(0))
         .unwrap_or_default()
@@ -89,75 +421,147 @@ fn set_schema_version(conn: &SyncConnection, version: i32) {
         .expect("failed to set schema version");
 }
 
+/// Whether the chat has a row, i.e. the bot has seen it before.
+pub async fn chat_exists(db: &Connection, chat_id: ChatId) -> bool {
+    db.call(move |conn| {
+        let exists = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM chats WHERE chat_id = ?1)",
+                [chat_id.0],
+                |row| row.get(0),
+            )
+            .expect("failed to look up chat row");
+        Ok::<bool, SqliteError>(exists)
+    })
+    .await
+    .expect("failed to check chat existence")
+}
+
 pub async fn load_conversation(db: &Connection, chat_id: ChatId) -> Conversation {
     let chat_id_val = chat_id.0;
 
     db.call(move |conn| {
-            // Fetch exactly one chat row; panic if multiple rows are found.
-            let (is_authorized, is_admin, openrouter_api_key, model_id, system_prompt, user_name) = conn
-                .query_row(
-                    "SELECT is_authorized, is_admin, openrouter_api_key, model_id, system_prompt, user_name FROM chats WHERE chat_id = ?1",
-                    [chat_id_val],
-                    |row| {
-                        Ok((
-                            row.get::<_, bool>(0)?,
-                            row.get::<_, bool>(1)?,
-                            row.get::<_, Option<String>>(2)?,
-                            row.get::<_, Option<String>>(3)?,
-                            row.get::<_, Option<String>>(4)?,
-                            row.get::<_, Option<String>>(5)?,
-                        ))
-                    },
-                )
-                .or_else(|err| {
-                    if matches!(err, tokio_rusqlite::rusqlite::Error::QueryReturnedNoRows) {
-                        let r = conn
-                            .execute(
-                                "INSERT INTO chats (chat_id, is_authorized, is_admin, openrouter_api_key, model_id, system_prompt, user_name) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-                                params![
-                                    chat_id_val,
-                                    false,
-                                    false,
-                                    Option::<String>::None,
-                                    Option::<String>::None,
-                                    Option::<String>::None,
-                                    Option::<String>::None
-                                ],
-                            )
-                            .expect("failed to insert chat row");
-                        if r != 1 {
-                            fatal_panic(format!(
-                                "failed to insert chat row for chat_id {}",
-                                chat_id.0
-                            ));
-                        }
-                        Ok((false, false, None, None, None, None))
-                    } else {
-                        Err(err)
-                    }
-                })
-                .expect("failed to fetch chat row");
+        // New chats start unauthorized with every setting unset.
+        let inserted = conn
+            .execute(
+                "INSERT OR IGNORE INTO chats (chat_id) VALUES (?1)",
+                [chat_id_val],
+            )
+            .expect("failed to insert chat row");
+        if inserted == 1 {
+            log::info!("Created chat row for chat_id {}", chat_id_val);
+        }
 
-            let system_prompt = system_prompt
-                .filter(|s| !s.is_empty())
-                .map(|text| conversation::Message {
-                    role: MessageRole::System,
-                    text,
-                });
+        let conversation = conn
+            .query_row(
+                "SELECT is_authorized, is_admin, model_id, system_prompt, user_name, tools, title, ephemeral, voice, web_search, utc_offset_minutes, cache, is_active, reply_mode, show_thinking, clean_thinking, stream, delete_commands, onboarding_step, temperature, top_p, frequency_penalty, presence_penalty, reasoning_effort, unauthorized_notified
+                 FROM chats WHERE chat_id = ?1",
+                [chat_id_val],
+                |row| {
+                    let system_prompt = row
+                        .get::<_, Option<String>>("system_prompt")?
+                        .filter(|s| !s.is_empty())
+                        .map(|text| conversation::Message {
+                            role: MessageRole::System,
+                            text,
+                        });
+                    let tools = row.get::<_, Option<String>>("tools")?.map(|json| {
+                        serde_json::from_str(&json).expect("stored tools JSON is invalid")
+                    });
+
+                    Ok(Conversation {
+                        chat_id: chat_id_val,
+                        history: Default::default(),
+                        is_authorized: row.get("is_authorized")?,
+                        is_admin: row.get("is_admin")?,
+                        unauthorized_notified: row.get("unauthorized_notified")?,
+                        provider_keys: Default::default(),
+                        model_id: row.get("model_id")?,
+                        system_prompt,
+                        prompt_sections: Vec::new(),
+                        user_name: row.get("user_name")?,
+                        tools,
+                        pending_tool_calls: None,
+                        skip_context_once: false,
+                        regenerations: 0,
+                        last_prompt: None,
+                        title: row.get("title")?,
+                        ephemeral: row.get("ephemeral")?,
+                        voice: row.get("voice")?,
+                        web_search: row.get("web_search")?,
+                        cache: row.get("cache")?,
+                        is_active: row.get("is_active")?,
+                        show_thinking: row.get("show_thinking")?,
+                        clean_thinking: row.get("clean_thinking")?,
+                        stream: row.get("stream")?,
+                        delete_commands: row.get("delete_commands")?,
+                        onboarding_step: row.get::<_, Option<String>>("onboarding_step")?.map(
+                            |step| {
+                                OnboardingStep::parse(&step)
+                                    .expect("stored onboarding_step is invalid")
+                            },
+                        ),
+                        reply_mode: row
+                            .get::<_, Option<String>>("reply_mode")?
+                            .map(|mode| {
+                                conversation::ReplyMode::parse(&mode)
+                                    .expect("stored reply_mode is invalid")
+                            })
+                            .unwrap_or_default(),
+                        utc_offset: timezone::offset_from_minutes(
+                            row.get("utc_offset_minutes")?,
+                        ),
+                        sampling: conversation::SamplingParams {
+                            temperature: row.get("temperature")?,
+                            top_p: row.get("top_p")?,
+                            frequency_penalty: row.get("frequency_penalty")?,
+                            presence_penalty: row.get("presence_penalty")?,
+                        },
+                        reasoning_effort: row
+                            .get::<_, Option<String>>("reasoning_effort")?
+                            .map(|effort| {
+                                conversation::ReasoningEffort::parse(&effort)
+                                    .expect("stored reasoning_effort is invalid")
+                            }),
+                        loaded_model: None,
+                    })
+                },
+            )
+            .expect("failed to fetch chat row");
+
+        let mut stmt = conn
+            .prepare("SELECT provider, key FROM provider_keys WHERE chat_id = ?1")
+            .expect("failed to prepare provider key lookup statement");
+        let rows = stmt
+            .query_map([chat_id_val], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("failed to query provider keys");
+        let mut conversation = conversation;
+        for row in rows {
+            let (provider, key): (String, String) = row.expect("failed to read provider key row");
+            conversation.provider_keys.insert(provider, key);
+        }
 
-            Ok::<Conversation, SqliteError>(Conversation {
-                chat_id: chat_id_val,
-                history: Default::default(),
-                is_authorized,
-                is_admin,
-                openrouter_api_key,
-                model_id,
-                system_prompt,
-                user_name,
+        let mut stmt = conn
+            .prepare("SELECT name, text FROM prompt_sections WHERE chat_id = ?1 ORDER BY position")
+            .expect("failed to prepare prompt section lookup statement");
+        let rows = stmt
+            .query_map([chat_id_val], |row| {
+                Ok(conversation::PromptSection {
+                    name: row.get(0)?,
+                    text: row.get(1)?,
+                })
             })
-        })
-        .await
-        .expect("failed to load conversation")
+            .expect("failed to query prompt sections");
+        for row in rows {
+            conversation
+                .prompt_sections
+                .push(row.expect("failed to read prompt section row"));
+        }
+
+        Ok::<Conversation, SqliteError>(conversation)
+    })
+    .await
+    .expect("failed to load conversation")
 }
 
 pub async fn load_history(db: &Connection, conversation: &mut Conversation, token_budget: u64) {
@@ -165,17 +569,20 @@ pub async fn load_history(db: &Connection, conversation: &mut Conversation, toke
 
     let chat_id = conversation.chat_id;
 
-    let messages: Vec<(u8, String)> = db
+    let messages: Vec<(u8, String, u64)> = db
         .call(move |conn| {
             let mut stmt = conn
-                .prepare("SELECT role, text FROM history WHERE chat_id = ?1 ORDER BY id DESC")
+                .prepare(
+                    "SELECT role, text, tokens FROM history WHERE chat_id = ?1 ORDER BY id DESC",
+                )
                 .expect("failed to prepare history lookup statement");
 
             let rows = stmt
                 .query_map([chat_id], |row| {
                     let role: u8 = row.get(0)?;
                     let text: String = row.get(1)?;
-                    Ok((role, text))
+                    let tokens: i64 = row.get(2)?;
+                    Ok((role, text, tokens as u64))
                 })
                 .expect("failed to query history rows");
 
@@ -183,18 +590,18 @@ pub async fn load_history(db: &Connection, conversation: &mut Conversation, toke
             for row in rows {
                 collected.push(row.expect("failed to read history row"));
             }
-            Ok::<Vec<(u8, String)>, SqliteError>(collected)
+            Ok::<Vec<(u8, String, u64)>, SqliteError>(collected)
         })
         .await
         .expect("failed to load history rows");
 
-    for (role_raw, text) in messages {
+    let mut estimated_tokens = openrouter_api::PER_PROMPT_OVERHEAD;
+    for (role_raw, text, tokens) in messages {
         let role = MessageRole::try_from(role_raw).expect("invalid message role");
         conversation
             .history
             .push_front(conversation::Message { role, text });
-        let estimated_tokens =
-            openrouter_api::estimate_tokens(conversation.history.iter().map(|m| m.text.as_str()));
+        estimated_tokens += tokens;
         if estimated_tokens > token_budget {
             break;
         }
@@ -204,16 +611,36 @@ pub async fn load_history(db: &Connection, conversation: &mut Conversation, toke
 pub async fn add_messages<I>(db: &Connection, chat_id: ChatId, messages: I)
 where
     I: IntoIterator<Item = Message>,
+{
+    add_messages_from(db, chat_id, messages, None).await;
+}
+
+/// Like [`add_messages`], recording `sender_name` (a group member) as their author.
+pub async fn add_messages_from<I>(
+    db: &Connection,
+    chat_id: ChatId,
+    messages: I,
+    sender_name: Option<String>,
+) where
+    I: IntoIterator<Item = Message>,
 {
     let messages: Vec<Message> = messages.into_iter().collect();
+    let created_at = chrono::Utc::now().timestamp();
 
     db.call(move |conn| {
         let tx = conn.transaction().expect("failed to start transaction");
 
         for msg in messages {
             tx.execute(
-                "INSERT INTO history (chat_id, role, text) VALUES (?1, ?2, ?3)",
-                params![chat_id.0, msg.role as u8, msg.text],
+                "INSERT INTO history (chat_id, role, text, created_at, sender_name, tokens) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    chat_id.0,
+                    msg.role as u8,
+                    msg.text,
+                    created_at,
+                    sender_name,
+                    openrouter_api::estimate_message_tokens(&msg.text) as i64
+                ],
             )
             .expect("failed to insert message");
         }
@@ -227,141 +654,1649 @@ where
     .expect("failed to add messages");
 }
 
-pub async fn set_openrouter_api_key(
-    db: &Connection,
-    chat_id: ChatId,
-    openrouter_api_key: Option<&str>,
-) {
-    let openrouter_api_key = openrouter_api_key.map(|s| s.to_owned());
-
+/// Update a single nullable column of the chat row, crashing if the row is missing.
+async fn update_chat_column<T>(db: &Connection, chat_id: ChatId, column: &'static str, value: T)
+where
+    T: ToSql + Send + 'static,
+{
     let updated = db
         .call(move |conn| {
             conn.execute(
-                "UPDATE chats SET openrouter_api_key = ?2 WHERE chat_id = ?1",
-                params![chat_id.0, openrouter_api_key],
+                &format!("UPDATE chats SET {column} = ?2 WHERE chat_id = ?1"),
+                params![chat_id.0, value],
             )
         })
         .await
-        .expect("failed to update api key");
+        .unwrap_or_else(|err| panic!("failed to update {column}: {err}"));
 
     if updated != 1 {
         fatal_panic(format!(
-            "failed to update api key for chat_id {} (updated {})",
-            chat_id.0, updated
+            "failed to update {} for chat_id {} (updated {})",
+            column, chat_id.0, updated
         ));
     }
 }
 
-pub async fn set_model_id(db: &Connection, chat_id: ChatId, model_id: Option<&str>) {
-    let model_id = model_id.map(|s| s.to_owned());
-
-    let updated = db
-        .call(move |conn| {
-            conn.execute(
-                "UPDATE chats SET model_id = ?2 WHERE chat_id = ?1",
-                params![chat_id.0, model_id],
-            )
-        })
-        .await
-        .expect("failed to update model id");
+/// A stored history row with its author, when one was recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributedMessage {
+    pub role: MessageRole,
+    pub sender_name: Option<String>,
+    pub text: String,
+    /// Unix timestamp (seconds) when the row was written.
+    pub created_at: i64,
+}
 
-    if updated != 1 {
-        fatal_panic(format!(
-            "failed to update model id for chat_id {} (updated {})",
-            chat_id.0, updated
-        ));
-    }
+/// The chat's last `limit` stored messages, oldest first, regardless of the token budget.
+pub async fn recent_messages(
+    db: &Connection,
+    chat_id: ChatId,
+    limit: usize,
+) -> Vec<AttributedMessage> {
+    load_last_messages(db, chat_id, limit as i64).await
 }
 
-pub async fn set_system_prompt(db: &Connection, chat_id: ChatId, system_prompt: Option<&str>) {
-    let system_prompt = system_prompt.map(|s| s.to_owned());
+/// Every stored message of the chat, oldest first, regardless of the token budget
+/// (archived rows aside).
+pub async fn load_full_history(db: &Connection, chat_id: ChatId) -> Vec<AttributedMessage> {
+    // SQLite reads a negative LIMIT as no limit.
+    load_last_messages(db, chat_id, -1).await
+}
 
-    let updated = db
+async fn load_last_messages(
+    db: &Connection,
+    chat_id: ChatId,
+    limit: i64,
+) -> Vec<AttributedMessage> {
+    let mut messages = db
         .call(move |conn| {
-            conn.execute(
-                "UPDATE chats SET system_prompt = ?2 WHERE chat_id = ?1",
-                params![chat_id.0, system_prompt],
-            )
+            let mut stmt = conn
+                .prepare(
+                    "SELECT role, sender_name, text, created_at FROM history WHERE chat_id = ?1
+                     ORDER BY id DESC LIMIT ?2",
+                )
+                .expect("failed to prepare recent messages query");
+
+            let rows = stmt
+                .query_map(params![chat_id.0, limit], |row| {
+                    Ok(AttributedMessage {
+                        role: MessageRole::try_from(row.get::<_, u8>(0)?)
+                            .expect("invalid stored message role"),
+                        sender_name: row.get(1)?,
+                        text: row.get(2)?,
+                        created_at: row.get(3)?,
+                    })
+                })
+                .expect("failed to query recent messages");
+
+            let mut collected = Vec::new();
+            for row in rows {
+                collected.push(row.expect("failed to read history row"));
+            }
+            Ok::<Vec<AttributedMessage>, SqliteError>(collected)
         })
         .await
-        .expect("failed to update system prompt");
-
-    if updated != 1 {
-        fatal_panic(format!(
-            "failed to update system prompt for chat_id {} (updated {})",
-            chat_id.0, updated
-        ));
-    }
+        .expect("failed to load recent messages");
+    messages.reverse();
+    messages
 }
 
-pub async fn set_user_name(db: &Connection, chat_id: ChatId, user_name: Option<&str>) {
-    let user_name = user_name.map(|s| s.to_owned());
-
-    let updated = db
-        .call(move |conn| {
-            conn.execute(
-                "UPDATE chats SET user_name = ?2 WHERE chat_id = ?1",
-                params![chat_id.0, user_name],
-            )
-        })
+/// Delete every stored history row of the chat; returns the number of rows removed.
+pub async fn clear_history(db: &Connection, chat_id: ChatId) -> usize {
+    let deleted = db
+        .call(move |conn| conn.execute("DELETE FROM history WHERE chat_id = ?1", [chat_id.0]))
         .await
-        .expect("failed to update user name");
+        .expect("failed to clear history");
 
-    if updated != 1 {
-        fatal_panic(format!(
-            "failed to update user name for chat_id {} (updated {})",
-            chat_id.0, updated
-        ));
-    }
+    log::info!("Cleared {} history rows for chat {}", deleted, chat_id);
+    deleted
 }
 
-pub async fn set_is_authorized(
+/// Move (or, with `delete`, drop) history rows written before `cutoff` (unix seconds) out of
+/// `history`. Returns the affected row count per chat.
+pub async fn archive_history_before(
     db: &Connection,
-    chat_id: ChatId,
-    is_authorized: bool,
-) -> anyhow::Result<()> {
-    let updated = db
-        .call(move |conn| {
-            conn.execute(
-                "UPDATE chats SET is_authorized = ?2 WHERE chat_id = ?1",
-                params![chat_id.0, is_authorized],
+    cutoff: i64,
+    delete: bool,
+) -> Vec<(i64, usize)> {
+    let archived_at = chrono::Utc::now().timestamp();
+
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+
+        let affected: Vec<(i64, usize)> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT chat_id, COUNT(*) FROM history WHERE created_at < ?1 GROUP BY chat_id ORDER BY chat_id",
+                )
+                .expect("failed to prepare archive candidates query");
+            let rows = stmt
+                .query_map([cutoff], |row| Ok((row.get(0)?, row.get(1)?)))
+                .expect("failed to query archive candidates");
+            rows.map(|row| row.expect("failed to read archive candidate row"))
+                .collect()
+        };
+
+        if !delete {
+            tx.execute(
+                "INSERT INTO history_archive (id, chat_id, role, text, created_at, sender_name, archived_at)
+                 SELECT id, chat_id, role, text, created_at, sender_name, ?2 FROM history WHERE created_at < ?1",
+                params![cutoff, archived_at],
             )
-        })
-        .await
-        .expect("failed to update is_authorized");
+            .expect("failed to copy history rows to archive");
+        }
+        let removed = tx
+            .execute("DELETE FROM history WHERE created_at < ?1", [cutoff])
+            .expect("failed to delete archived history rows");
+        assert_eq!(
+            removed,
+            affected.iter().map(|(_, count)| count).sum::<usize>(),
+            "archived row count doesn't match the candidates"
+        );
 
-    if updated == 1 {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!(
-            "failed to update is_authorized for chat_id {}",
-            chat_id.0
-        ))
-    }
+        tx.commit().expect("failed to commit history archival");
+        Ok::<Vec<(i64, usize)>, SqliteError>(affected)
+    })
+    .await
+    .expect("failed to archive history")
 }
 
-pub async fn list_unauthorized_chats(db: &Connection) -> Vec<(i64, Option<String>)> {
-    db.call(|conn| {
+/// Ids of the chat's latest user/assistant pair in `history`, oldest first; `None` when the
+/// two newest rows aren't such a pair.
+pub async fn last_turn_ids(db: &Connection, chat_id: ChatId) -> Option<Vec<i64>> {
+    db.call(move |conn| {
         let mut stmt = conn
-            .prepare(
-                "SELECT chat_id, user_name FROM chats WHERE is_authorized = 0 ORDER BY chat_id",
-            )
-            .expect("failed to prepare unauthorized chats query");
-
+            .prepare("SELECT id, role FROM history WHERE chat_id = ?1 ORDER BY id DESC LIMIT 2")
+            .expect("failed to prepare last turn lookup");
         let rows = stmt
-            .query_map([], |row| {
-                let chat_id: i64 = row.get(0)?;
-                let user_name: Option<String> = row.get(1)?;
-                Ok((chat_id, user_name))
-            })
-            .expect("failed to query unauthorized chats");
+            .query_map([chat_id.0], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("failed to query last turn");
+        let last_rows: Vec<(i64, u8)> = rows
+            .map(|row| row.expect("failed to read history row"))
+            .collect();
 
-        let mut collected = Vec::new();
-        for row in rows {
-            collected.push(row.expect("failed to read unauthorized chat row"));
+        let ids = match last_rows.as_slice() {
+            [(assistant_id, assistant), (user_id, user)]
+                if *assistant == MessageRole::Assistant as u8
+                    && *user == MessageRole::User as u8 =>
+            {
+                Some(vec![*user_id, *assistant_id])
+            }
+            _ => None,
+        };
+        Ok::<Option<Vec<i64>>, SqliteError>(ids)
+    })
+    .await
+    .expect("failed to look up last turn")
+}
+
+/// Delete the chat's history rows with these ids; returns how many were still there.
+pub async fn delete_history_rows(db: &Connection, chat_id: ChatId, ids: Vec<i64>) -> usize {
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+        let mut deleted = 0;
+        for id in &ids {
+            deleted += tx
+                .execute(
+                    "DELETE FROM history WHERE chat_id = ?1 AND id = ?2",
+                    params![chat_id.0, id],
+                )
+                .expect("failed to delete history row");
         }
-        Ok::<Vec<(i64, Option<String>)>, SqliteError>(collected)
+        tx.commit().expect("failed to commit transaction");
+        Ok::<usize, SqliteError>(deleted)
     })
     .await
-    .expect("failed to list unauthorized chats")
+    .expect("failed to delete history rows")
+}
+
+/// Delete the chat's last `turns` turns (see [`conversation::last_turns_start`]) from
+/// `history`. Returns the number of rows removed, or `None`, deleting nothing, when fewer
+/// turns are stored.
+pub async fn delete_last_n_turns(db: &Connection, chat_id: ChatId, turns: usize) -> Option<usize> {
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+
+        let rows: Vec<(i64, MessageRole)> = {
+            let mut stmt = tx
+                .prepare("SELECT id, role FROM history WHERE chat_id = ?1 ORDER BY id")
+                .expect("failed to prepare turn lookup");
+            let rows = stmt
+                .query_map([chat_id.0], |row| {
+                    let role = MessageRole::try_from(row.get::<_, u8>(1)?)
+                        .expect("invalid stored message role");
+                    Ok((row.get(0)?, role))
+                })
+                .expect("failed to query turns");
+            rows.map(|row| row.expect("failed to read history row"))
+                .collect()
+        };
+
+        let roles: Vec<MessageRole> = rows.iter().map(|(_, role)| *role).collect();
+        let Some(start) = conversation::last_turns_start(&roles, turns) else {
+            return Ok::<Option<usize>, SqliteError>(None);
+        };
+        let deleted = tx
+            .execute(
+                "DELETE FROM history WHERE chat_id = ?1 AND id >= ?2",
+                params![chat_id.0, rows[start].0],
+            )
+            .expect("failed to delete forgotten turns");
+        assert_eq!(
+            deleted,
+            rows.len() - start,
+            "deleted row count doesn't match the forgotten turns"
+        );
+        tx.commit().expect("failed to commit forgotten turns");
+
+        log::info!(
+            "Forgot {} turn(s) ({} rows) of chat {}",
+            turns,
+            deleted,
+            chat_id
+        );
+        Ok::<Option<usize>, SqliteError>(Some(deleted))
+    })
+    .await
+    .expect("failed to delete last turns")
+}
+
+/// Replace the stored rows of `replaced`, the oldest messages of a history that continues with
+/// `newer` more, by `summary`. The summary takes over the id and age of the newest replaced
+/// row, so it keeps their place in the history. Returns false, changing nothing, when the
+/// chat's newest rows don't line up with those messages.
+pub async fn replace_with_summary(
+    db: &Connection,
+    chat_id: ChatId,
+    replaced: Vec<Message>,
+    newer: usize,
+    summary: Message,
+) -> bool {
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+
+        let mut rows: Vec<(i64, u8, String, i64)> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, role, text, created_at FROM history WHERE chat_id = ?1
+                     ORDER BY id DESC LIMIT ?2",
+                )
+                .expect("failed to prepare summarized rows lookup");
+            let rows = stmt
+                .query_map(
+                    params![chat_id.0, (replaced.len() + newer) as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .expect("failed to query summarized rows");
+            rows.map(|row| row.expect("failed to read history row"))
+                .collect()
+        };
+        rows.reverse();
+
+        let lines_up = !replaced.is_empty()
+            && rows.len() == replaced.len() + newer
+            && rows
+                .iter()
+                .zip(&replaced)
+                .all(|((_, role, text, _), message)| {
+                    *role == message.role as u8 && *text == message.text
+                });
+        if !lines_up {
+            return Ok::<bool, SqliteError>(false);
+        }
+        let oldest_id = rows[0].0;
+        let (newest_id, _, _, created_at) = rows[replaced.len() - 1];
+
+        let removed = tx
+            .execute(
+                "DELETE FROM history WHERE chat_id = ?1 AND id BETWEEN ?2 AND ?3",
+                params![chat_id.0, oldest_id, newest_id],
+            )
+            .expect("failed to delete summarized history rows");
+        assert_eq!(
+            removed,
+            replaced.len(),
+            "deleted row count doesn't match the summarized messages"
+        );
+        tx.execute(
+            "INSERT INTO history (id, chat_id, role, text, created_at, tokens) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                newest_id,
+                chat_id.0,
+                summary.role as u8,
+                summary.text,
+                created_at,
+                openrouter_api::estimate_message_tokens(&summary.text) as i64
+            ],
+        )
+        .expect("failed to insert history summary");
+        tx.commit().expect("failed to commit history summary");
+
+        Ok::<bool, SqliteError>(true)
+    })
+    .await
+    .expect("failed to replace history with a summary")
+}
+
+/// One LLM call as recorded in the `request_log` table.
+#[derive(Debug, Clone)]
+pub struct RequestLogEntry {
+    /// Unix timestamp (seconds) when the request finished.
+    pub created_at: i64,
+    pub model_id: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    pub latency_ms: u64,
+    /// `None` for successful requests.
+    pub error: Option<String>,
+}
+
+pub async fn add_request_log(db: &Connection, chat_id: ChatId, entry: RequestLogEntry) {
+    db.call(move |conn| {
+        conn.execute(
+            "INSERT INTO request_log (chat_id, created_at, model_id, prompt_tokens, completion_tokens, cost, latency_ms, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                chat_id.0,
+                entry.created_at,
+                entry.model_id,
+                entry.prompt_tokens as i64,
+                entry.completion_tokens as i64,
+                entry.cost,
+                entry.latency_ms as i64,
+                entry.error
+            ],
+        )
+    })
+    .await
+    .expect("failed to insert request log entry");
+}
+
+/// Most recent `limit` request log entries for the chat, newest first.
+pub async fn list_request_log(
+    db: &Connection,
+    chat_id: ChatId,
+    limit: usize,
+) -> Vec<RequestLogEntry> {
+    db.call(move |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT created_at, model_id, prompt_tokens, completion_tokens, cost, latency_ms, error
+                 FROM request_log WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2",
+            )
+            .expect("failed to prepare request log query");
+
+        let rows = stmt
+            .query_map(params![chat_id.0, limit as i64], |row| {
+                Ok(RequestLogEntry {
+                    created_at: row.get(0)?,
+                    model_id: row.get(1)?,
+                    prompt_tokens: row.get::<_, i64>(2)? as u64,
+                    completion_tokens: row.get::<_, i64>(3)? as u64,
+                    cost: row.get(4)?,
+                    latency_ms: row.get::<_, i64>(5)? as u64,
+                    error: row.get(6)?,
+                })
+            })
+            .expect("failed to query request log");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read request log row"));
+        }
+        Ok::<Vec<RequestLogEntry>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to list request log")
+}
+
+/// Tokens and cost of one answered request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestUsage {
+    pub model_id: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost: f64,
+}
+
+/// A chat's lifetime totals from the `usage` table, with its latest request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost: f64,
+    pub last: RequestUsage,
+}
+
+/// Add one request to the chat's totals.
+pub async fn add_usage(db: &Connection, chat_id: ChatId, usage: RequestUsage) {
+    db.call(move |conn| {
+        conn.execute(
+            "INSERT INTO usage (chat_id, requests, prompt_tokens, completion_tokens, total_tokens, cost,
+                                last_model_id, last_prompt_tokens, last_completion_tokens, last_total_tokens, last_cost)
+             VALUES (?1, 1, ?3, ?4, ?5, ?6, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (chat_id) DO UPDATE SET
+                requests = requests + 1,
+                prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                completion_tokens = completion_tokens + excluded.completion_tokens,
+                total_tokens = total_tokens + excluded.total_tokens,
+                cost = cost + excluded.cost,
+                last_model_id = excluded.last_model_id,
+                last_prompt_tokens = excluded.last_prompt_tokens,
+                last_completion_tokens = excluded.last_completion_tokens,
+                last_total_tokens = excluded.last_total_tokens,
+                last_cost = excluded.last_cost",
+            params![
+                chat_id.0,
+                usage.model_id,
+                usage.prompt_tokens as i64,
+                usage.completion_tokens as i64,
+                usage.total_tokens as i64,
+                usage.cost
+            ],
+        )
+    })
+    .await
+    .expect("failed to update usage");
+}
+
+/// `None` until the chat's first answered request.
+pub async fn load_usage(db: &Connection, chat_id: ChatId) -> Option<ChatUsage> {
+    db.call(move |conn| {
+        conn.query_row(
+            "SELECT requests, prompt_tokens, completion_tokens, total_tokens, cost,
+                    last_model_id, last_prompt_tokens, last_completion_tokens, last_total_tokens, last_cost
+             FROM usage WHERE chat_id = ?1",
+            [chat_id.0],
+            |row| {
+                Ok(ChatUsage {
+                    requests: row.get::<_, i64>(0)? as u64,
+                    prompt_tokens: row.get::<_, i64>(1)? as u64,
+                    completion_tokens: row.get::<_, i64>(2)? as u64,
+                    total_tokens: row.get::<_, i64>(3)? as u64,
+                    cost: row.get(4)?,
+                    last: RequestUsage {
+                        model_id: row.get(5)?,
+                        prompt_tokens: row.get::<_, i64>(6)? as u64,
+                        completion_tokens: row.get::<_, i64>(7)? as u64,
+                        total_tokens: row.get::<_, i64>(8)? as u64,
+                        cost: row.get(9)?,
+                    },
+                })
+            },
+        )
+        .optional()
+    })
+    .await
+    .expect("failed to load usage")
+}
+
+/// `request_log` totals for one chat, UTC day and model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyUsage {
+    pub chat_id: i64,
+    /// `YYYY-MM-DD` (UTC).
+    pub date: String,
+    pub model_id: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+/// Aggregate `request_log` rows with `from <= created_at < to` (unix seconds).
+pub async fn usage_by_day(db: &Connection, from: i64, to: i64) -> Vec<DailyUsage> {
+    assert!(from <= to, "usage range is reversed");
+
+    db.call(move |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT chat_id, date(created_at, 'unixepoch') AS day, model_id, COUNT(*),
+                        SUM(prompt_tokens), SUM(completion_tokens), SUM(cost)
+                 FROM request_log WHERE created_at >= ?1 AND created_at < ?2
+                 GROUP BY chat_id, day, model_id ORDER BY day, chat_id, model_id",
+            )
+            .expect("failed to prepare usage query");
+
+        let rows = stmt
+            .query_map(params![from, to], |row| {
+                Ok(DailyUsage {
+                    chat_id: row.get(0)?,
+                    date: row.get(1)?,
+                    model_id: row.get(2)?,
+                    requests: row.get::<_, i64>(3)? as u64,
+                    prompt_tokens: row.get::<_, i64>(4)? as u64,
+                    completion_tokens: row.get::<_, i64>(5)? as u64,
+                    cost: row.get(6)?,
+                })
+            })
+            .expect("failed to query usage");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read usage row"));
+        }
+        Ok::<Vec<DailyUsage>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to aggregate usage")
+}
+
+pub async fn set_tools(db: &Connection, chat_id: ChatId, tools: Option<&serde_json::Value>) {
+    let tools = tools.map(|t| t.to_string());
+    update_chat_column(db, chat_id, "tools", tools).await;
+}
+
+pub async fn set_ephemeral(db: &Connection, chat_id: ChatId, ephemeral: bool) {
+    update_chat_column(db, chat_id, "ephemeral", ephemeral).await;
+}
+
+pub async fn set_voice(db: &Connection, chat_id: ChatId, voice: bool) {
+    update_chat_column(db, chat_id, "voice", voice).await;
+}
+
+pub async fn set_utc_offset(db: &Connection, chat_id: ChatId, offset: chrono::FixedOffset) {
+    update_chat_column(
+        db,
+        chat_id,
+        "utc_offset_minutes",
+        timezone::offset_minutes(offset),
+    )
+    .await;
+}
+
+pub async fn set_reply_mode(db: &Connection, chat_id: ChatId, reply_mode: conversation::ReplyMode) {
+    update_chat_column(db, chat_id, "reply_mode", reply_mode.to_db()).await;
+}
+
+pub async fn set_reasoning_effort(
+    db: &Connection,
+    chat_id: ChatId,
+    effort: Option<conversation::ReasoningEffort>,
+) {
+    update_chat_column(
+        db,
+        chat_id,
+        "reasoning_effort",
+        effort.map(conversation::ReasoningEffort::as_str),
+    )
+    .await;
+}
+
+/// Store all sampling parameters at once, e.g. after a `/mode` preset.
+pub async fn set_sampling(
+    db: &Connection,
+    chat_id: ChatId,
+    sampling: conversation::SamplingParams,
+) {
+    let updated = db
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE chats SET temperature = ?2, top_p = ?3, frequency_penalty = ?4, presence_penalty = ?5 WHERE chat_id = ?1",
+                params![
+                    chat_id.0,
+                    sampling.temperature,
+                    sampling.top_p,
+                    sampling.frequency_penalty,
+                    sampling.presence_penalty
+                ],
+            )
+        })
+        .await
+        .expect("failed to update sampling parameters");
+
+    if updated != 1 {
+        fatal_panic(format!(
+            "failed to update sampling parameters for chat_id {} (updated {})",
+            chat_id.0, updated
+        ));
+    }
+}
+
+/// Replace the chat's stored sections with `sections`, keeping their order.
+pub async fn set_prompt_sections(
+    db: &Connection,
+    chat_id: ChatId,
+    sections: Vec<conversation::PromptSection>,
+) {
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+
+        tx.execute(
+            "DELETE FROM prompt_sections WHERE chat_id = ?1",
+            [chat_id.0],
+        )
+        .expect("failed to delete prompt sections");
+        for (position, section) in sections.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO prompt_sections (chat_id, name, position, text) VALUES (?1, ?2, ?3, ?4)",
+                params![chat_id.0, section.name, position as i64, section.text],
+            )
+            .expect("failed to insert prompt section");
+        }
+
+        tx.commit().expect("failed to commit transaction");
+        Ok::<(), SqliteError>(())
+    })
+    .await
+    .expect("failed to store prompt sections");
+}
+
+/// Store a new schedule and return its id.
+pub async fn add_schedule(
+    db: &Connection,
+    chat_id: ChatId,
+    recurrence: Recurrence,
+    time: chrono::NaiveTime,
+    prompt: &str,
+    next_run: i64,
+) -> i64 {
+    let prompt = prompt.to_string();
+    db.call(move |conn| {
+        conn.execute(
+            "INSERT INTO schedules (chat_id, recurrence, time_of_day, prompt, next_run)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                chat_id.0,
+                recurrence.to_db(),
+                time.format("%H:%M").to_string(),
+                prompt,
+                next_run
+            ],
+        )?;
+        Ok::<i64, SqliteError>(conn.last_insert_rowid())
+    })
+    .await
+    .expect("failed to insert schedule")
+}
+
+/// The chat's schedules, oldest first.
+pub async fn list_schedules(db: &Connection, chat_id: ChatId) -> Vec<Schedule> {
+    query_schedules(db, "WHERE chat_id = ?1", chat_id.0).await
+}
+
+/// Schedules of every chat whose next run is at or before `now` (unix seconds).
+pub async fn due_schedules(db: &Connection, now: i64) -> Vec<Schedule> {
+    query_schedules(db, "WHERE next_run <= ?1", now).await
+}
+
+async fn query_schedules(db: &Connection, filter: &'static str, value: i64) -> Vec<Schedule> {
+    db.call(move |conn| {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT id, chat_id, recurrence, time_of_day, prompt, next_run
+                 FROM schedules {filter} ORDER BY id"
+            ))
+            .expect("failed to prepare schedules query");
+
+        let rows = stmt
+            .query_map([value], |row| {
+                let recurrence: String = row.get(2)?;
+                let time: String = row.get(3)?;
+                Ok(Schedule {
+                    id: row.get(0)?,
+                    chat_id: row.get(1)?,
+                    recurrence: Recurrence::parse(&recurrence).unwrap_or_else(|| {
+                        fatal_panic(format!("invalid stored recurrence {recurrence:?}"))
+                    }),
+                    time: schedule::parse_time(&time).unwrap_or_else(|| {
+                        fatal_panic(format!("invalid stored schedule time {time:?}"))
+                    }),
+                    prompt: row.get(4)?,
+                    next_run: row.get(5)?,
+                })
+            })
+            .expect("failed to query schedules");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read schedule row"));
+        }
+        Ok::<Vec<Schedule>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to list schedules")
+}
+
+pub async fn set_schedule_next_run(db: &Connection, id: i64, next_run: i64) {
+    db.call(move |conn| {
+        conn.execute(
+            "UPDATE schedules SET next_run = ?1 WHERE id = ?2",
+            params![next_run, id],
+        )
+    })
+    .await
+    .expect("failed to update schedule");
+}
+
+/// Delete one of the chat's schedules; false if it has none with that id.
+pub async fn delete_schedule(db: &Connection, chat_id: ChatId, id: i64) -> bool {
+    db.call(move |conn| {
+        conn.execute(
+            "DELETE FROM schedules WHERE id = ?1 AND chat_id = ?2",
+            params![id, chat_id.0],
+        )
+    })
+    .await
+    .expect("failed to delete schedule")
+        > 0
+}
+
+pub async fn load_config_overrides(db: &Connection) -> Vec<(String, String)> {
+    db.call(|conn| {
+        let mut stmt = conn
+            .prepare("SELECT name, value FROM config ORDER BY name")
+            .expect("failed to prepare config query");
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("failed to query config");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read config row"));
+        }
+        Ok::<Vec<(String, String)>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to load config overrides")
+}
+
+pub async fn set_config_override(db: &Connection, name: &'static str, value: String) {
+    db.call(move |conn| {
+        conn.execute(
+            "INSERT INTO config (name, value) VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET value = excluded.value",
+            params![name, value],
+        )
+    })
+    .await
+    .expect("failed to store config override");
+}
+
+/// Drop an override so the environment applies again; false if there was none.
+pub async fn delete_config_override(db: &Connection, name: &'static str) -> bool {
+    db.call(move |conn| conn.execute("DELETE FROM config WHERE name = ?1", [name]))
+        .await
+        .expect("failed to delete config override")
+        > 0
+}
+
+pub async fn set_unauthorized_notified(db: &Connection, chat_id: ChatId, notified: bool) {
+    update_chat_column(db, chat_id, "unauthorized_notified", notified).await;
+}
+
+pub async fn set_is_active(db: &Connection, chat_id: ChatId, is_active: bool) {
+    update_chat_column(db, chat_id, "is_active", is_active).await;
+}
+
+pub async fn set_show_thinking(db: &Connection, chat_id: ChatId, show_thinking: bool) {
+    update_chat_column(db, chat_id, "show_thinking", show_thinking).await;
+}
+
+pub async fn set_clean_thinking(db: &Connection, chat_id: ChatId, clean_thinking: bool) {
+    update_chat_column(db, chat_id, "clean_thinking", clean_thinking).await;
+}
+
+pub async fn set_stream(db: &Connection, chat_id: ChatId, stream: Option<bool>) {
+    update_chat_column(db, chat_id, "stream", stream).await;
+}
+
+pub async fn set_delete_commands(db: &Connection, chat_id: ChatId, delete_commands: bool) {
+    update_chat_column(db, chat_id, "delete_commands", delete_commands).await;
+}
+
+pub async fn set_onboarding_step(db: &Connection, chat_id: ChatId, step: OnboardingStep) {
+    update_chat_column(db, chat_id, "onboarding_step", step.to_db()).await;
+}
+
+pub async fn set_web_search(db: &Connection, chat_id: ChatId, web_search: bool) {
+    update_chat_column(db, chat_id, "web_search", web_search).await;
+}
+
+pub async fn set_cache(db: &Connection, chat_id: ChatId, cache: bool) {
+    update_chat_column(db, chat_id, "cache", cache).await;
+}
+
+pub async fn set_title(db: &Connection, chat_id: ChatId, title: Option<&str>) {
+    let title = title.map(|s| s.to_owned());
+    update_chat_column(db, chat_id, "title", title).await;
+}
+
+/// Store (or with `None`, remove) the chat's key for one provider.
+pub async fn set_provider_key(
+    db: &Connection,
+    chat_id: ChatId,
+    provider: &'static str,
+    key: Option<&str>,
+) {
+    let key = key.map(|s| s.to_owned());
+    db.call(move |conn| {
+        match key {
+            Some(key) => conn.execute(
+                "INSERT INTO provider_keys (chat_id, provider, key) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (chat_id, provider) DO UPDATE SET key = excluded.key",
+                params![chat_id.0, provider, key],
+            ),
+            None => conn.execute(
+                "DELETE FROM provider_keys WHERE chat_id = ?1 AND provider = ?2",
+                params![chat_id.0, provider],
+            ),
+        }
+        .expect("failed to update provider key");
+        Ok::<(), SqliteError>(())
+    })
+    .await
+    .expect("failed to update provider key");
+}
+
+pub async fn set_model_id(db: &Connection, chat_id: ChatId, model_id: Option<&str>) {
+    let model_id = model_id.map(|s| s.to_owned());
+    update_chat_column(db, chat_id, "model_id", model_id).await;
+}
+
+pub async fn set_system_prompt(db: &Connection, chat_id: ChatId, system_prompt: Option<&str>) {
+    let system_prompt = system_prompt.map(|s| s.to_owned());
+    update_chat_column(db, chat_id, "system_prompt", system_prompt).await;
+}
+
+pub async fn set_user_name(db: &Connection, chat_id: ChatId, user_name: Option<&str>) {
+    let user_name = user_name.map(|s| s.to_owned());
+    update_chat_column(db, chat_id, "user_name", user_name).await;
+}
+
+/// Also clears `unauthorized_notified`, so a denied chat is told (once) again.
+pub async fn set_is_authorized(
+    db: &Connection,
+    chat_id: ChatId,
+    is_authorized: bool,
+) -> anyhow::Result<()> {
+    let updated = db
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE chats SET is_authorized = ?2, unauthorized_notified = 0 WHERE chat_id = ?1",
+                params![chat_id.0, is_authorized],
+            )
+        })
+        .await
+        .expect("failed to update is_authorized");
+
+    if updated == 1 {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "failed to update is_authorized for chat_id {}",
+            chat_id.0
+        ))
+    }
+}
+
+pub async fn list_admin_chats(db: &Connection) -> Vec<(i64, Option<String>)> {
+    db.call(|conn| {
+        let mut stmt = conn
+            .prepare("SELECT chat_id, user_name FROM chats WHERE is_admin = 1 ORDER BY chat_id")
+            .expect("failed to prepare admin chats query");
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("failed to query admin chats");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read admin chat row"));
+        }
+        Ok::<Vec<(i64, Option<String>)>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to list admin chats")
+}
+
+/// Store the `ADMIN_CHATS` grants: rows are created for chats that never wrote yet, and the
+/// flags stay set even after an id is removed from the environment.
+pub async fn grant_admins(db: &Connection, chat_ids: Vec<i64>) {
+    db.call(move |conn| {
+        let tx = conn.transaction().expect("failed to start transaction");
+        for chat_id in chat_ids {
+            tx.execute("INSERT OR IGNORE INTO chats (chat_id) VALUES (?1)", [chat_id])
+                .expect("failed to insert admin chat row");
+            let updated = tx
+                .execute(
+                    "UPDATE chats SET is_admin = 1, is_authorized = 1 WHERE chat_id = ?1 AND (is_admin = 0 OR is_authorized = 0)",
+                    [chat_id],
+                )
+                .expect("failed to grant admin");
+            if updated == 1 {
+                log::info!("granted admin to chat {} from ADMIN_CHATS", chat_id);
+            }
+        }
+        tx.commit().expect("failed to commit transaction");
+        Ok::<(), SqliteError>(())
+    })
+    .await
+    .expect("failed to grant admins");
+}
+
+/// Chats authorized in the database (not counting `AUTHORIZED_CHATS`/`ADMIN_CHATS`).
+pub async fn list_authorized_chats(db: &Connection) -> Vec<ChatId> {
+    db.call(|conn| {
+        let mut stmt = conn
+            .prepare("SELECT chat_id FROM chats WHERE is_authorized = 1 ORDER BY chat_id")
+            .expect("failed to prepare authorized chats query");
+
+        let rows = stmt
+            .query_map([], |row| row.get(0).map(ChatId))
+            .expect("failed to query authorized chats");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read authorized chat row"));
+        }
+        Ok::<Vec<ChatId>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to list authorized chats")
+}
+
+pub async fn list_unauthorized_chats(db: &Connection) -> Vec<(i64, Option<String>)> {
+    db.call(|conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT chat_id, user_name FROM chats WHERE is_authorized = 0 ORDER BY chat_id",
+            )
+            .expect("failed to prepare unauthorized chats query");
+
+        let rows = stmt
+            .query_map([], |row| {
+                let chat_id: i64 = row.get(0)?;
+                let user_name: Option<String> = row.get(1)?;
+                Ok((chat_id, user_name))
+            })
+            .expect("failed to query unauthorized chats");
+
+        let mut collected = Vec::new();
+        for row in rows {
+            collected.push(row.expect("failed to read unauthorized chat row"));
+        }
+        Ok::<Vec<(i64, Option<String>)>, SqliteError>(collected)
+    })
+    .await
+    .expect("failed to list unauthorized chats")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_columns(conn: &SyncConnection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT name FROM pragma_table_info('{table}') ORDER BY name"
+            ))
+            .expect("failed to prepare table_info query");
+        stmt.query_map([], |row| row.get(0))
+            .expect("failed to query table_info")
+            .map(|name| name.expect("failed to read column name"))
+            .collect()
+    }
+
+    #[test]
+    fn migrations_upgrade_old_databases_like_fresh_ones() {
+        let fresh = SyncConnection::open_in_memory().expect("failed to open fresh database");
+        prepare_schema(&fresh);
+
+        // A database from before the first migration, with data in it.
+        let old = SyncConnection::open_in_memory().expect("failed to open old database");
+        init_schema(&old);
+        set_schema_version(&old, 1);
+        old.execute(
+            "INSERT INTO chats (chat_id, is_authorized) VALUES (7, 1)",
+            [],
+        )
+        .expect("failed to insert chat");
+        old.execute(
+            "INSERT INTO history (chat_id, role, text) VALUES (7, 1, 'hello there')",
+            [],
+        )
+        .expect("failed to insert message");
+        prepare_schema(&old);
+
+        for conn in [&fresh, &old] {
+            assert_eq!(get_schema_version(conn), SCHEMA_VERSION);
+        }
+        for table in ["chats", "history"] {
+            assert_eq!(table_columns(&old, table), table_columns(&fresh, table));
+        }
+        let tokens: i64 = old
+            .query_row("SELECT tokens FROM history WHERE chat_id = 7", [], |row| {
+                row.get(0)
+            })
+            .expect("message survives the migrations");
+        assert!(tokens > 0);
+
+        // Running again at the current version changes nothing.
+        prepare_schema(&old);
+        assert_eq!(get_schema_version(&old), SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn persists_reasoning_settings() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(42);
+        load_conversation(&db, chat_id).await;
+        set_show_thinking(&db, chat_id, true).await;
+        assert!(load_conversation(&db, chat_id).await.show_thinking);
+        set_clean_thinking(&db, chat_id, true).await;
+        assert!(load_conversation(&db, chat_id).await.clean_thinking);
+        assert!(load_conversation(&db, chat_id).await.web_search);
+        set_web_search(&db, chat_id, false).await;
+        assert!(!load_conversation(&db, chat_id).await.web_search);
+        assert_eq!(load_conversation(&db, chat_id).await.reasoning_effort, None);
+        set_reasoning_effort(&db, chat_id, Some(conversation::ReasoningEffort::High)).await;
+        assert_eq!(
+            load_conversation(&db, chat_id).await.reasoning_effort,
+            Some(conversation::ReasoningEffort::High)
+        );
+    }
+
+    #[tokio::test]
+    async fn archives_rows_with_their_sender() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(-100);
+        let user = |text: &str| Message {
+            role: MessageRole::User,
+            text: text.to_string(),
+        };
+        add_messages_from(&db, chat_id, [user("hello")], Some("Alice".to_string())).await;
+        add_messages(&db, chat_id, [user("private")]).await;
+
+        let cutoff = chrono::Utc::now().timestamp() + 1;
+        assert_eq!(
+            archive_history_before(&db, cutoff, false).await,
+            [(chat_id.0, 2)]
+        );
+
+        let archived: Vec<(String, Option<String>)> = db
+            .call(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT text, sender_name FROM history_archive ORDER BY id")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .expect("failed to read archive");
+        assert_eq!(
+            archived,
+            [
+                ("hello".to_string(), Some("Alice".to_string())),
+                ("private".to_string(), None),
+            ]
+        );
+        assert!(load_full_history(&db, chat_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgets_the_last_turns() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let message = |role, text: &str| Message {
+            role,
+            text: text.to_string(),
+        };
+        let chat_id = ChatId(42);
+        add_messages(
+            &db,
+            chat_id,
+            [
+                message(MessageRole::User, "first"),
+                message(MessageRole::Assistant, "first answer"),
+                message(MessageRole::User, "second"),
+                message(MessageRole::Assistant, "second answer"),
+            ],
+        )
+        .await;
+        add_messages(&db, ChatId(7), [message(MessageRole::User, "elsewhere")]).await;
+
+        assert_eq!(delete_last_n_turns(&db, chat_id, 3).await, None);
+        assert_eq!(delete_last_n_turns(&db, chat_id, 1).await, Some(2));
+        let texts: Vec<String> = recent_messages(&db, chat_id, 10)
+            .await
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, ["first", "first answer"]);
+        assert_eq!(delete_last_n_turns(&db, chat_id, 1).await, Some(2));
+        assert!(recent_messages(&db, chat_id, 10).await.is_empty());
+        assert_eq!(recent_messages(&db, ChatId(7), 10).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn summaries_replace_the_oldest_turns() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(42);
+        let turns: Vec<Message> = (0..6)
+            .flat_map(|i| {
+                [
+                    Message {
+                        role: MessageRole::User,
+                        text: format!("question {i}"),
+                    },
+                    Message {
+                        role: MessageRole::Assistant,
+                        text: format!("answer {i}"),
+                    },
+                ]
+            })
+            .collect();
+        add_messages(&db, chat_id, turns.clone()).await;
+        let mut conversation = load_conversation(&db, chat_id).await;
+        conversation.add_messages(turns);
+        assert_eq!(conversation.summarizable_prefix(2), 4);
+        assert_eq!(conversation.summarizable_prefix(100), 12);
+
+        let replaced: Vec<Message> = conversation.history.iter().take(4).cloned().collect();
+        let summary = conversation.replace_with_summary(4, " The user asked twice. ");
+        assert_eq!(
+            summary.text,
+            format!("{}The user asked twice.", conversation::SUMMARY_PREFIX)
+        );
+        // A later summary takes the earlier one along.
+        assert_eq!(conversation.summarizable_prefix(1), 3);
+
+        // Rows that don't match the in-memory history stay untouched.
+        let stale = vec![Message::default(); 4];
+        assert!(!replace_with_summary(&db, chat_id, stale, 8, summary.clone()).await);
+        assert!(replace_with_summary(&db, chat_id, replaced, 8, summary.clone()).await);
+        let mut reloaded = load_conversation(&db, chat_id).await;
+        load_history(&db, &mut reloaded, u64::MAX).await;
+        assert_eq!(reloaded.history, conversation.history);
+
+        // Room for the summary and one turn: the turns after the summary go first.
+        let summary_tokens = openrouter_api::estimate_message_tokens(&summary.text);
+        let turn_tokens: u64 = ["question 5", "answer 5"]
+            .iter()
+            .map(|text| openrouter_api::estimate_message_tokens(text))
+            .sum();
+        let budget = openrouter_api::PER_PROMPT_OVERHEAD + summary_tokens + turn_tokens;
+        assert!(conversation.exceeds_token_budget(budget));
+        conversation.prune_to_token_budget(budget);
+        assert!(!conversation.exceeds_token_budget(budget));
+        let texts: Vec<&str> = conversation
+            .history
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect();
+        assert_eq!(texts, [summary.text.as_str(), "question 5", "answer 5"]);
+    }
+
+    #[tokio::test]
+    async fn tool_heavy_payloads_prune_more_history() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let model = openrouter_api::ModelSummary {
+            id: "test/small".to_string(),
+            name: "Small".to_string(),
+            // Both the reserved texts and the history estimate carry the per-prompt overhead.
+            context_length: 26_000,
+            max_completion_tokens: 2_000,
+            capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+            pricing: None,
+        };
+        let reserved = ["You are a helpful assistant.", "And now?"];
+        let mut conversation = load_conversation(&db, ChatId(42)).await;
+        conversation.add_messages((0..200).map(|i| Message {
+            role: if i % 2 == 0 {
+                MessageRole::User
+            } else {
+                MessageRole::Assistant
+            },
+            text: format!("message {i} {}", "words ".repeat(10)),
+        }));
+
+        let tools = openrouter_api::PayloadOptions {
+            tools: Some(serde_json::json!([{
+                "type": "function",
+                "name": "search",
+                "description": "Searches a large external system. ".repeat(200),
+            }])),
+            ..Default::default()
+        };
+
+        conversation.prune_to_token_budget(
+            model.history_budget(&reserved, &openrouter_api::PayloadOptions::default()),
+        );
+        let kept_plain = conversation.history.len();
+        conversation.prune_to_token_budget(model.history_budget(&reserved, &tools));
+        let kept_with_tools = conversation.history.len();
+        assert!(
+            0 < kept_with_tools && kept_with_tools < kept_plain && kept_plain < 200,
+            "kept {kept_with_tools} with tools vs {kept_plain} without"
+        );
+    }
+
+    #[tokio::test]
+    async fn migrates_openrouter_key_to_provider_keys() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            init_schema(conn);
+            for version in 1..13 {
+                migrate_schema(conn, version);
+            }
+            conn.execute(
+                "INSERT INTO chats (chat_id, openrouter_api_key) VALUES (7, 'sk-or-old'), (8, NULL)",
+                [],
+            )?;
+            set_schema_version(conn, 13);
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to migrate schema");
+
+        let conversation = load_conversation(&db, ChatId(7)).await;
+        assert_eq!(conversation.api_key(), Some("sk-or-old"));
+        assert_eq!(load_conversation(&db, ChatId(8)).await.api_key(), None);
+
+        set_provider_key(&db, ChatId(7), "openai", Some("sk-openai")).await;
+        set_provider_key(&db, ChatId(7), openrouter_api::PROVIDER, Some("sk-or-new")).await;
+        let conversation = load_conversation(&db, ChatId(7)).await;
+        assert_eq!(conversation.api_key(), Some("sk-or-new"));
+        assert_eq!(conversation.provider_keys.len(), 2);
+
+        set_provider_key(&db, ChatId(7), openrouter_api::PROVIDER, None).await;
+        let conversation = load_conversation(&db, ChatId(7)).await;
+        assert_eq!(conversation.api_key(), None);
+        assert_eq!(
+            conversation.provider_keys.get("openai").map(String::as_str),
+            Some("sk-openai")
+        );
+    }
+
+    #[tokio::test]
+    async fn history_rows_store_their_token_estimates() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            init_schema(conn);
+            for version in 1..24 {
+                migrate_schema(conn, version);
+            }
+            conn.execute(
+                "INSERT INTO history (chat_id, role, text) VALUES (7, 1, 'written before the migration')",
+                [],
+            )?;
+            set_schema_version(conn, 24);
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to migrate schema");
+
+        let chat_id = ChatId(7);
+        add_messages(
+            &db,
+            chat_id,
+            ["middle", "newest"].map(|text| Message {
+                role: MessageRole::User,
+                text: text.to_string(),
+            }),
+        )
+        .await;
+        let stored: Vec<(String, i64)> = db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT text, tokens FROM history ORDER BY id")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .expect("failed to read history");
+        for (text, tokens) in &stored {
+            assert_eq!(
+                *tokens as u64,
+                openrouter_api::estimate_message_tokens(text)
+            );
+        }
+
+        // Loading sums the stored counts: a row claiming a huge count is the last one kept.
+        db.call(|conn| {
+            conn.execute(
+                "UPDATE history SET tokens = 50000 WHERE text = 'middle'",
+                [],
+            )
+        })
+        .await
+        .expect("failed to update tokens");
+        let mut conversation = load_conversation(&db, chat_id).await;
+        load_history(&db, &mut conversation, 20_000).await;
+        let texts: Vec<&str> = conversation
+            .history
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect();
+        assert_eq!(texts, ["middle", "newest"]);
+    }
+
+    #[tokio::test]
+    async fn schedules_are_due_by_time_and_cancelled_per_chat() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let nine = chrono::NaiveTime::from_hms_opt(9, 0, 0).expect("valid time");
+        let early = add_schedule(&db, ChatId(1), Recurrence::Daily, nine, "news", 100).await;
+        let late = add_schedule(&db, ChatId(2), Recurrence::Weekdays, nine, "digest", 200).await;
+
+        let due: Vec<i64> = due_schedules(&db, 150).await.iter().map(|s| s.id).collect();
+        assert_eq!(due, [early]);
+        set_schedule_next_run(&db, early, 300).await;
+        let due: Vec<i64> = due_schedules(&db, 250).await.iter().map(|s| s.id).collect();
+        assert_eq!(due, [late]);
+
+        let listed = list_schedules(&db, ChatId(2)).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].recurrence, Recurrence::Weekdays);
+        assert_eq!(listed[0].time, nine);
+        assert_eq!(listed[0].prompt, "digest");
+
+        assert!(!delete_schedule(&db, ChatId(1), late).await);
+        assert!(delete_schedule(&db, ChatId(2), late).await);
+        assert!(list_schedules(&db, ChatId(2)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clearing_history_keeps_chat_settings() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(7);
+        load_conversation(&db, chat_id).await;
+        set_is_authorized(&db, chat_id, true)
+            .await
+            .expect("chat row exists");
+        set_model_id(&db, chat_id, Some("openai/gpt-4o")).await;
+        set_system_prompt(&db, chat_id, Some("Be brief.")).await;
+        set_provider_key(&db, chat_id, "openrouter", Some("sk-test")).await;
+        add_messages(
+            &db,
+            chat_id,
+            [Message {
+                role: MessageRole::User,
+                text: "hello".to_string(),
+            }],
+        )
+        .await;
+        add_messages(
+            &db,
+            ChatId(8),
+            [Message {
+                role: MessageRole::User,
+                text: "other chat".to_string(),
+            }],
+        )
+        .await;
+
+        assert_eq!(clear_history(&db, chat_id).await, 1);
+        let conversation = load_conversation(&db, chat_id).await;
+        assert!(conversation.is_authorized);
+        assert_eq!(conversation.model_id.as_deref(), Some("openai/gpt-4o"));
+        assert_eq!(
+            conversation
+                .system_prompt
+                .as_ref()
+                .map(|prompt| prompt.text.as_str()),
+            Some("Be brief.")
+        );
+        assert_eq!(conversation.api_key(), Some("sk-test"));
+        assert!(recent_messages(&db, chat_id, 10).await.is_empty());
+        assert_eq!(recent_messages(&db, ChatId(8), 10).await.len(), 1);
+
+        assert!(!chat_exists(&db, ChatId(9)).await);
+        load_conversation(&db, ChatId(9)).await;
+        assert!(chat_exists(&db, ChatId(9)).await);
+        assert_eq!(list_authorized_chats(&db).await, [chat_id]);
+
+        // An env admin that never wrote gets a row, so the flag sticks.
+        grant_admins(&db, vec![-100, 9]).await;
+        assert_eq!(list_admin_chats(&db).await, [(-100, None), (9, None)]);
+        let admin = load_conversation(&db, ChatId(-100)).await;
+        assert!(admin.is_admin && admin.is_authorized);
+    }
+
+    #[tokio::test]
+    async fn usage_accumulates_per_chat() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(3);
+        assert_eq!(load_usage(&db, chat_id).await, None);
+
+        let request = |model_id: &str, prompt_tokens, completion_tokens, cost| RequestUsage {
+            model_id: model_id.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            cost,
+        };
+        add_usage(&db, chat_id, request("openai/gpt-4o", 100, 20, 0.0015)).await;
+        add_usage(&db, chat_id, request("x-ai/grok-4", 300, 50, 0.0025)).await;
+        add_usage(&db, ChatId(4), request("openai/gpt-4o", 1, 1, 1.0)).await;
+
+        let usage = load_usage(&db, chat_id).await.expect("usage recorded");
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage.prompt_tokens, 400);
+        assert_eq!(usage.completion_tokens, 70);
+        assert_eq!(usage.total_tokens, 470);
+        assert!((usage.cost - 0.004).abs() < 1e-12);
+        assert_eq!(usage.last, request("x-ai/grok-4", 300, 50, 0.0025));
+    }
+
+    #[tokio::test]
+    async fn config_overrides_are_replaced_and_deleted() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        set_config_override(&db, "GROUP_LLM_LIMIT", "5".to_string()).await;
+        set_config_override(&db, "AUTO_TITLE", "true".to_string()).await;
+        set_config_override(&db, "GROUP_LLM_LIMIT", "20".to_string()).await;
+        assert_eq!(
+            load_config_overrides(&db).await,
+            [
+                ("AUTO_TITLE".to_string(), "true".to_string()),
+                ("GROUP_LLM_LIMIT".to_string(), "20".to_string())
+            ]
+        );
+
+        assert!(delete_config_override(&db, "AUTO_TITLE").await);
+        assert!(!delete_config_override(&db, "AUTO_TITLE").await);
+        assert_eq!(load_config_overrides(&db).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn recent_messages_keep_order_and_senders() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(-100);
+        let user = |text: &str| Message {
+            role: MessageRole::User,
+            text: text.to_string(),
+        };
+        add_messages_from(&db, chat_id, [user("first")], Some("Alice".to_string())).await;
+        add_messages_from(&db, chat_id, [user("second")], Some("Bob".to_string())).await;
+        add_messages(
+            &db,
+            chat_id,
+            [
+                user("third"),
+                Message {
+                    role: MessageRole::Assistant,
+                    text: "answer".to_string(),
+                },
+            ],
+        )
+        .await;
+
+        let recent = recent_messages(&db, chat_id, 3).await;
+        let summary: Vec<(Option<&str>, &str)> = recent
+            .iter()
+            .map(|m| (m.sender_name.as_deref(), m.text.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [(Some("Bob"), "second"), (None, "third"), (None, "answer")]
+        );
+        assert_eq!(recent[2].role, MessageRole::Assistant);
+
+        let full = load_full_history(&db, chat_id).await;
+        assert_eq!(full.len(), 4);
+        assert_eq!(full[0].text, "first");
+        assert_eq!(full[1..], recent[..]);
+    }
+
+    #[tokio::test]
+    async fn edits_replace_only_the_latest_turn() {
+        let db = Connection::open_in_memory()
+            .await
+            .expect("failed to open in-memory database");
+        db.call(|conn| {
+            prepare_schema(conn);
+            Ok::<(), SqliteError>(())
+        })
+        .await
+        .expect("failed to prepare schema");
+
+        let chat_id = ChatId(7);
+        let turn = |question: &str, answer: &str| {
+            [
+                Message {
+                    role: MessageRole::User,
+                    text: question.to_string(),
+                },
+                Message {
+                    role: MessageRole::Assistant,
+                    text: answer.to_string(),
+                },
+            ]
+        };
+        add_messages(&db, chat_id, turn("first", "one")).await;
+        add_messages(&db, chat_id, turn("second", "two")).await;
+
+        let mut conversation = load_conversation(&db, chat_id).await;
+        load_history(&db, &mut conversation, 100_000).await;
+        // Nothing is known about the messages behind turns loaded from the database.
+        assert!(conversation.pop_edited_turn(41).is_none());
+
+        conversation.last_prompt = Some(conversation::LastPrompt {
+            message_id: 42,
+            text: "second".to_string(),
+        });
+        assert!(conversation.pop_edited_turn(41).is_none());
+        let (question, answer) = conversation
+            .pop_edited_turn(42)
+            .expect("the latest prompt was edited");
+        assert_eq!(
+            (question.text.as_str(), answer.text.as_str()),
+            ("second", "two")
+        );
+        // Its turn is gone, so a second edit of the same message finds nothing to replace.
+        assert!(conversation.pop_edited_turn(42).is_none());
+
+        let ids = last_turn_ids(&db, chat_id)
+            .await
+            .expect("history ends with a turn");
+        assert_eq!(delete_history_rows(&db, chat_id, ids.clone()).await, 2);
+        // Rows already gone (e.g. archived meanwhile) are just not counted.
+        assert_eq!(delete_history_rows(&db, chat_id, ids).await, 0);
+        let mut reloaded = load_conversation(&db, chat_id).await;
+        load_history(&db, &mut reloaded, 100_000).await;
+        let texts: Vec<String> = reloaded
+            .history
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, ["first", "one"]);
+    }
 }
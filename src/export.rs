@@ -0,0 +1,307 @@
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::conversation::MessageRole;
+use crate::db::AttributedMessage;
+
+/// File format of a `/export`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+impl ExportFormat {
+    /// Parse `md`/`markdown` or `json`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// What an export states about the chat before its messages.
+#[derive(Debug)]
+pub struct ExportHeader {
+    pub chat_id: i64,
+    pub title: Option<String>,
+    pub model_id: String,
+    /// System messages sent with every request, labelled as `/effective_prompt` shows them.
+    pub system: Vec<(String, String)>,
+    pub exported_at: DateTime<Utc>,
+}
+
+/// Render the whole stored history; nothing is cut, since the file isn't a Telegram message.
+pub fn render(
+    header: &ExportHeader,
+    messages: &[AttributedMessage],
+    format: ExportFormat,
+) -> String {
+    match format {
+        ExportFormat::Markdown => render_markdown(header, messages),
+        ExportFormat::Json => render_json(header, messages),
+    }
+}
+
+/// Longest title part of an export's file name, in characters.
+const MAX_FILE_TITLE_CHARS: usize = 50;
+
+/// `<title>_<date>.<ext>` with the title reduced to letters, digits and `_`, or
+/// `chat_<id>_<date>.<ext>` when the chat has no title (or nothing of it survives).
+pub fn file_name(header: &ExportHeader, format: ExportFormat) -> String {
+    let title = header
+        .title
+        .as_deref()
+        .map(sanitize_file_title)
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| format!("chat_{}", header.chat_id));
+    format!(
+        "{}_{}.{}",
+        title,
+        header.exported_at.format("%Y-%m-%d"),
+        format.file_extension()
+    )
+}
+
+/// Keep letters and digits, turning every run of anything else into one `_`.
+fn sanitize_file_title(title: &str) -> String {
+    let mut out = String::new();
+    for ch in title.chars() {
+        if ch.is_alphanumeric() {
+            out.push(ch);
+        } else if !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    out.chars()
+        .take(MAX_FILE_TITLE_CHARS)
+        .collect::<String>()
+        .trim_end_matches('_')
+        .to_string()
+}
+
+fn render_markdown(header: &ExportHeader, messages: &[AttributedMessage]) -> String {
+    let mut out = format!(
+        "# {}\n\n- Chat: {}\n- Model: {}\n- Exported: {}\n- Messages: {}\n",
+        header.title.as_deref().unwrap_or("Chat export"),
+        header.chat_id,
+        header.model_id,
+        header.exported_at.format("%Y-%m-%d %H:%M:%S UTC"),
+        messages.len()
+    );
+    for (label, text) in &header.system {
+        out.push_str(&format!("\n## System: {label}\n\n{text}\n"));
+    }
+
+    out.push_str("\n---\n");
+    for message in messages {
+        let role = role_label(message.role);
+        let written = written_at(message).format("%Y-%m-%d %H:%M:%S UTC");
+        match &message.sender_name {
+            Some(sender) => out.push_str(&format!("\n### {role} ({sender}), {written}\n\n")),
+            None => out.push_str(&format!("\n### {role}, {written}\n\n")),
+        }
+        out.push_str(&message.text);
+        out.push('\n');
+    }
+    out
+}
+
+fn render_json(header: &ExportHeader, messages: &[AttributedMessage]) -> String {
+    let export = json!({
+        "chat_id": header.chat_id,
+        "title": header.title,
+        "model": header.model_id,
+        "exported_at": header.exported_at.to_rfc3339(),
+        "system": header
+            .system
+            .iter()
+            .map(|(label, text)| json!({ "label": label, "text": text }))
+            .collect::<Vec<_>>(),
+        "messages": messages
+            .iter()
+            .map(|message| {
+                json!({
+                    "role": message.role.to_string(),
+                    "sender_name": message.sender_name,
+                    "created_at": written_at(message).to_rfc3339(),
+                    "text": message.text,
+                })
+            })
+            .collect::<Vec<_>>(),
+    });
+    serde_json::to_string_pretty(&export).expect("failed to serialize chat export")
+}
+
+fn role_label(role: MessageRole) -> &'static str {
+    match role {
+        MessageRole::System => "System",
+        MessageRole::User => "User",
+        MessageRole::Assistant => "Assistant",
+        MessageRole::Tool => "Tool",
+    }
+}
+
+fn written_at(message: &AttributedMessage) -> DateTime<Utc> {
+    DateTime::from_timestamp(message.created_at, 0).expect("stored timestamp out of range")
+}
+
+/// Plain-text listing of recent messages for `/history`, each under its role, author and
+/// age relative to `now` (unix seconds).
+pub fn render_recent(messages: &[AttributedMessage], now: i64) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let role = role_label(message.role);
+            let author = match &message.sender_name {
+                Some(sender) => format!("{role} ({sender})"),
+                None => role.to_string(),
+            };
+            format!(
+                "{author}, {}:\n{}",
+                relative_time(now - message.created_at),
+                message.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// How long ago something happened `elapsed` seconds back, in the largest whole unit.
+fn relative_time(elapsed: i64) -> String {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+
+    // Clock adjustments can put a row slightly in the future.
+    if elapsed < MINUTE {
+        "just now".to_string()
+    } else if elapsed < HOUR {
+        format!("{} min ago", elapsed / MINUTE)
+    } else if elapsed < DAY {
+        format!("{} h ago", elapsed / HOUR)
+    } else if elapsed < 2 * DAY {
+        "1 day ago".to_string()
+    } else {
+        format!("{} days ago", elapsed / DAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-08 09:30:00 UTC, half an hour before the test exports.
+    const WRITTEN_AT: i64 = 1_709_890_200;
+
+    fn message(role: MessageRole, sender_name: Option<&str>, text: &str) -> AttributedMessage {
+        AttributedMessage {
+            role,
+            sender_name: sender_name.map(str::to_string),
+            text: text.to_string(),
+            created_at: WRITTEN_AT,
+        }
+    }
+
+    #[test]
+    fn renders_header_and_every_message_in_full() {
+        let header = ExportHeader {
+            chat_id: 42,
+            title: Some("Rust questions".to_string()),
+            model_id: "openai/gpt-4o".to_string(),
+            system: vec![("chat system prompt".to_string(), "Be brief.".to_string())],
+            exported_at: DateTime::parse_from_rfc3339("2024-03-08T10:00:00Z")
+                .expect("valid timestamp")
+                .with_timezone(&Utc),
+        };
+        let long_answer = "word ".repeat(2000);
+        let messages = vec![
+            message(MessageRole::User, Some("Ann"), "How long is a word?"),
+            message(MessageRole::Assistant, None, &long_answer),
+        ];
+
+        let markdown = render(&header, &messages, ExportFormat::Markdown);
+        assert!(markdown.starts_with(
+            "# Rust questions\n\n- Chat: 42\n- Model: openai/gpt-4o\n- Exported: 2024-03-08 10:00:00 UTC\n- Messages: 2\n"
+        ));
+        assert!(markdown.contains("\n## System: chat system prompt\n\nBe brief.\n"));
+        assert!(
+            markdown.contains("\n### User (Ann), 2024-03-08 09:30:00 UTC\n\nHow long is a word?\n")
+        );
+        assert!(markdown.ends_with(&format!(
+            "\n### Assistant, 2024-03-08 09:30:00 UTC\n\n{long_answer}\n"
+        )));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&header, &messages, ExportFormat::Json))
+                .expect("valid JSON");
+        assert_eq!(json["model"], "openai/gpt-4o");
+        assert_eq!(json["system"][0]["text"], "Be brief.");
+        assert_eq!(json["messages"][0]["role"], "user");
+        assert_eq!(json["messages"][0]["sender_name"], "Ann");
+        assert_eq!(json["messages"][1]["sender_name"], serde_json::Value::Null);
+        assert_eq!(
+            json["messages"][0]["created_at"],
+            "2024-03-08T09:30:00+00:00"
+        );
+        assert_eq!(json["messages"][1]["text"], long_answer.as_str());
+
+        assert_eq!(ExportFormat::parse("MD"), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::parse("csv"), None);
+    }
+
+    #[test]
+    fn names_the_file_after_the_title() {
+        let mut header = ExportHeader {
+            chat_id: -42,
+            title: Some("Rust: lifetimes & borrows?".to_string()),
+            model_id: "openai/gpt-4o".to_string(),
+            system: Vec::new(),
+            exported_at: DateTime::parse_from_rfc3339("2024-03-08T10:00:00Z")
+                .expect("valid timestamp")
+                .with_timezone(&Utc),
+        };
+        assert_eq!(
+            file_name(&header, ExportFormat::Markdown),
+            "Rust_lifetimes_borrows_2024-03-08.md"
+        );
+
+        header.title = Some("../../".to_string());
+        assert_eq!(
+            file_name(&header, ExportFormat::Json),
+            "chat_-42_2024-03-08.json"
+        );
+        header.title = None;
+        assert_eq!(
+            file_name(&header, ExportFormat::Json),
+            "chat_-42_2024-03-08.json"
+        );
+    }
+
+    #[test]
+    fn lists_recent_messages_with_their_age() {
+        let messages = vec![
+            message(MessageRole::User, Some("Ann"), "Hi"),
+            message(MessageRole::Assistant, None, "Hello!"),
+        ];
+        assert_eq!(
+            render_recent(&messages, WRITTEN_AT + 30 * 60),
+            "User (Ann), 30 min ago:\nHi\n\nAssistant, 30 min ago:\nHello!"
+        );
+
+        assert_eq!(relative_time(-5), "just now");
+        assert_eq!(relative_time(59), "just now");
+        assert_eq!(relative_time(3 * 60 * 60 + 59), "3 h ago");
+        assert_eq!(relative_time(36 * 60 * 60), "1 day ago");
+        assert_eq!(relative_time(10 * 24 * 60 * 60), "10 days ago");
+    }
+}
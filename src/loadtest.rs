@@ -0,0 +1,429 @@
+//! Load test: many private chats talk to the bot at once while a local mock stands in for
+//! both the Telegram Bot API and OpenRouter. Run with `cargo test --features loadtest`.
+
+use crate::{App, config, conversation::MessageRole, model_prompts, models, openrouter_api};
+use std::sync::{
+    Arc,
+    atomic::{AtomicI32, Ordering},
+};
+use std::time::Duration;
+use teloxide::{
+    prelude::Bot,
+    types::{ChatId, Message, UserId},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rusqlite::rusqlite::Error as SqliteError;
+
+const CHATS: i64 = 24;
+const MESSAGES_PER_CHAT: usize = 30;
+const BOT_ID: UserId = UserId(4242);
+/// Small context (on top of the estimator's fixed per-prompt overhead) so histories get
+/// pruned many times during the run.
+const CONTEXT_LENGTH: u64 = 12_000;
+const MAX_COMPLETION_TOKENS: u64 = 256;
+/// Simulated model latency, long enough for requests of different chats to overlap.
+const MOCK_LATENCY: Duration = Duration::from_millis(5);
+/// A prompt the mock model takes far too long to answer, for `/stop`.
+const SLOW_PROMPT: &str = "take your time";
+/// A prompt the mock model answers with reasoning alongside the answer.
+const REASONING_PROMPT: &str = "think it through";
+const MOCK_REASONING: &str = "SECRET REASONING";
+
+/// Serve until the test ends; returns the base URL.
+async fn spawn_mock_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind mock server");
+    let addr = listener.local_addr().expect("mock server has an address");
+    let next_message_id = Arc::new(AtomicI32::new(1));
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.expect("mock accept failed");
+            tokio::spawn(serve_connection(stream, next_message_id.clone()));
+        }
+    });
+
+    format!("http://{addr}")
+}
+
+/// Minimal HTTP/1.1 keep-alive loop: JSON request in, JSON response out.
+async fn serve_connection(mut stream: TcpStream, next_message_id: Arc<AtomicI32>) {
+    let mut buffer = Vec::new();
+    loop {
+        let header_end = loop {
+            if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let mut chunk = [0u8; 4096];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+        let path = head
+            .split_whitespace()
+            .nth(1)
+            .expect("request line has a path")
+            .to_ascii_lowercase();
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| {
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .expect("numeric content-length")
+            })
+            .unwrap_or(0);
+
+        while buffer.len() < header_end + content_length {
+            let mut chunk = [0u8; 4096];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+        let body: serde_json::Value =
+            serde_json::from_slice(&buffer[header_end..header_end + content_length])
+                .unwrap_or(serde_json::Value::Null);
+        buffer.drain(..header_end + content_length);
+
+        let response = mock_response(&path, &body, &next_message_id).await;
+        let response = response.to_string();
+        let reply = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            response.len(),
+            response
+        );
+        if stream.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn mock_response(
+    path: &str,
+    body: &serde_json::Value,
+    next_message_id: &AtomicI32,
+) -> serde_json::Value {
+    if path.ends_with("/responses") {
+        let prompt = body["input"]
+            .as_array()
+            .expect("payload has input items")
+            .iter()
+            .rev()
+            .find(|item| item["role"] == "user")
+            .and_then(|item| item["content"][0]["text"].as_str())
+            .expect("payload has a user message");
+        tokio::time::sleep(if prompt == SLOW_PROMPT {
+            Duration::from_secs(60)
+        } else {
+            MOCK_LATENCY
+        })
+        .await;
+        let mut output = vec![serde_json::json!({
+            "type": "message",
+            "content": [{ "type": "output_text", "text": echo(prompt) }],
+        })];
+        if prompt == REASONING_PROMPT {
+            output.insert(
+                0,
+                serde_json::json!({
+                    "type": "reasoning",
+                    "summary": [{ "type": "summary_text", "text": MOCK_REASONING }],
+                }),
+            );
+        }
+        return serde_json::json!({
+            "output": output,
+            "usage": { "input_tokens": 10, "output_tokens": 10, "total_tokens": 20, "cost": 0.0 },
+        });
+    }
+
+    if path.ends_with("/sendmessage") {
+        return serde_json::json!({
+            "ok": true,
+            "result": {
+                "message_id": next_message_id.fetch_add(1, Ordering::Relaxed),
+                "date": 1_700_000_000,
+                "chat": { "id": body["chat_id"], "type": "private", "first_name": "Load" },
+                "from": { "id": BOT_ID.0, "is_bot": true, "first_name": "Bot" },
+                "text": body["text"],
+            },
+        });
+    }
+
+    // sendChatAction and anything else the bot does on the side.
+    serde_json::json!({ "ok": true, "result": true })
+}
+
+fn echo(prompt: &str) -> String {
+    format!("echo: {prompt}")
+}
+
+/// Long enough that a chat's history exceeds the context several times over.
+fn prompt(chat: i64, index: usize) -> String {
+    format!("chat {chat} message {index}{}", " lorem ipsum".repeat(15))
+}
+
+fn private_message(chat: i64, index: usize) -> Message {
+    text_message(chat, index, &prompt(chat, index))
+}
+
+fn text_message(chat: i64, index: usize, text: &str) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "message_id": index + 1,
+        "date": 1_700_000_000,
+        "chat": { "id": chat, "type": "private", "first_name": "Load" },
+        "from": { "id": chat, "is_bot": false, "first_name": "Load" },
+        "text": text,
+    }))
+    .expect("valid load test message")
+}
+
+async fn stored_history(app: &App, chat: i64) -> Vec<(MessageRole, String)> {
+    app.db
+        .call(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT role, text FROM history WHERE chat_id = ?1 ORDER BY id")?;
+            let rows = stmt.query_map([chat], |row| {
+                Ok((row.get::<_, u8>(0)?, row.get::<_, String>(1)?))
+            })?;
+            rows.collect::<Result<Vec<_>, SqliteError>>()
+        })
+        .await
+        .expect("failed to read history")
+        .into_iter()
+        .map(|(role, text)| (MessageRole::try_from(role).expect("valid role"), text))
+        .collect()
+}
+
+async fn test_app(base_url: &str) -> App {
+    let chats: Vec<String> = (1..=CHATS).map(|chat| chat.to_string()).collect();
+    let chats = chats.join(",");
+    let context_length = CONTEXT_LENGTH.to_string();
+    let max_completion_tokens = MAX_COMPLETION_TOKENS.to_string();
+    let config = config::Config::from_lookup(|name| match name {
+        "AUTHORIZED_CHATS" => Some(chats.clone()),
+        "FALLBACK_OPENROUTER_KEY" => Some("sk-load-test".to_string()),
+        "OPENROUTER_BASE_URL" => Some(base_url.to_string()),
+        "DEFAULT_MODEL_CONTEXT_LENGTH" => Some(context_length.clone()),
+        "DEFAULT_MODEL_MAX_COMPLETION_TOKENS" => Some(max_completion_tokens.clone()),
+        "TELEGRAM_SEND_RETRIES" => Some("0".to_string()),
+        // Every chat sends a burst of prompts; the per-chat rate limit would turn most away.
+        "CHAT_RATE_LIMIT" => Some("0".to_string()),
+        _ => None,
+    });
+    let bot = Bot::new("4242:LOADTEST")
+        .set_api_url(format!("{base_url}/").parse().expect("valid mock URL"));
+
+    App::new(
+        bot,
+        ("loadbot".to_string(), BOT_ID),
+        reqwest::Client::new(),
+        Arc::new(models::ModelStore::default()),
+        crate::db::open_db(":memory:").await,
+        "mock/model".to_string(),
+        Arc::new(config::ConfigStore::new(config)),
+        model_prompts::ModelPrompts::default(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_chats_keep_ordered_bounded_histories() {
+    let base_url = spawn_mock_server().await;
+    let app = test_app(&base_url).await;
+
+    // Like the dispatcher: updates of one chat run in order, different chats concurrently.
+    let workers: Vec<_> = (1..=CHATS)
+        .map(|chat| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                for index in 0..MESSAGES_PER_CHAT {
+                    app.process_message(private_message(chat, index))
+                        .await
+                        .expect("message handling failed");
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await.expect("chat worker panicked");
+    }
+
+    let model = app.resolve_model(None).await;
+    // The longest message of the run, with the estimator's per-message overhead.
+    let message_tokens = openrouter_api::estimate_tokens([echo(&prompt(CHATS, 0)).as_str()])
+        - openrouter_api::estimate_tokens([]);
+    for chat in 1..=CHATS {
+        let expected: Vec<(MessageRole, String)> = (0..MESSAGES_PER_CHAT)
+            .flat_map(|index| {
+                let prompt = prompt(chat, index);
+                [
+                    (MessageRole::User, prompt.clone()),
+                    (MessageRole::Assistant, echo(&prompt)),
+                ]
+            })
+            .collect();
+
+        let stored = stored_history(&app, chat).await;
+        assert_eq!(
+            stored, expected,
+            "stored history of chat {chat} out of order"
+        );
+
+        let conversation = app.get_conversation(ChatId(chat)).await;
+        let in_memory: Vec<(MessageRole, String)> = conversation
+            .history
+            .iter()
+            .map(|message| (message.role, message.text.clone()))
+            .collect();
+        assert!(
+            !in_memory.is_empty()
+                && in_memory.len() < expected.len()
+                && expected.ends_with(&in_memory),
+            "in-memory history of chat {chat} isn't a pruned tail of the stored one"
+        );
+
+        // Pruning runs before each request, so at most the last exchange sits above the budget.
+        let tokens =
+            openrouter_api::estimate_tokens(conversation.history.iter().map(|m| m.text.as_str()));
+        assert!(
+            tokens <= model.token_budget() + 2 * message_tokens,
+            "chat {chat} keeps {tokens} tokens in memory"
+        );
+    }
+
+    assert_eq!(app.conversations.lock().await.len(), CHATS as usize);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_busy_chat_does_not_hold_up_the_others() {
+    let base_url = spawn_mock_server().await;
+    let app = test_app(&base_url).await;
+
+    let _busy = app.get_conversation(ChatId(1)).await;
+    tokio::time::timeout(Duration::from_secs(5), app.get_conversation(ChatId(2)))
+        .await
+        .expect("chat 2 waited for chat 1's conversation lock");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn stopping_a_request_keeps_only_the_prompt() {
+    let base_url = spawn_mock_server().await;
+    let app = test_app(&base_url).await;
+
+    let worker = {
+        let app = app.clone();
+        tokio::spawn(async move {
+            app.process_message(text_message(1, 0, SLOW_PROMPT))
+                .await
+                .expect("message handling failed");
+        })
+    };
+    while !app
+        .generations
+        .lock()
+        .expect("generation lock poisoned")
+        .contains_key(&ChatId(1))
+    {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    app.process_message(text_message(1, 1, "/stop"))
+        .await
+        .expect("/stop handling failed");
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("the stopped request kept running")
+        .expect("chat worker panicked");
+
+    assert_eq!(
+        stored_history(&app, 1).await,
+        [(MessageRole::User, SLOW_PROMPT.to_string())]
+    );
+    assert!(
+        app.generations
+            .lock()
+            .expect("generation lock poisoned")
+            .is_empty()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn shown_reasoning_stays_out_of_history() {
+    let base_url = spawn_mock_server().await;
+    let app = test_app(&base_url).await;
+
+    // As `/showthinking on` sets it.
+    app.get_conversation(ChatId(1)).await.show_thinking = true;
+    crate::db::set_show_thinking(&app.db, ChatId(1), true).await;
+
+    let worker = {
+        let app = app.clone();
+        tokio::spawn(async move {
+            app.process_message(text_message(1, 1, REASONING_PROMPT))
+                .await
+                .expect("message handling failed");
+        })
+    };
+    worker.await.expect("chat worker panicked");
+
+    let stored = stored_history(&app, 1).await;
+    assert_eq!(
+        stored,
+        [
+            (MessageRole::User, REASONING_PROMPT.to_string()),
+            (MessageRole::Assistant, echo(REASONING_PROMPT)),
+        ]
+    );
+    assert!(
+        stored
+            .iter()
+            .all(|(_, text)| !text.contains(MOCK_REASONING))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn group_context_is_brought_over_once() {
+    let base_url = spawn_mock_server().await;
+    let app = test_app(&base_url).await;
+    let group = ChatId(-100);
+    let context: Vec<_> = (0..3)
+        .map(|index| crate::conversation::Message {
+            role: MessageRole::User,
+            text: format!("group message {index}"),
+        })
+        .collect();
+    app.persist_messages(group, &context).await;
+    app.dm_handoffs.lock().await.insert(
+        "token".to_string(),
+        crate::DmHandoff {
+            user_id: UserId(1),
+            group_chat_id: group,
+            created: std::time::Instant::now(),
+        },
+    );
+
+    // What `/start token` does in the private chat.
+    app.continue_from_group(ChatId(1), "token")
+        .await
+        .expect("handoff failed");
+
+    let in_memory: Vec<String> = app
+        .get_conversation(ChatId(1))
+        .await
+        .history
+        .iter()
+        .map(|message| message.text.clone())
+        .collect();
+    let expected: Vec<String> = context.iter().map(|message| message.text.clone()).collect();
+    assert_eq!(in_memory, expected);
+    assert_eq!(stored_history(&app, 1).await.len(), context.len());
+}
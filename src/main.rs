@@ -1,60 +1,302 @@
 mod commands;
+mod config;
+mod context_overrides;
 mod conversation;
 mod db;
+mod export;
+#[cfg(all(test, feature = "loadtest"))]
+mod loadtest;
+mod model_picker;
+mod model_prompts;
 mod models;
+mod onboarding;
 mod openrouter_api;
 mod panic_handler;
+mod postprocess;
+mod rate_limit;
+mod request_id;
+mod response_cache;
+mod schedule;
+mod settings;
 mod telegram;
+mod timezone;
+mod tts;
 mod typing;
+mod usage_csv;
 
 use conversation::{Conversation, MessageRole};
 use flexi_logger::{Cleanup, Criterion, Duplicate, FileSpec, Logger, Naming};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
     time::{Duration, Instant},
 };
 use telegram::{bot_split_send_formatted, escape_markdown_v2};
 use teloxide::{
     prelude::*,
-    types::{ChatId, MessageId, MessageKind, ParseMode, ReactionType},
+    types::{
+        CallbackQuery, ChatId, InputFile, MessageId, MessageKind, MessageReactionUpdated,
+        ParseMode, ReactionType, ReplyParameters, UpdateKind, UserId,
+    },
 };
-use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, RwLock};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use tokio::time;
+use tokio_util::{
+    sync::CancellationToken,
+    task::{TaskTracker, task_tracker::TaskTrackerToken},
+};
 use typing::TypingIndicator;
 
 const DEFAULT_MODEL_FALLBACK: &str = "xiaomi/mimo-v2-flash:free";
+const TITLE_PROMPT: &str = "Write a 3-5 word title for the conversation below. Reply with the title only, without quotes or punctuation at the end.";
+const TITLE_MAX_CHARS: usize = 64;
+/// Characters of each message `/history` shows.
+const HISTORY_PREVIEW_CHARS: usize = 300;
+const SUMMARY_PROMPT: &str = "Summarize the conversation below so the summary can stand in for it as context: keep facts, names, decisions, the user's preferences and open questions, and drop small talk. A summary of even earlier turns may come first; fold it in. Reply in the language of the conversation, in plain text, in at most 200 words.";
+const TLDR_PROMPT: &str = "Summarize the group chat discussion below in a few short bullet points: the main topics, decisions and open questions, naming who said what where it matters. Reply in the language of the discussion, in plain text.";
+/// Least time between edits of a streaming answer, to stay within Telegram's rate limits.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(750);
+/// Pause between the messages of a `/broadcast`, well under Telegram's ~30 messages/second.
+const BROADCAST_SEND_INTERVAL: Duration = Duration::from_millis(100);
+/// How long shutdown waits for background work before closing the database anyway.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+const HISTORY_ARCHIVAL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often due `/schedule` prompts are looked up.
+const SCHEDULE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a `/dm` deep-link stays valid.
+const DM_HANDOFF_TTL: Duration = Duration::from_secs(10 * 60);
+/// Most recent group messages copied into the private chat by a `/dm` link.
+const DM_HANDOFF_MESSAGES: usize = 20;
+/// Quiet period after telling a chat (`MEDIA_DECLINE`) that only text is understood.
+const MEDIA_DECLINE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MEDIA_DECLINE_MESSAGE: &str =
+    "I can only read text messages, so I can't see that one. Please type your question instead.";
+/// Stands in for a photo in the stored prompt; the image itself is sent once and not kept.
+const IMAGE_MARKER: &str = "[image]";
 
 #[derive(Debug, Clone)]
 struct App {
     bot: Bot,
     bot_username: String,
+    bot_user_id: UserId,
     http_client: reqwest::Client,
-    models: Arc<RwLock<Vec<openrouter_api::ModelSummary>>>,
-    conversations: Arc<Mutex<HashMap<ChatId, Conversation>>>,
+    models: Arc<models::ModelStore>,
+    /// Loaded chats, each behind its own lock: the map's lock is only held to look one up,
+    /// so a chat waiting on the network never holds up the others.
+    conversations: Arc<Mutex<HashMap<ChatId, Arc<Mutex<Conversation>>>>>,
     group_llm_rate_limits: Arc<Mutex<HashMap<ChatId, VecDeque<Instant>>>>,
+    /// Per-chat buckets for `CHAT_RATE_LIMIT`; admins have none.
+    chat_rate_limits: Arc<Mutex<HashMap<ChatId, rate_limit::Bucket>>>,
+    /// Per-chat (local day, request count) on the operator's fallback key.
+    fallback_key_usage: Arc<Mutex<HashMap<ChatId, (chrono::NaiveDate, u32)>>>,
+    approval_requests: Arc<Mutex<ApprovalRequests>>,
+    response_cache: Arc<Mutex<response_cache::ResponseCache>>,
+    /// Pending `/dm` deep-link tokens.
+    dm_handoffs: Arc<Mutex<HashMap<String, DmHandoff>>>,
+    /// When each chat was last told that media isn't supported.
+    media_declines: Arc<Mutex<HashMap<ChatId, Instant>>>,
     db: tokio_rusqlite::Connection,
     system_prompt0: conversation::Message,
     default_model: String,
+    config: Arc<config::ConfigStore>,
+    model_prompts: Arc<model_prompts::ModelPrompts>,
+    /// Update handlers and the work they start (titles, scheduled prompts); shutdown waits
+    /// for them before the database is closed.
+    tasks: TaskTracker,
+    /// Cancelled on Ctrl-C or SIGTERM to stop the periodic background loops.
+    shutdown: CancellationToken,
+    /// Updates being handled per chat, for the shutdown log.
+    in_flight: Arc<std::sync::Mutex<HashMap<ChatId, usize>>>,
+    /// The request being generated per chat, cancelled by `/stop`.
+    generations: Arc<std::sync::Mutex<HashMap<ChatId, Arc<CancellationToken>>>>,
+}
+
+/// Counts an update as in flight for its chat, and as a task shutdown waits for, until
+/// dropped.
+#[derive(Debug)]
+struct InFlight {
+    chats: Arc<std::sync::Mutex<HashMap<ChatId, usize>>>,
+    chat_id: ChatId,
+    _task: TaskTrackerToken,
+}
+
+impl InFlight {
+    fn new(app: &App, chat_id: ChatId) -> Self {
+        *app.in_flight
+            .lock()
+            .expect("in-flight lock poisoned")
+            .entry(chat_id)
+            .or_default() += 1;
+        Self {
+            chats: Arc::clone(&app.in_flight),
+            chat_id,
+            _task: app.tasks.token(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut chats = self.chats.lock().expect("in-flight lock poisoned");
+        let count = chats
+            .get_mut(&self.chat_id)
+            .expect("in-flight chat registered");
+        *count -= 1;
+        if *count == 0 {
+            chats.remove(&self.chat_id);
+        }
+    }
+}
+
+/// Registers a chat's request for `/stop` until dropped.
+#[derive(Debug)]
+struct Generation {
+    generations: Arc<std::sync::Mutex<HashMap<ChatId, Arc<CancellationToken>>>>,
+    chat_id: ChatId,
+    stop: Arc<CancellationToken>,
+}
+
+impl Generation {
+    fn start(app: &App, chat_id: ChatId) -> Self {
+        let stop = Arc::new(CancellationToken::new());
+        app.generations
+            .lock()
+            .expect("generation lock poisoned")
+            .insert(chat_id, Arc::clone(&stop));
+        Self {
+            generations: Arc::clone(&app.generations),
+            chat_id,
+            stop,
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        let mut generations = self.generations.lock().expect("generation lock poisoned");
+        // A later request of the chat (a scheduled prompt, say) may have replaced this one.
+        if generations
+            .get(&self.chat_id)
+            .is_some_and(|stop| Arc::ptr_eq(stop, &self.stop))
+        {
+            generations.remove(&self.chat_id);
+        }
+    }
+}
+
+/// Admin notifications about chats waiting for approval (in memory only).
+#[derive(Debug, Default)]
+struct ApprovalRequests {
+    /// Pending chats the admins were already told about.
+    notified: HashSet<ChatId>,
+    /// Notification (admin chat, message) -> pending chat, so a reaction can answer it.
+    by_message: HashMap<(ChatId, MessageId), ChatId>,
+}
+
+/// A group conversation a user asked to continue privately with `/dm`.
+#[derive(Debug)]
+struct DmHandoff {
+    /// Only the user who asked may redeem the link.
+    user_id: UserId,
+    group_chat_id: ChatId,
+    created: Instant,
 }
 
 #[tokio::main]
 async fn main() {
     let app = init().await;
+    app.spawn_history_archival();
+    app.spawn_schedules();
+
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(|app: App, msg: Message| {
+            request_id::scope(async move {
+                let chat_id = msg.chat.id;
+                let _in_flight = InFlight::new(&app, chat_id);
+                if let Err(err) = app.process_message(msg).await {
+                    if telegram::is_send_forbidden(&err) {
+                        app.mark_chat_unreachable(chat_id, &err).await;
+                    } else {
+                        log::error!("Error processing message: {}", err);
+                    }
+                }
+                respond(())
+            })
+        }))
+        .branch(
+            Update::filter_edited_message().endpoint(|app: App, msg: Message| {
+                request_id::scope(async move {
+                    let _in_flight = InFlight::new(&app, msg.chat.id);
+                    if let Err(err) = app.process_edited_message(msg).await {
+                        log::error!("Error processing edited message: {}", err);
+                    }
+                    respond(())
+                })
+            }),
+        )
+        .branch(
+            Update::filter_callback_query().endpoint(|app: App, query: CallbackQuery| {
+                request_id::scope(async move {
+                    // Buttons are only sent to private chats, whose id is the user's.
+                    let chat_id = query
+                        .message
+                        .as_ref()
+                        .map_or(ChatId::from(query.from.id), |message| message.chat().id);
+                    let _in_flight = InFlight::new(&app, chat_id);
+                    if let Err(err) = app.process_callback_query(query).await {
+                        log::error!("Error processing callback query: {}", err);
+                    }
+                    respond(())
+                })
+            }),
+        )
+        .branch(Update::filter_message_reaction_updated().endpoint(
+            |app: App, reaction: MessageReactionUpdated| {
+                request_id::scope(async move {
+                    let _in_flight = InFlight::new(&app, reaction.chat.id);
+                    if let Err(err) = app.process_reaction(reaction).await {
+                        log::error!("Error processing reaction: {}", err);
+                    }
+                    respond(())
+                })
+            },
+        ));
 
-    teloxide::repl(app.bot.clone(), move |_bot: Bot, msg: Message| {
-        let app = app.clone();
-        async move {
-            let result = app.process_message(msg).await;
+    let mut dispatcher = Dispatcher::builder(app.bot.clone(), handler)
+        .dependencies(dptree::deps![app.clone()])
+        .distribution_function(distribution_key)
+        .default_handler(|_| async {})
+        .build();
+    tokio::spawn(app.clone().stop_on_signal(dispatcher.shutdown_token()));
+    // Returns once a signal stopped it and every update being handled is done.
+    dispatcher.dispatch().await;
+    app.finish_shutdown().await;
+}
 
-            if let Err(err) = result {
-                log::error!("Error processing message: {}", err);
-            }
+/// Updates of one chat are handled one after another, except `/stop`, which has to get
+/// through while the chat's answer is still being generated. Prompts, edits, regenerations
+/// and tool results of a chat are therefore answered one at a time, so each request sees
+/// the history the previous one left and turns are stored in order.
+fn distribution_key(update: &Update) -> Option<ChatId> {
+    if let UpdateKind::Message(msg) = &update.kind
+        && msg.text().is_some_and(commands::is_stop)
+    {
+        return None;
+    }
+    update.chat().map(|chat| chat.id)
+}
 
-            respond(())
+/// Wait for Ctrl-C or SIGTERM (as sent by `docker stop`), returning its name.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to listen for SIGTERM");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.expect("failed to listen for Ctrl-C");
+            "Ctrl-C"
         }
-    })
-    .await;
+        _ = terminate.recv() => "SIGTERM",
+    }
 }
 
 async fn init() -> App {
@@ -73,26 +315,46 @@ async fn init() -> App {
             Cleanup::KeepLogFiles(3),
         )
         .duplicate_to_stdout(Duplicate::All)
+        .format(request_id::log_format)
         .start()
         .expect("failed to start logger");
 
     let bot = Bot::from_env();
     let http_client = reqwest::Client::new();
 
-    let (bot_username, models, db) = tokio::join!(
-        fetch_bot_username(&bot),
-        models::spawn_model_refresh(http_client.clone()),
+    let config = config::Config::from_env();
+    telegram::configure_send_retries(
+        config.telegram_send_retries,
+        config.telegram_send_retry_delay,
+    );
+    let model_prompts = config
+        .model_prompts_file
+        .as_deref()
+        .map(model_prompts::ModelPrompts::load)
+        .unwrap_or_default();
+
+    let ((bot_username, bot_user_id), models, db) = tokio::join!(
+        fetch_bot_identity(&bot),
+        models::spawn_model_refresh(http_client.clone(), &config),
         db::init_db()
     );
 
-    let conversations: Arc<Mutex<HashMap<ChatId, Conversation>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let group_llm_rate_limits: Arc<Mutex<HashMap<ChatId, VecDeque<Instant>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let system_prompt0 = conversation::Message {
-        role: conversation::MessageRole::System,
-        text: "You are a Telegram bot. In group chats you may see many messages, but only treat the latest message that explicitly mentions @<bot_name> (or replies to you) as the user's prompt; ignore the rest. Respond in plain text only (no Markdown).".to_string(),
+    // `/config set` overrides stored in the database win over the environment.
+    let overrides = config::checked_overrides(db::load_config_overrides(&db).await);
+    let config = if overrides.is_empty() {
+        config
+    } else {
+        log::info!(
+            "applying config overrides: {}",
+            overrides.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        config::Config::from_env_with(&overrides)
     };
+
+    if !config.admin_chats.is_empty() {
+        db::grant_admins(&db, config.admin_chats.iter().copied().collect()).await;
+    }
+
     let default_model =
         std::env::var("DEFAULT_MODEL").unwrap_or_else(|_| DEFAULT_MODEL_FALLBACK.to_string());
 
@@ -101,25 +363,316 @@ async fn init() -> App {
         bot_username,
         default_model
     );
+    models::check_default(&models.load(), &default_model);
 
-    App {
+    App::new(
         bot,
-        bot_username,
+        (bot_username, bot_user_id),
         http_client,
         models,
-        conversations,
-        group_llm_rate_limits,
         db,
-        system_prompt0,
         default_model,
-    }
+        Arc::new(config::ConfigStore::new(config)),
+        model_prompts,
+    )
 }
 
 impl App {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        bot: Bot,
+        (bot_username, bot_user_id): (String, UserId),
+        http_client: reqwest::Client,
+        models: Arc<models::ModelStore>,
+        db: tokio_rusqlite::Connection,
+        default_model: String,
+        config: Arc<config::ConfigStore>,
+        model_prompts: model_prompts::ModelPrompts,
+    ) -> Self {
+        let system_prompt0 = conversation::Message {
+            role: conversation::MessageRole::System,
+            text: config.load().base_system_prompt(&bot_username),
+        };
+
+        App {
+            bot,
+            bot_username,
+            bot_user_id,
+            http_client,
+            models,
+            conversations: Arc::new(Mutex::new(HashMap::new())),
+            group_llm_rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            chat_rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            fallback_key_usage: Arc::new(Mutex::new(HashMap::new())),
+            approval_requests: Arc::new(Mutex::new(ApprovalRequests::default())),
+            dm_handoffs: Arc::new(Mutex::new(HashMap::new())),
+            media_declines: Arc::new(Mutex::new(HashMap::new())),
+            tasks: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            in_flight: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            generations: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            response_cache: Arc::new(Mutex::new(response_cache::ResponseCache::new(
+                config.load().response_cache_size,
+                config.load().response_cache_ttl,
+            ))),
+            db,
+            system_prompt0,
+            default_model,
+            config,
+            model_prompts: Arc::new(model_prompts),
+        }
+    }
+
+    /// Stop on the first Ctrl-C or SIGTERM: the dispatcher takes no more updates and the
+    /// background loops end after their current round. A second signal exits at once.
+    async fn stop_on_signal(self, dispatcher: teloxide::dispatching::ShutdownToken) {
+        let signal = shutdown_signal().await;
+        let chats = self
+            .in_flight
+            .lock()
+            .expect("in-flight lock poisoned")
+            .len();
+        log::info!("{signal} received; shutting down with {chats} chat(s) in flight");
+        self.shutdown.cancel();
+        // Dispatching ends on its own once the handlers are done; no need to wait here.
+        if dispatcher.shutdown().is_err() {
+            log::warn!("the dispatcher isn't running yet; nothing to stop");
+        }
+
+        let signal = shutdown_signal().await;
+        log::warn!("{signal} received again; exiting without waiting");
+        std::process::exit(1);
+    }
+
+    /// Once the dispatcher is done: wait up to `SHUTDOWN_GRACE` for work the handlers
+    /// started (titles, scheduled prompts) and the background loops, then close the
+    /// database. Typing indicators stop with the handlers that own them.
+    async fn finish_shutdown(self) {
+        self.tasks.close();
+        if time::timeout(SHUTDOWN_GRACE, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "{} task(s) still running after {:?}; closing the database anyway",
+                self.tasks.len(),
+                SHUTDOWN_GRACE
+            );
+        }
+        match self.db.clone().close().await {
+            Ok(()) => log::info!("database closed; bye"),
+            Err(err) => log::error!("failed to close the database: {err}"),
+        }
+    }
+
+    /// Periodically archive history older than `HISTORY_MAX_AGE_DAYS`, if configured.
+    fn spawn_history_archival(&self) {
+        let Some(max_age) = self.config.load().history_max_age else {
+            return;
+        };
+
+        let app = self.clone();
+        self.tasks.spawn(async move {
+            loop {
+                app.run_history_archival(max_age).await;
+                tokio::select! {
+                    _ = app.shutdown.cancelled() => break,
+                    _ = time::sleep(HISTORY_ARCHIVAL_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Archive (or delete) history older than `max_age` and resync the affected cached
+    /// conversations. Returns (rows, chats) affected.
+    async fn run_history_archival(&self, max_age: Duration) -> (usize, usize) {
+        let max_age = chrono::Duration::from_std(max_age).expect("history max age out of range");
+        let cutoff = (chrono::Utc::now() - max_age).timestamp();
+        let delete = self.config.load().history_archive_mode == config::ArchiveMode::Delete;
+
+        let affected = db::archive_history_before(&self.db, cutoff, delete).await;
+        let rows = affected.iter().map(|(_, count)| count).sum::<usize>();
+        if affected.is_empty() {
+            return (0, 0);
+        }
+        log::info!(
+            "{} {} history row(s) older than {} day(s) across {} chat(s)",
+            if delete { "deleted" } else { "archived" },
+            rows,
+            max_age.num_days(),
+            affected.len()
+        );
+
+        // Cached histories mirror the stored tail; reload them so they don't keep rows the
+        // database no longer has. Ephemeral sessions were never stored and stay untouched.
+        for (chat_id, _) in &affected {
+            let Some(conv) = self.cached_conversation(ChatId(*chat_id)).await else {
+                continue;
+            };
+            let mut conv = conv.lock().await;
+            if conv.ephemeral {
+                continue;
+            }
+            let model = self.resolve_model(conv.model_id.as_deref()).await;
+            db::load_history(&self.db, &mut conv, model.token_budget()).await;
+        }
+
+        (rows, affected.len())
+    }
+
+    /// Fire due `/schedule` prompts in the background.
+    fn spawn_schedules(&self) {
+        let app = self.clone();
+        self.tasks.spawn(async move {
+            loop {
+                app.run_due_schedules().await;
+                tokio::select! {
+                    _ = app.shutdown.cancelled() => break,
+                    _ = time::sleep(SCHEDULE_POLL_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    async fn run_due_schedules(&self) {
+        let now = chrono::Utc::now();
+        for due in db::due_schedules(&self.db, now.timestamp()).await {
+            // Advance before running so a failing prompt isn't retried every poll; runs
+            // missed while the bot was down are skipped, not caught up.
+            let offset = { self.get_conversation(ChatId(due.chat_id)).await.utc_offset };
+            let next = schedule::next_run(due.recurrence, due.time, offset, now);
+            db::set_schedule_next_run(&self.db, due.id, next.timestamp()).await;
+
+            let app = self.clone();
+            self.tasks.spawn(request_id::scope(async move {
+                if let Err(err) = app.run_schedule(&due).await {
+                    if telegram::is_send_forbidden(&err) {
+                        app.mark_chat_unreachable(ChatId(due.chat_id), &err).await;
+                    } else {
+                        log::error!("Error running schedule {}: {}", due.id, err);
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Run a schedule's prompt without the chat history, with the chat's model, key and
+    /// system prompts, and post the answer.
+    async fn run_schedule(&self, due: &schedule::Schedule) -> anyhow::Result<()> {
+        let chat_id = ChatId(due.chat_id);
+        log::info!("running schedule {} for chat {}", due.id, chat_id);
+
+        let ready = {
+            let conversation = self.get_conversation(chat_id).await;
+            if !conversation.is_authorized {
+                log::info!(
+                    "skipping schedule {} of unauthorized chat {}",
+                    due.id,
+                    chat_id
+                );
+                return Ok(());
+            }
+            let model = self.resolve_model(conversation.model_id.as_deref()).await;
+            let mut messages: Vec<conversation::Message> = self
+                .system_messages(&conversation, &model.id)
+                .into_iter()
+                .map(|(_, message)| message)
+                .collect();
+            messages.push(conversation::Message {
+                role: MessageRole::User,
+                text: due.prompt.clone(),
+            });
+            // Nobody is around to answer tool calls, so only the web plugin is offered.
+            let options = openrouter_api::PayloadOptions {
+                tools: None,
+                web_search: conversation.web_search,
+                sampling: conversation.sampling,
+                reasoning_effort: conversation
+                    .reasoning_effort
+                    .filter(|_| model.capabilities.reasoning),
+            };
+            let api_key = self.api_key_for(chat_id, &conversation).await;
+            api_key.map(|(openrouter_api_key, quota_day)| LlmRequestReady {
+                payload: openrouter_api::prepare_payload(
+                    &model.id,
+                    messages.iter(),
+                    false,
+                    &options,
+                ),
+                openrouter_api_key,
+                quota_day,
+                model_id: model.id,
+                use_cache: false,
+                truncated_input: None,
+                ramped_temperature: None,
+                fallback: None,
+            })
+        };
+        let ready = match ready {
+            Ok(ready) => ready,
+            Err(err) => {
+                let message = format!("Scheduled prompt #{}: {}", due.id, err.user_message());
+                self.bot.send_message(chat_id, message).await?;
+                return Ok(());
+            }
+        };
+
+        let llm_call = self.call_llm(chat_id, ready).await;
+        self.log_request(chat_id, &llm_call).await;
+        let message = match llm_call.response {
+            Ok(mut response) => {
+                let (system_prompt, clean_thinking) = {
+                    let conversation = self.get_conversation(chat_id).await;
+                    let system_prompt = conversation
+                        .system_prompt
+                        .as_ref()
+                        .map(|prompt| prompt.text.clone())
+                        .or_else(|| {
+                            self.model_prompts
+                                .for_model(&llm_call.model_id)
+                                .map(str::to_string)
+                        });
+                    (system_prompt, conversation.clean_thinking)
+                };
+                self.clean_answer(
+                    chat_id,
+                    &mut response,
+                    system_prompt.as_deref(),
+                    clean_thinking,
+                );
+                format!(
+                    "{}\n\n{}",
+                    escape_markdown_v2(&format!("⏰ Scheduled #{}: {}", due.id, due.prompt)),
+                    telegram::markdown_to_telegram(&response.completion_text)
+                )
+            }
+            Err(err) => {
+                log::error!("schedule {} failed for chat {}: {err}", due.id, chat_id);
+                escape_markdown_v2(&format!(
+                    "Scheduled prompt #{} failed; it will run again next time.",
+                    due.id
+                ))
+            }
+        };
+        bot_split_send_formatted(&self.bot, chat_id, &message, None, ParseMode::MarkdownV2).await?;
+        self.mark_chat_reachable(chat_id).await;
+        Ok(())
+    }
+
     async fn process_message(&self, msg: Message) -> anyhow::Result<()> {
-        if !is_common_text_message(&msg) {
+        if telegram::is_own_message(&msg, self.bot_user_id) {
+            log::debug!("ignoring the bot's own message in chat {}", msg.chat.id);
             return Ok(());
         }
+        if msg.chat.is_private()
+            && msg.photo().is_some()
+            && !telegram::is_automated_message(&msg, self.bot_user_id)
+        {
+            return self.process_photo(msg).await;
+        }
+        if !is_common_text_message(&msg) {
+            return self.maybe_decline_media(&msg).await;
+        }
 
         let chat_id = msg.chat.id;
         let is_public = msg.chat.is_group() || msg.chat.is_supergroup() || msg.chat.is_channel();
@@ -128,34 +681,111 @@ impl App {
 
         self.maybe_update_user_name(&msg).await;
 
-        if is_public && !self.should_process_group_message(&msg).await {
+        // `/dm`, `/delete_commands`, `/tldr` and `/whoami` are the only commands groups may
+        // use, with or without mentioning the bot; other commands are at most cleaned up.
+        let message_text = msg.text().unwrap().trim();
+        if is_public
+            && is_command(message_text)
+            && !telegram::is_automated_message(&msg, self.bot_user_id)
+        {
+            match commands::parse_command(message_text, &self.bot_username) {
+                Ok(commands::Command::Dm) => {
+                    if !self.ensure_authorized(chat_id).await? {
+                        return Ok(());
+                    }
+                    self.offer_private_continuation(&msg).await?;
+                    return Ok(());
+                }
+                Ok(commands::Command::DeleteCommands(arg)) => {
+                    if !self.ensure_authorized(chat_id).await? {
+                        return Ok(());
+                    }
+                    self.set_group_delete_commands(&msg, arg).await?;
+                    return Ok(());
+                }
+                // Works before authorization: it's how a group learns the id to get approved.
+                Ok(commands::Command::Whoami) => {
+                    self.send_whoami(chat_id).await?;
+                    return Ok(());
+                }
+                Ok(commands::Command::Tldr(arg)) => {
+                    if !self.ensure_authorized(chat_id).await? {
+                        return Ok(());
+                    }
+                    self.summarize_group(chat_id, msg.id, arg).await?;
+                    return Ok(());
+                }
+                // A key posted in a group must never reach the history, authorized or not.
+                Ok(commands::Command::Key(_)) => {
+                    self.delete_command_message(&msg, true).await?;
+                    return Ok(());
+                }
+                Ok(commands::Command::Ignore) | Err(_) => {}
+                Ok(_) => {
+                    let delete_commands = { self.get_conversation(chat_id).await.delete_commands };
+                    if delete_commands {
+                        self.delete_command_message(&msg, false).await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if is_public && !self.should_process_group_message(&msg) {
             let user_message = self.extract_user_message(&msg).await?;
-            self.persist_messages(chat_id, std::slice::from_ref(&user_message))
+            self.persist_group_message(chat_id, user_message, telegram::sender_name(&msg))
                 .await;
             log::info!("ignored group message without mention for chat {}", chat_id);
             return Ok(());
         }
 
-        if is_from_bot(&msg) {
-            log::info!("ignoring message from bot account in chat {}", msg.chat.id);
+        if telegram::is_automated_message(&msg, self.bot_user_id) {
+            log::info!(
+                "ignoring message from a bot or channel in chat {}",
+                msg.chat.id
+            );
+            return Ok(());
+        }
+
+        if !is_public
+            && is_command(message_text)
+            && matches!(
+                commands::parse_command(message_text, &self.bot_username),
+                Ok(commands::Command::Whoami)
+            )
+        {
+            // Answered before authorization, so new users can find their chat id.
+            self.send_whoami(chat_id).await?;
             return Ok(());
         }
 
-        self.ensure_authorized(chat_id).await?;
+        if !self.ensure_authorized(chat_id).await? {
+            return Ok(());
+        }
 
-        let message_text = msg.text().unwrap().trim();
         if is_command(message_text) {
             if !is_public {
-                self.process_command(chat_id, message_text).await?;
+                let replied_text = msg.reply_to_message().and_then(|reply| reply.text());
+                self.process_command(chat_id, msg.id, message_text, replied_text)
+                    .await?;
             }
 
             return Ok(());
         }
 
+        if !is_public && self.handle_onboarding_reply(chat_id, message_text).await? {
+            return Ok(());
+        }
+
+        if !self.check_chat_rate_limit(chat_id, msg.id).await? {
+            return Ok(());
+        }
+
         if is_public && let Err(wait_time) = self.check_group_llm_rate_limit(chat_id).await {
             let wait_minutes = wait_time.as_secs().div_ceil(60);
             let message = format!(
-                "Rate limit reached: max 10 LLM requests per hour for group chats. Try again in about {wait_minutes} minute(s)."
+                "Rate limit reached: max {} LLM requests per hour for group chats. Try again in about {wait_minutes} minute(s).",
+                self.config.load().group_llm_limit
             );
             self.bot.send_message(chat_id, message).await?;
             log::info!(
@@ -167,649 +797,4727 @@ impl App {
         }
 
         let user_message = self.extract_user_message(&msg).await?;
-        let (payload, openai_api_key) = match self.prepare_llm_request(chat_id, &user_message).await
-        {
-            Ok(ready) => (ready.payload, ready.openrouter_api_key),
-            Err(LlmRequestError::NoApiKeyProvided) => {
-                let message = format!("No API key provided for chat id {}", chat_id);
-                self.bot.send_message(chat_id, &message).await?;
-                return Err(anyhow::anyhow!("No API key provided"));
-            }
-        };
-
-        let llm_response = {
-            let _typing_indicator = TypingIndicator::new(self.bot.clone(), chat_id);
-            openrouter_api::send(&self.http_client, &openai_api_key, payload).await
-        };
-
-        self.handle_llm_response(chat_id, msg.id, is_public, user_message, llm_response)
+        self.answer_prompt(chat_id, msg.id, is_public, user_message, None)
             .await
     }
 
-    async fn check_group_llm_rate_limit(&self, chat_id: ChatId) -> Result<(), Duration> {
-        const GROUP_LLM_LIMIT: usize = 10;
-        const GROUP_LLM_WINDOW: Duration = Duration::from_secs(60 * 60);
-
-        let mut rate_limits = self.group_llm_rate_limits.lock().await;
-        let timestamps = rate_limits.entry(chat_id).or_default();
-        let now = Instant::now();
-
-        while let Some(&oldest) = timestamps.front() {
-            if now.duration_since(oldest) >= GROUP_LLM_WINDOW {
-                timestamps.pop_front();
-            } else {
-                break;
-            }
+    /// A photo in an authorized private chat is sent with its caption to the chat's model,
+    /// when the model accepts images. History keeps only the caption, marked as an image.
+    async fn process_photo(&self, msg: Message) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        if !self.ensure_authorized(chat_id).await? {
+            return Ok(());
         }
-
-        if timestamps.len() >= GROUP_LLM_LIMIT {
-            let oldest = *timestamps
-                .front()
-                .expect("timestamps should be non-empty when over limit");
-            let elapsed = now.duration_since(oldest);
-            let wait_time = GROUP_LLM_WINDOW
-                .checked_sub(elapsed)
-                .unwrap_or_else(|| Duration::from_secs(0));
-            return Err(wait_time);
+        log::info!("received photo from chat {}", chat_id);
+        if !self.check_chat_rate_limit(chat_id, msg.id).await? {
+            return Ok(());
         }
 
-        timestamps.push_back(now);
-        Ok(())
-    }
-
-    async fn ensure_authorized(&self, chat_id: ChatId) -> anyhow::Result<()> {
-        if self.get_conversation(chat_id).await.is_authorized {
+        let model_id = { self.get_conversation(chat_id).await.model_id.clone() };
+        let model = self.resolve_model(model_id.as_deref()).await;
+        if !model.capabilities.vision {
+            let err = LlmRequestError::ImagesUnsupported { model_id: model.id };
+            self.bot
+                .send_message(chat_id, err.user_message())
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
             return Ok(());
         }
 
-        let message = format!(
-            "You are not authorized to use this bot. Chat id {}",
-            chat_id
-        );
-        self.bot.send_message(chat_id, &message).await?;
-
-        Err(anyhow::anyhow!("Unauthorized"))
-    }
-
-    /// In group chats, only process messages that mention or reply to the bot; otherwise, just record them.
-    async fn should_process_group_message(&self, msg: &Message) -> bool {
-        let bot_username = self.bot_username.to_ascii_lowercase();
-
-        let mentions_bot = msg
-            .text()
-            .map(|t| {
-                t.to_ascii_lowercase()
-                    .contains(&format!("@{}", bot_username))
-            })
-            .unwrap_or(false);
-
-        let is_reply_to_bot = msg
-            .reply_to_message()
-            .and_then(|m| m.from.as_ref())
-            .map(|user| {
-                user.is_bot
-                    && user
-                        .username
-                        .as_deref()
-                        .map(|u| u.eq_ignore_ascii_case(&bot_username))
-                        .unwrap_or(false)
-            })
-            .unwrap_or(false);
-
-        mentions_bot || is_reply_to_bot
+        let photo = msg.photo().expect("only photo messages get here");
+        let image_url =
+            openrouter_api::jpeg_data_url(&telegram::download_photo(&self.bot, photo).await?);
+        let caption = msg.caption().unwrap_or_default().trim();
+        let user_message = conversation::Message {
+            role: MessageRole::User,
+            text: if caption.is_empty() {
+                IMAGE_MARKER.to_string()
+            } else {
+                format!("{IMAGE_MARKER} {caption}")
+            },
+        };
+        self.answer_prompt(chat_id, msg.id, false, user_message, Some(image_url))
+            .await
     }
 
-    async fn handle_llm_response(
+    /// Send a new prompt (the text of message `msg_id`, plus its image if any) to the chat's
+    /// model and answer it.
+    async fn answer_prompt(
         &self,
         chat_id: ChatId,
         msg_id: MessageId,
-        is_group: bool,
+        is_public: bool,
         user_message: conversation::Message,
-        llm_response: anyhow::Result<openrouter_api::Response>,
+        image_url: Option<String>,
     ) -> anyhow::Result<()> {
-        match llm_response {
-            Ok(llm_response) => {
-                log::info!(
-                    "LLM usage: prompt_tokens={}, completion_tokens={}, total_tokens={}, cost={}",
-                    llm_response.prompt_tokens,
-                    llm_response.completion_tokens,
-                    llm_response.total_tokens,
-                    llm_response.cost
-                );
-                let reply_to = if is_group { Some(msg_id) } else { None };
-                telegram::bot_split_send(
-                    &self.bot,
+        {
+            let mut conversation = self.get_conversation(chat_id).await;
+            if conversation.pending_tool_calls.take().is_some() {
+                log::info!("discarding pending tool calls for chat {}", chat_id);
+            }
+            conversation.regenerations = 0;
+            conversation.last_prompt = Some(conversation::LastPrompt {
+                message_id: msg_id.0,
+                text: user_message.text.clone(),
+            });
+        }
+        let mut ready = match self.prepare_llm_request(chat_id, &user_message).await {
+            Ok(ready) => ready,
+            Err(err @ LlmRequestError::FallbackKeyLimitReached { .. }) => {
+                self.bot.send_message(chat_id, err.user_message()).await?;
+                log::info!("fallback key daily limit hit for chat {}", chat_id);
+                return Ok(());
+            }
+            // A chat without a key is a normal state, not an error.
+            Err(
+                err @ (LlmRequestError::NoApiKeyProvided
+                | LlmRequestError::InputTooLarge { .. }
+                | LlmRequestError::ImagesUnsupported { .. }),
+            ) => {
+                self.bot.send_message(chat_id, err.user_message()).await?;
+                return Ok(());
+            }
+        };
+        if let Some(image_url) = image_url {
+            ready.attach_image(image_url);
+        }
+        if let Some((tokens, max_tokens)) = ready.truncated_input {
+            self.bot
+                .send_message(
                     chat_id,
-                    &llm_response.completion_text,
-                    reply_to,
+                    format!(
+                        "Your message is too large for the selected model (≈{tokens} tokens, max {max_tokens}); only the first part was sent."
+                    ),
                 )
                 .await?;
-                let assistant_message = conversation::Message {
-                    role: MessageRole::Assistant,
-                    text: llm_response.completion_text,
-                };
-                let messages = [user_message, assistant_message];
-                self.persist_messages(chat_id, &messages).await;
-            }
-            Err(err) => {
-                log::error!("failed to get llm response: {err}");
-
-                self.bot
-                    .set_message_reaction(chat_id, msg_id)
-                    .reaction(vec![ReactionType::Emoji {
-                        emoji: "🖕".to_string(),
-                    }])
-                    .await?;
-            }
         }
 
-        Ok(())
-    }
+        let llm_call = self
+            .call_llm_for_reply(chat_id, msg_id, is_public, ready)
+            .await;
 
-    async fn maybe_update_user_name(&self, msg: &Message) {
-        let user_name = if msg.chat.is_group() || msg.chat.is_supergroup() {
-            msg.chat.title().map(str::to_owned)
-        } else {
-            let Some(user) = msg.from.as_ref() else {
-                return;
-            };
+        self.handle_llm_response(chat_id, msg_id, is_public, user_message, llm_call)
+            .await
+    }
 
-            user.username.clone().or_else(|| {
-                let mut name = user.first_name.clone();
-                if let Some(last) = user.last_name.as_ref()
-                    && !last.is_empty()
-                {
-                    if !name.is_empty() {
-                        name.push(' ');
-                    }
-                    name.push_str(last);
+    /// An edit of the message behind the newest turn replaces that turn: the old question and
+    /// answer are dropped and the edited text is answered instead. Edits of older messages
+    /// (or of ones that never got an answer) are only logged.
+    async fn process_edited_message(&self, msg: Message) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        if telegram::is_automated_message(&msg, self.bot_user_id) || !is_common_text_message(&msg) {
+            return Ok(());
+        }
+        let is_public = msg.chat.is_group() || msg.chat.is_supergroup() || msg.chat.is_channel();
+        let message_text = msg.text().unwrap().trim();
+        if is_command(message_text)
+            || (is_public && !self.should_process_group_message(&msg))
+            || !self.get_conversation(chat_id).await.is_authorized
+        {
+            return Ok(());
+        }
+        if !self.check_chat_rate_limit(chat_id, msg.id).await? {
+            return Ok(());
+        }
+        if is_public && self.check_group_llm_rate_limit(chat_id).await.is_err() {
+            log::info!(
+                "ignoring edit of message {} in chat {}: rate limit reached",
+                msg.id,
+                chat_id
+            );
+            return Ok(());
+        }
+
+        let user_message = self.extract_user_message(&msg).await?;
+        let (last_turn, stored_turn) = {
+            let mut conversation = self.get_conversation(chat_id).await;
+            let last_turn = conversation.pop_edited_turn(msg.id.0);
+            let mut stored_turn = None;
+            if last_turn.is_some() {
+                conversation.pending_tool_calls = None;
+                conversation.regenerations = 0;
+                stored_turn = self.stored_last_turn(&conversation).await;
+            }
+            (last_turn, stored_turn)
+        };
+        let Some((old_message, old_answer)) = last_turn else {
+            log::info!(
+                "ignoring edit of message {} in chat {}: not the latest answered prompt",
+                msg.id,
+                chat_id
+            );
+            return Ok(());
+        };
+        log::info!(
+            "message {} in chat {} was edited; answering it again",
+            msg.id,
+            chat_id
+        );
+
+        let ready = match self.prepare_llm_request(chat_id, &user_message).await {
+            Ok(ready) => ready,
+            Err(err) => {
+                self.get_conversation(chat_id)
+                    .await
+                    .add_messages([old_message, old_answer]);
+                self.bot.send_message(chat_id, err.user_message()).await?;
+                return Ok(());
+            }
+        };
+
+        let llm_call = self
+            .call_llm_for_reply(chat_id, msg.id, is_public, ready)
+            .await;
+        if llm_call.stopped && llm_call.response.is_err() {
+            // Nothing arrived before /stop; the previous turn stays as it was.
+            self.get_conversation(chat_id)
+                .await
+                .add_messages([old_message, old_answer]);
+            self.log_request(chat_id, &llm_call).await;
+            return self
+                .send_stopped_notice(chat_id, msg.id, is_public, llm_call.streamed)
+                .await;
+        }
+        if llm_call.response.is_ok() {
+            self.get_conversation(chat_id).await.last_prompt = Some(conversation::LastPrompt {
+                message_id: msg.id.0,
+                text: user_message.text.clone(),
+            });
+            if let Some(ids) = stored_turn {
+                self.delete_replaced_turn(chat_id, ids).await;
+            }
+        } else {
+            // Keep the previous turn when the new request fails.
+            self.get_conversation(chat_id)
+                .await
+                .add_messages([old_message, old_answer]);
+        }
+
+        self.handle_llm_response(chat_id, msg.id, is_public, user_message, llm_call)
+            .await
+    }
+
+    /// Apply `RESPONSE_STRIP_RULES` to the answer that gets sent and stored; the raw text is
+    /// logged at debug level whenever a rule changed it.
+    fn clean_answer(
+        &self,
+        chat_id: ChatId,
+        response: &mut openrouter_api::Response,
+        system_prompt: Option<&str>,
+        clean_thinking: bool,
+    ) {
+        if self.config.load().response_strip_rules.is_empty() && !clean_thinking {
+            return;
+        }
+
+        let system_prompts = [
+            self.system_prompt0.text.as_str(),
+            system_prompt.unwrap_or(""),
+        ];
+        let mut cleaned = postprocess::apply_rules(
+            &self.config.load().response_strip_rules,
+            &response.completion_text,
+            &system_prompts,
+        );
+        if clean_thinking {
+            cleaned = self.config.load().thinking_filter.strip(&cleaned);
+        }
+        if cleaned != response.completion_text {
+            log::debug!(
+                "post-processing changed the answer for chat {}; raw text: {:?}",
+                chat_id,
+                response.completion_text
+            );
+            response.completion_text = cleaned;
+        }
+    }
+
+    /// Send the prepared request while showing the typing indicator, timing the call.
+    async fn call_llm(&self, chat_id: ChatId, ready: LlmRequestReady) -> LlmCall {
+        self.call_llm_streaming(chat_id, ready, None).await
+    }
+
+    /// [`Self::call_llm`] for an answer to `msg_id`, streamed into the chat when the chat's
+    /// `/stream` choice (or the default for its kind) says so. When the model fails with
+    /// anything but an auth or moderation error, the `FALLBACK_MODELS` are asked in turn.
+    async fn call_llm_for_reply(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        is_group: bool,
+        ready: LlmRequestReady,
+    ) -> LlmCall {
+        let fallback_models = self.config.load().fallback_models.clone();
+        if fallback_models.is_empty() || ready.fallback.is_none() {
+            return self
+                .call_model_for_reply(chat_id, msg_id, is_group, ready)
+                .await;
+        }
+
+        let primary = ready.model_id.clone();
+        let mut tried = vec![primary.clone()];
+        let mut request = ready.clone();
+        let mut llm_call = self
+            .call_model_for_reply(chat_id, msg_id, is_group, ready)
+            .await;
+        for candidate in &fallback_models {
+            match &llm_call.response {
+                Err(err) if !llm_call.stopped && should_fall_back(err) => {}
+                _ => break,
+            }
+            // Models missing from the list resolve to the default, which may be tried already.
+            let model_id = self.resolve_model(Some(candidate)).await.id;
+            if tried.contains(&model_id) {
+                continue;
+            }
+            let next = match self
+                .prepare_fallback_request(chat_id, &request, &model_id)
+                .await
+            {
+                Ok(next) => next,
+                Err(err) => {
+                    log::info!(
+                        "skipping fallback model {} for chat {}: {:?}",
+                        model_id,
+                        chat_id,
+                        err
+                    );
+                    tried.push(model_id);
+                    continue;
                 }
-                if name.is_empty() { None } else { Some(name) }
+            };
+
+            log::warn!(
+                "model {} failed for chat {}; falling back to {}",
+                llm_call.model_id,
+                chat_id,
+                model_id
+            );
+            self.log_request(chat_id, &llm_call).await;
+            if let Some(streamed) = llm_call.streamed.as_mut() {
+                streamed.clear(chat_id).await;
+            }
+            tried.push(model_id);
+            request = next.clone();
+            llm_call = self
+                .call_model_for_reply(chat_id, msg_id, is_group, next)
+                .await;
+        }
+
+        if llm_call.model_id != primary {
+            llm_call.fallback_from = Some(primary);
+        }
+        llm_call
+    }
+
+    /// One attempt of [`Self::call_llm_for_reply`], with the request's own model.
+    async fn call_model_for_reply(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        is_group: bool,
+        ready: LlmRequestReady,
+    ) -> LlmCall {
+        let target = if self.stream_enabled(chat_id, is_group).await {
+            Some(StreamTarget {
+                reply_to: self.reply_target(chat_id, msg_id, is_group).await,
+                show_thinking: self.get_conversation(chat_id).await.show_thinking,
             })
+        } else {
+            None
         };
+        self.call_llm_streaming(chat_id, ready, target).await
+    }
 
-        let Some(user_name) = user_name else {
-            return;
+    /// [`Self::call_llm`], showing the answer in live-edited messages as it arrives when a
+    /// stream target is given. Cached answers are never streamed.
+    async fn call_llm_streaming(
+        &self,
+        chat_id: ChatId,
+        ready: LlmRequestReady,
+        target: Option<StreamTarget>,
+    ) -> LlmCall {
+        let cache_key = if ready.use_cache {
+            response_cache::ResponseCache::key(&ready.payload)
+        } else {
+            None
         };
+        if let Some(key) = cache_key {
+            let cached = self.response_cache.lock().await.get(key, Instant::now());
+            if let Some(completion_text) = cached {
+                log::info!("serving cached response to chat {}", chat_id);
+                return LlmCall {
+                    model_id: ready.model_id,
+                    fallback_from: None,
+                    ramped_temperature: ready.ramped_temperature,
+                    streamed: None,
+                    stopped: false,
+                    latency: Duration::ZERO,
+                    response: Ok(openrouter_api::Response {
+                        prompt_tokens: 0,
+                        completion_tokens: 0,
+                        reasoning_tokens: 0,
+                        total_tokens: 0,
+                        cost: 0.0,
+                        completion_text,
+                        reasoning_text: String::new(),
+                        tool_calls: Vec::new(),
+                        interrupted: false,
+                    }),
+                };
+            }
+        }
 
-        let chat_id = msg.chat.id;
-        let (should_update, old_name) = {
-            let mut conv = self.get_conversation(chat_id).await;
-            if conv.user_name.as_deref() != Some(user_name.as_str()) {
-                let old_name = conv.user_name.clone();
-                conv.user_name = Some(user_name.clone());
-                (true, old_name)
-            } else {
-                (false, None)
+        let _typing_indicator = TypingIndicator::new(self.bot.clone(), chat_id);
+        let generation = Generation::start(self, chat_id);
+        let started = Instant::now();
+        let base_url = self.config.load().openrouter_base_url.clone();
+        let (response, streamed) = match target {
+            None => {
+                // Stopping drops the request future, which aborts the HTTP request.
+                let response = tokio::select! {
+                    response = openrouter_api::send(
+                        &self.http_client,
+                        &base_url,
+                        &ready.openrouter_api_key,
+                        ready.payload,
+                    ) => response,
+                    _ = generation.stop.cancelled() => Err(anyhow::anyhow!("stopped with /stop")),
+                };
+                (response, None)
+            }
+            Some(target) => {
+                let (response, streamed) = self
+                    .stream_answer(
+                        chat_id,
+                        &base_url,
+                        &ready.openrouter_api_key,
+                        ready.payload,
+                        target,
+                        &generation.stop,
+                    )
+                    .await;
+                (response, Some(streamed))
             }
         };
+        let stopped = generation.stop.is_cancelled();
+        if stopped {
+            log::info!("generation for chat {} stopped with /stop", chat_id);
+        }
+        if let (Some(day), Ok(_)) = (ready.quota_day, &response) {
+            self.count_fallback_request(chat_id, day).await;
+        }
 
-        if should_update {
-            log::info!(
-                "Updating user name for chat {}: {:?} -> {:?}",
-                chat_id,
-                old_name,
-                user_name
+        // Tool call requests depend on what the client does next; only plain answers are reused.
+        if let (Some(key), Ok(response)) = (cache_key, &response)
+            && response.tool_calls.is_empty()
+            && !response.interrupted
+            && !stopped
+        {
+            self.response_cache.lock().await.insert(
+                key,
+                response.completion_text.clone(),
+                Instant::now(),
             );
-            db::set_user_name(&self.db, chat_id, Some(&user_name)).await;
+        }
+
+        LlmCall {
+            model_id: ready.model_id,
+            fallback_from: None,
+            ramped_temperature: ready.ramped_temperature,
+            streamed,
+            stopped,
+            latency: started.elapsed(),
+            response,
         }
     }
 
-    async fn process_command(&self, chat_id: ChatId, message_text: &str) -> anyhow::Result<()> {
-        let command = match commands::parse_command(message_text, &self.bot_username) {
-            Ok(commands::Command::Ignore) => {
-                // Command addressed to a different bot; ignore silently.
-                return Ok(());
+    /// Run a streaming request, editing the answer (and, with `/showthinking`, the reasoning)
+    /// into the chat at most every `STREAM_EDIT_INTERVAL`, less often while Telegram's flood
+    /// control asks to slow down. Failed edits are only logged; the final text is shown by
+    /// `handle_llm_response` either way. When `stop` is cancelled the text received so far is
+    /// returned as an interrupted response.
+    async fn stream_answer(
+        &self,
+        chat_id: ChatId,
+        base_url: &str,
+        api_key: &str,
+        payload: serde_json::Value,
+        target: StreamTarget,
+        stop: &CancellationToken,
+    ) -> (anyhow::Result<openrouter_api::Response>, StreamedReply) {
+        let marker = self.config.load().split_marker.clone();
+        let mut streamed = StreamedReply {
+            thinking: target
+                .show_thinking
+                .then(|| telegram::LiveReply::new(self.bot.clone(), chat_id, target.reply_to, "")),
+            answer: telegram::LiveReply::new(self.bot.clone(), chat_id, target.reply_to, &marker),
+        };
+
+        let (progress_tx, mut progress_rx) =
+            tokio::sync::watch::channel(openrouter_api::StreamProgress::default());
+        let request = openrouter_api::send_streaming(
+            &self.http_client,
+            base_url,
+            api_key,
+            payload,
+            &progress_tx,
+        );
+        tokio::pin!(request);
+        let mut edits = time::interval(STREAM_EDIT_INTERVAL);
+        edits.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        let response = loop {
+            tokio::select! {
+                response = &mut request => break response,
+                _ = stop.cancelled() => {
+                    let progress = progress_rx.borrow();
+                    break if progress.text.trim().is_empty() {
+                        Err(anyhow::anyhow!("stopped with /stop"))
+                    } else {
+                        Ok(progress.interrupted_response())
+                    };
+                }
+                _ = edits.tick() => {
+                    // While flood control pauses edits, the update stays pending for a later tick.
+                    if streamed.answer.is_paused() || !progress_rx.has_changed().unwrap_or(false) {
+                        continue;
+                    }
+                    let progress = progress_rx.borrow_and_update().clone();
+                    if let Some(thinking) = streamed.thinking.as_mut()
+                        && !progress.reasoning.trim().is_empty()
+                        && let Err(err) = thinking.preview(&format!("💭 {}", progress.reasoning)).await
+                    {
+                        log::warn!("failed to update streamed reasoning in chat {}: {}", chat_id, err);
+                    }
+                    if let Err(err) = streamed.answer.preview(&progress.text).await {
+                        log::warn!("failed to update streamed answer in chat {}: {}", chat_id, err);
+                    }
+                }
             }
-            Ok(command) => command,
-            Err(message) => {
-                log::warn!("Failed to parse command: {}", message);
-                self.bot.send_message(chat_id, message).await?;
+        };
+
+        (response, streamed)
+    }
+
+    /// With `MEDIA_DECLINE` on, answer a sticker, photo, poll etc. in an authorized private
+    /// chat with a hint to send text, at most once per `MEDIA_DECLINE_INTERVAL`.
+    async fn maybe_decline_media(&self, msg: &Message) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        // Service messages (joins, pins, ...) aren't something the user sent us.
+        if !self.config.load().media_decline
+            || !msg.chat.is_private()
+            || !matches!(msg.kind, MessageKind::Common(..))
+            || telegram::is_automated_message(msg, self.bot_user_id)
+        {
+            return Ok(());
+        }
+        // Unauthorized chats already get the approval flow; stay quiet for them.
+        if !self.get_conversation(chat_id).await.is_authorized {
+            return Ok(());
+        }
+
+        {
+            let mut declines = self.media_declines.lock().await;
+            let now = Instant::now();
+            if declines
+                .get(&chat_id)
+                .is_some_and(|&last| now.duration_since(last) < MEDIA_DECLINE_INTERVAL)
+            {
                 return Ok(());
             }
+            declines.insert(chat_id, now);
+        }
+
+        log::info!("declining non-text message in chat {}", chat_id);
+        telegram::send_message_checked(&self.bot, chat_id, MEDIA_DECLINE_MESSAGE, Some(msg.id))
+            .await?;
+        Ok(())
+    }
+
+    /// Take one request from the chat's `CHAT_RATE_LIMIT` bucket. When it's empty, tell the
+    /// chat how long to wait and return false; the prompt is then not sent to the model.
+    async fn check_chat_rate_limit(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+    ) -> anyhow::Result<bool> {
+        let Some(per_minute) = self.config.load().chat_rate_limit else {
+            return Ok(true);
+        };
+        if self.get_conversation(chat_id).await.is_admin {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        let result = {
+            let mut buckets = self.chat_rate_limits.lock().await;
+            buckets
+                .entry(chat_id)
+                .or_insert_with(|| rate_limit::Bucket::new(per_minute, now))
+                .try_take(per_minute, now)
+        };
+        let Err(wait_time) = result else {
+            return Ok(true);
+        };
+
+        let wait_secs = wait_time.as_secs_f64().ceil().max(1.0) as u64;
+        log::info!("rate limit hit for chat {} (wait {} s)", chat_id, wait_secs);
+        let message = format!(
+            "Slow down: at most {per_minute} requests per minute. Try again in {wait_secs} s."
+        );
+        self.bot
+            .send_message(chat_id, message)
+            .reply_parameters(ReplyParameters::new(msg_id))
+            .await?;
+        Ok(false)
+    }
+
+    async fn check_group_llm_rate_limit(&self, chat_id: ChatId) -> Result<(), Duration> {
+        const GROUP_LLM_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+        let mut rate_limits = self.group_llm_rate_limits.lock().await;
+        let timestamps = rate_limits.entry(chat_id).or_default();
+        let now = Instant::now();
+
+        while let Some(&oldest) = timestamps.front() {
+            if now.duration_since(oldest) >= GROUP_LLM_WINDOW {
+                timestamps.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if timestamps.len() >= self.config.load().group_llm_limit {
+            let oldest = *timestamps
+                .front()
+                .expect("timestamps should be non-empty when over limit");
+            let elapsed = now.duration_since(oldest);
+            let wait_time = GROUP_LLM_WINDOW
+                .checked_sub(elapsed)
+                .unwrap_or_else(|| Duration::from_secs(0));
+            return Err(wait_time);
+        }
+
+        timestamps.push_back(now);
+        Ok(())
+    }
+
+    /// Whether the chat may use the bot; an unauthorized chat is told how to get access (per
+    /// `UNAUTHORIZED_REPLY`) and the admins are asked about it.
+    async fn ensure_authorized(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+        let reply = {
+            let mut conv = self.get_conversation(chat_id).await;
+            if conv.is_authorized {
+                return Ok(true);
+            }
+
+            match self.config.load().unauthorized_reply {
+                config::UnauthorizedReply::Always => true,
+                config::UnauthorizedReply::Once => {
+                    !std::mem::replace(&mut conv.unauthorized_notified, true)
+                }
+                config::UnauthorizedReply::Never => false,
+            }
+        };
+
+        if reply {
+            if self.config.load().unauthorized_reply == config::UnauthorizedReply::Once {
+                db::set_unauthorized_notified(&self.db, chat_id, true).await;
+            }
+            let message = format!(
+                "This chat isn't authorized to use this bot yet. Ask an admin of the bot to approve it by sending /approve {chat_id} true."
+            );
+            self.bot.send_message(chat_id, &message).await?;
+        } else {
+            log::info!("ignoring message from unauthorized chat {}", chat_id);
+        }
+        self.notify_admins_of_pending_chat(chat_id).await;
+
+        Ok(false)
+    }
+
+    /// Record that the bot can't post in the chat; logged once until it can again.
+    async fn mark_chat_unreachable(&self, chat_id: ChatId, err: &anyhow::Error) {
+        let was_active = {
+            let mut conv = self.get_conversation(chat_id).await;
+            std::mem::replace(&mut conv.is_active, false)
+        };
+        if !was_active {
+            log::debug!("still can't send to chat {}: {}", chat_id, err);
+            return;
+        }
+
+        log::warn!(
+            "can't send to chat {} ({}); marking it inactive",
+            chat_id,
+            err
+        );
+        db::set_is_active(&self.db, chat_id, false).await;
+    }
+
+    /// Clear the inactive mark once a reply got through again.
+    async fn mark_chat_reachable(&self, chat_id: ChatId) {
+        let was_active = {
+            let mut conv = self.get_conversation(chat_id).await;
+            std::mem::replace(&mut conv.is_active, true)
         };
+        if !was_active {
+            log::info!("chat {} is reachable again", chat_id);
+            db::set_is_active(&self.db, chat_id, true).await;
+        }
+    }
+
+    /// Send `text` to every authorized chat, one at a time with `BROADCAST_SEND_INTERVAL`
+    /// between sends, then tell the admin how many got it. A failed send is counted and
+    /// skipped; chats that blocked the bot are marked inactive.
+    async fn broadcast(&self, admin_chat_id: ChatId, text: &str) -> anyhow::Result<()> {
+        let mut chat_ids = db::list_authorized_chats(&self.db).await;
+        {
+            let config = self.config.load();
+            chat_ids.extend(
+                config
+                    .authorized_chats
+                    .iter()
+                    .chain(&config.admin_chats)
+                    .map(|&id| ChatId(id)),
+            );
+        }
+        chat_ids.sort_unstable_by_key(|chat_id| chat_id.0);
+        chat_ids.dedup();
+        log::info!(
+            "broadcasting {} character(s) from chat {} to {} chat(s)",
+            text.chars().count(),
+            admin_chat_id,
+            chat_ids.len()
+        );
+
+        let (mut sent, mut blocked, mut failed) = (0usize, 0usize, 0usize);
+        for (index, &chat_id) in chat_ids.iter().enumerate() {
+            if index > 0 {
+                time::sleep(BROADCAST_SEND_INTERVAL).await;
+            }
+            match telegram::bot_split_send(&self.bot, chat_id, text, None).await {
+                Ok(()) => {
+                    sent += 1;
+                    self.mark_chat_reachable(chat_id).await;
+                }
+                Err(err) if telegram::is_send_forbidden(&err) => {
+                    blocked += 1;
+                    self.mark_chat_unreachable(chat_id, &err).await;
+                }
+                Err(err) => {
+                    failed += 1;
+                    log::warn!("broadcast to chat {} failed: {}", chat_id, err);
+                }
+            }
+        }
+
+        let mut report = format!("Broadcast sent to {sent} of {} chat(s).", chat_ids.len());
+        if blocked > 0 {
+            report.push_str(&format!(" {blocked} blocked the bot or removed it."));
+        }
+        if failed > 0 {
+            report.push_str(&format!(
+                " {failed} failed for other reasons (see the log)."
+            ));
+        }
+        log::info!("{report}");
+        self.bot.send_message(admin_chat_id, report).await?;
+        Ok(())
+    }
+
+    /// Tell every admin about a chat waiting for approval, once per chat while it's pending.
+    async fn notify_admins_of_pending_chat(&self, chat_id: ChatId) {
+        if !self.approval_requests.lock().await.notified.insert(chat_id) {
+            return;
+        }
+
+        let user_name = { self.get_conversation(chat_id).await.user_name.clone() };
+        let message = format!(
+            "Chat {} ({}) is waiting for approval. React 👍 to approve or 👎 to deny, or use /approve {} true|false.",
+            chat_id,
+            user_name.as_deref().unwrap_or("unknown"),
+            chat_id
+        );
+
+        let mut admin_ids: Vec<i64> = db::list_admin_chats(&self.db)
+            .await
+            .into_iter()
+            .map(|(admin_id, _)| admin_id)
+            .collect();
+        admin_ids.extend(self.config.load().admin_chats.iter().copied());
+        admin_ids.sort_unstable();
+        admin_ids.dedup();
+        for admin_id in admin_ids {
+            let admin_id = ChatId(admin_id);
+            match self.bot.send_message(admin_id, &message).await {
+                Ok(sent) => {
+                    self.approval_requests
+                        .lock()
+                        .await
+                        .by_message
+                        .insert((admin_id, sent.id), chat_id);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "failed to notify admin {} about pending chat {}: {}",
+                        admin_id,
+                        chat_id,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Buttons of the `/models` keyboard: page through the list in place, or pick a model.
+    async fn process_callback_query(&self, query: CallbackQuery) -> anyhow::Result<()> {
+        // Answered first so the tapped button stops showing a spinner whatever happens next.
+        self.bot.answer_callback_query(query.id.clone()).await?;
+        let (Some(data), Some(message)) = (query.data.as_deref(), query.message.as_ref()) else {
+            return Ok(());
+        };
+        let chat_id = message.chat().id;
+        // The keyboard is only sent in private chats, like the commands behind it.
+        if !message.chat().is_private() || !self.ensure_authorized(chat_id).await? {
+            return Ok(());
+        }
+
+        match model_picker::Action::parse(data) {
+            Some(model_picker::Action::Page { provider, page }) => {
+                let current_model_id = { self.get_conversation(chat_id).await.model_id.clone() };
+                let current = self.resolve_model(current_model_id.as_deref()).await;
+                let page =
+                    model_picker::page(&self.models.load(), provider.as_deref(), page, &current.id);
+                match self
+                    .bot
+                    .edit_message_text(chat_id, message.id(), page.text)
+                    .parse_mode(ParseMode::MarkdownV2)
+                    .reply_markup(page.keyboard)
+                    .await
+                {
+                    // A double tap asks for the page already shown.
+                    Ok(_)
+                    | Err(teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            Some(model_picker::Action::Select(data)) => {
+                let models = self.models.load();
+                match model_picker::find(&models, &data) {
+                    Some(model) => self.select_model(chat_id, model).await?,
+                    None => {
+                        self.bot
+                            .send_message(
+                                chat_id,
+                                "That model is no longer available; /models shows the current list.",
+                            )
+                            .await?;
+                    }
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Approve or deny a pending chat when an admin reacts to its notification.
+    async fn process_reaction(&self, reaction: MessageReactionUpdated) -> anyhow::Result<()> {
+        let admin_id = reaction.chat.id;
+        let pending_id = {
+            let requests = self.approval_requests.lock().await;
+            requests
+                .by_message
+                .get(&(admin_id, reaction.message_id))
+                .copied()
+        };
+        let Some(pending_id) = pending_id else {
+            return Ok(());
+        };
+
+        let decision = reaction
+            .new_reaction
+            .iter()
+            .filter_map(ReactionType::emoji)
+            .find_map(|emoji| match emoji.as_str() {
+                "👍" | "✅" => Some(true),
+                "👎" | "❌" => Some(false),
+                _ => None,
+            });
+        let Some(is_authorized) = decision else {
+            return Ok(());
+        };
+
+        // Admin rights may have been revoked since the notification was sent.
+        if !self.check_admin(admin_id, "approval reactions").await? {
+            return Ok(());
+        }
+
+        log::info!(
+            "admin {} reacted to approve chat {}: {}",
+            admin_id,
+            pending_id,
+            is_authorized
+        );
+        self.set_chat_authorization(admin_id, pending_id, is_authorized)
+            .await
+    }
+
+    /// Store a chat's authorization, forget its approval request and report back to the admin.
+    async fn set_chat_authorization(
+        &self,
+        admin_id: ChatId,
+        target_id: ChatId,
+        is_authorized: bool,
+    ) -> anyhow::Result<()> {
+        let result = db::set_is_authorized(&self.db, target_id, is_authorized).await;
+        if result.is_err() {
+            self.bot
+                .send_message(admin_id, "Failed to authorize chat")
+                .await?;
+            return Ok(());
+        }
+
+        let env_granted = self.config.load().grants_authorization(target_id.0);
+        if let Some(conv) = self.cached_conversation(target_id).await {
+            let mut conv = conv.lock().await;
+            conv.is_authorized = is_authorized || env_granted;
+            conv.unauthorized_notified = false;
+        }
+
+        {
+            // A denied chat that writes again gets a fresh notification.
+            let mut requests = self.approval_requests.lock().await;
+            requests.notified.remove(&target_id);
+            requests
+                .by_message
+                .retain(|_, pending_id| *pending_id != target_id);
+        }
+
+        let mut message = format!("Chat {} approved: {}", target_id, is_authorized);
+        if !is_authorized && env_granted {
+            message.push_str(" (stored, but AUTHORIZED_CHATS/ADMIN_CHATS still grants access)");
+        }
+        self.bot.send_message(admin_id, message).await?;
+        Ok(())
+    }
+
+    /// In group chats, only process messages that mention or reply to the bot; otherwise, just record them.
+    fn should_process_group_message(&self, msg: &Message) -> bool {
+        telegram::mentions_bot(msg, &self.bot_username, self.bot_user_id)
+            || telegram::is_reply_to_bot(msg, self.bot_user_id)
+    }
+
+    async fn handle_llm_response(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        is_group: bool,
+        user_message: conversation::Message,
+        llm_call: LlmCall,
+    ) -> anyhow::Result<()> {
+        self.log_request(chat_id, &llm_call).await;
+        if llm_call.stopped && llm_call.response.is_err() {
+            // Nothing of the answer arrived: the prompt is kept, without an answer.
+            self.send_stopped_notice(chat_id, msg_id, is_group, llm_call.streamed)
+                .await?;
+            self.persist_messages(chat_id, &[user_message]).await;
+            return Ok(());
+        }
+        let mut streamed = llm_call.streamed;
+
+        match llm_call.response {
+            Ok(llm_response) if !llm_response.tool_calls.is_empty() => {
+                log::info!(
+                    "LLM requested {} tool call(s) for chat {}",
+                    llm_response.tool_calls.len(),
+                    chat_id
+                );
+
+                let mut lines = Vec::new();
+                if let Some(streamed) = streamed.as_mut() {
+                    streamed.answer.show(&llm_response.completion_text).await?;
+                } else if !llm_response.completion_text.is_empty() {
+                    lines.push(llm_response.completion_text.clone());
+                    lines.push(String::new());
+                }
+                lines.push("The model requested tool call(s):".to_string());
+                for call in &llm_response.tool_calls {
+                    lines.push(format!(
+                        "- {} (call id {}): {}",
+                        call.name, call.call_id, call.arguments
+                    ));
+                }
+                lines.push(String::new());
+                lines.push(
+                    "Reply with /tool_result <output>, or a JSON object mapping call ids to outputs when there are several calls."
+                        .to_string(),
+                );
+
+                {
+                    let mut conversation = self.get_conversation(chat_id).await;
+                    let completed = conversation
+                        .pending_tool_calls
+                        .take()
+                        .map(|pending| pending.completed)
+                        .unwrap_or_default();
+                    conversation.pending_tool_calls = Some(conversation::PendingToolCalls {
+                        user_message,
+                        completed,
+                        tool_calls: llm_response.tool_calls,
+                    });
+                }
+
+                let reply_to = self.reply_target(chat_id, msg_id, is_group).await;
+                telegram::bot_split_send(&self.bot, chat_id, &lines.join("\n"), reply_to).await?;
+            }
+            Ok(mut llm_response) => {
+                let (system_prompt, completed_tools, clean_thinking) = {
+                    let mut conversation = self.get_conversation(chat_id).await;
+                    let completed_tools = conversation
+                        .pending_tool_calls
+                        .take()
+                        .map(|pending| pending.completed)
+                        .unwrap_or_default();
+                    let system_prompt = conversation
+                        .system_prompt
+                        .as_ref()
+                        .map(|prompt| prompt.text.clone())
+                        .or_else(|| {
+                            self.model_prompts
+                                .for_model(&llm_call.model_id)
+                                .map(str::to_string)
+                        });
+                    (system_prompt, completed_tools, conversation.clean_thinking)
+                };
+                self.clean_answer(
+                    chat_id,
+                    &mut llm_response,
+                    system_prompt.as_deref(),
+                    clean_thinking,
+                );
+                log::info!(
+                    "LLM usage: prompt_tokens={}, completion_tokens={}, reasoning_tokens={}, total_tokens={}, cost={}",
+                    llm_response.prompt_tokens,
+                    llm_response.completion_tokens,
+                    llm_response.reasoning_tokens,
+                    llm_response.total_tokens,
+                    llm_response.cost
+                );
+                let reply_to = self.reply_target(chat_id, msg_id, is_group).await;
+                let show_thinking = { self.get_conversation(chat_id).await.show_thinking };
+                // Some models reason without returning any of it; the count still tells
+                // where the time and completion tokens went.
+                let thinking = match (
+                    llm_response.reasoning_text.is_empty(),
+                    llm_response.reasoning_tokens,
+                ) {
+                    (true, 0) => String::new(),
+                    (true, tokens) => {
+                        format!("💭 {tokens} reasoning tokens (the model returned no summary)")
+                    }
+                    (false, 0) => format!("💭 {}", llm_response.reasoning_text),
+                    (false, tokens) => format!(
+                        "💭 {tokens} reasoning tokens\n{}",
+                        llm_response.reasoning_text
+                    ),
+                };
+                match streamed
+                    .as_mut()
+                    .and_then(|streamed| streamed.thinking.as_mut())
+                {
+                    Some(live) => live.show(&thinking).await?,
+                    None if show_thinking && !thinking.is_empty() => {
+                        telegram::bot_split_send(&self.bot, chat_id, &thinking, reply_to).await?;
+                    }
+                    None => {}
+                }
+                let mut notes = String::new();
+                if let Some(primary) = &llm_call.fallback_from {
+                    notes.push_str(&format!(
+                        "\n\n🔁 answered by {} because {} failed",
+                        llm_call.model_id, primary
+                    ));
+                }
+                if let Some(temperature) = llm_call.ramped_temperature {
+                    notes.push_str(&format!(
+                        "\n\n🌡 temperature {temperature} (raised for retry)"
+                    ));
+                }
+                let config = self.config.load();
+                // The partial answer is still sent and stored; only the note says it's cut.
+                if llm_call.stopped {
+                    notes.push_str(&format!("\n\n{}", config.stopped_message));
+                } else if llm_response.interrupted {
+                    notes
+                        .push_str("\n\n⚠️ The connection broke off, so this answer is incomplete.");
+                }
+                // Only the sent text is decorated; history keeps the model's own words.
+                match streamed.as_mut() {
+                    // Live edits stay plain text: half-received Markdown doesn't parse.
+                    Some(streamed) => {
+                        let reply = format!(
+                            "{}{}{}{notes}",
+                            config.reply_prefix, llm_response.completion_text, config.reply_suffix
+                        );
+                        streamed.answer.show(&reply).await?
+                    }
+                    None => {
+                        let reply = format!(
+                            "{}{}{}{}",
+                            escape_markdown_v2(&config.reply_prefix),
+                            telegram::markdown_to_telegram(&llm_response.completion_text),
+                            escape_markdown_v2(&config.reply_suffix),
+                            escape_markdown_v2(&notes)
+                        );
+                        telegram::bot_split_send_formatted_capped(
+                            &self.bot,
+                            chat_id,
+                            &reply,
+                            reply_to,
+                            ParseMode::MarkdownV2,
+                            config.max_reply_chunks,
+                            &config.split_marker,
+                        )
+                        .await?
+                    }
+                }
+                self.mark_chat_reachable(chat_id).await;
+                self.maybe_send_voice(chat_id, &llm_response.completion_text, reply_to)
+                    .await;
+                // Reasoning is display-only; the history keeps just the answer, preceded by
+                // the tool calls it was built on.
+                let mut messages = vec![user_message];
+                messages.extend(
+                    completed_tools
+                        .iter()
+                        .map(|(call, output)| openrouter_api::tool_turn(call, output)),
+                );
+                messages.push(llm_response.answer_message());
+                self.persist_messages(chat_id, &messages).await;
+                self.maybe_spawn_title_generation(chat_id).await;
+            }
+            Err(err) => {
+                if let Some(openrouter_api::ApiError::ContentBlocked { reasons, .. }) =
+                    err.downcast_ref::<openrouter_api::ApiError>()
+                {
+                    log::warn!(
+                        "request from chat {} blocked by moderation: {}",
+                        chat_id,
+                        if reasons.is_empty() {
+                            "no category given".to_string()
+                        } else {
+                            reasons.join(", ")
+                        }
+                    );
+                    // The turn is dropped, so the blocked prompt never enters the history.
+                    let reply_to = self.reply_target(chat_id, msg_id, is_group).await;
+                    let mut request = self.bot.send_message(
+                        chat_id,
+                        "The model provider's content policy blocked this request, so there's no answer this time. Rephrasing the message usually helps.",
+                    );
+                    if let Some(reply_to) = reply_to {
+                        request = request.reply_parameters(ReplyParameters::new(reply_to));
+                    }
+                    request.await?;
+                    return Ok(());
+                }
+
+                let kind = openrouter_api::FailureKind::of(&err);
+                log::error!("failed to get llm response ({kind:?}): {err:#}");
+
+                if let Some(emoji) = self.config.load().error_reaction_emoji.clone() {
+                    // Telegram only accepts some emoji as reactions; the text below still
+                    // explains the failure when this one isn't.
+                    if let Err(err) = self
+                        .bot
+                        .set_message_reaction(chat_id, msg_id)
+                        .reaction(vec![ReactionType::Emoji { emoji }])
+                        .await
+                    {
+                        log::warn!("failed to react to failed prompt in chat {chat_id}: {err}");
+                    }
+                }
+                let explanation = match kind {
+                    openrouter_api::FailureKind::Network => {
+                        "I couldn't reach OpenRouter (network error or timeout), so there's no answer this time. Please try again in a moment."
+                    }
+                    openrouter_api::FailureKind::Auth => {
+                        "OpenRouter refused the API key: it is invalid, revoked or out of credit. Check it with /key."
+                    }
+                    openrouter_api::FailureKind::Provider => {
+                        "The model provider returned an error, so there's no answer this time. Try again, or pick another model with /model."
+                    }
+                };
+                let reply_to = self.reply_target(chat_id, msg_id, is_group).await;
+                let mut request = self.bot.send_message(chat_id, explanation);
+                if let Some(reply_to) = reply_to {
+                    request = request.reply_parameters(ReplyParameters::new(reply_to));
+                }
+                request.await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tell the chat its answer was stopped before any of it arrived, removing the live
+    /// messages a streamed answer had started.
+    async fn send_stopped_notice(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        is_group: bool,
+        streamed: Option<StreamedReply>,
+    ) -> anyhow::Result<()> {
+        if let Some(mut streamed) = streamed {
+            streamed.clear(chat_id).await;
+        }
+        let reply_to = self.reply_target(chat_id, msg_id, is_group).await;
+        let mut request = self
+            .bot
+            .send_message(chat_id, self.config.load().stopped_message.clone());
+        if let Some(reply_to) = reply_to {
+            request = request.reply_parameters(ReplyParameters::new(reply_to));
+        }
+        request.await?;
+        Ok(())
+    }
+
+    /// Delete a group member's command message. A failure (usually the bot lacking the
+    /// "Delete messages" right) is reported so the user can remove it manually.
+    async fn delete_command_message(&self, msg: &Message, has_secret: bool) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        match self.bot.delete_message(chat_id, msg.id).await {
+            Ok(_) => {
+                log::info!("deleted command message in chat {}", chat_id);
+                if has_secret {
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            "I deleted your /key message so the key doesn't stay in the group. Set keys in a private chat with me.",
+                        )
+                        .await?;
+                }
+            }
+            Err(err) => {
+                log::warn!(
+                    "failed to delete command message in chat {}: {}",
+                    chat_id,
+                    err
+                );
+                let message = if has_secret {
+                    "Please delete your /key message right away: it contains an API key and I'm not allowed to delete messages here. Set keys in a private chat with me."
+                } else {
+                    "I couldn't delete this command (I need the \"Delete messages\" right); please remove it manually."
+                };
+                self.bot
+                    .send_message(chat_id, message)
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `/delete_commands` in a group; only the group's Telegram admins may change it.
+    async fn set_group_delete_commands(
+        &self,
+        msg: &Message,
+        arg: commands::ToggleArg,
+    ) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        let delete_commands = match arg {
+            commands::ToggleArg::Show => {
+                let delete_commands = { self.get_conversation(chat_id).await.delete_commands };
+                let message = if delete_commands {
+                    "Command messages are deleted after handling."
+                } else {
+                    "Command messages are kept (/key messages are always deleted)."
+                };
+                self.bot.send_message(chat_id, message).await?;
+                return Ok(());
+            }
+            commands::ToggleArg::On => true,
+            commands::ToggleArg::Off => false,
+            commands::ToggleArg::Invalid => {
+                self.bot
+                    .send_message(chat_id, "Usage: /delete_commands [on|off]")
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let Some(user) = msg.from.as_ref() else {
+            return Ok(());
+        };
+        let member = self.bot.get_chat_member(chat_id, user.id).await?;
+        if !member.is_privileged() {
+            self.bot
+                .send_message(chat_id, "Only group admins can change /delete_commands.")
+                .await?;
+            return Ok(());
+        }
+
+        {
+            self.get_conversation(chat_id).await.delete_commands = delete_commands;
+        }
+        db::set_delete_commands(&self.db, chat_id, delete_commands).await;
+        let message = if delete_commands {
+            "Command messages will be deleted after handling. I need the \"Delete messages\" admin right for that."
+        } else {
+            "Command messages will be kept (/key messages are still deleted)."
+        };
+        self.bot.send_message(chat_id, message).await?;
+        if delete_commands {
+            self.delete_command_message(msg, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Begin the onboarding on a chat's first `/start`; false when it doesn't apply.
+    async fn start_onboarding(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+        let started = {
+            self.get_conversation(chat_id)
+                .await
+                .onboarding_step
+                .is_some()
+        };
+        if !self.config.load().onboarding || started {
+            return Ok(false);
+        }
+
+        let step = onboarding::OnboardingStep::FIRST;
+        self.set_onboarding_step(chat_id, step).await;
+        let question = step
+            .question(&self.default_model)
+            .expect("first onboarding step asks a question");
+        self.bot
+            .send_message(
+                chat_id,
+                format!("{}\n\n{}", self.config.load().onboarding_welcome, question),
+            )
+            .await?;
+        Ok(true)
+    }
+
+    /// Take a plain message as the answer to the open onboarding step; false when no step is
+    /// waiting, so the message goes to the model as usual.
+    async fn handle_onboarding_reply(&self, chat_id: ChatId, text: &str) -> anyhow::Result<bool> {
+        let step = { self.get_conversation(chat_id).await.onboarding_step };
+        match step {
+            Some(onboarding::OnboardingStep::Language) => {
+                let instruction = onboarding::language_instruction(text);
+                let system_prompt = {
+                    let mut conv = self.get_conversation(chat_id).await;
+                    let text = match &conv.system_prompt {
+                        Some(prompt) => format!("{}\n\n{}", prompt.text, instruction),
+                        None => instruction,
+                    };
+                    conv.system_prompt = Some(conversation::Message {
+                        role: MessageRole::System,
+                        text: text.clone(),
+                    });
+                    text
+                };
+                db::set_system_prompt(&self.db, chat_id, Some(&system_prompt)).await;
+                log::info!("onboarding: chat {} chose language {}", chat_id, text);
+            }
+            Some(onboarding::OnboardingStep::Model) => {
+                let model_id = text.trim();
+                let exists = self.models.load().iter().any(|m| m.id == model_id);
+                if !exists {
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            format!(
+                                "Model not found: {model_id}. Reply with an id from /models, or /skip."
+                            ),
+                        )
+                        .await?;
+                    return Ok(true);
+                }
+                {
+                    self.get_conversation(chat_id).await.model_id = Some(model_id.to_string());
+                }
+                db::set_model_id(&self.db, chat_id, Some(model_id)).await;
+                log::info!("onboarding: chat {} chose model {}", chat_id, model_id);
+            }
+            Some(onboarding::OnboardingStep::Done) | None => return Ok(false),
+        }
+
+        let next = step.expect("an onboarding step was open").next();
+        self.set_onboarding_step(chat_id, next).await;
+        let message = next.question(&self.default_model).unwrap_or_else(|| {
+            "All set! Send me a message to start; /help lists the settings.".to_string()
+        });
+        self.bot.send_message(chat_id, message).await?;
+        Ok(true)
+    }
+
+    async fn set_onboarding_step(&self, chat_id: ChatId, step: onboarding::OnboardingStep) {
+        {
+            self.get_conversation(chat_id).await.onboarding_step = Some(step);
+        }
+        db::set_onboarding_step(&self.db, chat_id, step).await;
+    }
+
+    /// Whether answers should stream: the chat's `/stream` choice, else the default for its kind.
+    async fn stream_enabled(&self, chat_id: ChatId, is_group: bool) -> bool {
+        let choice = { self.get_conversation(chat_id).await.stream };
+        choice.unwrap_or_else(|| self.config.load().stream_default(is_group))
+    }
+
+    /// Message to thread an answer to, per the chat's `/reply_mode`.
+    async fn reply_target(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        is_group: bool,
+    ) -> Option<MessageId> {
+        let reply_mode = { self.get_conversation(chat_id).await.reply_mode };
+        reply_mode.threads(is_group).then_some(msg_id)
+    }
+
+    /// Follow a text answer with its spoken version when the chat enabled `/voice`. Failures
+    /// are only logged since the text has already been delivered.
+    async fn maybe_send_voice(&self, chat_id: ChatId, text: &str, reply_to: Option<MessageId>) {
+        let Some(tts) = &self.config.load().tts else {
+            return;
+        };
+        if !self.get_conversation(chat_id).await.voice || text.trim().is_empty() {
+            return;
+        }
+
+        // Long answers are cut rather than split into several voice messages.
+        let text = if text.chars().count() > tts.max_chars {
+            truncate_chars(text, tts.max_chars - 1)
+        } else {
+            text.to_string()
+        };
+
+        let audio = match tts::synthesize(&self.http_client, tts, &text).await {
+            Ok(audio) => audio,
+            Err(err) => {
+                log::warn!(
+                    "failed to synthesize voice reply for chat {}: {}",
+                    chat_id,
+                    err
+                );
+                return;
+            }
+        };
+
+        let mut request = self
+            .bot
+            .send_voice(chat_id, InputFile::memory(audio).file_name("reply.ogg"));
+        if let Some(reply_to) = reply_to {
+            request = request.reply_parameters(ReplyParameters::new(reply_to));
+        }
+        if let Err(err) = request.await {
+            log::warn!("failed to send voice reply to chat {}: {}", chat_id, err);
+        }
+    }
+
+    /// Record the call in the `request_log` table when enabled.
+    async fn log_request(&self, chat_id: ChatId, llm_call: &LlmCall) {
+        // Cached and interrupted answers report no usage; they'd only skew `/usage`.
+        if let Ok(response) = &llm_call.response
+            && response.total_tokens > 0
+        {
+            let usage = db::RequestUsage {
+                model_id: llm_call.model_id.clone(),
+                prompt_tokens: response.prompt_tokens,
+                completion_tokens: response.completion_tokens,
+                total_tokens: response.total_tokens,
+                cost: response.cost,
+            };
+            db::add_usage(&self.db, chat_id, usage).await;
+        }
+
+        if !self.config.load().request_log {
+            return;
+        }
+
+        let (prompt_tokens, completion_tokens, cost, error) = match &llm_call.response {
+            Ok(response) => (
+                response.prompt_tokens,
+                response.completion_tokens,
+                response.cost,
+                None,
+            ),
+            Err(err) => (0, 0, 0.0, Some(truncate_chars(&err.to_string(), 500))),
+        };
+
+        let entry = db::RequestLogEntry {
+            created_at: chrono::Utc::now().timestamp(),
+            model_id: llm_call.model_id.clone(),
+            prompt_tokens,
+            completion_tokens,
+            cost,
+            latency_ms: llm_call.latency.as_millis() as u64,
+            error,
+        };
+        db::add_request_log(&self.db, chat_id, entry).await;
+    }
+
+    /// Send a request the bot makes on the chat's behalf (a title, a history summary) with the
+    /// chat's key, counting it in `/usage` and the request log like the chat's own prompts.
+    async fn send_background_request(
+        &self,
+        chat_id: ChatId,
+        model_id: &str,
+        api_key: &str,
+        payload: serde_json::Value,
+    ) -> anyhow::Result<openrouter_api::Response> {
+        let started = Instant::now();
+        let response = openrouter_api::send(
+            &self.http_client,
+            &self.config.load().openrouter_base_url,
+            api_key,
+            payload,
+        )
+        .await;
+        let llm_call = LlmCall {
+            model_id: model_id.to_string(),
+            fallback_from: None,
+            ramped_temperature: None,
+            streamed: None,
+            stopped: false,
+            latency: started.elapsed(),
+            response,
+        };
+        self.log_request(chat_id, &llm_call).await;
+        llm_call.response
+    }
+
+    /// After the first exchange of an untitled conversation, ask the model for a short
+    /// title in the background.
+    async fn maybe_spawn_title_generation(&self, chat_id: ChatId) {
+        if !self.config.load().auto_title {
+            return;
+        }
+
+        let (api_key, model_id, exchange) = {
+            let conv = self.get_conversation(chat_id).await;
+            let assistant_turns = conv
+                .history
+                .iter()
+                .filter(|m| m.role == MessageRole::Assistant)
+                .count();
+            if conv.title.is_some() || conv.ephemeral || assistant_turns != 1 {
+                return;
+            }
+            let Some(api_key) = conv.api_key().map(str::to_string) else {
+                return;
+            };
+
+            // Only the exchange that just happened; groups may have unrelated chatter before it.
+            let exchange = conv
+                .history
+                .iter()
+                .rev()
+                .take(2)
+                .rev()
+                .map(|m| format!("{}: {}", m.role, truncate_chars(&m.text, 500)))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            (api_key, conv.model_id.clone(), exchange)
+        };
+
+        let model = self.resolve_model(model_id.as_deref()).await;
+        let app = self.clone();
+        self.tasks.spawn(request_id::propagate(async move {
+            app.generate_title(chat_id, &model.id, &api_key, exchange)
+                .await;
+        }));
+    }
+
+    async fn generate_title(
+        &self,
+        chat_id: ChatId,
+        model_id: &str,
+        api_key: &str,
+        exchange: String,
+    ) {
+        let messages = [
+            conversation::Message {
+                role: MessageRole::System,
+                text: TITLE_PROMPT.to_string(),
+            },
+            conversation::Message {
+                role: MessageRole::User,
+                text: exchange,
+            },
+        ];
+        let payload = openrouter_api::prepare_payload(
+            model_id,
+            messages.iter(),
+            false,
+            &openrouter_api::PayloadOptions::default(),
+        );
+
+        let response = match self
+            .send_background_request(chat_id, model_id, api_key, payload)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("failed to generate title for chat {}: {err}", chat_id);
+                return;
+            }
+        };
+
+        let title = clean_title(&response.completion_text);
+        if title.is_empty() {
+            log::warn!("model returned an empty title for chat {}", chat_id);
+            return;
+        }
+
+        {
+            let mut conv = self.get_conversation(chat_id).await;
+            if conv.title.is_some() {
+                return;
+            }
+            conv.title = Some(title.clone());
+        }
+        db::set_title(&self.db, chat_id, Some(&title)).await;
+        log::info!("Titled conversation {}: {:?}", chat_id, title);
+    }
+
+    async fn maybe_update_user_name(&self, msg: &Message) {
+        let user_name = if msg.chat.is_group() || msg.chat.is_supergroup() {
+            msg.chat.title().map(str::to_owned)
+        } else {
+            let Some(user) = msg.from.as_ref() else {
+                return;
+            };
+
+            user.username.clone().or_else(|| {
+                let mut name = user.first_name.clone();
+                if let Some(last) = user.last_name.as_ref()
+                    && !last.is_empty()
+                {
+                    if !name.is_empty() {
+                        name.push(' ');
+                    }
+                    name.push_str(last);
+                }
+                if name.is_empty() { None } else { Some(name) }
+            })
+        };
+
+        let Some(user_name) = user_name else {
+            return;
+        };
+
+        let chat_id = msg.chat.id;
+        let (should_update, old_name) = {
+            let mut conv = self.get_conversation(chat_id).await;
+            if conv.user_name.as_deref() != Some(user_name.as_str()) {
+                let old_name = conv.user_name.clone();
+                conv.user_name = Some(user_name.clone());
+                (true, old_name)
+            } else {
+                (false, None)
+            }
+        };
+
+        if should_update {
+            log::info!(
+                "Updating user name for chat {}: {:?} -> {:?}",
+                chat_id,
+                old_name,
+                user_name
+            );
+            db::set_user_name(&self.db, chat_id, Some(&user_name)).await;
+        }
+    }
+
+    async fn process_command(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        message_text: &str,
+        replied_text: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let command = match commands::parse_command(message_text, &self.bot_username) {
+            Ok(commands::Command::Ignore) => {
+                // Command addressed to a different bot; ignore silently.
+                return Ok(());
+            }
+            Ok(command) => command,
+            Err(message) => {
+                log::warn!("Failed to parse command: {}", message);
+                self.bot.send_message(chat_id, message).await?;
+                return Ok(());
+            }
+        };
+
+        log::info!("Received command: {:?}", command);
+        match command {
+            commands::Command::Ignore => {
+                // Command addressed to a different bot; ignore silently.
+            }
+            commands::Command::Start {
+                payload: Some(token),
+            } => {
+                self.continue_from_group(chat_id, &token).await?;
+            }
+            commands::Command::Dm => {
+                self.bot
+                    .send_message(chat_id, "/dm only works in group chats.")
+                    .await?;
+            }
+            commands::Command::Tldr(_) => {
+                self.bot
+                    .send_message(chat_id, "/tldr only works in group chats.")
+                    .await?;
+            }
+            command @ (commands::Command::Help | commands::Command::Start { payload: None }) => {
+                if matches!(command, commands::Command::Start { .. })
+                    && self.start_onboarding(chat_id).await?
+                {
+                    return Ok(());
+                }
+                let message = [
+                    "Commands:",
+                    "/help - show this help",
+                    "/start - show this help",
+                    "/models [query] - browse models by provider, or search ids and names; tap one to use it",
+                    "/model [id|none] - show or set model",
+                    "/key [key|none] - show or set API key",
+                    "/system_prompt [text|none] - show or set system prompt",
+                    "/approve [chat_id true|false] - admin only",
+                    "/admins - list admin chats (admin only)",
+                    "/config [set <name> <value>|reset <name>] - admin only: show or change runtime settings",
+                    "/mdtest - send a MarkdownV2 rendering test (admin only)",
+                    "/tools [set <json>|none] - show, set or clear tool definitions",
+                    "/tool_result <output> - answer the pending tool call(s)",
+                    "/log <chat_id> [n] - show recent LLM requests (admin only)",
+                    "/whoami - show chat id, authorization, model, API key, system prompt and history size",
+                    "/regenerate [instruction] - redo the last answer, optionally with a tweak; each retry raises the temperature a little (alias /retry)",
+                    "/settings export [reveal]|import <json> - back up or restore chat settings",
+                    "/voice [on|off] - also send answers as voice messages",
+                    "/cache [on|off] - reuse answers to repeated identical questions",
+                    "/stop - stop the answer being generated",
+                    "/websearch [on|off] - let the model search the web (slower and costs more)",
+                    "/stream [on|off|none] - show or set live-edited answers (none = default)",
+                    "/clear_context - send the next message without earlier context (history is kept)",
+                    "/reset - delete the conversation history and start over (settings are kept)",
+                    "/forget [n] - delete the last n turns of the history (default 1)",
+                    "/history [n] - show the last n stored turns with their age (default 5)",
+                    "/tokens <text> - estimate the token count of a text (or reply to a message)",
+                    "/showthinking [on|off] - show the model's reasoning before its answer",
+                    "/clean_thinking [on|off] - drop reasoning written before the final answer",
+                    "/reply_mode [thread|inline|none] - show or set whether answers reply to your message",
+                    "/mode [creative|balanced|precise|none] - show or set sampling parameters as a preset",
+                    "/reasoning [low|medium|high|none] - show or set how hard reasoning models think",
+                    "/temperature [0.0-2.0|none] - show or set the sampling temperature",
+                    "/top_p [0.0-1.0|none] - show or set nucleus sampling (top_p)",
+                    "/section [add <name> <text>|remove <name>|move <name> <position>] - list or edit named system prompt sections",
+                    "/effective_prompt - show the full system context sent with each request",
+                    "/timezone [UTC+hh:mm|none] - show or set the offset used for daily limits and schedules",
+                    "/schedule [daily|weekdays|<weekday> HH:MM <prompt>|cancel <id>] - list or manage recurring prompts",
+                    "/archive run - archive history past HISTORY_MAX_AGE_DAYS now (admin only)",
+                    "/usage - tokens and cost used by this chat, and by the last request",
+                    "/usage_csv [from] [to] - usage and cost per chat and day as CSV, dates as YYYY-MM-DD (admin only)",
+                    "/export [md|json] - the whole stored history as a file, with the model and system prompts",
+                    "/broadcast <text> - send the text to every authorized chat (admin only)",
+                    "/ephemeral [on [purge]|off] - stop or resume storing history",
+                    "/features - list feature toggles and what the current model supports",
+                    "/skip - leave the first-run setup",
+                    "/dm - (in a group) get a link to continue the conversation privately",
+                    "/tldr [n] - (in a group) summarize the last n messages (default 50)",
+                    "/delete_commands [on|off] - (in a group, admins only) delete command messages after handling",
+                ]
+                .join("\n");
+                telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
+            }
+            commands::Command::Models { query } => {
+                let current_model_id = { self.get_conversation(chat_id).await.model_id.clone() };
+                let current = self.resolve_model(current_model_id.as_deref()).await;
+                let models = self.models.load();
+                let page = match query {
+                    Some(query) => model_picker::search(&models, &query, &current.id),
+                    None => model_picker::page(&models, None, 0, &current.id),
+                };
+                self.bot
+                    .send_message(chat_id, page.text)
+                    .parse_mode(ParseMode::MarkdownV2)
+                    .reply_markup(page.keyboard)
+                    .await?;
+            }
+            commands::Command::Model(arg) => match arg {
+                commands::CommandArg::Empty => {
+                    let current_model_id = {
+                        let conv = self.get_conversation(chat_id).await;
+                        conv.model_id.clone()
+                    };
+                    let model = self.resolve_model(current_model_id.as_deref()).await;
+                    let message = match current_model_id {
+                        None => format!(
+                            "Current model\\: `{}` {}",
+                            telegram::escape_markdown_v2(&model.id),
+                            telegram::escape_markdown_v2(
+                                "(the default; it follows the operator's DEFAULT_MODEL, pin one with /model <id>)"
+                            )
+                        ),
+                        Some(pinned) if pinned == model.id => format!(
+                            "Current model\\: `{}` {}",
+                            telegram::escape_markdown_v2(&model.id),
+                            telegram::escape_markdown_v2(
+                                "(pinned; /model none returns to the default)"
+                            )
+                        ),
+                        Some(pinned) => format!(
+                            "Pinned model `{}` isn't available right now, so the default `{}` is used\\.",
+                            telegram::escape_markdown_v2(&pinned),
+                            telegram::escape_markdown_v2(&model.id)
+                        ),
+                    };
+                    self.bot
+                        .send_message(chat_id, message)
+                        .parse_mode(ParseMode::MarkdownV2)
+                        .await?;
+                }
+                commands::CommandArg::None => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        let old_model = self.resolve_model(conv.model_id.as_deref()).await;
+                        conv.model_id = None;
+                        let new_model = self.resolve_model(None).await;
+                        let should_reload = old_model.id != new_model.id
+                            && new_model.context_length >= old_model.context_length;
+                        if should_reload {
+                            db::load_history(&self.db, &mut conv, new_model.token_budget()).await;
+                        }
+                        conv.loaded_model = Some(conversation::LoadedModel::of(&new_model));
+                    }
+                    db::set_model_id(&self.db, chat_id, None).await;
+                    self.bot
+                        .send_message(chat_id, "Model cleared; using default.")
+                        .await?;
+                }
+                commands::CommandArg::Text(model_id) => {
+                    let available_models = self.models.load();
+                    let selected_model = available_models.iter().find(|m| m.id == model_id);
+
+                    if let Some(model) = selected_model {
+                        self.select_model(chat_id, model).await?;
+                    } else {
+                        log::warn!(
+                            "User {} tried to select non-existent model: `{}`",
+                            chat_id,
+                            model_id
+                        );
+                        self.bot
+                            .send_message(
+                                chat_id,
+                                format!(
+                                    "Model not found\\: `{}`",
+                                    telegram::escape_markdown_v2(&model_id)
+                                ),
+                            )
+                            .parse_mode(ParseMode::MarkdownV2)
+                            .await?;
+                    }
+                }
+            },
+            commands::Command::Key(arg) => match arg {
+                commands::CommandArg::Empty => {
+                    let (current_key, provider_keys) = {
+                        let conv = self.get_conversation(chat_id).await;
+                        (
+                            conv.api_key().map(str::to_string),
+                            conv.provider_keys.clone(),
+                        )
+                    };
+                    match current_key {
+                        Some(_) => {
+                            let lines = provider_keys
+                                .iter()
+                                .map(|(provider, key)| {
+                                    format!(
+                                        "{}{}\\: `{}`",
+                                        telegram::escape_markdown_v2(provider),
+                                        if provider == openrouter_api::PROVIDER {
+                                            " \\(active\\)"
+                                        } else {
+                                            ""
+                                        },
+                                        telegram::escape_markdown_v2(&mask_api_key(key))
+                                    )
+                                })
+                                .collect::<Vec<_>>();
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!("API keys \\(masked\\)\\:\n{}", lines.join("\n")),
+                                )
+                                .parse_mode(ParseMode::MarkdownV2)
+                                .await?;
+                        }
+                        None if self.config.load().fallback_openrouter_key.is_some() => {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    "No API key set; using the operator-provided shared key.",
+                                )
+                                .await?;
+                        }
+                        None => {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!(
+                                        "No API key set. Create one at {} and send it with /key <your-openrouter-key>.",
+                                        openrouter_api::KEYS_URL
+                                    ),
+                                )
+                                .await?;
+                        }
+                    }
+                }
+                commands::CommandArg::None => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.set_api_key(None);
+                    }
+                    db::set_provider_key(&self.db, chat_id, openrouter_api::PROVIDER, None).await;
+                    self.bot.send_message(chat_id, "API key cleared.").await?;
+                }
+                commands::CommandArg::Text(key) => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.set_api_key(Some(key.clone()));
+                    }
+                    db::set_provider_key(&self.db, chat_id, openrouter_api::PROVIDER, Some(&key))
+                        .await;
+                    self.bot.send_message(chat_id, "API key updated.").await?;
+                }
+            },
+            commands::Command::SystemPrompt(arg) => match arg {
+                commands::CommandArg::Empty => {
+                    let current_prompt = {
+                        let conv = self.get_conversation(chat_id).await;
+                        conv.system_prompt.as_ref().map(|p| p.text.clone())
+                    };
+                    match current_prompt {
+                        Some(prompt) => {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!(
+                                        "Current system prompt\\: ```\n{}\n```",
+                                        telegram::escape_markdown_v2(&prompt)
+                                    ),
+                                )
+                                .parse_mode(ParseMode::MarkdownV2)
+                                .await?;
+                        }
+                        None => {
+                            let model_id =
+                                { self.get_conversation(chat_id).await.model_id.clone() };
+                            let model = self.resolve_model(model_id.as_deref()).await;
+                            let message = match self.model_prompts.for_model(&model.id) {
+                                Some(default) => format!(
+                                    "No system prompt set; the default for {} applies:\n{}",
+                                    model.id, default
+                                ),
+                                None => "No system prompt set.".to_string(),
+                            };
+                            self.bot.send_message(chat_id, message).await?;
+                        }
+                    }
+                }
+                commands::CommandArg::None => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.system_prompt = None;
+                    }
+                    db::set_system_prompt(&self.db, chat_id, None).await;
+                    self.bot
+                        .send_message(chat_id, "System prompt cleared.")
+                        .await?;
+                }
+                commands::CommandArg::Text(prompt) => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.system_prompt = Some(conversation::Message {
+                            role: MessageRole::System,
+                            text: prompt.clone(),
+                        });
+                    }
+                    db::set_system_prompt(&self.db, chat_id, Some(&prompt)).await;
+                    self.bot
+                        .send_message(chat_id, "System prompt updated.")
+                        .await?;
+                }
+            },
+            commands::Command::Approve(approve) => {
+                if !self.check_admin(chat_id, "/approve").await? {
+                    return Ok(());
+                }
+
+                match approve {
+                    commands::ApproveArg::Empty => {
+                        let pending = db::list_unauthorized_chats(&self.db).await;
+                        if pending.is_empty() {
+                            self.bot.send_message(chat_id, "No pending users.").await?;
+                            return Ok(());
+                        }
+
+                        let mut lines = Vec::with_capacity(pending.len());
+                        for (pending_id, name) in pending {
+                            let display_name = name.unwrap_or_else(|| "unknown".to_string());
+                            let display_name = escape_markdown_v2(&display_name);
+                            lines.push(format!("`{}` \\- {}", pending_id, display_name));
+                        }
+
+                        let message = format!("Pending users\\:\n{}", lines.join("\n"));
+                        bot_split_send_formatted(
+                            &self.bot,
+                            chat_id,
+                            &message,
+                            None,
+                            ParseMode::MarkdownV2,
+                        )
+                        .await?;
+                    }
+                    commands::ApproveArg::ApproveChat {
+                        chat_id: target_chat_id,
+                        is_authorized,
+                    } => {
+                        self.set_chat_authorization(chat_id, ChatId(target_chat_id), is_authorized)
+                            .await?;
+                    }
+                    commands::ApproveArg::Invalid => {
+                        self.bot
+                            .send_message(chat_id, "Usage: /approve <chat_id> <true|false>")
+                            .await?;
+                    }
+                }
+            }
+            commands::Command::Config(arg) => {
+                if !self.check_admin(chat_id, "/config").await? {
+                    return Ok(());
+                }
+
+                let setting = match &arg {
+                    commands::ConfigArg::Show => {
+                        self.send_runtime_config(chat_id).await?;
+                        return Ok(());
+                    }
+                    commands::ConfigArg::Set { name, .. } | commands::ConfigArg::Reset { name } => {
+                        let Some(setting) = config::RuntimeSetting::find(name) else {
+                            let names: Vec<_> = config::RUNTIME_SETTINGS
+                                .iter()
+                                .map(|setting| setting.name)
+                                .collect();
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!(
+                                        "{name} can't be changed at runtime. Runtime settings: {}",
+                                        names.join(", ")
+                                    ),
+                                )
+                                .await?;
+                            return Ok(());
+                        };
+                        setting
+                    }
+                    commands::ConfigArg::Invalid => {
+                        self.bot
+                            .send_message(
+                                chat_id,
+                                "Usage: /config, /config set <name> <value>, /config reset <name>",
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                let message = if let commands::ConfigArg::Set { value, .. } = &arg {
+                    match setting.validate(value) {
+                        Ok(value) => {
+                            db::set_config_override(&self.db, setting.name, value).await;
+                            self.reload_config().await;
+                            log::info!("admin {} set {} at runtime", chat_id, setting.name);
+                            format!(
+                                "{} is now {}. The override is kept across restarts until /config reset {}.",
+                                setting.name,
+                                (setting.current)(&self.config.load()),
+                                setting.name
+                            )
+                        }
+                        Err(err) => err,
+                    }
+                } else if db::delete_config_override(&self.db, setting.name).await {
+                    self.reload_config().await;
+                    log::info!(
+                        "admin {} reset {} to the environment",
+                        chat_id,
+                        setting.name
+                    );
+                    format!(
+                        "{} is back to {} from the environment.",
+                        setting.name,
+                        (setting.current)(&self.config.load())
+                    )
+                } else {
+                    format!("{} has no override.", setting.name)
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::MdTest => {
+                if !self.check_admin(chat_id, "/mdtest").await? {
+                    return Ok(());
+                }
+
+                let samples = telegram::markdown_v2_samples();
+                let message = samples
+                    .iter()
+                    .map(|(name, sample)| format!("*{}*\n{}", escape_markdown_v2(name), sample))
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+
+                let result = telegram::bot_split_send_formatted_strict(
+                    &self.bot,
+                    chat_id,
+                    &message,
+                    None,
+                    ParseMode::MarkdownV2,
+                )
+                .await;
+                if let Err(err) = result {
+                    log::warn!("Markdown test message rejected by Telegram: {err}");
+
+                    // Re-send each construct on its own to pinpoint the offending ones.
+                    let mut failed = Vec::new();
+                    for (name, sample) in &samples {
+                        if let Err(err) = telegram::bot_split_send_formatted_strict(
+                            &self.bot,
+                            chat_id,
+                            sample,
+                            None,
+                            ParseMode::MarkdownV2,
+                        )
+                        .await
+                        {
+                            failed.push(format!("{name}: {err}"));
+                        }
+                    }
+
+                    let report = if failed.is_empty() {
+                        format!(
+                            "Markdown test failed as a whole but every construct passed alone: {err}"
+                        )
+                    } else {
+                        format!("Markdown test failed for:\n{}", failed.join("\n"))
+                    };
+                    telegram::bot_split_send(&self.bot, chat_id, &report, None).await?;
+                }
+            }
+            commands::Command::Tools(arg) => match arg {
+                commands::ToolsArg::Show => {
+                    let tools = { self.get_conversation(chat_id).await.tools.clone() };
+                    match tools {
+                        Some(tools) => {
+                            let pretty = serde_json::to_string_pretty(&tools)
+                                .expect("tools JSON should serialize");
+                            let message = format!(
+                                "Current tools\\:\n```json\n{}\n```",
+                                escape_markdown_v2(&pretty)
+                            );
+                            bot_split_send_formatted(
+                                &self.bot,
+                                chat_id,
+                                &message,
+                                None,
+                                ParseMode::MarkdownV2,
+                            )
+                            .await?;
+                        }
+                        None => {
+                            self.bot.send_message(chat_id, "No tools set.").await?;
+                        }
+                    }
+                }
+                commands::ToolsArg::Clear => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.tools = None;
+                        conv.pending_tool_calls = None;
+                    }
+                    db::set_tools(&self.db, chat_id, None).await;
+                    self.bot.send_message(chat_id, "Tools cleared.").await?;
+                }
+                commands::ToolsArg::Set(json) => {
+                    let tools = serde_json::from_str::<serde_json::Value>(&json)
+                        .map_err(|err| format!("Invalid JSON: {err}"))
+                        .and_then(|tools| openrouter_api::validate_tools(&tools).map(|()| tools));
+                    match tools {
+                        Ok(tools) => {
+                            {
+                                let mut conv = self.get_conversation(chat_id).await;
+                                conv.tools = Some(tools.clone());
+                            }
+                            db::set_tools(&self.db, chat_id, Some(&tools)).await;
+                            let count = tools.as_array().map(Vec::len).unwrap_or_default();
+                            self.bot
+                                .send_message(chat_id, format!("Tools updated ({count} defined)."))
+                                .await?;
+                        }
+                        Err(message) => {
+                            self.bot
+                                .send_message(chat_id, format!("Tools not updated: {message}"))
+                                .await?;
+                        }
+                    }
+                }
+                commands::ToolsArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /tools [set <json>|none]")
+                        .await?;
+                }
+            },
+            commands::Command::ToolResult(arg) => {
+                let commands::CommandArg::Text(output) = arg else {
+                    self.bot
+                        .send_message(chat_id, "Usage: /tool_result <output>")
+                        .await?;
+                    return Ok(());
+                };
+
+                let resolved = {
+                    let mut conv = self.get_conversation(chat_id).await;
+                    match conv.pending_tool_calls.as_mut() {
+                        Some(pending) if !pending.tool_calls.is_empty() => {
+                            match match_tool_outputs(&pending.tool_calls, &output) {
+                                Ok(outputs) => {
+                                    pending.completed.extend(outputs);
+                                    pending.tool_calls.clear();
+                                    Ok((pending.user_message.clone(), pending.completed.clone()))
+                                }
+                                Err(message) => Err(message),
+                            }
+                        }
+                        _ => Err("No tool calls are pending.".to_string()),
+                    }
+                };
+                let (user_message, completed) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(message) => {
+                        self.bot.send_message(chat_id, message).await?;
+                        return Ok(());
+                    }
+                };
+
+                let mut ready = match self.prepare_llm_request(chat_id, &user_message).await {
+                    Ok(ready) => ready,
+                    Err(err) => {
+                        self.bot.send_message(chat_id, err.user_message()).await?;
+                        return Ok(());
+                    }
+                };
+                ready.append_tool_results(&completed);
+
+                let llm_call = self.call_llm_for_reply(chat_id, msg_id, false, ready).await;
+
+                self.handle_llm_response(chat_id, msg_id, false, user_message, llm_call)
+                    .await?;
+            }
+            commands::Command::Whoami => self.send_whoami(chat_id).await?,
+            commands::Command::Stop => {
+                let generation = self
+                    .generations
+                    .lock()
+                    .expect("generation lock poisoned")
+                    .get(&chat_id)
+                    .cloned();
+                // The stopped request confirms it itself, under what it had received.
+                match generation {
+                    Some(stop) => stop.cancel(),
+                    None => {
+                        self.bot
+                            .send_message(chat_id, "Nothing is being generated right now.")
+                            .await?;
+                    }
+                }
+            }
+            commands::Command::Admins => {
+                if !self.check_admin(chat_id, "/admins").await? {
+                    return Ok(());
+                }
+
+                let env_admins = self.config.load().admin_chats.clone();
+                let lines: Vec<String> = db::list_admin_chats(&self.db)
+                    .await
+                    .into_iter()
+                    .map(|(admin_id, user_name)| {
+                        format!(
+                            "{} ({}){}",
+                            admin_id,
+                            user_name.as_deref().unwrap_or("unknown"),
+                            if env_admins.contains(&admin_id) {
+                                ", from ADMIN_CHATS"
+                            } else {
+                                ""
+                            }
+                        )
+                    })
+                    .collect();
+                self.bot
+                    .send_message(chat_id, format!("Admins:\n{}", lines.join("\n")))
+                    .await?;
+            }
+            commands::Command::Regenerate(arg) => {
+                let tweak = match arg {
+                    commands::CommandArg::Text(tweak) => Some(tweak),
+                    commands::CommandArg::Empty | commands::CommandArg::None => None,
+                };
+                self.regenerate_last_answer(chat_id, msg_id, tweak).await?;
+            }
+            commands::Command::Ephemeral(arg) => match arg {
+                commands::EphemeralArg::Show => {
+                    let ephemeral = { self.get_conversation(chat_id).await.ephemeral };
+                    let message = if ephemeral {
+                        "Ephemeral mode is on: new messages are kept in memory only."
+                    } else {
+                        "Ephemeral mode is off: history is stored."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::EphemeralArg::On { purge } => {
+                    {
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.ephemeral = true;
+                        if purge {
+                            conv.history.clear();
+                        }
+                    }
+                    db::set_ephemeral(&self.db, chat_id, true).await;
+
+                    let message = if purge {
+                        let deleted = db::clear_history(&self.db, chat_id).await;
+                        format!(
+                            "Ephemeral mode on. Deleted {deleted} stored message(s); new messages won't be stored."
+                        )
+                    } else {
+                        "Ephemeral mode on: new messages won't be stored. Use /ephemeral on purge to also delete stored history.".to_string()
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::EphemeralArg::Off => {
+                    {
+                        // Drop the in-memory-only session and continue from what is stored.
+                        let mut conv = self.get_conversation(chat_id).await;
+                        conv.ephemeral = false;
+                        let model = self.resolve_model(conv.model_id.as_deref()).await;
+                        db::load_history(&self.db, &mut conv, model.token_budget()).await;
+                    }
+                    db::set_ephemeral(&self.db, chat_id, false).await;
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            "Ephemeral mode off: messages are stored again. The unsaved session was discarded.",
+                        )
+                        .await?;
+                }
+                commands::EphemeralArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /ephemeral [on [purge]|off]")
+                        .await?;
+                }
+            },
+            commands::Command::Usage => {
+                let message = match db::load_usage(&self.db, chat_id).await {
+                    None => "No usage recorded for this chat yet.".to_string(),
+                    Some(usage) => format!(
+                        "Usage of this chat: {} request(s), {} tokens ({} prompt, {} completion), ${:.4}.\nLast request ({}): {} tokens ({} prompt, {} completion), ${:.4}.",
+                        usage.requests,
+                        usage.total_tokens,
+                        usage.prompt_tokens,
+                        usage.completion_tokens,
+                        usage.cost,
+                        usage.last.model_id,
+                        usage.last.total_tokens,
+                        usage.last.prompt_tokens,
+                        usage.last.completion_tokens,
+                        usage.last.cost
+                    ),
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::UsageCsv(arg) => {
+                if !self.check_admin(chat_id, "/usage_csv").await? {
+                    return Ok(());
+                }
+
+                let commands::UsageCsvArg::Range { from, to } = arg else {
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            "Usage: /usage_csv [from] [to] with dates as YYYY-MM-DD (default: this month)",
+                        )
+                        .await?;
+                    return Ok(());
+                };
+                let today = chrono::Utc::now().date_naive();
+                let (from, to) = match usage_csv::resolve_range(from, to, today) {
+                    Ok(range) => range,
+                    Err(err) => {
+                        self.bot
+                            .send_message(chat_id, format!("Invalid range: {err}."))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                let day_start = |date: chrono::NaiveDate| {
+                    date.and_hms_opt(0, 0, 0)
+                        .expect("midnight is a valid time")
+                        .and_utc()
+                        .timestamp()
+                };
+                let end = to.succ_opt().expect("end date out of range");
+                let rows = db::usage_by_day(&self.db, day_start(from), day_start(end)).await;
+                if rows.is_empty() && !self.config.load().request_log {
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            "No usage recorded. Request logging is disabled (REQUEST_LOG).",
+                        )
+                        .await?;
+                    return Ok(());
+                }
+
+                let csv = usage_csv::render(&rows);
+                self.bot
+                    .send_document(
+                        chat_id,
+                        InputFile::memory(csv.into_bytes())
+                            .file_name(format!("usage_{from}_{to}.csv")),
+                    )
+                    .caption(format!(
+                        "Usage from {from} to {to} (UTC): {} row(s).",
+                        rows.len()
+                    ))
+                    .await?;
+            }
+            commands::Command::Export(arg) => {
+                let commands::ExportArg::Format(format) = arg else {
+                    self.bot
+                        .send_message(chat_id, "Usage: /export [md|json]")
+                        .await?;
+                    return Ok(());
+                };
+                let messages = db::load_full_history(&self.db, chat_id).await;
+                if messages.is_empty() {
+                    self.bot
+                        .send_message(chat_id, "Nothing to export: no messages are stored.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let header = {
+                    let conversation = self.get_conversation(chat_id).await;
+                    let model = self.resolve_model(conversation.model_id.as_deref()).await;
+                    export::ExportHeader {
+                        chat_id: chat_id.0,
+                        title: conversation.title.clone(),
+                        system: self
+                            .system_messages(&conversation, &model.id)
+                            .into_iter()
+                            .map(|(label, message)| (label, message.text))
+                            .collect(),
+                        model_id: model.id,
+                        exported_at: chrono::Utc::now(),
+                    }
+                };
+                let file = export::render(&header, &messages, format);
+                self.bot
+                    .send_document(
+                        chat_id,
+                        InputFile::memory(file.into_bytes())
+                            .file_name(export::file_name(&header, format)),
+                    )
+                    .caption(format!("Full history: {} message(s).", messages.len()))
+                    .await?;
+            }
+            commands::Command::Broadcast(text) => {
+                if !self.check_admin(chat_id, "/broadcast").await? {
+                    return Ok(());
+                }
+                if text.is_empty() {
+                    self.bot
+                        .send_message(chat_id, "Usage: /broadcast <text>")
+                        .await?;
+                    return Ok(());
+                }
+                self.broadcast(chat_id, &text).await?;
+            }
+            commands::Command::ReplyMode(arg) => {
+                let reply_mode = match arg {
+                    commands::CommandArg::Empty => {
+                        let reply_mode = { self.get_conversation(chat_id).await.reply_mode };
+                        let message = match reply_mode {
+                            conversation::ReplyMode::Auto => {
+                                "Reply mode: auto (answers reply to your message in groups only)."
+                            }
+                            conversation::ReplyMode::Thread => {
+                                "Reply mode: thread (answers always reply to your message)."
+                            }
+                            conversation::ReplyMode::Inline => {
+                                "Reply mode: inline (answers never reply to your message)."
+                            }
+                        };
+                        self.bot.send_message(chat_id, message).await?;
+                        return Ok(());
+                    }
+                    commands::CommandArg::None => conversation::ReplyMode::Auto,
+                    commands::CommandArg::Text(text) => {
+                        let Some(reply_mode) = conversation::ReplyMode::parse(&text) else {
+                            self.bot
+                                .send_message(chat_id, "Usage: /reply_mode [thread|inline|none]")
+                                .await?;
+                            return Ok(());
+                        };
+                        reply_mode
+                    }
+                };
+
+                {
+                    self.get_conversation(chat_id).await.reply_mode = reply_mode;
+                }
+                db::set_reply_mode(&self.db, chat_id, reply_mode).await;
+                self.bot
+                    .send_message(chat_id, format!("Reply mode set to {reply_mode}."))
+                    .await?;
+            }
+            commands::Command::Reasoning(arg) => {
+                let model = {
+                    let conv = self.get_conversation(chat_id).await;
+                    self.resolve_model(conv.model_id.as_deref()).await
+                };
+                let effort = match arg {
+                    commands::CommandArg::Empty => {
+                        let effort = { self.get_conversation(chat_id).await.reasoning_effort };
+                        let message = match (effort, model.capabilities.reasoning) {
+                            (_, false) => format!(
+                                "The current model ({}) doesn't support reasoning effort.",
+                                model.id
+                            ),
+                            (Some(effort), true) => format!("Reasoning effort: {effort}."),
+                            (None, true) => "Reasoning effort: model default.".to_string(),
+                        };
+                        self.bot.send_message(chat_id, message).await?;
+                        return Ok(());
+                    }
+                    commands::CommandArg::None => None,
+                    commands::CommandArg::Text(text) => {
+                        let Some(effort) = conversation::ReasoningEffort::parse(&text) else {
+                            self.bot
+                                .send_message(chat_id, "Usage: /reasoning [low|medium|high|none]")
+                                .await?;
+                            return Ok(());
+                        };
+                        if !model.capabilities.reasoning {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!(
+                                        "The current model ({}) doesn't support reasoning effort, so it wasn't set. Pick a reasoning model with /models first.",
+                                        model.id
+                                    ),
+                                )
+                                .await?;
+                            return Ok(());
+                        }
+                        Some(effort)
+                    }
+                };
+
+                {
+                    self.get_conversation(chat_id).await.reasoning_effort = effort;
+                }
+                db::set_reasoning_effort(&self.db, chat_id, effort).await;
+                let message = match effort {
+                    Some(effort) => format!("Reasoning effort set to {effort}."),
+                    None => "Reasoning effort reset to the model default.".to_string(),
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::Mode(arg) => {
+                let sampling = match arg {
+                    commands::CommandArg::Empty => {
+                        let sampling = { self.get_conversation(chat_id).await.sampling };
+                        let mode = conversation::SamplingMode::matching(sampling)
+                            .map_or("custom".to_string(), |mode| mode.to_string());
+                        self.bot
+                            .send_message(chat_id, format!("Mode: {mode} ({sampling})."))
+                            .await?;
+                        return Ok(());
+                    }
+                    commands::CommandArg::None => conversation::SamplingParams::default(),
+                    commands::CommandArg::Text(text) => {
+                        let Some(mode) = conversation::SamplingMode::parse(&text) else {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    "Usage: /mode [creative|balanced|precise|none]",
+                                )
+                                .await?;
+                            return Ok(());
+                        };
+                        mode.params()
+                    }
+                };
+
+                {
+                    self.get_conversation(chat_id).await.sampling = sampling;
+                }
+                db::set_sampling(&self.db, chat_id, sampling).await;
+                let message = match conversation::SamplingMode::matching(sampling) {
+                    Some(mode) => format!("Mode set to {mode}: {sampling}."),
+                    None => format!("Sampling reset to the model defaults: {sampling}."),
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::Sampling { param, arg } => {
+                let value = match arg {
+                    commands::SamplingArg::Show => {
+                        let value = { param.get(&self.get_conversation(chat_id).await.sampling) };
+                        let value = value.map_or("model default".to_string(), |v| v.to_string());
+                        self.bot
+                            .send_message(chat_id, format!("{}: {value}.", param.name()))
+                            .await?;
+                        return Ok(());
+                    }
+                    commands::SamplingArg::Clear => None,
+                    commands::SamplingArg::Set(value) => Some(value),
+                    commands::SamplingArg::Invalid => {
+                        self.bot.send_message(chat_id, param.usage()).await?;
+                        return Ok(());
+                    }
+                };
 
-        log::info!("Received command: {:?}", command);
-        match command {
-            commands::Command::Ignore => {
-                // Command addressed to a different bot; ignore silently.
-            }
-            commands::Command::Help | commands::Command::Start => {
-                let message = [
-                    "Commands:",
-                    "/help - show this help",
-                    "/start - show this help",
-                    "/models - list available models",
-                    "/model [id|none] - show or set model",
-                    "/key [key|none] - show or set API key",
-                    "/system_prompt [text|none] - show or set system prompt",
-                    "/approve [chat_id true|false] - admin only",
-                ]
-                .join("\n");
-                telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
+                let sampling = {
+                    let mut conv = self.get_conversation(chat_id).await;
+                    param.set(&mut conv.sampling, value);
+                    conv.sampling
+                };
+                db::set_sampling(&self.db, chat_id, sampling).await;
+                let message = match value {
+                    Some(value) => format!("{} set to {value}.", param.name()),
+                    None => format!("{} reset to the model default.", param.name()),
+                };
+                self.bot.send_message(chat_id, message).await?;
             }
-            commands::Command::Models => {
-                let models = self.models.read().await;
-                let models = models
-                    .iter()
-                    .filter_map(|f| {
-                        if f.id.starts_with("openai")
-                            || f.id.starts_with("anthropic")
-                            || f.id.starts_with("google")
-                            || f.id.starts_with("x-ai")
-                            || f.id.starts_with("deepseek")
-                        {
-                            Some(format!(
-                                "`{}` \\- {}",
-                                telegram::escape_markdown_v2(&f.id),
-                                telegram::escape_markdown_v2(&f.name)
-                            ))
+            commands::Command::Section(arg) => {
+                let mut sections = { self.get_conversation(chat_id).await.prompt_sections.clone() };
+                let message = match arg {
+                    commands::SectionArg::List => {
+                        let message = if sections.is_empty() {
+                            "No prompt sections. Add one with /section add <name> <text>."
+                                .to_string()
                         } else {
-                            None
+                            let lines: Vec<String> = sections
+                                .iter()
+                                .enumerate()
+                                .map(|(index, section)| {
+                                    format!("{}. {}: {}", index + 1, section.name, section.text)
+                                })
+                                .collect();
+                            format!("Prompt sections, in order:\n{}", lines.join("\n"))
+                        };
+                        telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
+                        return Ok(());
+                    }
+                    commands::SectionArg::Add { name, text } => {
+                        if !conversation::PromptSection::is_valid_name(&name) {
+                            self.bot
+                                .send_message(
+                                    chat_id,
+                                    format!(
+                                        "Section names use letters, digits, _ and - only, at most {} characters.",
+                                        conversation::MAX_SECTION_NAME_CHARS
+                                    ),
+                                )
+                                .await?;
+                            return Ok(());
                         }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n");
+                        if conversation::upsert_section(&mut sections, &name, &text) {
+                            format!("Section {name} added at position {}.", sections.len())
+                        } else {
+                            format!("Section {name} updated.")
+                        }
+                    }
+                    commands::SectionArg::Remove { name } => {
+                        let before = sections.len();
+                        sections.retain(|section| section.name != name);
+                        if sections.len() == before {
+                            self.bot
+                                .send_message(chat_id, format!("No section named {name}."))
+                                .await?;
+                            return Ok(());
+                        }
+                        format!("Section {name} removed.")
+                    }
+                    commands::SectionArg::Move { name, position } => {
+                        if !conversation::move_section(&mut sections, &name, position) {
+                            self.bot
+                                .send_message(chat_id, format!("No section named {name}."))
+                                .await?;
+                            return Ok(());
+                        }
+                        let names: Vec<&str> = sections
+                            .iter()
+                            .map(|section| section.name.as_str())
+                            .collect();
+                        format!("Section order: {}.", names.join(", "))
+                    }
+                    commands::SectionArg::Invalid => {
+                        self.bot
+                            .send_message(
+                                chat_id,
+                                "Usage: /section [add <name> <text>|remove <name>|move <name> <position>]",
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+                };
 
-                let message = format!("Available models\\:\n{}", models);
-                bot_split_send_formatted(&self.bot, chat_id, &message, None, ParseMode::MarkdownV2)
-                    .await?;
+                {
+                    self.get_conversation(chat_id).await.prompt_sections = sections.clone();
+                }
+                db::set_prompt_sections(&self.db, chat_id, sections).await;
+                self.bot.send_message(chat_id, message).await?;
             }
-            commands::Command::Model(arg) => match arg {
-                commands::CommandArg::Empty => {
-                    let current_model_id = {
-                        let conv = self.get_conversation(chat_id).await;
-                        conv.model_id.clone()
-                    };
-                    let model = self.resolve_model(current_model_id.as_deref()).await;
+            commands::Command::EffectivePrompt => {
+                let parts = {
+                    let conversation = self.get_conversation(chat_id).await;
+                    let model = self.resolve_model(conversation.model_id.as_deref()).await;
+                    self.system_messages(&conversation, &model.id)
+                };
+                let parts: Vec<String> = parts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (label, message))| {
+                        format!("{}. [{}]\n{}", index + 1, label, message.text)
+                    })
+                    .collect();
+                let message = format!(
+                    "System context, in the order it is sent:\n\n{}",
+                    parts.join("\n\n")
+                );
+                telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
+            }
+            commands::Command::Archive(arg) => {
+                if !self.check_admin(chat_id, "/archive").await? {
+                    return Ok(());
+                }
+                if matches!(arg, commands::ArchiveArg::Invalid) {
+                    self.bot
+                        .send_message(chat_id, "Usage: /archive run")
+                        .await?;
+                    return Ok(());
+                }
+                let Some(max_age) = self.config.load().history_max_age else {
                     self.bot
                         .send_message(
                             chat_id,
-                            format!(
-                                "Current model\\: `{}`",
-                                telegram::escape_markdown_v2(&model.id)
-                            ),
+                            "History archival is disabled (set HISTORY_MAX_AGE_DAYS).",
                         )
-                        .parse_mode(ParseMode::MarkdownV2)
                         .await?;
+                    return Ok(());
+                };
+
+                let (rows, chats) = self.run_history_archival(max_age).await;
+                let action = match self.config.load().history_archive_mode {
+                    config::ArchiveMode::Archive => "Archived",
+                    config::ArchiveMode::Delete => "Deleted",
+                };
+                self.bot
+                    .send_message(
+                        chat_id,
+                        format!("{action} {rows} history row(s) from {chats} chat(s)."),
+                    )
+                    .await?;
+            }
+            commands::Command::Schedule(arg) => match arg {
+                commands::ScheduleArg::List => {
+                    let schedules = db::list_schedules(&self.db, chat_id).await;
+                    let offset = { self.get_conversation(chat_id).await.utc_offset };
+                    let message = if schedules.is_empty() {
+                        "No schedules. Add one with /schedule daily 09:00 <prompt>.".to_string()
+                    } else {
+                        let lines: Vec<String> = schedules
+                            .iter()
+                            .map(|entry| {
+                                let next_run = chrono::DateTime::from_timestamp(entry.next_run, 0)
+                                    .expect("stored next run in range")
+                                    .with_timezone(&offset);
+                                format!(
+                                    "#{} {} at {} (next {}): {}",
+                                    entry.id,
+                                    entry.recurrence,
+                                    entry.time.format("%H:%M"),
+                                    next_run.format("%Y-%m-%d %H:%M"),
+                                    entry.prompt
+                                )
+                            })
+                            .collect();
+                        format!(
+                            "Schedules ({}):\n{}",
+                            timezone::format_utc_offset(offset),
+                            lines.join("\n")
+                        )
+                    };
+                    telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
                 }
-                commands::CommandArg::None => {
+                commands::ScheduleArg::Add {
+                    recurrence,
+                    time,
+                    prompt,
+                } => {
+                    if db::list_schedules(&self.db, chat_id).await.len()
+                        >= schedule::MAX_SCHEDULES_PER_CHAT
                     {
-                        let mut conv = self.get_conversation(chat_id).await;
-                        let old_model = self.resolve_model(conv.model_id.as_deref()).await;
-                        conv.model_id = None;
-                        let new_model = self.resolve_model(None).await;
-                        let should_reload = old_model.id != new_model.id
-                            && new_model.context_length >= old_model.context_length;
-                        if should_reload {
-                            db::load_history(&self.db, &mut conv, new_model.token_budget()).await;
-                        }
-                    }
-                    db::set_model_id(&self.db, chat_id, None).await;
-                    self.bot
-                        .send_message(chat_id, "Model cleared; using default.")
-                        .await?;
-                }
-                commands::CommandArg::Text(model_id) => {
-                    let available_models = self.models.read().await;
-                    let selected_model = available_models.iter().find(|m| m.id == model_id);
-
-                    if let Some(model) = selected_model {
-                        {
-                            let mut conv = self.get_conversation(chat_id).await;
-                            let old_model = self.resolve_model(conv.model_id.as_deref()).await;
-                            conv.model_id = Some(model.id.clone());
-                            let should_reload = old_model.id != model.id
-                                && model.context_length >= old_model.context_length;
-                            if should_reload {
-                                db::load_history(&self.db, &mut conv, model.token_budget()).await;
-                            }
-                        }
-                        db::set_model_id(&self.db, chat_id, Some(&model.id)).await;
-                        log::info!("User {} selected model: `{}`", chat_id, model.name);
                         self.bot
                             .send_message(
                                 chat_id,
                                 format!(
-                                    "Selected model\\: `{}`",
-                                    telegram::escape_markdown_v2(&model.name)
+                                    "A chat can keep at most {} schedules; cancel one first.",
+                                    schedule::MAX_SCHEDULES_PER_CHAT
                                 ),
                             )
-                            .parse_mode(ParseMode::MarkdownV2)
                             .await?;
+                        return Ok(());
+                    }
+                    let offset = { self.get_conversation(chat_id).await.utc_offset };
+                    let next_run = schedule::next_run(recurrence, time, offset, chrono::Utc::now())
+                        .with_timezone(&offset);
+                    let id = db::add_schedule(
+                        &self.db,
+                        chat_id,
+                        recurrence,
+                        time,
+                        &prompt,
+                        next_run.timestamp(),
+                    )
+                    .await;
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            format!(
+                                "Schedule #{id} runs {recurrence} at {} ({}), next on {}. Cancel it with /schedule cancel {id}.",
+                                time.format("%H:%M"),
+                                timezone::format_utc_offset(offset),
+                                next_run.format("%Y-%m-%d")
+                            ),
+                        )
+                        .await?;
+                }
+                commands::ScheduleArg::Cancel { id } => {
+                    let message = if db::delete_schedule(&self.db, chat_id, id).await {
+                        format!("Schedule #{id} cancelled.")
                     } else {
-                        log::warn!(
-                            "User {} tried to select non-existent model: `{}`",
+                        format!("No schedule #{id} in this chat.")
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ScheduleArg::Invalid => {
+                    self.bot
+                        .send_message(
                             chat_id,
-                            model_id
-                        );
+                            "Usage: /schedule [list], /schedule daily|weekdays|<weekday> HH:MM <prompt>, /schedule cancel <id>\nExample: /schedule daily 09:00 \"Summarize today's top AI news\"",
+                        )
+                        .await?;
+                }
+            },
+            commands::Command::Timezone(arg) => {
+                let offset = match arg {
+                    commands::CommandArg::Empty => {
+                        let offset = { self.get_conversation(chat_id).await.utc_offset };
                         self.bot
                             .send_message(
                                 chat_id,
                                 format!(
-                                    "Model not found\\: `{}`",
-                                    telegram::escape_markdown_v2(&model_id)
+                                    "Time zone: {}. Daily limits reset at midnight in this zone.",
+                                    timezone::format_utc_offset(offset)
                                 ),
                             )
-                            .parse_mode(ParseMode::MarkdownV2)
                             .await?;
+                        return Ok(());
                     }
-                }
-            },
-            commands::Command::Key(arg) => match arg {
-                commands::CommandArg::Empty => {
-                    let current_key = {
-                        let conv = self.get_conversation(chat_id).await;
-                        conv.openrouter_api_key.clone()
-                    };
-                    match current_key {
-                        Some(key) => {
-                            let masked_key = mask_api_key(&key);
+                    commands::CommandArg::None => timezone::offset_from_minutes(0),
+                    commands::CommandArg::Text(text) => {
+                        let Some(offset) = timezone::parse_utc_offset(&text) else {
                             self.bot
                                 .send_message(
                                     chat_id,
-                                    format!(
-                                        "API key is set \\(masked\\)\\: `{}`",
-                                        telegram::escape_markdown_v2(&masked_key)
-                                    ),
+                                    "Usage: /timezone [UTC+hh:mm|none], e.g. /timezone +3 or /timezone UTC-05:30",
                                 )
-                                .parse_mode(ParseMode::MarkdownV2)
                                 .await?;
+                            return Ok(());
+                        };
+                        offset
+                    }
+                };
+
+                {
+                    self.get_conversation(chat_id).await.utc_offset = offset;
+                }
+                db::set_utc_offset(&self.db, chat_id, offset).await;
+                self.bot
+                    .send_message(
+                        chat_id,
+                        format!("Time zone set to {}.", timezone::format_utc_offset(offset)),
+                    )
+                    .await?;
+            }
+            commands::Command::Cache(arg) => match arg {
+                commands::ToggleArg::Show => {
+                    let cache = { self.get_conversation(chat_id).await.cache };
+                    let message = if cache {
+                        "Response cache is on."
+                    } else {
+                        "Response cache is off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::On | commands::ToggleArg::Off => {
+                    let cache = matches!(arg, commands::ToggleArg::On);
+                    {
+                        self.get_conversation(chat_id).await.cache = cache;
+                    }
+                    db::set_cache(&self.db, chat_id, cache).await;
+                    let message = if cache {
+                        "Response cache on: with the temperature at 0 (/temperature 0), repeating the same question in the same context reuses the earlier answer."
+                    } else {
+                        "Response cache off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /cache [on|off]")
+                        .await?;
+                }
+            },
+            commands::Command::WebSearch(arg) => match arg {
+                commands::ToggleArg::Show => {
+                    let web_search = { self.get_conversation(chat_id).await.web_search };
+                    let message = if web_search {
+                        "Web search is on."
+                    } else {
+                        "Web search is off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::On | commands::ToggleArg::Off => {
+                    let web_search = matches!(arg, commands::ToggleArg::On);
+                    {
+                        self.get_conversation(chat_id).await.web_search = web_search;
+                    }
+                    db::set_web_search(&self.db, chat_id, web_search).await;
+                    let message = if web_search {
+                        "Web search on: the model may look things up, which makes answers slower and adds search costs."
+                    } else {
+                        "Web search off: answers come from the model alone."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /websearch [on|off]")
+                        .await?;
+                }
+            },
+            commands::Command::DeleteCommands(_) => {
+                self.bot
+                    .send_message(
+                        chat_id,
+                        "/delete_commands only applies to groups; send it in the group.",
+                    )
+                    .await?;
+            }
+            commands::Command::Features => {
+                let stream = self.stream_enabled(chat_id, false).await;
+                let conv = self.get_conversation(chat_id).await;
+                let model = self.resolve_model(conv.model_id.as_deref()).await;
+                let on_off = |on: bool| if on { "on" } else { "off" };
+                // Only features the model may lack get a support column.
+                let support = |supported: bool| {
+                    if supported {
+                        ""
+                    } else {
+                        " (not supported by this model)"
+                    }
+                };
+                let lines = [
+                    format!("Model: {}", model.id),
+                    format!("Web search (/websearch): {}", on_off(conv.web_search)),
+                    format!(
+                        "Tools: {}{}",
+                        if conv.tools.is_some() { "set" } else { "none" },
+                        support(model.capabilities.tools)
+                    ),
+                    format!(
+                        "Reasoning display (/showthinking): {}{}",
+                        on_off(conv.show_thinking),
+                        support(model.capabilities.reasoning)
+                    ),
+                    format!(
+                        "Reasoning effort (/reasoning): {}{}",
+                        conv.reasoning_effort
+                            .map_or("model default".to_string(), |effort| effort.to_string()),
+                        support(model.capabilities.reasoning)
+                    ),
+                    format!(
+                        "Images: {}",
+                        if model.capabilities.vision {
+                            "send a photo with a caption"
+                        } else {
+                            "not supported by this model"
                         }
-                        None => {
-                            self.bot.send_message(chat_id, "No API key set.").await?;
+                    ),
+                    format!(
+                        "Reasoning cleanup (/clean_thinking): {}",
+                        on_off(conv.clean_thinking)
+                    ),
+                    format!(
+                        "Streaming (/stream): {}{}",
+                        on_off(stream),
+                        if conv.stream.is_none() {
+                            " by default"
+                        } else {
+                            ""
+                        }
+                    ),
+                    format!("Voice replies (/voice): {}", on_off(conv.voice)),
+                    format!("Response cache (/cache): {}", on_off(conv.cache)),
+                    format!("Ephemeral history (/ephemeral): {}", on_off(conv.ephemeral)),
+                    format!("Reply mode (/reply_mode): {}", conv.reply_mode),
+                    format!(
+                        "Next message without context (/clear_context): {}",
+                        if conv.skip_context_once { "yes" } else { "no" }
+                    ),
+                ];
+                drop(conv);
+                self.bot.send_message(chat_id, lines.join("\n")).await?;
+            }
+            commands::Command::Skip => {
+                let in_onboarding = {
+                    let conv = self.get_conversation(chat_id).await;
+                    conv.onboarding_step
+                        .is_some_and(|step| step != onboarding::OnboardingStep::Done)
+                };
+                if !in_onboarding {
+                    self.bot.send_message(chat_id, "Nothing to skip.").await?;
+                    return Ok(());
+                }
+                self.set_onboarding_step(chat_id, onboarding::OnboardingStep::Done)
+                    .await;
+                self.bot
+                    .send_message(
+                        chat_id,
+                        "Setup skipped. Just send a message to start; /help lists the settings.",
+                    )
+                    .await?;
+            }
+            commands::Command::ClearContext => {
+                {
+                    self.get_conversation(chat_id).await.skip_context_once = true;
+                }
+                self.bot
+                    .send_message(
+                        chat_id,
+                        "Your next message will be sent without the earlier conversation. The history is kept and used again after that.",
+                    )
+                    .await?;
+            }
+            commands::Command::Reset => {
+                let deleted = {
+                    // Cleared under the conversation lock, so no message in between can
+                    // reload or extend the old history.
+                    let mut conv = self.get_conversation(chat_id).await;
+                    conv.history.clear();
+                    conv.pending_tool_calls = None;
+                    conv.skip_context_once = false;
+                    conv.regenerations = 0;
+                    db::clear_history(&self.db, chat_id).await
+                };
+                self.bot
+                    .send_message(
+                        chat_id,
+                        format!(
+                            "Conversation reset: deleted {deleted} stored message(s). Model, key, system prompt and other settings are unchanged."
+                        ),
+                    )
+                    .await?;
+            }
+            commands::Command::Forget(arg) => {
+                let commands::ForgetArg::Turns(turns) = arg else {
+                    self.bot
+                        .send_message(chat_id, "Usage: /forget [n], n a positive number of turns.")
+                        .await?;
+                    return Ok(());
+                };
+                let removed = {
+                    // Under the conversation lock, like /reset, so no message in between
+                    // sees the turns half forgotten.
+                    let mut conv = self.get_conversation(chat_id).await;
+                    let removed = if conv.ephemeral {
+                        conv.forget_last_turns(turns)
+                    } else {
+                        let removed = db::delete_last_n_turns(&self.db, chat_id, turns).await;
+                        if removed.is_some() {
+                            // Memory holds the stored tail, maybe fewer turns than were deleted.
+                            let model = self.resolve_model(conv.model_id.as_deref()).await;
+                            db::load_history(&self.db, &mut conv, model.token_budget()).await;
+                            conv.loaded_model = Some(conversation::LoadedModel::of(&model));
                         }
+                        removed
+                    };
+                    if removed.is_some() {
+                        conv.pending_tool_calls = None;
+                        conv.regenerations = 0;
+                        conv.last_prompt = None;
+                    }
+                    removed
+                };
+                let message = match removed {
+                    Some(removed) => {
+                        format!("Forgot the last {turns} turn(s): deleted {removed} message(s).")
+                    }
+                    None => format!(
+                        "The history has fewer than {turns} turn(s); /reset deletes all of it."
+                    ),
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::History(arg) => {
+                let commands::HistoryArg::Show { turns } = arg else {
+                    self.bot
+                        .send_message(chat_id, "Usage: /history [n], n between 1 and 20.")
+                        .await?;
+                    return Ok(());
+                };
+                let messages = db::load_full_history(&self.db, chat_id).await;
+                if messages.is_empty() {
+                    self.bot
+                        .send_message(chat_id, "No messages are stored yet.")
+                        .await?;
+                    return Ok(());
+                }
+
+                let roles: Vec<MessageRole> = messages.iter().map(|m| m.role).collect();
+                // With fewer turns stored, all of them are shown.
+                let start = conversation::last_turns_start(&roles, turns).unwrap_or(0);
+                let shown: Vec<db::AttributedMessage> = messages[start..]
+                    .iter()
+                    .map(|message| db::AttributedMessage {
+                        text: truncate_chars(&message.text, HISTORY_PREVIEW_CHARS),
+                        ..message.clone()
+                    })
+                    .collect();
+                let listing = export::render_recent(&shown, chrono::Utc::now().timestamp());
+                let message = format!(
+                    "Last {} stored message(s), oldest first (/export has them all):\n\n{listing}",
+                    shown.len()
+                );
+                telegram::bot_split_send(&self.bot, chat_id, &message, None).await?;
+            }
+            commands::Command::Stream(arg) => {
+                let stream = match arg {
+                    commands::StreamArg::Show => {
+                        let choice = { self.get_conversation(chat_id).await.stream };
+                        let message = match choice {
+                            Some(true) => "Streaming: on.".to_string(),
+                            Some(false) => "Streaming: off.".to_string(),
+                            None => format!(
+                                "Streaming: {} (default for private chats).",
+                                if self.config.load().stream_default(false) {
+                                    "on"
+                                } else {
+                                    "off"
+                                }
+                            ),
+                        };
+                        self.bot.send_message(chat_id, message).await?;
+                        return Ok(());
+                    }
+                    commands::StreamArg::Set(stream) => stream,
+                    commands::StreamArg::Invalid => {
+                        self.bot
+                            .send_message(chat_id, "Usage: /stream [on|off|none]")
+                            .await?;
+                        return Ok(());
                     }
+                };
+
+                {
+                    self.get_conversation(chat_id).await.stream = stream;
+                }
+                db::set_stream(&self.db, chat_id, stream).await;
+                let message = match stream {
+                    Some(true) => "Streaming on.",
+                    Some(false) => "Streaming off.",
+                    None => "Streaming reset to the default for this kind of chat.",
+                };
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::Tokens { text } => {
+                let Some(text) = text.as_deref().or(replied_text) else {
+                    self.bot
+                        .send_message(
+                            chat_id,
+                            "Usage: /tokens <text>, or reply to a message with /tokens",
+                        )
+                        .await?;
+                    return Ok(());
+                };
+
+                let model_id = {
+                    let conversation = self.get_conversation(chat_id).await;
+                    conversation
+                        .model_id
+                        .clone()
+                        .unwrap_or_else(|| self.default_model.clone())
+                };
+                let message = format!(
+                    "≈{} tokens (estimated by character class: words, punctuation, CJK and whitespace).\nThe bot has no tokenizer, so {model_id} may count differently: usually within about 20% for English and code, while CJK text may come out up to half lower.",
+                    openrouter_api::estimate_text_tokens(text),
+                );
+                self.bot.send_message(chat_id, message).await?;
+            }
+            commands::Command::ShowThinking(arg) => match arg {
+                commands::ToggleArg::Show => {
+                    let show_thinking = { self.get_conversation(chat_id).await.show_thinking };
+                    let message = if show_thinking {
+                        "Reasoning display is on."
+                    } else {
+                        "Reasoning display is off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
                 }
-                commands::CommandArg::None => {
+                commands::ToggleArg::On | commands::ToggleArg::Off => {
+                    let show_thinking = matches!(arg, commands::ToggleArg::On);
                     {
-                        let mut conv = self.get_conversation(chat_id).await;
-                        conv.openrouter_api_key = None;
+                        self.get_conversation(chat_id).await.show_thinking = show_thinking;
                     }
-                    db::set_openrouter_api_key(&self.db, chat_id, None).await;
-                    self.bot.send_message(chat_id, "API key cleared.").await?;
+                    db::set_show_thinking(&self.db, chat_id, show_thinking).await;
+                    let message = if show_thinking {
+                        "Reasoning display on: when the model returns its reasoning, it is sent before the answer. It is never kept in the history."
+                    } else {
+                        "Reasoning display off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
                 }
-                commands::CommandArg::Text(key) => {
+                commands::ToggleArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /showthinking [on|off]")
+                        .await?;
+                }
+            },
+            commands::Command::CleanThinking(arg) => match arg {
+                commands::ToggleArg::Show => {
+                    let clean_thinking = { self.get_conversation(chat_id).await.clean_thinking };
+                    let message = if clean_thinking {
+                        "Reasoning cleanup is on."
+                    } else {
+                        "Reasoning cleanup is off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::On | commands::ToggleArg::Off => {
+                    let clean_thinking = matches!(arg, commands::ToggleArg::On);
                     {
-                        let mut conv = self.get_conversation(chat_id).await;
-                        conv.openrouter_api_key = Some(key.clone());
+                        self.get_conversation(chat_id).await.clean_thinking = clean_thinking;
                     }
-                    db::set_openrouter_api_key(&self.db, chat_id, Some(&key)).await;
-                    self.bot.send_message(chat_id, "API key updated.").await?;
+                    db::set_clean_thinking(&self.db, chat_id, clean_thinking).await;
+                    let message = if clean_thinking {
+                        "Reasoning cleanup on: text the model writes before a marker such as \"Final answer:\" or </think> is dropped from answers. The raw answer is logged at debug level."
+                    } else {
+                        "Reasoning cleanup off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::Invalid => {
+                    self.bot
+                        .send_message(chat_id, "Usage: /clean_thinking [on|off]")
+                        .await?;
                 }
             },
-            commands::Command::SystemPrompt(arg) => match arg {
-                commands::CommandArg::Empty => {
-                    let current_prompt = {
-                        let conv = self.get_conversation(chat_id).await;
-                        conv.system_prompt.as_ref().map(|p| p.text.clone())
+            commands::Command::Voice(arg) => match arg {
+                commands::ToggleArg::Show => {
+                    let voice = { self.get_conversation(chat_id).await.voice };
+                    let message = match (voice, self.config.load().tts.is_some()) {
+                        (_, false) => "Voice replies are not available on this bot.",
+                        (true, true) => "Voice replies are on.",
+                        (false, true) => "Voice replies are off.",
                     };
-                    match current_prompt {
-                        Some(prompt) => {
-                            self.bot
-                                .send_message(
-                                    chat_id,
-                                    format!(
-                                        "Current system prompt\\: ```\n{}\n```",
-                                        telegram::escape_markdown_v2(&prompt)
-                                    ),
-                                )
-                                .parse_mode(ParseMode::MarkdownV2)
-                                .await?;
-                        }
-                        None => {
-                            self.bot
-                                .send_message(chat_id, "No system prompt set.")
-                                .await?;
-                        }
-                    }
+                    self.bot.send_message(chat_id, message).await?;
                 }
-                commands::CommandArg::None => {
+                commands::ToggleArg::On | commands::ToggleArg::Off => {
+                    let voice = matches!(arg, commands::ToggleArg::On);
+                    if voice && self.config.load().tts.is_none() {
+                        self.bot
+                            .send_message(
+                                chat_id,
+                                "Voice replies are not available: no text-to-speech provider is configured.",
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+
                     {
-                        let mut conv = self.get_conversation(chat_id).await;
-                        conv.system_prompt = None;
+                        self.get_conversation(chat_id).await.voice = voice;
                     }
-                    db::set_system_prompt(&self.db, chat_id, None).await;
+                    db::set_voice(&self.db, chat_id, voice).await;
+                    let message = if voice {
+                        "Voice replies on: answers are also sent as voice messages."
+                    } else {
+                        "Voice replies off."
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                }
+                commands::ToggleArg::Invalid => {
                     self.bot
-                        .send_message(chat_id, "System prompt cleared.")
+                        .send_message(chat_id, "Usage: /voice [on|off]")
                         .await?;
                 }
-                commands::CommandArg::Text(prompt) => {
-                    {
-                        let mut conv = self.get_conversation(chat_id).await;
-                        conv.system_prompt = Some(conversation::Message {
-                            role: MessageRole::System,
-                            text: prompt.clone(),
-                        });
+            },
+            commands::Command::Settings(arg) => match arg {
+                commands::SettingsArg::Export { reveal_key } => {
+                    if reveal_key && !self.check_admin(chat_id, "/settings export reveal").await? {
+                        return Ok(());
                     }
-                    db::set_system_prompt(&self.db, chat_id, Some(&prompt)).await;
+
+                    let exported = {
+                        let conv = self.get_conversation(chat_id).await;
+                        let api_key = conv.api_key().map(|key| {
+                            if reveal_key {
+                                key.to_string()
+                            } else {
+                                mask_api_key(key)
+                            }
+                        });
+                        settings::export(&conv, api_key)
+                    };
+                    let json = serde_json::to_string_pretty(&exported)
+                        .expect("failed to serialize chat settings");
+                    bot_split_send_formatted(
+                        &self.bot,
+                        chat_id,
+                        &format!("```json\n{}\n```", escape_markdown_v2(&json)),
+                        None,
+                        ParseMode::MarkdownV2,
+                    )
+                    .await?;
+                }
+                commands::SettingsArg::Import(json) => {
+                    self.import_settings(chat_id, &json).await?;
+                }
+                commands::SettingsArg::Invalid => {
                     self.bot
-                        .send_message(chat_id, "System prompt updated.")
+                        .send_message(chat_id, "Usage: /settings export [reveal] | import <json>")
                         .await?;
                 }
             },
-            commands::Command::Approve(approve) => {
-                let is_admin = { self.get_conversation(chat_id).await.is_admin };
-                if !is_admin {
+            commands::Command::Log(arg) => {
+                if !self.check_admin(chat_id, "/log").await? {
+                    return Ok(());
+                }
+
+                let commands::LogArg::Show {
+                    chat_id: target_chat_id,
+                    limit,
+                } = arg
+                else {
                     self.bot
-                        .send_message(chat_id, "You are not authorized to use /approve.")
+                        .send_message(chat_id, "Usage: /log <chat_id> [n]")
                         .await?;
                     return Ok(());
+                };
+
+                let entries = db::list_request_log(&self.db, ChatId(target_chat_id), limit).await;
+                if entries.is_empty() {
+                    let message = if self.config.load().request_log {
+                        format!("No requests logged for chat {target_chat_id}.")
+                    } else {
+                        format!(
+                            "No requests logged for chat {target_chat_id}. Request logging is disabled (REQUEST_LOG)."
+                        )
+                    };
+                    self.bot.send_message(chat_id, message).await?;
+                    return Ok(());
                 }
 
-                match approve {
-                    commands::ApproveArg::Empty => {
-                        let pending = db::list_unauthorized_chats(&self.db).await;
-                        if pending.is_empty() {
-                            self.bot.send_message(chat_id, "No pending users.").await?;
-                            return Ok(());
-                        }
+                let mut lines = vec![format!(
+                    "Last {} request(s) for chat {}:",
+                    entries.len(),
+                    target_chat_id
+                )];
+                for entry in entries {
+                    let time = chrono::DateTime::from_timestamp(entry.created_at, 0)
+                        .expect("stored request timestamp out of range")
+                        .format("%Y-%m-%d %H:%M:%S");
+                    let status = match entry.error {
+                        Some(error) => format!("error: {error}"),
+                        None => "ok".to_string(),
+                    };
+                    lines.push(format!(
+                        "{} UTC | {} | {}+{} tokens | ${:.4} | {} ms | {}",
+                        time,
+                        entry.model_id,
+                        entry.prompt_tokens,
+                        entry.completion_tokens,
+                        entry.cost,
+                        entry.latency_ms,
+                        status
+                    ));
+                }
+                telegram::bot_split_send(&self.bot, chat_id, &lines.join("\n"), None).await?;
+            }
+        }
+        Ok(())
+    }
 
-                        let mut lines = Vec::with_capacity(pending.len());
-                        for (pending_id, name) in pending {
-                            let display_name = name.unwrap_or_else(|| "unknown".to_string());
-                            let display_name = escape_markdown_v2(&display_name);
-                            lines.push(format!("`{}` \\- {}", pending_id, display_name));
-                        }
+    /// Replace the last assistant answer with a fresh one for the same prompt. An optional
+    /// tweak is appended to the prompt for this request only and never persisted.
+    async fn regenerate_last_answer(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        tweak: Option<String>,
+    ) -> anyhow::Result<()> {
+        let (last_turn, stored_turn) = {
+            let mut conversation = self.get_conversation(chat_id).await;
+            let last_turn = conversation.pop_last_turn();
+            let mut stored_turn = None;
+            if last_turn.is_some() {
+                conversation.regenerations += 1;
+                stored_turn = self.stored_last_turn(&conversation).await;
+            }
+            (last_turn, stored_turn)
+        };
+        let Some((user_message, old_answer)) = last_turn else {
+            self.bot
+                .send_message(
+                    chat_id,
+                    "Nothing to regenerate: there is no previous answer.",
+                )
+                .await?;
+            return Ok(());
+        };
 
-                        let message = format!("Pending users\\:\n{}", lines.join("\n"));
-                        bot_split_send_formatted(
-                            &self.bot,
-                            chat_id,
-                            &message,
-                            None,
-                            ParseMode::MarkdownV2,
-                        )
-                        .await?;
-                    }
-                    commands::ApproveArg::ApproveChat {
-                        chat_id: target_chat_id,
-                        is_authorized,
-                    } => {
-                        let target_id = ChatId(target_chat_id);
-                        let result =
-                            db::set_is_authorized(&self.db, target_id, is_authorized).await;
-                        if result.is_err() {
-                            self.bot
-                                .send_message(chat_id, "Failed to authorize chat")
-                                .await?;
-                        } else {
-                            {
-                                let mut conv_map = self.conversations.lock().await;
-                                if let Some(conv) = conv_map.get_mut(&target_id) {
-                                    conv.is_authorized = is_authorized;
-                                }
-                            }
+        let request_message = match tweak.as_deref() {
+            Some(tweak) => conversation::Message {
+                role: MessageRole::User,
+                text: format!(
+                    "{}\n\n(Answer again, this time following this instruction: {})",
+                    user_message.text, tweak
+                ),
+            },
+            None => user_message.clone(),
+        };
+
+        let mut ready = match self.prepare_llm_request(chat_id, &request_message).await {
+            Ok(ready) => ready,
+            Err(err) => {
+                self.get_conversation(chat_id)
+                    .await
+                    .add_messages([user_message, old_answer]);
+                self.bot.send_message(chat_id, err.user_message()).await?;
+                return Ok(());
+            }
+        };
+        // A cached copy would just repeat the answer being replaced.
+        ready.use_cache = false;
+
+        let llm_call = self.call_llm_for_reply(chat_id, msg_id, false, ready).await;
+        if llm_call.stopped && llm_call.response.is_err() {
+            // Nothing arrived before /stop; the previous answer stays.
+            self.get_conversation(chat_id)
+                .await
+                .add_messages([user_message, old_answer]);
+            self.log_request(chat_id, &llm_call).await;
+            return self
+                .send_stopped_notice(chat_id, msg_id, false, llm_call.streamed)
+                .await;
+        }
+        if llm_call.response.is_ok() {
+            if let Some(ids) = stored_turn {
+                self.delete_replaced_turn(chat_id, ids).await;
+            }
+        } else {
+            // Keep the previous answer when the new request fails.
+            self.get_conversation(chat_id)
+                .await
+                .add_messages([user_message.clone(), old_answer]);
+        }
+
+        self.handle_llm_response(chat_id, msg_id, false, user_message, llm_call)
+            .await
+    }
+
+    /// Row ids of the stored turn an edit or `/regenerate` is about to replace. They are taken
+    /// before the new request, since archival may move rows while it runs. `None` for
+    /// ephemeral chats, whose turns were never stored.
+    async fn stored_last_turn(&self, conversation: &Conversation) -> Option<Vec<i64>> {
+        if conversation.ephemeral {
+            return None;
+        }
+        let chat_id = ChatId(conversation.chat_id);
+        let ids = db::last_turn_ids(&self.db, chat_id).await;
+        if ids.is_none() {
+            log::warn!(
+                "stored history of chat {} doesn't end with the replaced turn; it stays stored",
+                chat_id
+            );
+        }
+        ids
+    }
+
+    /// Delete the stored rows of a replaced turn, as captured by `stored_last_turn`.
+    async fn delete_replaced_turn(&self, chat_id: ChatId, ids: Vec<i64>) {
+        let expected = ids.len();
+        let deleted = db::delete_history_rows(&self.db, chat_id, ids).await;
+        if deleted != expected {
+            log::warn!(
+                "only {} of {} rows of the replaced turn in chat {} were still stored",
+                deleted,
+                expected,
+                chat_id
+            );
+        }
+    }
+
+    /// Validate and apply a `/settings import`; nothing is changed unless every field is valid.
+    async fn import_settings(&self, chat_id: ChatId, json: &str) -> anyhow::Result<()> {
+        let patch = match settings::parse_import(json) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.bot
+                    .send_message(chat_id, format!("Settings not imported: {err}."))
+                    .await?;
+                return Ok(());
+            }
+        };
+        if patch.is_empty() {
+            self.bot
+                .send_message(chat_id, "Settings not imported: no fields given.")
+                .await?;
+            return Ok(());
+        }
+        if let Some(Some(model_id)) = &patch.model_id {
+            let exists = self.models.load().iter().any(|m| &m.id == model_id);
+            if !exists {
+                self.bot
+                    .send_message(
+                        chat_id,
+                        format!("Settings not imported: model not found: {model_id}."),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let mut updated = Vec::new();
+        {
+            let mut conv = self.get_conversation(chat_id).await;
+            let old_model = self.resolve_model(conv.model_id.as_deref()).await;
+            let was_ephemeral = conv.ephemeral;
+
+            if let Some(model_id) = patch.model_id {
+                db::set_model_id(&self.db, chat_id, model_id.as_deref()).await;
+                conv.model_id = model_id;
+                updated.push("model_id");
+            }
+            if let Some(prompt) = patch.system_prompt {
+                db::set_system_prompt(&self.db, chat_id, prompt.as_deref()).await;
+                conv.system_prompt = prompt.map(|text| conversation::Message {
+                    role: MessageRole::System,
+                    text,
+                });
+                updated.push("system_prompt");
+            }
+            if let Some(key) = patch.openrouter_api_key {
+                db::set_provider_key(&self.db, chat_id, openrouter_api::PROVIDER, key.as_deref())
+                    .await;
+                conv.set_api_key(key);
+                updated.push("openrouter_api_key");
+            }
+            if let Some(tools) = patch.tools {
+                db::set_tools(&self.db, chat_id, tools.as_ref()).await;
+                conv.tools = tools;
+                updated.push("tools");
+            }
+            if let Some(title) = patch.title {
+                db::set_title(&self.db, chat_id, title.as_deref()).await;
+                conv.title = title;
+                updated.push("title");
+            }
+            if let Some(ephemeral) = patch.ephemeral {
+                db::set_ephemeral(&self.db, chat_id, ephemeral).await;
+                conv.ephemeral = ephemeral;
+                updated.push("ephemeral");
+            }
+            if let Some(voice) = patch.voice {
+                db::set_voice(&self.db, chat_id, voice).await;
+                conv.voice = voice;
+                updated.push("voice");
+            }
+            if let Some(web_search) = patch.web_search {
+                db::set_web_search(&self.db, chat_id, web_search).await;
+                conv.web_search = web_search;
+                updated.push("web_search");
+            }
+            if let Some(cache) = patch.cache {
+                db::set_cache(&self.db, chat_id, cache).await;
+                conv.cache = cache;
+                updated.push("cache");
+            }
+            if let Some(show_thinking) = patch.show_thinking {
+                db::set_show_thinking(&self.db, chat_id, show_thinking).await;
+                conv.show_thinking = show_thinking;
+                updated.push("show_thinking");
+            }
+            if let Some(clean_thinking) = patch.clean_thinking {
+                db::set_clean_thinking(&self.db, chat_id, clean_thinking).await;
+                conv.clean_thinking = clean_thinking;
+                updated.push("clean_thinking");
+            }
+            if let Some(stream) = patch.stream {
+                db::set_stream(&self.db, chat_id, stream).await;
+                conv.stream = stream;
+                updated.push("stream");
+            }
+            if let Some(reply_mode) = patch.reply_mode {
+                db::set_reply_mode(&self.db, chat_id, reply_mode).await;
+                conv.reply_mode = reply_mode;
+                updated.push("reply_mode");
+            }
+            if let Some(utc_offset) = patch.utc_offset {
+                db::set_utc_offset(&self.db, chat_id, utc_offset).await;
+                conv.utc_offset = utc_offset;
+                updated.push("utc_offset");
+            }
+
+            // Same rules as /model and /ephemeral off: reload when the budget grows or when
+            // leaving ephemeral mode.
+            let new_model = self.resolve_model(conv.model_id.as_deref()).await;
+            let model_grew = old_model.id != new_model.id
+                && new_model.context_length >= old_model.context_length;
+            let left_ephemeral = was_ephemeral && !conv.ephemeral;
+            if model_grew || left_ephemeral {
+                db::load_history(&self.db, &mut conv, new_model.token_budget()).await;
+            }
+            conv.loaded_model = Some(conversation::LoadedModel::of(&new_model));
+        }
+
+        log::info!("chat {} imported settings: {}", chat_id, updated.join(", "));
+        self.bot
+            .send_message(
+                chat_id,
+                format!("Settings imported: {}.", updated.join(", ")),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Reply in the group with a deep link that opens a private chat carrying the group's
+    /// recent context.
+    async fn offer_private_continuation(&self, msg: &Message) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        let Some(user) = msg.from.as_ref().filter(|user| !user.is_anonymous()) else {
+            self.bot
+                .send_message(chat_id, "/dm needs a regular (non-anonymous) account.")
+                .await?;
+            return Ok(());
+        };
+
+        // Per-process random keys make the token unguessable; it is also bound to the user.
+        let token = format!(
+            "{:016x}",
+            std::hash::BuildHasher::hash_one(
+                &std::hash::RandomState::new(),
+                (chat_id, user.id, Instant::now())
+            )
+        );
+        {
+            let mut handoffs = self.dm_handoffs.lock().await;
+            handoffs.retain(|_, handoff| handoff.created.elapsed() < DM_HANDOFF_TTL);
+            handoffs.insert(
+                token.clone(),
+                DmHandoff {
+                    user_id: user.id,
+                    group_chat_id: chat_id,
+                    created: Instant::now(),
+                },
+            );
+        }
+
+        let link = format!("https://t.me/{}?start={}", self.bot_username, token);
+        self.bot
+            .send_message(
+                chat_id,
+                format!(
+                    "Continue privately: {link}\nThe link works for you only and expires in {} minutes.",
+                    DM_HANDOFF_TTL.as_secs() / 60
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        Ok(())
+    }
+
+    /// `/tldr`: summarize the group's last stored messages, oldest dropped first when they
+    /// don't fit the model's context, and reply to the command.
+    async fn summarize_group(
+        &self,
+        chat_id: ChatId,
+        msg_id: MessageId,
+        arg: commands::TldrArg,
+    ) -> anyhow::Result<()> {
+        let commands::TldrArg::Summarize { messages: count } = arg else {
+            self.bot
+                .send_message(chat_id, "Usage: /tldr [n], n between 1 and 300.")
+                .reply_parameters(ReplyParameters::new(msg_id))
+                .await?;
+            return Ok(());
+        };
+
+        let stored = db::recent_messages(&self.db, chat_id, count).await;
+        if stored.is_empty() {
+            self.bot
+                .send_message(chat_id, "There are no stored messages to summarize yet.")
+                .reply_parameters(ReplyParameters::new(msg_id))
+                .await?;
+            return Ok(());
+        }
+
+        if let Err(wait_time) = self.check_group_llm_rate_limit(chat_id).await {
+            let wait_minutes = wait_time.as_secs().div_ceil(60);
+            self.bot
+                .send_message(
+                    chat_id,
+                    format!(
+                        "Rate limit reached: max {} LLM requests per hour for group chats. Try again in about {wait_minutes} minute(s).",
+                        self.config.load().group_llm_limit
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        let ready = {
+            let conversation = self.get_conversation(chat_id).await;
+            let model = self.resolve_model(conversation.model_id.as_deref()).await;
+            let options = openrouter_api::PayloadOptions::default();
+            let budget = model.input_budget(&[TLDR_PROMPT], &options);
+
+            let mut lines: VecDeque<String> = stored
+                .iter()
+                .map(|message| {
+                    let speaker = match (message.role, message.sender_name.as_deref()) {
+                        (MessageRole::Assistant, _) => "Bot",
+                        (MessageRole::Tool, _) => "Tool",
+                        (_, Some(name)) => name,
+                        (_, None) => "Someone",
+                    };
+                    format!("{speaker}: {}", message.text)
+                })
+                .collect();
+            let mut tokens: u64 = lines
+                .iter()
+                .map(|line| openrouter_api::estimate_text_tokens(line))
+                .sum();
+            while tokens > budget && lines.len() > 1 {
+                let dropped = lines.pop_front().expect("more than one line");
+                tokens -= openrouter_api::estimate_text_tokens(&dropped);
+            }
+            let transcript = lines.into_iter().collect::<Vec<_>>().join("\n\n");
+            let transcript = openrouter_api::truncate_to_tokens(&transcript, budget).to_string();
+
+            let messages = [
+                conversation::Message {
+                    role: MessageRole::System,
+                    text: TLDR_PROMPT.to_string(),
+                },
+                conversation::Message {
+                    role: MessageRole::User,
+                    text: transcript,
+                },
+            ];
+            let api_key = self.api_key_for(chat_id, &conversation).await;
+            api_key.map(|(openrouter_api_key, quota_day)| LlmRequestReady {
+                payload: openrouter_api::prepare_payload(
+                    &model.id,
+                    messages.iter(),
+                    false,
+                    &options,
+                ),
+                openrouter_api_key,
+                quota_day,
+                model_id: model.id,
+                use_cache: false,
+                truncated_input: None,
+                ramped_temperature: None,
+                fallback: None,
+            })
+        };
+        let ready = match ready {
+            Ok(ready) => ready,
+            Err(err) => {
+                self.bot
+                    .send_message(chat_id, err.user_message())
+                    .reply_parameters(ReplyParameters::new(msg_id))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let llm_call = self.call_llm(chat_id, ready).await;
+        self.log_request(chat_id, &llm_call).await;
+        let summary = match llm_call.response {
+            Ok(response) => telegram::markdown_to_telegram(&response.completion_text),
+            Err(err) => {
+                log::error!("failed to summarize group {}: {err}", chat_id);
+                escape_markdown_v2(
+                    "Couldn't summarize the discussion right now, please try again later.",
+                )
+            }
+        };
+        bot_split_send_formatted(
+            &self.bot,
+            chat_id,
+            &summary,
+            Some(msg_id),
+            ParseMode::MarkdownV2,
+        )
+        .await?;
+        Ok(())
+    }
 
-                            let message =
-                                format!("Chat {} approved: {}", target_chat_id, is_authorized);
-                            self.bot.send_message(chat_id, message).await?;
-                        }
-                    }
-                    commands::ApproveArg::Invalid => {
-                        self.bot
-                            .send_message(chat_id, "Usage: /approve <chat_id> <true|false>")
-                            .await?;
-                    }
-                }
+    /// Redeem a `/dm` token: copy the group's recent messages into this private chat.
+    async fn continue_from_group(&self, chat_id: ChatId, token: &str) -> anyhow::Result<()> {
+        let handoff = {
+            let mut handoffs = self.dm_handoffs.lock().await;
+            handoffs.retain(|_, handoff| handoff.created.elapsed() < DM_HANDOFF_TTL);
+            match handoffs.get(token) {
+                // In a private chat the chat id is the user id.
+                Some(handoff) if handoff.user_id.0 as i64 == chat_id.0 => handoffs.remove(token),
+                _ => None,
             }
+        };
+        let Some(handoff) = handoff else {
+            self.bot
+                .send_message(
+                    chat_id,
+                    "This link is invalid or has expired. Run /dm in the group again.",
+                )
+                .await?;
+            return Ok(());
+        };
+
+        let context = {
+            let group = self.get_conversation(handoff.group_chat_id).await;
+            let skip = group.history.len().saturating_sub(DM_HANDOFF_MESSAGES);
+            group.history.iter().skip(skip).cloned().collect::<Vec<_>>()
+        };
+        if context.is_empty() {
+            self.bot
+                .send_message(
+                    chat_id,
+                    "There was no group context to bring over; just start writing.",
+                )
+                .await?;
+            return Ok(());
         }
+
+        self.persist_messages(chat_id, &context).await;
+        log::info!(
+            "copied {} message(s) from group {} into private chat {}",
+            context.len(),
+            handoff.group_chat_id,
+            chat_id
+        );
+        self.bot
+            .send_message(
+                chat_id,
+                format!(
+                    "Brought over the last {} message(s) from the group. Continue here.",
+                    context.len()
+                ),
+            )
+            .await?;
         Ok(())
     }
 
-    async fn extract_user_message(&self, msg: &Message) -> anyhow::Result<conversation::Message> {
-        let mut user_text = msg
-            .text()
-            .expect("Only text messages are supported.")
-            .to_owned();
-
-        if !user_text.starts_with('/') {
-            let replied_text = msg
-                .reply_to_message()
-                .and_then(|reply| reply.text())
-                .map(|text| text.trim())
-                .filter(|text| !text.is_empty());
-
-            if let Some(replied_text) = replied_text {
-                let replied_quoted = replied_text
-                    .lines()
-                    .map(|line| format!("> {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-
-                let selection = msg
-                    .quote()
-                    .map(|quote| quote.text.as_str())
-                    .map(|text| text.trim())
-                    .filter(|text| !text.is_empty())
-                    .map(|text| {
-                        text.lines()
-                            .map(|line| format!("> {}", line))
-                            .collect::<Vec<_>>()
-                            .join("\n")
-                    });
-
-                let quoted = match selection {
-                    Some(selection) => format!("{}\n\n\n{}", replied_quoted, selection),
-                    None => replied_quoted,
-                };
+    /// Rebuild the config from the environment and the stored `/config` overrides.
+    async fn reload_config(&self) {
+        let overrides = config::checked_overrides(db::load_config_overrides(&self.db).await);
+        self.config.store(config::Config::from_env_with(&overrides));
+    }
 
-                user_text = format!("{}\n\n{}", quoted, user_text);
+    /// `/whoami`: everything needed to self-diagnose (or ask an admin for access) in one place.
+    async fn send_whoami(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        let conv = self.get_conversation(chat_id).await;
+        let model = self.resolve_model(conv.model_id.as_deref()).await;
+        let key_status = match conv.api_key() {
+            Some(key) => format!("own key ({})", mask_api_key(key)),
+            None if self.config.load().fallback_openrouter_key.is_some() => {
+                "shared operator key".to_string()
             }
+            None => "not set".to_string(),
+        };
+        let system_prompt = match &conv.system_prompt {
+            Some(prompt) => format!(
+                "\"{}\" ({} characters)",
+                truncate_chars(prompt.text.lines().next().unwrap_or(""), 60),
+                prompt.text.chars().count()
+            ),
+            None => "none".to_string(),
+        };
+        let yes_no = |yes: bool| if yes { "yes" } else { "no" };
+        let lines = [
+            format!("Chat id: {}", chat_id),
+            format!("Authorized: {}", yes_no(conv.is_authorized)),
+            format!("Admin: {}", yes_no(conv.is_admin)),
+            format!(
+                "Model: {}{}",
+                model.id,
+                if conv.model_id.is_none() {
+                    " (default)"
+                } else {
+                    ""
+                }
+            ),
+            format!("API key: {}", key_status),
+            format!("System prompt: {}", system_prompt),
+            format!(
+                "History: {} message(s), ≈{} tokens",
+                conv.history.len(),
+                openrouter_api::estimate_tokens(conv.history.iter().map(|m| m.text.as_str()))
+            ),
+            format!(
+                "History persistence: {}",
+                if conv.ephemeral {
+                    "off (ephemeral)"
+                } else {
+                    "on"
+                }
+            ),
+        ];
+        drop(conv);
+        self.bot.send_message(chat_id, lines.join("\n")).await?;
+        Ok(())
+    }
+
+    /// `/config`: the runtime settings with their effective values, then the ones fixed at
+    /// startup.
+    async fn send_runtime_config(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        let config = self.config.load();
+        let overridden: HashSet<String> = db::load_config_overrides(&self.db)
+            .await
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        let mut lines = vec![
+            "Runtime settings (/config set <name> <value>, /config reset <name>):".to_string(),
+        ];
+        for setting in &config::RUNTIME_SETTINGS {
+            lines.push(format!(
+                "{} = {}{}",
+                setting.name,
+                (setting.current)(&config),
+                if overridden.contains(setting.name) {
+                    " (override)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        lines.push(String::new());
+        lines.push("Fixed until restart:".to_string());
+        lines.push(format!("DEFAULT_MODEL = {}", self.default_model));
+        lines.push(format!(
+            "TELEGRAM_SEND_RETRIES = {}",
+            config.telegram_send_retries
+        ));
+        lines.push(format!(
+            "TELEGRAM_SEND_RETRY_DELAY_MS = {}",
+            config.telegram_send_retry_delay.as_millis()
+        ));
+        lines.push(format!(
+            "MODEL_REFRESH_INTERVAL_SECS = {}",
+            config.model_refresh_interval.as_secs()
+        ));
+        lines.push(format!(
+            "RESPONSE_CACHE_SIZE = {}",
+            config.response_cache_size
+        ));
+        lines.push(format!(
+            "RESPONSE_CACHE_TTL_SECS = {}",
+            config.response_cache_ttl.as_secs()
+        ));
+        lines.push(format!(
+            "ERROR_REACTION_EMOJI = {}",
+            config.error_reaction_emoji.as_deref().unwrap_or("(none)")
+        ));
+        lines.push(format!("STOPPED_MESSAGE = {:?}", config.stopped_message));
+        lines.push(format!(
+            "HISTORY_SUMMARIZE_TURNS = {}",
+            config.history_summarize_turns.map_or_else(
+                || "0 (drop old turns)".to_string(),
+                |turns| turns.to_string()
+            )
+        ));
+        lines.push(format!(
+            "HISTORY_MAX_AGE_DAYS = {}",
+            config.history_max_age.map_or_else(
+                || "0 (keep forever)".to_string(),
+                |age| { (age.as_secs() / (24 * 60 * 60)).to_string() }
+            )
+        ));
+
+        telegram::bot_split_send(&self.bot, chat_id, &lines.join("\n"), None).await?;
+        Ok(())
+    }
+
+    /// Tell non-admin chats they cannot use `command`; returns whether the chat is an admin.
+    async fn check_admin(&self, chat_id: ChatId, command: &str) -> anyhow::Result<bool> {
+        let is_admin = { self.get_conversation(chat_id).await.is_admin };
+        if !is_admin {
+            self.bot
+                .send_message(chat_id, format!("You are not authorized to use {command}."))
+                .await?;
         }
 
+        Ok(is_admin)
+    }
+
+    async fn extract_user_message(&self, msg: &Message) -> anyhow::Result<conversation::Message> {
         Ok(conversation::Message {
             role: MessageRole::User,
-            text: user_text,
+            text: telegram::prompt_with_reply_context(msg, self.bot_user_id),
         })
     }
 
+    /// The system messages sent before the history, in order, each with a label for
+    /// `/effective_prompt`.
+    fn system_messages(
+        &self,
+        conversation: &Conversation,
+        model_id: &str,
+    ) -> Vec<(String, conversation::Message)> {
+        let mut messages = vec![("base prompt".to_string(), self.system_prompt0.clone())];
+
+        // A chat's own prompt replaces the operator's per-model default.
+        if let Some(prompt) = &conversation.system_prompt {
+            messages.push(("chat system prompt".to_string(), prompt.clone()));
+        } else if let Some(text) = self.model_prompts.for_model(model_id) {
+            messages.push((
+                format!("default prompt for {model_id}"),
+                conversation::Message {
+                    role: MessageRole::System,
+                    text: text.to_string(),
+                },
+            ));
+        }
+
+        if let Some(sections) = conversation::assemble_sections(&conversation.prompt_sections) {
+            let names: Vec<&str> = conversation
+                .prompt_sections
+                .iter()
+                .map(|section| section.name.as_str())
+                .collect();
+            messages.push((format!("sections: {}", names.join(", ")), sections));
+        }
+
+        messages
+    }
+
     async fn prepare_llm_request(
         &self,
         chat_id: ChatId,
         user_message: &conversation::Message,
+    ) -> LlmRequestResult {
+        self.build_llm_request(chat_id, user_message, None).await
+    }
+
+    /// Prepare the request that asks `model_id` instead of a failed `failed` request.
+    async fn prepare_fallback_request(
+        &self,
+        chat_id: ChatId,
+        failed: &LlmRequestReady,
+        model_id: &str,
+    ) -> LlmRequestResult {
+        let fallback = failed
+            .fallback
+            .as_ref()
+            .expect("only requests with a fallback are retried");
+        if fallback.image_url.is_some()
+            && !self.resolve_model(Some(model_id)).await.capabilities.vision
+        {
+            return Err(LlmRequestError::ImagesUnsupported {
+                model_id: model_id.to_string(),
+            });
+        }
+        let mut ready = self
+            .build_llm_request(
+                chat_id,
+                &fallback.user_message,
+                Some((fallback, model_id, &failed.openrouter_api_key)),
+            )
+            .await?;
+        ready.use_cache = failed.use_cache;
+        ready.quota_day = failed.quota_day;
+        ready.append_tool_results(&fallback.tool_results);
+        if let Some(image_url) = fallback.image_url.clone() {
+            ready.attach_image(image_url);
+        }
+        Ok(ready)
+    }
+
+    /// Build the payload for the chat's model, or for a fallback model answering the same
+    /// turn with the key the original request already got.
+    async fn build_llm_request(
+        &self,
+        chat_id: ChatId,
+        user_message: &conversation::Message,
+        retry: Option<(&Fallback, &str, &str)>,
     ) -> LlmRequestResult {
         let mut conversation = self.get_conversation(chat_id).await;
-        let model = self.resolve_model(conversation.model_id.as_deref()).await;
+        let model_id = match retry {
+            Some((_, model_id, _)) => Some(model_id),
+            None => conversation.model_id.as_deref(),
+        };
+        let model = self.resolve_model(model_id).await;
+        if retry.is_none() {
+            self.follow_model_drift(chat_id, &mut conversation, &model)
+                .await;
+        }
 
-        let reserved_tokens = openrouter_api::estimate_tokens([
-            self.system_prompt0.text.as_str(),
-            conversation
-                .system_prompt
-                .as_ref()
-                .map(|s| s.text.as_str())
-                .unwrap_or(""),
-            user_message.text.as_str(),
-        ]);
-
-        conversation.prune_to_token_budget(model.token_budget().saturating_sub(reserved_tokens));
-
-        let mut history = Vec::new();
-        history.push(self.system_prompt0.clone());
-        if let Some(system_prompt) = conversation.system_prompt.as_ref() {
-            history.push(system_prompt.clone());
-        }
-        history.extend(conversation.history.iter().cloned());
-        history.push(user_message.clone());
-
-        let Some(openai_api_key) = conversation.openrouter_api_key.clone() else {
-            log::warn!("No API key provided for chat id {}", chat_id);
-            return Err(LlmRequestError::NoApiKeyProvided);
+        let system_messages: Vec<conversation::Message> = self
+            .system_messages(&conversation, &model.id)
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect();
+        let system_texts: Vec<&str> = system_messages
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect();
+
+        let ramped = conversation.sampling.ramped(conversation.regenerations);
+        let options = openrouter_api::PayloadOptions {
+            tools: conversation.tools.clone(),
+            web_search: conversation.web_search,
+            sampling: ramped.unwrap_or(conversation.sampling),
+            // A fallback model may not reason at all.
+            reasoning_effort: conversation
+                .reasoning_effort
+                .filter(|_| model.capabilities.reasoning),
+        };
+
+        // Pruning history can't help a message that doesn't fit on its own.
+        let input_budget = model.input_budget(&system_texts, &options);
+        let input_tokens = openrouter_api::estimate_text_tokens(&user_message.text);
+        let original_message = user_message.clone();
+        let mut user_message = user_message.clone();
+        let mut truncated_input = None;
+        if input_tokens > input_budget {
+            match self.config.load().oversized_input {
+                config::OversizedInput::Reject => {
+                    log::info!(
+                        "rejecting oversized input for chat {} ({} tokens, max {})",
+                        chat_id,
+                        input_tokens,
+                        input_budget
+                    );
+                    return Err(LlmRequestError::InputTooLarge {
+                        model_id: model.id,
+                        tokens: input_tokens,
+                        max_tokens: input_budget,
+                    });
+                }
+                config::OversizedInput::Truncate => {
+                    user_message.text =
+                        openrouter_api::truncate_to_tokens(&user_message.text, input_budget)
+                            .to_string();
+                    log::info!(
+                        "truncated oversized input for chat {} ({} tokens, max {})",
+                        chat_id,
+                        input_tokens,
+                        input_budget
+                    );
+                    truncated_input = Some((input_tokens, input_budget));
+                }
+            }
+        }
+
+        let reserved_texts: Vec<&str> = system_texts
+            .iter()
+            .copied()
+            .chain([user_message.text.as_str()])
+            .collect();
+        let history_budget = model.history_budget(&reserved_texts, &options);
+
+        // With `/clear_context` pending the history isn't sent, so there's nothing to prune.
+        let skip_context = retry.map_or(conversation.skip_context_once, |(fallback, _, _)| {
+            fallback.skip_context
+        });
+        if !skip_context {
+            // A fallback answers the same turn, whose history was already summarized.
+            if retry.is_none() {
+                conversation = self
+                    .summarize_overflow(chat_id, conversation, &model, history_budget)
+                    .await;
+            }
+            conversation.prune_to_token_budget(history_budget);
+        }
+
+        let mut history = system_messages.clone();
+        if skip_context {
+            log::info!("sending request without history for chat {}", chat_id);
+        } else {
+            history.extend(conversation.history.iter().cloned());
+        }
+        history.push(user_message);
+
+        // A fallback answers the same turn; the original request's quota day comes with it.
+        let (openai_api_key, quota_day) = match retry {
+            Some((_, _, api_key)) => (api_key.to_string(), None),
+            None => self.api_key_for(chat_id, &conversation).await?,
         };
+
+        let use_cache = conversation.cache;
+        // Only a request that actually goes out uses up `/clear_context`.
+        conversation.skip_context_once = false;
         drop(conversation);
 
-        let payload = openrouter_api::prepare_payload(&model.id, history.iter(), false);
+        let payload = openrouter_api::prepare_payload(&model.id, history.iter(), false, &options);
 
         Ok(LlmRequestReady {
             payload,
             openrouter_api_key: openai_api_key,
+            quota_day,
+            model_id: model.id,
+            use_cache,
+            truncated_input,
+            ramped_temperature: ramped.and_then(|sampling| sampling.temperature),
+            fallback: Some(Fallback {
+                user_message: original_message,
+                skip_context,
+                tool_results: Vec::new(),
+                image_url: None,
+            }),
         })
     }
 
+    /// With `HISTORY_SUMMARIZE_TURNS` set, replace the oldest turns of a history that outgrew
+    /// `history_budget` with a summary the model writes, in memory and in `history`, instead
+    /// of letting pruning drop them. The conversation is unlocked during the request.
+    async fn summarize_overflow(
+        &self,
+        chat_id: ChatId,
+        conversation: OwnedMutexGuard<Conversation>,
+        model: &openrouter_api::ModelSummary,
+        history_budget: u64,
+    ) -> OwnedMutexGuard<Conversation> {
+        let Some(turns) = self.config.load().history_summarize_turns else {
+            return conversation;
+        };
+        // Like titles, summaries are only written with the chat's own key.
+        let Some(api_key) = conversation.api_key().map(str::to_string) else {
+            return conversation;
+        };
+        let count = conversation.summarizable_prefix(turns);
+        if count == 0 || !conversation.exceeds_token_budget(history_budget) {
+            return conversation;
+        }
+        let replaced: Vec<conversation::Message> =
+            conversation.history.iter().take(count).cloned().collect();
+        let newer = conversation.history.len() - count;
+        drop(conversation);
+
+        // The summary must leave room for the turns it was written to make room for.
+        let summary = self
+            .write_summary(chat_id, model, &api_key, &replaced, history_budget / 2)
+            .await;
+        let mut conversation = self.get_conversation(chat_id).await;
+        let Some(summary) = summary else {
+            return conversation;
+        };
+        if !conversation.history.iter().take(count).eq(replaced.iter()) {
+            log::warn!(
+                "history of chat {} changed while it was summarized; summary discarded",
+                chat_id
+            );
+            return conversation;
+        }
+
+        let summary = conversation.replace_with_summary(count, &summary);
+        // Ephemeral sessions aren't stored, so the summary isn't either.
+        if !conversation.ephemeral
+            && !db::replace_with_summary(&self.db, chat_id, replaced, newer, summary).await
+        {
+            log::warn!(
+                "stored history of chat {} doesn't match the summarized turns; summary kept in memory only",
+                chat_id
+            );
+        }
+        log::info!(
+            "summarized {} history message(s) of chat {}",
+            count,
+            chat_id
+        );
+        conversation
+    }
+
+    /// Ask `model` for a summary of `messages` of at most `max_tokens`; failures are logged
+    /// and leave the history to pruning.
+    async fn write_summary(
+        &self,
+        chat_id: ChatId,
+        model: &openrouter_api::ModelSummary,
+        api_key: &str,
+        messages: &[conversation::Message],
+        max_tokens: u64,
+    ) -> Option<String> {
+        let options = openrouter_api::PayloadOptions::default();
+        let transcript = messages
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.text))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let transcript = openrouter_api::truncate_to_tokens(
+            &transcript,
+            model.input_budget(&[SUMMARY_PROMPT], &options),
+        );
+        let request = [
+            conversation::Message {
+                role: MessageRole::System,
+                text: SUMMARY_PROMPT.to_string(),
+            },
+            conversation::Message {
+                role: MessageRole::User,
+                text: transcript.to_string(),
+            },
+        ];
+        let payload = openrouter_api::prepare_payload(&model.id, request.iter(), false, &options);
+
+        let response = match self
+            .send_background_request(chat_id, &model.id, api_key, payload)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("failed to summarize history of chat {}: {err}", chat_id);
+                return None;
+            }
+        };
+
+        let summary =
+            openrouter_api::truncate_to_tokens(response.completion_text.trim(), max_tokens);
+        if summary.is_empty() {
+            log::warn!(
+                "model returned an empty history summary for chat {}",
+                chat_id
+            );
+            return None;
+        }
+        Some(summary.to_string())
+    }
+
+    /// The chat's own key, else the operator's fallback key as long as the chat's daily
+    /// quota on it lasts, with the chat's local day the request counts against once answered
+    /// (see [`Self::count_fallback_request`]).
+    async fn api_key_for(
+        &self,
+        chat_id: ChatId,
+        conversation: &Conversation,
+    ) -> Result<(String, Option<chrono::NaiveDate>), LlmRequestError> {
+        if let Some(key) = conversation.api_key() {
+            return Ok((key.to_string(), None));
+        }
+
+        let Some(key) = self.config.load().fallback_openrouter_key.clone() else {
+            log::info!("no API key set for chat {}", chat_id);
+            return Err(LlmRequestError::NoApiKeyProvided);
+        };
+        let Some(limit) = self.config.load().fallback_key_daily_limit else {
+            log::info!("using fallback API key for chat {}", chat_id);
+            return Ok((key, None));
+        };
+        let today = timezone::local_day(chrono::Utc::now(), conversation.utc_offset);
+        if self.fallback_requests_on(chat_id, today).await >= limit {
+            return Err(LlmRequestError::FallbackKeyLimitReached { limit });
+        }
+        log::info!("using fallback API key for chat {}", chat_id);
+        Ok((key, Some(today)))
+    }
+
+    /// Requests the chat got answered on the fallback key on `today`, its local day.
+    async fn fallback_requests_on(&self, chat_id: ChatId, today: chrono::NaiveDate) -> u32 {
+        match self.fallback_key_usage.lock().await.get(&chat_id) {
+            Some((day, count)) if *day == today => *count,
+            _ => 0,
+        }
+    }
+
+    /// Count an answered request against the fallback key's daily cap for the chat on `day`,
+    /// the local day it was checked on; failed requests don't use up the quota.
+    async fn count_fallback_request(&self, chat_id: ChatId, day: chrono::NaiveDate) {
+        let mut usage = self.fallback_key_usage.lock().await;
+        let (counted_day, count) = usage.entry(chat_id).or_insert((day, 0));
+        if *counted_day != day {
+            *counted_day = day;
+            *count = 0;
+        }
+        *count += 1;
+    }
+
+    /// Catch up with a model the chat didn't pick (the default changed, or the pinned model
+    /// left the list, see `LoadedModel::drifted`): log it and reload the history when the new
+    /// model has room for more of it.
+    async fn follow_model_drift(
+        &self,
+        chat_id: ChatId,
+        conversation: &mut Conversation,
+        model: &openrouter_api::ModelSummary,
+    ) {
+        let Some(loaded) = conversation.loaded_model.clone() else {
+            return;
+        };
+        if loaded.drifted(conversation.model_id.as_deref(), model) {
+            log::info!(
+                "chat {} now uses {} instead of {} without choosing it",
+                chat_id,
+                model.id,
+                loaded.id
+            );
+        }
+        // Ephemeral sessions aren't stored, so there's nothing to reload them from.
+        if loaded.needs_reload(model) && !conversation.ephemeral {
+            db::load_history(&self.db, conversation, model.token_budget()).await;
+        }
+        conversation.loaded_model = Some(conversation::LoadedModel::of(model));
+    }
+
+    /// Pin `model` for the chat, as `/model <id>` and the `/models` buttons do, and confirm it.
+    async fn select_model(
+        &self,
+        chat_id: ChatId,
+        model: &openrouter_api::ModelSummary,
+    ) -> anyhow::Result<()> {
+        let unsupported = {
+            let mut conv = self.get_conversation(chat_id).await;
+            let old_model = self.resolve_model(conv.model_id.as_deref()).await;
+            conv.model_id = Some(model.id.clone());
+            let should_reload =
+                old_model.id != model.id && model.context_length >= old_model.context_length;
+            if should_reload {
+                db::load_history(&self.db, &mut conv, model.token_budget()).await;
+            }
+            conv.loaded_model = Some(conversation::LoadedModel::of(model));
+            conv.unsupported_features(&model.capabilities)
+        };
+        db::set_model_id(&self.db, chat_id, Some(&model.id)).await;
+        log::info!("User {} selected model: `{}`", chat_id, model.name);
+        self.bot
+            .send_message(
+                chat_id,
+                match model.price_label() {
+                    Some(label) => format!(
+                        "Selected model\\: `{}`\nPricing\\: {}",
+                        telegram::escape_markdown_v2(&model.name),
+                        telegram::escape_markdown_v2(&label)
+                    ),
+                    None => format!(
+                        "Selected model\\: `{}`",
+                        telegram::escape_markdown_v2(&model.name)
+                    ),
+                },
+            )
+            .parse_mode(ParseMode::MarkdownV2)
+            .await?;
+        if !unsupported.is_empty() {
+            self.bot
+                .send_message(
+                    chat_id,
+                    format!(
+                        "Heads-up: {} doesn't support these enabled features, so they won't work with it: {}.",
+                        model.id,
+                        unsupported.join(", ")
+                    ),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn resolve_model(&self, model_id: Option<&str>) -> openrouter_api::ModelSummary {
         let requested = model_id.unwrap_or(self.default_model.as_str());
-        let models = self.models.read().await;
-        models
-            .iter()
-            .find(|m| m.id == requested)
-            .cloned()
-            .or_else(|| {
-                models
-                    .iter()
-                    .find(|m| m.id == self.default_model.as_str())
-                    .cloned()
-            })
-            .expect("default model not found")
+        let models = self.models.load();
+        models::resolve(&models, requested, &self.default_model, &self.config.load())
+    }
+
+    /// Like `persist_messages` for one group message, storing who wrote it for `/tldr`.
+    async fn persist_group_message(
+        &self,
+        chat_id: ChatId,
+        message: conversation::Message,
+        sender_name: Option<String>,
+    ) {
+        let ephemeral = {
+            let mut conversation = self.get_conversation(chat_id).await;
+            conversation.add_messages([message.clone()]);
+            conversation.ephemeral
+        };
+
+        if !ephemeral {
+            db::add_messages_from(&self.db, chat_id, [message], sender_name).await;
+        }
     }
 
     async fn persist_messages(&self, chat_id: ChatId, messages: &[conversation::Message]) {
-        {
+        let ephemeral = {
             let mut conversation = self.get_conversation(chat_id).await;
             conversation.add_messages(messages.iter().cloned());
-        }
+            conversation.ephemeral
+        };
 
-        db::add_messages(&self.db, chat_id, messages.iter().cloned()).await;
+        if !ephemeral {
+            db::add_messages(&self.db, chat_id, messages.iter().cloned()).await;
+        }
     }
 
-    async fn get_conversation(&self, chat_id: ChatId) -> MappedMutexGuard<'_, Conversation> {
-        let mut conv_map = self.conversations.lock().await;
-
-        if let std::collections::hash_map::Entry::Vacant(entry) = conv_map.entry(chat_id) {
-            let mut conversation = db::load_conversation(&self.db, chat_id).await;
-            let model = self.resolve_model(conversation.model_id.as_deref()).await;
+    /// The chat's conversation if it's loaded, without loading it.
+    async fn cached_conversation(&self, chat_id: ChatId) -> Option<Arc<Mutex<Conversation>>> {
+        self.conversations.lock().await.get(&chat_id).cloned()
+    }
 
-            db::load_history(&self.db, &mut conversation, model.token_budget()).await;
+    /// Lock the chat's conversation, loading it from the database on first use. Only this
+    /// chat waits while the guard is held.
+    async fn get_conversation(&self, chat_id: ChatId) -> OwnedMutexGuard<Conversation> {
+        let conversation = match self.cached_conversation(chat_id).await {
+            Some(conversation) => conversation,
+            None => {
+                // Loaded without the map locked; should another update of the chat have
+                // loaded it meanwhile, that copy is kept and this one dropped.
+                let loaded = self.load_conversation(chat_id).await;
+                Arc::clone(
+                    self.conversations
+                        .lock()
+                        .await
+                        .entry(chat_id)
+                        .or_insert_with(|| Arc::new(Mutex::new(loaded))),
+                )
+            }
+        };
+        conversation.lock_owned().await
+    }
 
+    async fn load_conversation(&self, chat_id: ChatId) -> Conversation {
+        let config = self.config.load();
+        // With AUTO_AUTHORIZE_ALL only new chats are authorized, so a denied one stays so.
+        let is_new = config.auto_authorize_all && !db::chat_exists(&self.db, chat_id).await;
+        let mut conversation = db::load_conversation(&self.db, chat_id).await;
+        if !conversation.is_authorized
+            && (is_new || config.auto_authorize_chats.contains(&chat_id.0))
+        {
             log::info!(
-                "Loaded conversation {} with {} messages. Model id is {}",
-                conversation.chat_id,
-                conversation.history.len(),
-                model.id
+                "auto-authorizing chat {} ({})",
+                chat_id,
+                if is_new {
+                    "AUTO_AUTHORIZE_ALL"
+                } else {
+                    "AUTO_AUTHORIZE_CHAT_IDS"
+                }
             );
-
-            entry.insert(conversation);
+            db::set_is_authorized(&self.db, chat_id, true)
+                .await
+                .expect("the chat row was just loaded");
+            conversation.is_authorized = true;
         }
+        // Env grants win over the database flags.
+        conversation.is_authorized |= self.config.load().grants_authorization(chat_id.0);
+        conversation.is_admin |= self.config.load().grants_admin(chat_id.0);
+        let model = self.resolve_model(conversation.model_id.as_deref()).await;
 
-        MutexGuard::map(conv_map, |map| {
-            map.get_mut(&chat_id)
-                .expect("conversation entry just inserted or already existed")
-        })
+        db::load_history(&self.db, &mut conversation, model.token_budget()).await;
+        conversation.loaded_model = Some(conversation::LoadedModel::of(&model));
+
+        log::info!(
+            "Loaded conversation {} with {} messages. Model id is {}",
+            conversation.chat_id,
+            conversation.history.len(),
+            model.id
+        );
+
+        conversation
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct LlmRequestReady {
     payload: serde_json::Value,
     openrouter_api_key: String,
+    /// Set when the request uses the fallback key under `FALLBACK_KEY_DAILY_LIMIT`: the chat's
+    /// local day it counts against once answered.
+    quota_day: Option<chrono::NaiveDate>,
+    model_id: String,
+    /// The chat enabled `/cache`; deterministic requests may be answered from the cache.
+    use_cache: bool,
+    /// Set when `OVERSIZED_INPUT=truncate` cut the user message: (original tokens, kept tokens).
+    truncated_input: Option<(u64, u64)>,
+    /// Temperature sent instead of the chat's own because of repeated `/regenerate`s.
+    ramped_temperature: Option<f64>,
+    /// How to ask a `FALLBACK_MODELS` entry instead; `None` for requests that don't fall back.
+    fallback: Option<Fallback>,
+}
+
+impl LlmRequestReady {
+    /// Add answered tool calls to the payload, and to any fallback request.
+    fn append_tool_results(&mut self, completed: &[(openrouter_api::ToolCall, String)]) {
+        openrouter_api::append_tool_results(&mut self.payload, completed);
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.tool_results.extend_from_slice(completed);
+        }
+    }
+
+    /// Attach an image to the prompt, and to any fallback request.
+    fn attach_image(&mut self, image_url: String) {
+        openrouter_api::attach_image(&mut self.payload, &image_url);
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.image_url = Some(image_url);
+        }
+    }
+}
+
+/// What a fallback model needs to answer the same turn: it is prepared from scratch for its
+/// own token budget, but the history choice and the tool outputs must stay the same.
+#[derive(Debug, Clone)]
+struct Fallback {
+    /// The user message before any `OVERSIZED_INPUT=truncate` cut.
+    user_message: conversation::Message,
+    /// `/clear_context` applied to the original request.
+    skip_context: bool,
+    tool_results: Vec<(openrouter_api::ToolCall, String)>,
+    /// Image sent with the prompt, as a `data:` URL.
+    image_url: Option<String>,
+}
+
+/// Outcome of one OpenRouter call, with the metadata needed for request logging.
+#[derive(Debug)]
+struct LlmCall {
+    model_id: String,
+    /// The chat's own model, when it failed and `model_id` is a fallback that answered instead.
+    fallback_from: Option<String>,
+    /// Temperature raised by `/regenerate`, noted under the answer.
+    ramped_temperature: Option<f64>,
+    /// Messages already showing the answer, when it was streamed.
+    streamed: Option<StreamedReply>,
+    /// Cancelled with `/stop`; `response` holds what arrived before, if anything.
+    stopped: bool,
+    latency: Duration,
+    response: anyhow::Result<openrouter_api::Response>,
+}
+
+/// Where a streamed answer is shown while it arrives.
+#[derive(Debug)]
+struct StreamTarget {
+    reply_to: Option<MessageId>,
+    show_thinking: bool,
+}
+
+/// The live messages of a streamed answer; the final text replaces what they show.
+#[derive(Debug)]
+struct StreamedReply {
+    thinking: Option<telegram::LiveReply>,
+    answer: telegram::LiveReply,
+}
+
+impl StreamedReply {
+    /// Delete whatever a failed attempt already showed.
+    async fn clear(&mut self, chat_id: ChatId) {
+        let live_replies = self.thinking.iter_mut().chain([&mut self.answer]);
+        for live in live_replies {
+            if let Err(err) = live.show("").await {
+                log::warn!(
+                    "failed to clear streamed reply in chat {}: {}",
+                    chat_id,
+                    err
+                );
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 enum LlmRequestError {
     NoApiKeyProvided,
+    FallbackKeyLimitReached {
+        limit: u32,
+    },
+    /// A photo was sent, but the model doesn't accept images.
+    ImagesUnsupported {
+        model_id: String,
+    },
+    /// The user message alone exceeds what the model can take (`OVERSIZED_INPUT=reject`).
+    InputTooLarge {
+        model_id: String,
+        tokens: u64,
+        max_tokens: u64,
+    },
+}
+
+impl LlmRequestError {
+    fn user_message(&self) -> String {
+        match self {
+            LlmRequestError::NoApiKeyProvided => format!(
+                "This chat has no OpenRouter API key yet. Create one at {} and send it with /key <your-openrouter-key> in a private chat with me.",
+                openrouter_api::KEYS_URL
+            ),
+            LlmRequestError::FallbackKeyLimitReached { limit } => format!(
+                "The shared API key allows {limit} requests per day and today's quota is used up. Set your own key with /key <key> or try again tomorrow."
+            ),
+            LlmRequestError::ImagesUnsupported { model_id } => format!(
+                "The selected model {model_id} can't see images. Pick one that can with /model, or describe the picture in words."
+            ),
+            LlmRequestError::InputTooLarge {
+                model_id,
+                tokens,
+                max_tokens,
+            } => format!(
+                "Your message is too large for the selected model {model_id} (≈{tokens} tokens, max {max_tokens}). Shorten it or pick a model with a larger context via /model."
+            ),
+        }
+    }
 }
 
 type LlmRequestResult = Result<LlmRequestReady, LlmRequestError>;
 
+/// Whether another model might answer where this error stopped the chat's own: not when the
+/// key itself is refused or the content was blocked, which would fail the same way.
+fn should_fall_back(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<openrouter_api::ApiError>() {
+        Some(err @ openrouter_api::ApiError::Http { .. }) => !err.is_auth(),
+        Some(openrouter_api::ApiError::ContentBlocked { .. }) => false,
+        None => true,
+    }
+}
+
 /// Return a minimally identifying, masked version of an API key, e.g. `sk-or-v1-bab...68c`.
 fn mask_api_key(key: &str) -> String {
     if key.len() <= 8 {
@@ -827,8 +5535,57 @@ fn mask_api_key(key: &str) -> String {
     format!("{prefix}...{suffix}")
 }
 
-fn is_from_bot(msg: &Message) -> bool {
-    msg.from.as_ref().map(|u| u.is_bot).unwrap_or(false)
+/// Pair each pending tool call with its output: a single call takes the raw text, several
+/// calls need a JSON object keyed by call id.
+fn match_tool_outputs(
+    tool_calls: &[openrouter_api::ToolCall],
+    text: &str,
+) -> Result<Vec<(openrouter_api::ToolCall, String)>, String> {
+    assert!(!tool_calls.is_empty(), "no tool calls to match outputs to");
+
+    if let [call] = tool_calls {
+        return Ok(vec![(call.clone(), text.to_string())]);
+    }
+
+    let outputs: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(text).map_err(|_| {
+            "Several tool calls are pending; send a JSON object mapping each call id to its output."
+                .to_string()
+        })?;
+
+    tool_calls
+        .iter()
+        .map(|call| {
+            let output = outputs
+                .get(&call.call_id)
+                .ok_or_else(|| format!("Missing output for call id {}", call.call_id))?;
+            let output = match output {
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            Ok((call.clone(), output))
+        })
+        .collect()
+}
+
+/// Keep at most `max_chars` characters of `text`, appending an ellipsis when cut.
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &text[..idx]),
+        None => text.to_string(),
+    }
+}
+
+/// Reduce a model-generated title to a single short line without decoration.
+fn clean_title(raw: &str) -> String {
+    let line = raw.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+    let line = line
+        .trim()
+        .trim_start_matches(['#', '*', ' '])
+        .trim_matches(['"', '\'', '*', '`', '.', ' ']);
+    let title = line.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    title.chars().take(TITLE_MAX_CHARS).collect()
 }
 
 fn is_common_text_message(msg: &Message) -> bool {
@@ -839,11 +5596,12 @@ fn is_command(message_text: &str) -> bool {
     message_text.starts_with('/')
 }
 
-async fn fetch_bot_username(bot: &Bot) -> String {
+/// Fetch the bot's username and user id, retrying until Telegram answers.
+async fn fetch_bot_identity(bot: &Bot) -> (String, UserId) {
     loop {
         match bot.get_me().await {
             Ok(me) => {
-                return me.user.username.unwrap_or_default();
+                return (me.user.username.unwrap_or_default(), me.user.id);
             }
             Err(err) => {
                 log::warn!("failed to fetch bot user info: {err}; retrying in 5s");
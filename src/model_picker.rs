@@ -0,0 +1,351 @@
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::openrouter_api::ModelSummary;
+use crate::telegram::escape_markdown_v2;
+
+/// Models shown on one page of the `/models` keyboard.
+pub const PAGE_SIZE: usize = 8;
+
+/// Most matches `/models <query>` shows; a broader query has to be narrowed down.
+pub const SEARCH_LIMIT: usize = 24;
+
+/// Providers offered in the filter rows, next to "All".
+const FEATURED_PROVIDERS: [&str; 5] = ["openai", "anthropic", "google", "x-ai", "deepseek"];
+
+/// Telegram rejects buttons whose `callback_data` is longer than this.
+const MAX_CALLBACK_DATA: usize = 64;
+
+const PAGE_PREFIX: &str = "mp:";
+const SELECT_PREFIX: &str = "ms:";
+const HASHED_SELECT_PREFIX: &str = "mh:";
+/// Data of the page counter button, which does nothing.
+const NOOP: &str = "mp:-";
+
+/// What a tapped `/models` button asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Show `page` of the models from `provider` (all providers when `None`).
+    Page {
+        provider: Option<String>,
+        page: usize,
+    },
+    /// Pick the model whose `select_data` this is, as `/model <id>` does.
+    Select(String),
+}
+
+impl Action {
+    pub fn parse(data: &str) -> Option<Self> {
+        if data == NOOP {
+            return None;
+        }
+        if let Some(rest) = data.strip_prefix(PAGE_PREFIX) {
+            let (page, provider) = rest.split_once(':')?;
+            return Some(Action::Page {
+                provider: Some(provider)
+                    .filter(|provider| !provider.is_empty())
+                    .map(str::to_string),
+                page: page.parse().ok()?,
+            });
+        }
+        if data.starts_with(SELECT_PREFIX) || data.starts_with(HASHED_SELECT_PREFIX) {
+            return Some(Action::Select(data.to_string()));
+        }
+        None
+    }
+}
+
+fn page_data(provider: Option<&str>, page: usize) -> String {
+    format!("{PAGE_PREFIX}{page}:{}", provider.unwrap_or_default())
+}
+
+/// The model id itself when it fits the callback data limit, else a stable hash of it.
+pub fn select_data(model_id: &str) -> String {
+    let data = format!("{SELECT_PREFIX}{model_id}");
+    if data.len() <= MAX_CALLBACK_DATA {
+        data
+    } else {
+        format!("{HASHED_SELECT_PREFIX}{:016x}", fnv1a(model_id))
+    }
+}
+
+/// FNV-1a, so hashed buttons keep working across restarts and Rust versions.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The listed model a select button stands for; `None` once it left the list.
+pub fn find<'a>(models: &'a [ModelSummary], data: &str) -> Option<&'a ModelSummary> {
+    models.iter().find(|model| select_data(&model.id) == data)
+}
+
+#[derive(Debug)]
+pub struct ModelPage {
+    /// MarkdownV2 text listing the page's models with their prices.
+    pub text: String,
+    pub keyboard: InlineKeyboardMarkup,
+}
+
+/// One page of the model list, `current_model` marked. Out-of-range pages show the last one,
+/// since the list may have shrunk since the keyboard was sent.
+pub fn page(
+    models: &[ModelSummary],
+    provider: Option<&str>,
+    page: usize,
+    current_model: &str,
+) -> ModelPage {
+    let mut listed: Vec<&ModelSummary> = models
+        .iter()
+        .filter(|model| provider.is_none_or(|provider| model.provider() == provider))
+        .collect();
+    // Grouped by provider, free variants after the paid ones.
+    listed.sort_by(|a, b| {
+        (a.provider(), a.is_free(), &a.id).cmp(&(b.provider(), b.is_free(), &b.id))
+    });
+
+    let pages = listed.len().div_ceil(PAGE_SIZE).max(1);
+    let page = page.min(pages - 1);
+    let shown = listed
+        .into_iter()
+        .skip(page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .collect::<Vec<_>>();
+
+    let scope = provider.unwrap_or("all providers");
+    let text = if shown.is_empty() {
+        format!(
+            "No models from {} are available right now\\.",
+            escape_markdown_v2(scope)
+        )
+    } else {
+        format!(
+            "Models from {}, page {}/{}\\. Tap one to use it\\.\n\n{}",
+            escape_markdown_v2(scope),
+            page + 1,
+            pages,
+            model_lines(&shown)
+        )
+    };
+
+    let mut rows = model_buttons(&shown, current_model);
+
+    let mut navigation = Vec::new();
+    if page > 0 {
+        navigation.push(InlineKeyboardButton::callback(
+            "‹ Prev",
+            page_data(provider, page - 1),
+        ));
+    }
+    navigation.push(InlineKeyboardButton::callback(
+        format!("{}/{}", page + 1, pages),
+        NOOP,
+    ));
+    if page + 1 < pages {
+        navigation.push(InlineKeyboardButton::callback(
+            "Next ›",
+            page_data(provider, page + 1),
+        ));
+    }
+    rows.push(navigation);
+
+    let filters = std::iter::once(None)
+        .chain(FEATURED_PROVIDERS.into_iter().map(Some))
+        .map(|filter| {
+            let name = filter.unwrap_or("All");
+            let label = if filter == provider {
+                format!("• {name}")
+            } else {
+                name.to_string()
+            };
+            InlineKeyboardButton::callback(label, page_data(filter, 0))
+        })
+        .collect::<Vec<_>>();
+    rows.extend(filters.chunks(3).map(<[_]>::to_vec));
+
+    ModelPage {
+        text,
+        keyboard: InlineKeyboardMarkup::new(rows),
+    }
+}
+
+/// Models whose id or name contains `query`, ignoring case, as one keyboard of at most
+/// `SEARCH_LIMIT` buttons.
+pub fn search(models: &[ModelSummary], query: &str, current_model: &str) -> ModelPage {
+    let needle = query.to_lowercase();
+    let mut matches: Vec<&ModelSummary> = models
+        .iter()
+        .filter(|model| {
+            model.id.to_lowercase().contains(&needle) || model.name.to_lowercase().contains(&needle)
+        })
+        .collect();
+    matches.sort_by(|a, b| (a.is_free(), &a.id).cmp(&(b.is_free(), &b.id)));
+
+    let total = matches.len();
+    matches.truncate(SEARCH_LIMIT);
+    let text = if matches.is_empty() {
+        format!(
+            "No models match `{}`\\. /models lists them all\\.",
+            escape_markdown_v2(query)
+        )
+    } else if total > SEARCH_LIMIT {
+        format!(
+            "{} models match `{}`; the first {} are below, a longer query narrows them down\\.\n\n{}",
+            total,
+            escape_markdown_v2(query),
+            SEARCH_LIMIT,
+            model_lines(&matches)
+        )
+    } else {
+        format!(
+            "Models matching `{}`\\. Tap one to use it\\.\n\n{}",
+            escape_markdown_v2(query),
+            model_lines(&matches)
+        )
+    };
+
+    ModelPage {
+        text,
+        keyboard: InlineKeyboardMarkup::new(model_buttons(&matches, current_model)),
+    }
+}
+
+/// One MarkdownV2 line per model: id, name and price.
+fn model_lines(models: &[&ModelSummary]) -> String {
+    models
+        .iter()
+        .map(|model| {
+            let price = match model.price_label() {
+                Some(label) if model.is_free() => format!(" \\- *{label}*"),
+                Some(label) => format!(" \\- {}", escape_markdown_v2(&label)),
+                None => String::new(),
+            };
+            format!(
+                "`{}` \\- {}{}",
+                escape_markdown_v2(&model.id),
+                escape_markdown_v2(&model.name),
+                price
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A select button per model, one per row, the current one checked.
+fn model_buttons(models: &[&ModelSummary], current_model: &str) -> Vec<Vec<InlineKeyboardButton>> {
+    models
+        .iter()
+        .map(|model| {
+            let label = if model.id == current_model {
+                format!("✓ {}", model.id)
+            } else {
+                model.id.clone()
+            };
+            vec![InlineKeyboardButton::callback(
+                label,
+                select_data(&model.id),
+            )]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openrouter_api::ModelCapabilities;
+    use teloxide::types::InlineKeyboardButtonKind;
+
+    fn model(id: &str) -> ModelSummary {
+        ModelSummary {
+            id: id.to_string(),
+            name: id.to_string(),
+            context_length: 32_000,
+            max_completion_tokens: 4_000,
+            capabilities: ModelCapabilities::UNKNOWN,
+            pricing: None,
+        }
+    }
+
+    fn callback_data(button: &InlineKeyboardButton) -> &str {
+        match &button.kind {
+            InlineKeyboardButtonKind::CallbackData(data) => data,
+            other => panic!("unexpected button kind {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pages_through_the_models_of_a_provider() {
+        let mut models: Vec<ModelSummary> =
+            (0..10).map(|i| model(&format!("openai/gpt-{i}"))).collect();
+        models.push(model("anthropic/claude"));
+
+        let first = page(&models, Some("openai"), 0, "openai/gpt-3");
+        let rows = &first.keyboard.inline_keyboard;
+        // Eight models, navigation, and the provider filters in two rows.
+        assert_eq!(rows.len(), PAGE_SIZE + 3);
+        assert_eq!(rows[3][0].text, "✓ openai/gpt-3");
+        assert!(!first.text.contains("anthropic"));
+
+        let navigation = &rows[PAGE_SIZE];
+        assert_eq!(navigation.len(), 2);
+        assert_eq!(navigation[0].text, "1/2");
+        let next = Action::parse(callback_data(&navigation[1])).expect("next button parses");
+        assert_eq!(
+            next,
+            Action::Page {
+                provider: Some("openai".to_string()),
+                page: 1
+            }
+        );
+
+        // Pages past the end clamp to the last one, which has a Prev button and two models.
+        let last = page(&models, Some("openai"), 7, "");
+        assert_eq!(last.keyboard.inline_keyboard[0][0].text, "openai/gpt-8");
+        assert_eq!(last.keyboard.inline_keyboard[2][0].text, "‹ Prev");
+        assert_eq!(Action::parse(NOOP), None);
+    }
+
+    #[test]
+    fn searches_ids_and_names_across_all_providers() {
+        let mut qwen = model("qwen/qwen3-235b");
+        qwen.name = "Qwen3 235B".to_string();
+        let mut mistral = model("mistralai/large");
+        mistral.name = "Mistral Large".to_string();
+        let models = [model("openai/gpt-4o"), qwen, mistral];
+
+        let found = search(&models, "QWEN", "");
+        assert_eq!(found.keyboard.inline_keyboard.len(), 1);
+        assert_eq!(found.keyboard.inline_keyboard[0][0].text, "qwen/qwen3-235b");
+        let found = search(&models, "mistral large", "mistralai/large");
+        assert_eq!(
+            found.keyboard.inline_keyboard[0][0].text,
+            "✓ mistralai/large"
+        );
+
+        let none = search(&models, "llama", "");
+        assert!(none.keyboard.inline_keyboard.is_empty());
+        assert!(none.text.starts_with("No models match"));
+
+        let many: Vec<ModelSummary> = (0..30).map(|i| model(&format!("qwen/q-{i}"))).collect();
+        let capped = search(&many, "qwen", "");
+        assert_eq!(capped.keyboard.inline_keyboard.len(), SEARCH_LIMIT);
+        assert!(capped.text.starts_with("30 models match"));
+    }
+
+    #[test]
+    fn select_buttons_fit_the_callback_data_limit() {
+        let long_id = format!("vendor/{}", "x".repeat(80));
+        let models = [model("openai/gpt-4o"), model(&long_id)];
+
+        for listed in &models {
+            let data = select_data(&listed.id);
+            assert!(data.len() <= MAX_CALLBACK_DATA);
+            let Some(Action::Select(selected)) = Action::parse(&data) else {
+                panic!("select button did not parse: {data}");
+            };
+            assert_eq!(find(&models, &selected).map(|m| &m.id), Some(&listed.id));
+        }
+        assert_eq!(select_data("openai/gpt-4o"), "ms:openai/gpt-4o");
+        assert!(find(&models, "ms:gone/model").is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::Value;
+
+use crate::panic_handler::fatal_panic;
+
+/// Default system prompts keyed by model-id prefix, used when a chat has no prompt of its own.
+#[derive(Debug, Default)]
+pub struct ModelPrompts {
+    /// (prefix, prompt), longest prefix first so the most specific entry wins.
+    entries: Vec<(String, String)>,
+}
+
+impl ModelPrompts {
+    /// Load the operator's JSON file; a missing or malformed file is a configuration error.
+    pub fn load(path: &str) -> Self {
+        let json = std::fs::read_to_string(path).unwrap_or_else(|err| {
+            fatal_panic(format!("failed to read model prompts file {path}: {err}"))
+        });
+        let prompts = Self::parse(&json)
+            .unwrap_or_else(|err| fatal_panic(format!("invalid model prompts file {path}: {err}")));
+        log::info!(
+            "loaded {} model prompt default(s) from {}",
+            prompts.entries.len(),
+            path
+        );
+        prompts
+    }
+
+    /// Parse `{"<model id prefix>": "<system prompt>", ...}`.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        let Value::Object(map) = value else {
+            return Err("expected a JSON object mapping model id prefixes to prompts".to_string());
+        };
+
+        let mut entries = Vec::with_capacity(map.len());
+        for (prefix, prompt) in map {
+            let Some(prompt) = prompt.as_str().map(str::trim).filter(|p| !p.is_empty()) else {
+                return Err(format!("prompt for `{prefix}` must be a non-empty string"));
+            };
+            entries.push((prefix, prompt.to_string()));
+        }
+        entries.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(Self { entries })
+    }
+
+    /// The prompt of the longest prefix matching `model_id`; an empty prefix matches every model.
+    pub fn for_model(&self, model_id: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(prefix, _)| model_id.starts_with(prefix.as_str()))
+            .map(|(_, prompt)| prompt.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let prompts = ModelPrompts::parse(
+            r#"{"": "Be helpful.", "openai/": "No markdown.", "openai/o3": "Think step by step."}"#,
+        )
+        .expect("valid prompts");
+
+        assert_eq!(
+            prompts.for_model("openai/o3-mini"),
+            Some("Think step by step.")
+        );
+        assert_eq!(prompts.for_model("openai/gpt-4o"), Some("No markdown."));
+        assert_eq!(prompts.for_model("x-ai/grok-4"), Some("Be helpful."));
+        assert_eq!(ModelPrompts::default().for_model("openai/gpt-4o"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(ModelPrompts::parse("[]").is_err());
+        assert!(ModelPrompts::parse(r#"{"openai/": 1}"#).is_err());
+        assert!(ModelPrompts::parse(r#"{"openai/": "  "}"#).is_err());
+    }
+}
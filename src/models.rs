@@ -1,39 +1,190 @@
-use std::sync::Arc;
-
-use tokio::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
+use crate::config::Config;
+use crate::context_overrides::ContextOverrides;
 use crate::openrouter_api;
 
-pub async fn spawn_model_refresh(
-    http_client: reqwest::Client,
-) -> Arc<RwLock<Vec<openrouter_api::ModelSummary>>> {
-    let models = Arc::new(RwLock::new(Vec::new()));
+/// The current model list. Readers take a snapshot (an `Arc` clone) and never wait for a
+/// refresh, which swaps the whole list in one step; the lock only guards the pointer.
+#[derive(Debug, Default)]
+pub struct ModelStore {
+    current: RwLock<Arc<Vec<openrouter_api::ModelSummary>>>,
+}
+
+impl ModelStore {
+    pub fn load(&self) -> Arc<Vec<openrouter_api::ModelSummary>> {
+        Arc::clone(&self.current.read().expect("model store lock poisoned"))
+    }
+
+    pub fn store(&self, models: Vec<openrouter_api::ModelSummary>) {
+        let models = Arc::new(models);
+        // The old list is dropped after the lock is released, by its last reader.
+        let _previous = std::mem::replace(
+            &mut *self.current.write().expect("model store lock poisoned"),
+            models,
+        );
+    }
+}
+
+/// Pick the requested model, else the default one from the list, else a stand-in for the
+/// default sized from the config, so requests never depend on the list being loaded.
+pub fn resolve(
+    models: &[openrouter_api::ModelSummary],
+    requested: &str,
+    default_model: &str,
+    config: &Config,
+) -> openrouter_api::ModelSummary {
+    models
+        .iter()
+        .find(|m| m.id == requested)
+        .or_else(|| models.iter().find(|m| m.id == default_model))
+        .cloned()
+        .unwrap_or_else(|| {
+            log::debug!(
+                "model {} not in the model list; using configured limits for {}",
+                requested,
+                default_model
+            );
+            openrouter_api::ModelSummary {
+                id: default_model.to_string(),
+                name: default_model.to_string(),
+                context_length: config.default_model_context_length,
+                max_completion_tokens: config.default_model_max_completion_tokens,
+                capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+                pricing: None,
+            }
+        })
+}
+
+/// Startup check that `default_model` is in the fetched list; a missing one is only warned
+/// about, since `resolve` keeps serving it with the configured limits. Returns whether it's
+/// listed (an empty list, when the fetch failed, proves nothing and isn't warned about).
+pub fn check_default(models: &[openrouter_api::ModelSummary], default_model: &str) -> bool {
+    if models.is_empty() {
+        return false;
+    }
+    let listed = models.iter().any(|model| model.id == default_model);
+    if !listed {
+        log::warn!(
+            "DEFAULT_MODEL {} is not in OpenRouter's model list ({} models); requests to it will likely fail until it is set to a listed model",
+            default_model,
+            models.len()
+        );
+    }
+    listed
+}
+
+/// The model list saved by the last successful refresh, parsed with the current overrides.
+/// A missing or unreadable cache only means starting the usual way.
+fn load_cache(
+    path: &str,
+    overrides: &ContextOverrides,
+) -> Option<Vec<openrouter_api::ModelSummary>> {
+    let body = match std::fs::read_to_string(path) {
+        Ok(body) => body,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            log::warn!("failed to read model cache {path}: {err}");
+            return None;
+        }
+    };
+    match openrouter_api::parse_models(&body, overrides) {
+        Ok(models) if !models.is_empty() => Some(models),
+        Ok(_) => None,
+        Err(err) => {
+            log::warn!("ignoring model cache {path}: {err:#}");
+            None
+        }
+    }
+}
+
+/// Replace the cache through a temporary file so a crash mid-write can't leave it truncated.
+fn save_cache(path: &str, body: &str) {
+    let temp = format!("{path}.tmp");
+    if let Err(err) = std::fs::write(&temp, body).and_then(|()| std::fs::rename(&temp, path)) {
+        log::warn!("failed to write model cache {path}: {err}");
+    }
+}
+
+pub async fn spawn_model_refresh(http_client: reqwest::Client, config: &Config) -> Arc<ModelStore> {
+    let models = Arc::new(ModelStore::default());
+    let retry_delay = config.model_refresh_retry_delay;
+    let interval = config.model_refresh_interval;
+    let base_url = config.openrouter_base_url.clone();
+    let overrides = Arc::new(
+        config
+            .context_overrides_file
+            .as_deref()
+            .map(ContextOverrides::load)
+            .unwrap_or_default(),
+    );
+    let cache_file = config.model_cache_file.clone();
 
     // Fetch helper keeps the refresh logic in one place.
     async fn refresh_models(
         http_client: &reqwest::Client,
-        models: &Arc<RwLock<Vec<openrouter_api::ModelSummary>>>,
+        base_url: &str,
+        overrides: &ContextOverrides,
+        cache_file: Option<&str>,
+        models: &ModelStore,
     ) -> anyhow::Result<()> {
-        let latest = openrouter_api::list_models(http_client).await?;
-
-        let mut guard = models.write().await;
-        *guard = latest;
+        let body = openrouter_api::fetch_models_json(http_client, base_url).await?;
+        let latest = openrouter_api::parse_models(&body, overrides)?;
+        models.store(latest);
+        if let Some(path) = cache_file {
+            save_cache(path, &body);
+        }
 
         Ok(())
     }
 
-    // Run once immediately; keep retrying so we always start with a model list.
-    let mut attempt = 1u32;
-    loop {
-        match refresh_models(&http_client, &models).await {
+    // A cached list is enough to start with; the background task refreshes it right away.
+    let cached = cache_file
+        .as_deref()
+        .and_then(|path| load_cache(path, &overrides));
+    let seeded = cached.is_some();
+    if let Some(cached) = cached {
+        log::info!(
+            "loaded {} models from cache {}; refreshing in the background",
+            cached.len(),
+            cache_file.as_deref().unwrap_or_default()
+        );
+        models.store(cached);
+    }
+
+    // Try a bounded number of times up front; after that start anyway with an empty list
+    // and let the background task keep trying.
+    let max_attempts = if seeded {
+        0
+    } else {
+        config.model_refresh_max_attempts
+    };
+    for attempt in 1..=max_attempts {
+        match refresh_models(
+            &http_client,
+            &base_url,
+            &overrides,
+            cache_file.as_deref(),
+            &models,
+        )
+        .await
+        {
             Ok(()) => break,
-            Err(err) => {
+            Err(err) if attempt < max_attempts => {
                 log::warn!(
-                    "initial model fetch failed (attempt {}): {err}; retrying in 5s",
-                    attempt
+                    "initial model fetch failed (attempt {}/{}): {err}; retrying in {}s",
+                    attempt,
+                    max_attempts,
+                    retry_delay.as_secs()
+                );
+                tokio::time::sleep(retry_delay).await;
+            }
+            Err(err) => {
+                log::error!(
+                    "initial model fetch failed (attempt {}/{}): {err}; starting without models",
+                    attempt,
+                    max_attempts
                 );
-                attempt += 1;
-                tokio::time::sleep(std::time::Duration::from_secs(30)).await;
             }
         }
     }
@@ -41,12 +192,175 @@ pub async fn spawn_model_refresh(
     let models_clone = models.clone();
     let http_client = http_client.clone();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10 * 60));
+        // A list seeded from the cache may be stale, so fetch once before waiting.
+        let mut fresh = !seeded;
         loop {
-            interval.tick().await;
-            refresh_models(&http_client, &models_clone).await.ok();
+            if fresh {
+                // Retry quickly while there's nothing to serve, otherwise refresh at the
+                // regular pace.
+                let is_empty = models_clone.load().is_empty();
+                tokio::time::sleep(if is_empty { retry_delay } else { interval }).await;
+            }
+            fresh = true;
+
+            if let Err(err) = refresh_models(
+                &http_client,
+                &base_url,
+                &overrides,
+                cache_file.as_deref(),
+                &models_clone,
+            )
+            .await
+            {
+                log::warn!("model refresh failed: {err}");
+            }
         }
     });
 
     models
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn starts_without_models_when_no_attempts_allowed() {
+        let config = Config::from_lookup(|name| match name {
+            "MODEL_REFRESH_MAX_ATTEMPTS" => Some("0".to_string()),
+            "MODEL_REFRESH_RETRY_SECS" => Some("3600".to_string()),
+            _ => None,
+        });
+
+        let models = spawn_model_refresh(reqwest::Client::new(), &config).await;
+        assert!(models.load().is_empty());
+    }
+
+    #[tokio::test]
+    async fn starts_from_the_cached_model_list() {
+        let path =
+            std::env::temp_dir().join(format!("tggpt-model-cache-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"data":[{"id":"openai/gpt-4o","name":"GPT-4o","context_length":128000,"top_provider":{"max_completion_tokens":16384}}]}"#,
+        )
+        .expect("failed to write model cache");
+        let cache_file = path.to_str().expect("temp path is not UTF-8").to_string();
+        let config = Config::from_lookup(|name| match name {
+            "MODEL_CACHE_FILE" => Some(cache_file.clone()),
+            "OPENROUTER_BASE_URL" => Some("http://127.0.0.1:1".to_string()),
+            "MODEL_REFRESH_RETRY_SECS" => Some("3600".to_string()),
+            _ => None,
+        });
+
+        let models = spawn_model_refresh(reqwest::Client::new(), &config).await;
+        let loaded = models.load();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, "openai/gpt-4o");
+        assert_eq!(loaded[0].context_length, 128_000);
+
+        // A corrupt cache is ignored rather than fatal.
+        std::fs::write(&path, "not json").expect("failed to write model cache");
+        assert!(load_cache(&cache_file, &ContextOverrides::default()).is_none());
+        std::fs::remove_file(&path).expect("failed to remove model cache");
+    }
+
+    #[test]
+    fn resolves_to_configured_default_without_list() {
+        let config = Config::from_lookup(|name| match name {
+            "DEFAULT_MODEL_CONTEXT_LENGTH" => Some("200000".to_string()),
+            "DEFAULT_MODEL_MAX_COMPLETION_TOKENS" => Some("8000".to_string()),
+            _ => None,
+        });
+        let listed = openrouter_api::ModelSummary {
+            id: "openai/gpt-4o".to_string(),
+            name: "GPT-4o".to_string(),
+            context_length: 128_000,
+            max_completion_tokens: 16_384,
+            capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+            pricing: None,
+        };
+
+        let model = resolve(&[], "openai/gpt-4o", "vendor/default", &config);
+        assert_eq!(model.id, "vendor/default");
+        assert_eq!(model.token_budget(), 192_000);
+
+        let models = [listed.clone()];
+        assert_eq!(
+            resolve(&models, "openai/gpt-4o", "vendor/default", &config),
+            listed
+        );
+        assert_eq!(
+            resolve(&models, "gone/model", "openai/gpt-4o", &config),
+            listed
+        );
+    }
+
+    #[test]
+    fn default_missing_from_the_list_is_not_fatal() {
+        let config = Config::from_lookup(|_| None);
+        let models = [openrouter_api::ModelSummary {
+            id: "openai/gpt-4o".to_string(),
+            name: "GPT-4o".to_string(),
+            context_length: 128_000,
+            max_completion_tokens: 16_384,
+            capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+            pricing: None,
+        }];
+
+        assert!(!check_default(&models, "renamed/default"));
+        assert!(check_default(&models, "openai/gpt-4o"));
+        assert!(!check_default(&[], "openai/gpt-4o"));
+
+        // Chats keep getting answers sized from the configured limits.
+        let model = resolve(&models, "renamed/default", "renamed/default", &config);
+        assert_eq!(model.id, "renamed/default");
+        assert_eq!(model.context_length, config.default_model_context_length);
+    }
+
+    #[test]
+    fn readers_see_whole_lists_while_refreshing() {
+        let model = |id: &str| openrouter_api::ModelSummary {
+            id: id.to_string(),
+            name: id.to_string(),
+            context_length: 8_192,
+            max_completion_tokens: 1_024,
+            capabilities: openrouter_api::ModelCapabilities::UNKNOWN,
+            pricing: None,
+        };
+        let old_list = vec![model("old/a"), model("old/b")];
+        let new_list = vec![model("new/a"), model("new/b"), model("new/c")];
+        let store = Arc::new(ModelStore::default());
+        store.store(old_list.clone());
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let (old_list, new_list) = (old_list.clone(), new_list.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let snapshot = store.load();
+                        assert!(*snapshot == old_list || *snapshot == new_list);
+                    }
+                })
+            })
+            .collect();
+        for round in 0..1_000 {
+            store.store(if round % 2 == 0 {
+                new_list.clone()
+            } else {
+                old_list.clone()
+            });
+        }
+        for reader in readers {
+            reader.join().expect("reader saw a partial list");
+        }
+
+        let config = Config::from_lookup(|_| None);
+        let snapshot = store.load();
+        assert_eq!(
+            resolve(&snapshot, "old/b", "old/a", &config),
+            model("old/b")
+        );
+    }
+}
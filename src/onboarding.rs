@@ -0,0 +1,88 @@
+/// Steps of the first-run onboarding that `/start` begins when `ONBOARDING` is on. Each open
+/// step takes the chat's next plain message as its answer; `/skip` ends the whole flow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnboardingStep {
+    /// Ask which language answers should use.
+    Language,
+    /// Offer to pick a model other than the default.
+    Model,
+    /// Finished or skipped; `/start` shows the help from now on.
+    Done,
+}
+
+impl OnboardingStep {
+    pub const FIRST: OnboardingStep = OnboardingStep::Language;
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "language" => Some(OnboardingStep::Language),
+            "model" => Some(OnboardingStep::Model),
+            "done" => Some(OnboardingStep::Done),
+            _ => None,
+        }
+    }
+
+    pub fn to_db(self) -> &'static str {
+        match self {
+            OnboardingStep::Language => "language",
+            OnboardingStep::Model => "model",
+            OnboardingStep::Done => "done",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            OnboardingStep::Language => OnboardingStep::Model,
+            OnboardingStep::Model | OnboardingStep::Done => OnboardingStep::Done,
+        }
+    }
+
+    /// Question sent when the step starts; `default_model` is mentioned in the model step.
+    pub fn question(self, default_model: &str) -> Option<String> {
+        match self {
+            OnboardingStep::Language => Some(
+                "Which language should I answer in? Reply with its name, e.g. English or Deutsch. /skip ends the setup."
+                    .to_string(),
+            ),
+            OnboardingStep::Model => Some(format!(
+                "Which model would you like? Reply with a model id from /models, or /skip to keep the default ({default_model})."
+            )),
+            OnboardingStep::Done => None,
+        }
+    }
+}
+
+/// System prompt line that pins the answer language chosen during onboarding.
+pub fn language_instruction(language: &str) -> String {
+    format!("Always answer in {}.", language.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_steps_in_order() {
+        let mut step = OnboardingStep::FIRST;
+        let mut visited = vec![step];
+        while step != OnboardingStep::Done {
+            assert!(step.question("vendor/default").is_some());
+            step = step.next();
+            visited.push(step);
+        }
+        assert_eq!(
+            visited,
+            [
+                OnboardingStep::Language,
+                OnboardingStep::Model,
+                OnboardingStep::Done
+            ]
+        );
+        assert_eq!(OnboardingStep::Done.next(), OnboardingStep::Done);
+        assert!(OnboardingStep::Done.question("vendor/default").is_none());
+
+        for step in visited {
+            assert_eq!(OnboardingStep::parse(step.to_db()), Some(step));
+        }
+    }
+}
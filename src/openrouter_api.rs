@@ -1,11 +1,19 @@
-use crate::conversation::{Message, MessageRole};
+use crate::context_overrides::ContextOverrides;
+use crate::conversation::{Message, MessageRole, ReasoningEffort, SamplingParams};
 use anyhow::{Context, anyhow};
+use base64::Engine;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
 
-#[allow(dead_code)]
-const MODELS_ENDPOINT: &str = "https://openrouter.ai/api/v1/models";
+/// Provider name under which chats store the key used for these requests.
+pub const PROVIDER: &str = "openrouter";
+
+/// API root used unless `OPENROUTER_BASE_URL` points elsewhere (a proxy or a local mock).
+pub const DEFAULT_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// Where users create the keys they set with `/key`.
+pub const KEYS_URL: &str = "https://openrouter.ai/keys";
 
 #[derive(Debug)]
 enum ContentType {
@@ -20,6 +28,85 @@ pub struct ModelSummary {
     pub context_length: u64,
     /// Provider-advertised maximum completion tokens (if provided by OpenRouter).
     pub max_completion_tokens: u64,
+    pub capabilities: ModelCapabilities,
+    /// `None` when OpenRouter lists no usable price (missing, or variable like its router).
+    pub pricing: Option<ModelPricing>,
+}
+
+/// USD per token, as OpenRouter's `pricing.prompt` and `pricing.completion` give it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPricing {
+    /// `None` unless both prices are present and non-negative; prices are decimal strings,
+    /// though plain numbers are accepted too.
+    fn from_record(record: &serde_json::Value) -> Option<Self> {
+        let parse = |key: &str| {
+            let price = match record.get(key)? {
+                serde_json::Value::String(price) => price.trim().parse::<f64>().ok(),
+                price => price.as_f64(),
+            };
+            price.filter(|p| *p >= 0.0)
+        };
+        Some(ModelPricing {
+            prompt: parse("prompt")?,
+            completion: parse("completion")?,
+        })
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.prompt == 0.0 && self.completion == 0.0
+    }
+}
+
+/// Price per million tokens, with enough digits that cheap models don't round to zero.
+fn format_per_million(per_token: f64) -> String {
+    let per_million = per_token * 1_000_000.0;
+    if per_million == 0.0 || per_million >= 0.1 {
+        format!("${per_million:.2}")
+    } else {
+        format!("${per_million:.4}")
+    }
+}
+
+/// Features a model supports, from OpenRouter's `supported_parameters` and `architecture`.
+/// Models without that metadata are assumed to support everything so no spurious warnings
+/// are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    pub tools: bool,
+    pub reasoning: bool,
+    /// Accepts images as input.
+    pub vision: bool,
+}
+
+impl ModelCapabilities {
+    pub const UNKNOWN: ModelCapabilities = ModelCapabilities {
+        tools: true,
+        reasoning: true,
+        vision: true,
+    };
+
+    fn from_metadata(parameters: Option<&[String]>, architecture: Option<&Architecture>) -> Self {
+        let mut capabilities = match parameters {
+            None => Self::UNKNOWN,
+            Some(parameters) => {
+                let supports = |name: &str| parameters.iter().any(|p| p == name);
+                ModelCapabilities {
+                    tools: supports("tools"),
+                    reasoning: supports("reasoning") || supports("include_reasoning"),
+                    vision: true,
+                }
+            }
+        };
+        if let Some(architecture) = architecture {
+            capabilities.vision = architecture.accepts_images();
+        }
+        capabilities
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,6 +120,36 @@ struct ModelRecord {
     name: String,
     context_length: u64,
     top_provider: TopProvider,
+    #[serde(default)]
+    supported_parameters: Option<Vec<String>>,
+    #[serde(default)]
+    architecture: Option<Architecture>,
+    /// Read leniently by [`ModelPricing::from_record`], so one model's odd pricing only makes
+    /// its price unknown instead of failing the whole list.
+    #[serde(default)]
+    pricing: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Architecture {
+    /// Input and output kinds, e.g. `text+image->text`.
+    #[serde(default)]
+    modality: Option<String>,
+    #[serde(default)]
+    input_modalities: Option<Vec<String>>,
+}
+
+impl Architecture {
+    fn accepts_images(&self) -> bool {
+        match (&self.input_modalities, &self.modality) {
+            (Some(inputs), _) => inputs.iter().any(|input| input == "image"),
+            (None, Some(modality)) => modality
+                .split("->")
+                .next()
+                .is_some_and(|inputs| inputs.split('+').any(|input| input == "image")),
+            (None, None) => true,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,9 +161,198 @@ struct TopProvider {
 pub struct Response {
     pub prompt_tokens: u64,
     pub completion_tokens: u64,
+    /// Part of `completion_tokens` the model spent reasoning (0 when not reported).
+    pub reasoning_tokens: u64,
     pub total_tokens: u64,
     pub cost: f64,
     pub completion_text: String,
+    /// Reasoning summary/content the model returned alongside the answer; shown on request
+    /// but never stored or sent back as context.
+    pub reasoning_text: String,
+    pub tool_calls: Vec<ToolCall>,
+    /// The stream broke off: the text is what arrived before, and usage is unknown.
+    pub interrupted: bool,
+}
+
+impl Response {
+    /// The assistant turn kept in history: the final answer only, never the reasoning.
+    pub fn answer_message(&self) -> Message {
+        Message {
+            role: MessageRole::Assistant,
+            text: self.completion_text.clone(),
+        }
+    }
+}
+
+/// Failed responses callers need to tell apart; returned inside `anyhow::Error`.
+#[derive(Debug)]
+pub enum ApiError {
+    /// OpenRouter or the provider refused the content on policy grounds.
+    ContentBlocked {
+        /// Moderation categories, when the provider names them.
+        reasons: Vec<String>,
+        message: String,
+    },
+    /// Any other non-success response.
+    Http {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::ContentBlocked { reasons, message } if reasons.is_empty() => {
+                write!(f, "content blocked by moderation: {message}")
+            }
+            ApiError::ContentBlocked { reasons, message } => write!(
+                f,
+                "content blocked by moderation ({}): {message}",
+                reasons.join(", ")
+            ),
+            ApiError::Http { status, body } => {
+                write!(f, "OpenRouter Responses API error {status}: {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    /// The key was refused or is out of credit, which no other model would change.
+    pub fn is_auth(&self) -> bool {
+        matches!(self, ApiError::Http { status, .. } if matches!(status.as_u16(), 401..=403))
+    }
+}
+
+/// Why a request failed, in the terms a user can act on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    /// OpenRouter couldn't be reached or the connection dropped.
+    Network,
+    /// The API key was refused or is out of credit.
+    Auth,
+    /// OpenRouter or the model provider answered with an error or an unusable response.
+    Provider,
+}
+
+impl FailureKind {
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(api_error) = err.downcast_ref::<ApiError>() {
+            return if api_error.is_auth() {
+                FailureKind::Auth
+            } else {
+                FailureKind::Provider
+            };
+        }
+        // A body that isn't valid JSON arrived fine; the provider sent garbage.
+        let network = err.chain().any(|cause| {
+            cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|err| !err.is_decode())
+        });
+        if network {
+            FailureKind::Network
+        } else {
+            FailureKind::Provider
+        }
+    }
+}
+
+/// Map an error response to `ApiError`. OpenRouter reports its own moderation as a 403 with
+/// `error.metadata.reasons`; providers passing through their filters use codes such as
+/// `content_policy_violation` or `content_filter`.
+pub fn classify_error(status: reqwest::StatusCode, body: &str) -> ApiError {
+    let error = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value.get("error").cloned());
+    let Some(error) = error else {
+        return ApiError::Http {
+            status,
+            body: body.to_string(),
+        };
+    };
+
+    let reasons = error
+        .pointer("/metadata/reasons")
+        .and_then(|reasons| reasons.as_array())
+        .map(|reasons| {
+            reasons
+                .iter()
+                .filter_map(|reason| reason.as_str().map(str::to_string))
+                .collect::<Vec<_>>()
+        });
+    let policy_code = ["code", "type"]
+        .iter()
+        .filter_map(|field| error.get(field).and_then(|v| v.as_str()))
+        .any(|code| {
+            let code = code.to_ascii_lowercase();
+            code.contains("content_policy")
+                || code.contains("content_filter")
+                || code.contains("moderation")
+        });
+
+    if reasons.is_none() && !policy_code {
+        return ApiError::Http {
+            status,
+            body: body.to_string(),
+        };
+    }
+
+    ApiError::ContentBlocked {
+        reasons: reasons.unwrap_or_default(),
+        message: error
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("request blocked")
+            .to_string(),
+    }
+}
+
+/// A `function_call` output item the model wants the client to execute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub call_id: String,
+    pub name: String,
+    /// JSON-encoded arguments exactly as returned by the model.
+    pub arguments: String,
+}
+
+/// Optional request parameters layered on top of the model and input messages.
+#[derive(Debug, Default, Clone)]
+pub struct PayloadOptions {
+    /// Function/tool definitions forwarded as the `tools` array.
+    pub tools: Option<serde_json::Value>,
+    /// Enable OpenRouter's `web` plugin.
+    pub web_search: bool,
+    /// Sampling parameters; unset ones are left out of the payload.
+    pub sampling: SamplingParams,
+    /// Sent as `reasoning.effort`; only set for models that support reasoning.
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+impl PayloadOptions {
+    /// Estimated size of what the options add to the payload (serialized `tools` and
+    /// `plugins`); providers count tool schemas against the context like any other text.
+    pub fn estimated_tokens(&self) -> u64 {
+        let tools = self
+            .tools
+            .as_ref()
+            .map(|tools| estimate_text_tokens(&tools.to_string()))
+            .unwrap_or(0);
+        let plugins = if self.web_search {
+            estimate_text_tokens(&web_plugins().to_string())
+        } else {
+            0
+        };
+        tools + plugins
+    }
+}
+
+fn web_plugins() -> serde_json::Value {
+    json!([{ "id": "web" }])
 }
 
 impl ModelSummary {
@@ -54,29 +360,168 @@ impl ModelSummary {
         self.context_length
             .saturating_sub(self.max_completion_tokens)
     }
+
+    /// Tokens left for the user message once the system prompts, the tool and plugin
+    /// definitions (and the per-message and per-prompt overhead) are counted; history is
+    /// pruned to make room, this part can't be.
+    pub fn input_budget(&self, system_prompts: &[&str], options: &PayloadOptions) -> u64 {
+        let reserved = estimate_tokens(system_prompts.iter().copied().chain([""]));
+        self.token_budget()
+            .saturating_sub(reserved)
+            .saturating_sub(options.estimated_tokens())
+    }
+
+    /// Tokens left for history next to `reserved_texts` (system prompts and the user
+    /// message) and the tool and plugin definitions sent along.
+    pub fn history_budget(&self, reserved_texts: &[&str], options: &PayloadOptions) -> u64 {
+        let reserved = estimate_tokens(reserved_texts.iter().copied());
+        self.token_budget()
+            .saturating_sub(reserved)
+            .saturating_sub(options.estimated_tokens())
+    }
+
+    /// `:free` variants and models priced at zero.
+    pub fn is_free(&self) -> bool {
+        self.id.ends_with(":free") || self.pricing.is_some_and(|pricing| pricing.is_zero())
+    }
+
+    /// Input and output price per million tokens, `free`, or `None` when unknown.
+    pub fn price_label(&self) -> Option<String> {
+        if self.is_free() {
+            return Some("free".to_string());
+        }
+        self.pricing.map(|pricing| {
+            format!(
+                "{} in / {} out per 1M tokens",
+                format_per_million(pricing.prompt),
+                format_per_million(pricing.completion)
+            )
+        })
+    }
+
+    /// Provider prefix of the model id, e.g. `openai` for `openai/gpt-4o`.
+    pub fn provider(&self) -> &str {
+        self.id
+            .split_once('/')
+            .map(|(provider, _)| provider)
+            .unwrap_or(self.id.as_str())
+    }
+}
+
+/// Word characters per token for Latin-script text; BPE vocabularies (cl100k, o200k) keep
+/// most English words whole, splitting only long ones.
+const ASCII_CHARS_PER_TOKEN: u64 = 6;
+/// Other alphabets (Cyrillic, Greek, Arabic, ...) get split about three times as often.
+const OTHER_CHARS_PER_TOKEN: u64 = 2;
+/// Whitespace characters per token beyond the single space a following word absorbs.
+const SPACES_PER_TOKEN: u64 = 8;
+
+/// Running token estimate, fed one character at a time so a prefix's estimate is exact.
+///
+/// No tokenizer vocabulary ships with the bot, so this approximates BPE by character class:
+/// words cost a token per `ASCII_CHARS_PER_TOKEN` characters, punctuation and CJK characters
+/// a token each (so code and Chinese aren't undercounted), and runs of whitespace beyond one
+/// space a token per `SPACES_PER_TOKEN`.
+///
+/// Against cl100k/o200k it is usually within about 20% for English and code, and CJK text
+/// may be overcounted by up to half, since those vocabularies merge common pairs. Other
+/// families (Llama, Claude, Gemini) can differ by more. A real tokenizer wasn't used because
+/// the chats' models come from many vendors, most of whose vocabularies aren't public, and
+/// bundling the ones that are would only make some models exact. `PER_PROMPT_OVERHEAD`
+/// absorbs the error.
+#[derive(Debug, Default)]
+struct TokenEstimate {
+    tokens: u64,
+    /// Length of the current word in 1/`ASCII_CHARS_PER_TOKEN` token units.
+    word_units: u64,
+    /// Length of the current whitespace run.
+    spaces: u64,
+}
+
+impl TokenEstimate {
+    fn push(&mut self, c: char) {
+        if c.is_whitespace() {
+            self.word_units = 0;
+            self.spaces += 1;
+            if self.spaces % SPACES_PER_TOKEN == 2 {
+                self.tokens += 1;
+            }
+        } else if c.is_ascii_alphanumeric() || c == '_' || (c.is_alphabetic() && !is_wide(c)) {
+            self.spaces = 0;
+            let units = if c.is_ascii() {
+                1
+            } else {
+                ASCII_CHARS_PER_TOKEN / OTHER_CHARS_PER_TOKEN
+            };
+            let before = self.word_units.div_ceil(ASCII_CHARS_PER_TOKEN);
+            self.word_units += units;
+            self.tokens += self.word_units.div_ceil(ASCII_CHARS_PER_TOKEN) - before;
+        } else {
+            // Punctuation, symbols, emoji and CJK characters.
+            self.word_units = 0;
+            self.spaces = 0;
+            self.tokens += 1;
+        }
+    }
+}
+
+/// CJK ideographs, kana, hangul and full-width forms, which tokenize about one per character.
+fn is_wide(c: char) -> bool {
+    matches!(
+        c,
+        '\u{1100}'..='\u{11FF}'
+            | '\u{2E80}'..='\u{9FFF}'
+            | '\u{AC00}'..='\u{D7AF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{FF00}'..='\u{FFEF}'
+            | '\u{20000}'..='\u{3FFFF}'
+    )
+}
+
+/// Estimated token count of a single text, without any message or prompt overhead.
+pub fn estimate_text_tokens(text: &str) -> u64 {
+    let mut estimate = TokenEstimate::default();
+    text.chars().for_each(|c| estimate.push(c));
+    estimate.tokens
+}
+
+/// Longest prefix of `text` whose estimate stays within `max_tokens`, cut at a character
+/// boundary.
+pub fn truncate_to_tokens(text: &str, max_tokens: u64) -> &str {
+    let mut estimate = TokenEstimate::default();
+    for (idx, c) in text.char_indices() {
+        estimate.push(c);
+        if estimate.tokens > max_tokens {
+            return &text[..idx];
+        }
+    }
+    text
+}
+
+const PER_MESSAGE_OVERHEAD: u64 = 10;
+/// Allowance for what a request adds around its messages.
+pub const PER_PROMPT_OVERHEAD: u64 = 10_000;
+
+/// Estimated share of one message in a request, its per-message overhead included; stored
+/// with each `history` row.
+pub fn estimate_message_tokens(text: &str) -> u64 {
+    estimate_text_tokens(text) + PER_MESSAGE_OVERHEAD
 }
 
 pub fn estimate_tokens<'a, I>(messages: I) -> u64
 where
     I: IntoIterator<Item = &'a str>,
 {
-    const AVG_BYTES_PER_TOKEN: u64 = 4;
-    const PER_MESSAGE_OVERHEAD: u64 = 10;
-    const PER_PROMPT_OVERHEAD: u64 = 10_000;
-
-    let (byte_count, message_count) = messages
+    messages
         .into_iter()
-        .fold((0u64, 0u64), |(bytes, msgs), message| {
-            (bytes + message.len() as u64, msgs + 1)
-        });
-
-    let text_tokens = byte_count.div_ceil(AVG_BYTES_PER_TOKEN);
-
-    text_tokens + message_count * PER_MESSAGE_OVERHEAD + PER_PROMPT_OVERHEAD
+        .map(estimate_message_tokens)
+        .sum::<u64>()
+        + PER_PROMPT_OVERHEAD
 }
 
-pub async fn list_models(http: &Client) -> anyhow::Result<Vec<ModelSummary>> {
-    let request = http.get(MODELS_ENDPOINT);
+/// Raw body of the models endpoint, as kept in the model cache file.
+pub async fn fetch_models_json(http: &Client, base_url: &str) -> anyhow::Result<String> {
+    let request = http.get(format!("{base_url}/models"));
 
     let response = request
         .send()
@@ -91,20 +536,92 @@ pub async fn list_models(http: &Client) -> anyhow::Result<Vec<ModelSummary>> {
         ));
     }
 
+    Ok(body)
+}
+
+pub fn parse_models(body: &str, overrides: &ContextOverrides) -> anyhow::Result<Vec<ModelSummary>> {
     let parsed: ModelsResponse =
-        serde_json::from_str(&body).context("failed to parse OpenRouter models response JSON")?;
+        serde_json::from_str(body).context("failed to parse OpenRouter models response JSON")?;
+
+    Ok(parsed
+        .data
+        .into_iter()
+        .map(|model| model_to_summary(model, overrides))
+        .collect())
+}
+
+/// Check that `tools` is a non-empty array of Responses API function definitions.
+pub fn validate_tools(tools: &serde_json::Value) -> Result<(), String> {
+    let tools = tools
+        .as_array()
+        .ok_or_else(|| "tools must be a JSON array".to_string())?;
+    if tools.is_empty() {
+        return Err("tools array must not be empty".to_string());
+    }
+
+    for (idx, tool) in tools.iter().enumerate() {
+        let tool = tool
+            .as_object()
+            .ok_or_else(|| format!("tool #{idx} must be an object"))?;
 
-    Ok(parsed.data.into_iter().map(model_to_summary).collect())
+        if tool.get("type").and_then(|t| t.as_str()) != Some("function") {
+            return Err(format!("tool #{idx} must have \"type\": \"function\""));
+        }
+
+        let name = tool
+            .get("name")
+            .and_then(|n| n.as_str())
+            .ok_or_else(|| format!("tool #{idx} must have a string \"name\""))?;
+        let name_is_valid = !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !name_is_valid {
+            return Err(format!(
+                "tool #{idx} name must be 1-64 characters of [a-zA-Z0-9_-]"
+            ));
+        }
+
+        if let Some(description) = tool.get("description")
+            && !description.is_string()
+        {
+            return Err(format!("tool `{name}` description must be a string"));
+        }
+
+        if let Some(parameters) = tool.get("parameters") {
+            let is_object_schema = parameters
+                .as_object()
+                .and_then(|p| p.get("type"))
+                .and_then(|t| t.as_str())
+                == Some("object");
+            if !is_object_schema {
+                return Err(format!(
+                    "tool `{name}` parameters must be a JSON schema with \"type\": \"object\""
+                ));
+            }
+        }
+    }
+
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn prepare_payload<'a, I>(model: &str, messages: I, stream: bool) -> serde_json::Value
+pub fn prepare_payload<'a, I>(
+    model: &str,
+    messages: I,
+    stream: bool,
+    options: &PayloadOptions,
+) -> serde_json::Value
 where
     I: IntoIterator<Item = &'a Message>,
 {
     let mut input_items = Vec::new();
 
     for (idx, msg) in messages.into_iter().enumerate() {
+        if msg.role == MessageRole::Tool {
+            input_items.extend(tool_turn_items(&msg.text));
+            continue;
+        }
         let content_type = if msg.role == MessageRole::Assistant {
             ContentType::Output
         } else {
@@ -113,24 +630,133 @@ where
         input_items.push(message_item(idx, msg.role, &msg.text, content_type));
     }
 
-    json!({
+    let mut payload = json!({
         "model": model,
         "input": input_items,
-        "plugins": [
-            { "id": "web" }
-        ],
         "usage": { "include": true },
         "stream": stream,
-    })
+    });
+
+    if options.web_search {
+        payload["plugins"] = web_plugins();
+    }
+
+    if let Some(tools) = options.tools.as_ref() {
+        payload["tools"] = tools.clone();
+    }
+
+    if let Some(effort) = options.reasoning_effort {
+        payload["reasoning"] = json!({ "effort": effort.as_str() });
+    }
+
+    let sampling = &options.sampling;
+    for (name, value) in [
+        ("temperature", sampling.temperature),
+        ("top_p", sampling.top_p),
+        ("frequency_penalty", sampling.frequency_penalty),
+        ("presence_penalty", sampling.presence_penalty),
+    ] {
+        if let Some(value) = value {
+            payload[name] = json!(value);
+        }
+    }
+
+    payload
+}
+
+/// `data:` URL carrying a JPEG (Telegram re-encodes every photo as one) for `attach_image`.
+pub fn jpeg_data_url(bytes: &[u8]) -> String {
+    format!(
+        "data:image/jpeg;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    )
+}
+
+/// Add an image to the last input message, the prompt being sent, next to its text.
+pub fn attach_image(payload: &mut serde_json::Value, image_url: &str) {
+    let prompt = payload["input"]
+        .as_array_mut()
+        .expect("payload input must be an array")
+        .last_mut()
+        .expect("payload input ends with the prompt");
+    prompt["content"]
+        .as_array_mut()
+        .expect("the prompt is a message with content parts")
+        .push(json!({
+            "type": "input_image",
+            "detail": "auto",
+            "image_url": image_url,
+        }));
+}
+
+/// Append answered tool calls and their client-provided outputs to the payload input,
+/// so the model can continue from the results.
+pub fn append_tool_results(payload: &mut serde_json::Value, completed: &[(ToolCall, String)]) {
+    let input = payload["input"]
+        .as_array_mut()
+        .expect("payload input must be an array");
+
+    for (call, output) in completed {
+        input.extend(tool_result_items(call, output));
+    }
+}
+
+/// History message recording an answered tool call, replayed by `prepare_payload` as the
+/// original `function_call` and `function_call_output` items.
+pub fn tool_turn(call: &ToolCall, output: &str) -> Message {
+    Message {
+        role: MessageRole::Tool,
+        text: json!({
+            "call_id": call.call_id,
+            "name": call.name,
+            "arguments": call.arguments,
+            "output": output,
+        })
+        .to_string(),
+    }
+}
+
+fn tool_turn_items(text: &str) -> [serde_json::Value; 2] {
+    let turn: serde_json::Value =
+        serde_json::from_str(text).expect("stored tool turn must be JSON");
+    let field = |name: &str| {
+        turn[name]
+            .as_str()
+            .unwrap_or_else(|| panic!("stored tool turn lacks {name}"))
+            .to_string()
+    };
+    let call = ToolCall {
+        call_id: field("call_id"),
+        name: field("name"),
+        arguments: field("arguments"),
+    };
+    tool_result_items(&call, &field("output"))
+}
+
+fn tool_result_items(call: &ToolCall, output: &str) -> [serde_json::Value; 2] {
+    [
+        json!({
+            "type": "function_call",
+            "call_id": call.call_id,
+            "name": call.name,
+            "arguments": call.arguments,
+        }),
+        json!({
+            "type": "function_call_output",
+            "call_id": call.call_id,
+            "output": output,
+        }),
+    ]
 }
 
 pub async fn send(
     http: &Client,
+    base_url: &str,
     api_key: &str,
     payload: serde_json::Value,
 ) -> anyhow::Result<Response> {
     let response = http
-        .post("https://openrouter.ai/api/v1/responses")
+        .post(format!("{base_url}/responses"))
         .bearer_auth(api_key)
         .json(&payload)
         .send()
@@ -140,66 +766,319 @@ pub async fn send(
     let body_text = response.text().await?;
 
     if !status.is_success() {
-        return Err(anyhow!(
-            "OpenRouter Responses API error {status}: {body_text}"
-        ));
+        return Err(classify_error(status, &body_text).into());
     }
 
     let response_body: serde_json::Value = serde_json::from_str(&body_text)?;
+    finish_response(&response_body)
+}
+
+/// Text received so far from a streaming request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamProgress {
+    pub text: String,
+    pub reasoning: String,
+}
 
-    let response = extract_output_text(&response_body);
-    if !response.completion_text.is_empty() {
+impl StreamProgress {
+    /// What arrived so far as a response cut short; its usage is unknown.
+    pub fn interrupted_response(&self) -> Response {
+        Response {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            reasoning_tokens: 0,
+            total_tokens: 0,
+            cost: 0.0,
+            completion_text: self.text.trim().to_string(),
+            reasoning_text: self.reasoning.trim().to_string(),
+            tool_calls: Vec::new(),
+            interrupted: true,
+        }
+    }
+}
+
+/// Like [`send`], but streamed: text and reasoning deltas are published to `progress` as
+/// they arrive, and the result is built from the final `response.completed` event. When the
+/// stream breaks after some answer text arrived, that text is returned as an interrupted
+/// response rather than an error.
+pub async fn send_streaming(
+    http: &Client,
+    base_url: &str,
+    api_key: &str,
+    mut payload: serde_json::Value,
+    progress: &tokio::sync::watch::Sender<StreamProgress>,
+) -> anyhow::Result<Response> {
+    payload["stream"] = json!(true);
+    let mut response = http
+        .post(format!("{base_url}/responses"))
+        .bearer_auth(api_key)
+        .json(&payload)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body_text = response.text().await?;
+        return Err(classify_error(status, &body_text).into());
+    }
+
+    let mut events = SseBuffer::default();
+    let mut stream = StreamState::default();
+    let broken = loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break None,
+            Err(err) => break Some(anyhow::Error::from(err)),
+        };
+        for event in events.push(&chunk) {
+            if stream.apply(&event) {
+                progress.send_replace(stream.progress.clone());
+            }
+        }
+        if stream.completed.is_some() || stream.error.is_some() {
+            break None;
+        }
+    };
+
+    if let Some(completed) = &stream.completed {
+        return finish_response(completed);
+    }
+    let err = broken
+        .or_else(|| stream.error.take().map(|message| anyhow!(message)))
+        .unwrap_or_else(|| anyhow!("OpenRouter stream ended without a completed response"));
+    if stream.progress.text.trim().is_empty() {
+        return Err(err);
+    }
+
+    log::warn!(
+        "stream broke off after {} character(s): {err:#}",
+        stream.progress.text.chars().count()
+    );
+    Ok(stream.progress.interrupted_response())
+}
+
+/// Splits a server-sent event stream into the JSON `data` of each event.
+#[derive(Debug, Default)]
+struct SseBuffer {
+    pending: Vec<u8>,
+}
+
+impl SseBuffer {
+    /// Add received bytes and return the events they complete. Comments (OpenRouter sends
+    /// `: OPENROUTER PROCESSING` keep-alives) and the final `[DONE]` are skipped.
+    fn push(&mut self, bytes: &[u8]) -> Vec<serde_json::Value> {
+        self.pending
+            .extend(bytes.iter().copied().filter(|&byte| byte != b'\r'));
+        let mut events = Vec::new();
+        while let Some(end) = self.pending.windows(2).position(|pair| pair == b"\n\n") {
+            let block: Vec<u8> = self.pending.drain(..end + 2).collect();
+            let block = String::from_utf8_lossy(&block);
+            let data: Vec<&str> = block
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect();
+            if data.is_empty() || data == ["[DONE]"] {
+                continue;
+            }
+            match serde_json::from_str(&data.join("\n")) {
+                Ok(event) => events.push(event),
+                Err(err) => log::warn!("skipping malformed stream event: {err}"),
+            }
+        }
+        events
+    }
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    progress: StreamProgress,
+    /// The full response from `response.completed`.
+    completed: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+impl StreamState {
+    /// Apply one event; true when the visible progress changed.
+    fn apply(&mut self, event: &serde_json::Value) -> bool {
+        let delta = || event["delta"].as_str().unwrap_or_default();
+        match event["type"].as_str().unwrap_or_default() {
+            "response.output_text.delta" => {
+                self.progress.text.push_str(delta());
+                true
+            }
+            "response.reasoning_text.delta" | "response.reasoning_summary_text.delta" => {
+                self.progress.reasoning.push_str(delta());
+                true
+            }
+            // An incomplete response (e.g. filtered) is final too; `finish_response` tells.
+            "response.completed" | "response.incomplete" => {
+                self.completed = Some(event["response"].clone());
+                false
+            }
+            "response.failed" | "error" => {
+                let error = event
+                    .pointer("/response/error")
+                    .or_else(|| event.get("error"))
+                    .unwrap_or(event);
+                self.error = Some(format!("OpenRouter stream failed: {error}"));
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Turn a complete response body into a [`Response`], or the error it stands for.
+fn finish_response(response_body: &serde_json::Value) -> anyhow::Result<Response> {
+    let response = extract_output_text(response_body);
+    if !response.completion_text.is_empty() || !response.tool_calls.is_empty() {
         return Ok(response);
     }
 
+    // Some providers filter the output instead of rejecting the request.
+    if response_body
+        .pointer("/incomplete_details/reason")
+        .and_then(|r| r.as_str())
+        == Some("content_filter")
+    {
+        return Err(ApiError::ContentBlocked {
+            reasons: Vec::new(),
+            message: "the answer was removed by the provider's content filter".to_string(),
+        }
+        .into());
+    }
+
     Err(anyhow!(
         "OpenRouter response missing text output: {response_body}"
     ))
 }
 
 fn extract_output_text(value: &serde_json::Value) -> Response {
-    let text = value
+    let output_items = || {
+        value
+            .get("output")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+    };
+    let is_reasoning =
+        |item: &serde_json::Value| item.get("type").and_then(|t| t.as_str()) == Some("reasoning");
+    // Reasoning items carry `text` parts too; keep them out of the answer.
+    let join_texts = |items: Vec<&serde_json::Value>, fields: &[&str]| {
+        items
+            .into_iter()
+            .flat_map(|item| {
+                fields
+                    .iter()
+                    .filter_map(|field| item.get(field).and_then(|c| c.as_array()))
+                    .flatten()
+            })
+            .filter_map(|v| v.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<&str>>()
+            .join("\n")
+            .trim()
+            .to_string()
+    };
+
+    let text = join_texts(
+        output_items().filter(|item| !is_reasoning(item)).collect(),
+        &["content"],
+    );
+    let reasoning_text = join_texts(
+        output_items().filter(|item| is_reasoning(item)).collect(),
+        &["summary", "content"],
+    );
+
+    let tool_calls = value
         .get("output")
         .and_then(|v| v.as_array())
         .into_iter()
         .flatten()
-        .filter_map(|v| v.get("content").and_then(|c| c.as_array()))
-        .flatten()
-        .filter_map(|v| v.get("text").and_then(|t| t.as_str()))
-        .collect::<Vec<&str>>()
-        .join("\n")
-        .trim()
-        .to_string();
+        .filter(|v| v.get("type").and_then(|t| t.as_str()) == Some("function_call"))
+        .filter_map(|v| {
+            let call_id = v.get("call_id").and_then(|c| c.as_str());
+            let name = v.get("name").and_then(|n| n.as_str());
+            // A call that can't be answered is dropped rather than taking the bot down.
+            let (Some(call_id), Some(name)) = (call_id, name) else {
+                log::warn!("ignoring function_call without call_id or name: {v}");
+                return None;
+            };
+            Some(ToolCall {
+                call_id: call_id.to_string(),
+                name: name.to_string(),
+                arguments: v
+                    .get("arguments")
+                    .and_then(|a| a.as_str())
+                    .unwrap_or("{}")
+                    .to_string(),
+            })
+        })
+        .collect();
 
-    let usage = value.get("usage").expect("Missing usage");
+    // Some providers leave out usage or parts of it; the answer is still good, it just
+    // can't be accounted for.
+    let usage = value.get("usage").unwrap_or_else(|| {
+        log::warn!("response has no usage; counting it as 0 tokens and $0");
+        &serde_json::Value::Null
+    });
+    let usage_field = |path: &[&str]| {
+        let field = path
+            .iter()
+            .try_fold(usage, |value, key| value.get(key))
+            .filter(|field| !field.is_null());
+        if field.is_none() && !usage.is_null() {
+            log::warn!("response usage has no {}; counting it as 0", path.join("."));
+        }
+        field
+    };
+    let tokens = |path: &[&str]| usage_field(path).and_then(|v| v.as_u64()).unwrap_or(0);
 
     Response {
-        prompt_tokens: usage
-            .get("input_tokens")
+        prompt_tokens: tokens(&["input_tokens"]),
+        completion_tokens: tokens(&["output_tokens"]),
+        // Only reasoning models report it, so its absence isn't worth a warning.
+        reasoning_tokens: usage
+            .pointer("/output_tokens_details/reasoning_tokens")
             .and_then(|v| v.as_u64())
-            .expect("Missing input_tokens"),
-        completion_tokens: usage
-            .get("output_tokens")
-            .and_then(|v| v.as_u64())
-            .expect("Missing output_tokens"),
-        total_tokens: usage
-            .get("total_tokens")
-            .and_then(|v| v.as_u64())
-            .expect("Missing total_tokens"),
-        cost: usage
-            .get("cost")
+            .unwrap_or(0),
+        total_tokens: tokens(&["total_tokens"]),
+        cost: usage_field(&["cost"])
             .and_then(|v| v.as_f64())
-            .expect("Missing cost"),
+            .unwrap_or(0.0),
         completion_text: text,
+        reasoning_text,
+        tool_calls,
+        interrupted: false,
     }
 }
 
-fn model_to_summary(model: ModelRecord) -> ModelSummary {
+/// Convert a listed model, replacing its advertised context length when the operator
+/// configured an override for it.
+fn model_to_summary(model: ModelRecord, overrides: &ContextOverrides) -> ModelSummary {
+    let context_length = match overrides.for_model(&model.id) {
+        Some(length) => {
+            log::info!(
+                "context length of {} overridden: {} advertised, using {}",
+                model.id,
+                model.context_length,
+                length
+            );
+            length
+        }
+        None => model.context_length,
+    };
+
     ModelSummary {
         id: model.id,
         name: model.name,
-        context_length: model.context_length,
+        context_length,
         max_completion_tokens: model.top_provider.max_completion_tokens.unwrap_or_default(),
+        capabilities: ModelCapabilities::from_metadata(
+            model.supported_parameters.as_deref(),
+            model.architecture.as_ref(),
+        ),
+        pricing: model.pricing.as_ref().and_then(ModelPricing::from_record),
     }
 }
 
@@ -251,26 +1130,455 @@ mod tests {
                 "max_completion_tokens": 4096,
                 "is_moderated": true
               }
+            },
+            {
+              "id": "openai/gpt-4o",
+              "name": "GPT-4o",
+              "context_length": 128000,
+              "top_provider": { "max_completion_tokens": 16384 },
+              "supported_parameters": ["tools", "temperature", "structured_outputs"]
+            },
+            {
+              "id": "openai/gpt-4o-search",
+              "name": "GPT-4o Search",
+              "context_length": 128000,
+              "top_provider": {},
+              "architecture": { "modality": "text+image->text" }
+            },
+            {
+              "id": "mistralai/mistral-7b-instruct",
+              "name": "Mistral 7B Instruct",
+              "context_length": 32768,
+              "top_provider": {},
+              "architecture": { "modality": "text->text", "input_modalities": ["text"] }
             }
           ]
         }"#;
 
         let parsed: ModelsResponse = serde_json::from_str(payload).unwrap();
-        let summaries: Vec<ModelSummary> = parsed.data.into_iter().map(model_to_summary).collect();
+        let overrides =
+            ContextOverrides::parse(r#"{"openai/gpt-4o": 64000}"#).expect("valid overrides");
+        let summaries: Vec<ModelSummary> = parsed
+            .data
+            .into_iter()
+            .map(|model| model_to_summary(model, &overrides))
+            .collect();
 
-        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries.len(), 4);
         let model = &summaries[0];
         assert_eq!(model.id, "openai/gpt-3.5-turbo");
         assert_eq!(model.name.as_str(), "GPT-4");
         assert_eq!(model.context_length, 8192);
         assert_eq!(model.max_completion_tokens, 4096);
+        assert_eq!(model.capabilities, ModelCapabilities::UNKNOWN);
+        assert_eq!(
+            summaries[1].capabilities,
+            ModelCapabilities {
+                tools: true,
+                reasoning: false,
+                vision: true,
+            }
+        );
+        assert_eq!(summaries[1].context_length, 64_000);
+        assert!(summaries[2].capabilities.vision);
+        assert!(!summaries[3].capabilities.vision);
+    }
+
+    #[test]
+    fn validates_tool_definitions() {
+        let valid = json!([{
+            "type": "function",
+            "name": "get_weather",
+            "description": "Current weather for a city",
+            "parameters": {
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"]
+            }
+        }]);
+        assert_eq!(validate_tools(&valid), Ok(()));
+
+        assert!(validate_tools(&json!({"type": "function"})).is_err());
+        assert!(validate_tools(&json!([])).is_err());
+        assert!(validate_tools(&json!([{ "type": "web", "name": "x" }])).is_err());
+        assert!(validate_tools(&json!([{ "type": "function", "name": "bad name" }])).is_err());
+        assert!(
+            validate_tools(&json!([{
+                "type": "function",
+                "name": "f",
+                "parameters": { "type": "string" }
+            }]))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn payload_includes_tools_and_results() {
+        let tools = json!([{ "type": "function", "name": "lookup" }]);
+        let options = PayloadOptions {
+            tools: Some(tools.clone()),
+            web_search: true,
+            sampling: SamplingParams {
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+            reasoning_effort: Some(ReasoningEffort::High),
+        };
+        let user_message = Message {
+            role: MessageRole::User,
+            text: "hi".to_string(),
+        };
+
+        let mut payload = prepare_payload("m", std::iter::once(&user_message), false, &options);
+        assert_eq!(payload["tools"], tools);
+        assert_eq!(payload["plugins"], json!([{ "id": "web" }]));
+        assert_eq!(payload["reasoning"], json!({ "effort": "high" }));
+        assert_eq!(payload["temperature"], json!(0.2));
+        assert!(payload.get("top_p").is_none());
+
+        let call = ToolCall {
+            call_id: "call_1".to_string(),
+            name: "lookup".to_string(),
+            arguments: "{}".to_string(),
+        };
+        append_tool_results(&mut payload, &[(call.clone(), "42".to_string())]);
+
+        let input = payload["input"].as_array().unwrap();
+        assert_eq!(input.len(), 3);
+        assert_eq!(input[1]["type"], "function_call");
+        assert_eq!(input[2]["type"], "function_call_output");
+        assert_eq!(input[2]["call_id"], "call_1");
+        assert_eq!(input[2]["output"], "42");
+
+        // A stored tool turn replays as the same items on later requests.
+        let history = [user_message.clone(), tool_turn(&call, "42")];
+        let replayed = prepare_payload("m", history.iter(), false, &options);
+        assert_eq!(replayed["input"], payload["input"]);
+
+        let without_tools = prepare_payload(
+            "m",
+            std::iter::once(&user_message),
+            false,
+            &PayloadOptions::default(),
+        );
+        assert!(without_tools.get("tools").is_none());
+        assert!(without_tools.get("plugins").is_none());
+        assert!(without_tools.get("reasoning").is_none());
+    }
+
+    #[test]
+    fn attaches_images_to_the_prompt() {
+        let history = [
+            Message {
+                role: MessageRole::User,
+                text: "earlier".to_string(),
+            },
+            Message {
+                role: MessageRole::User,
+                text: "[image] what is this?".to_string(),
+            },
+        ];
+        let mut payload = prepare_payload("m", history.iter(), false, &PayloadOptions::default());
+        let image_url = jpeg_data_url(b"jpeg");
+        assert_eq!(image_url, "data:image/jpeg;base64,anBlZw==");
+        attach_image(&mut payload, &image_url);
+
+        assert_eq!(payload["input"][0]["content"].as_array().unwrap().len(), 1);
+        let content = &payload["input"][1]["content"];
+        assert_eq!(content[0]["text"], "[image] what is this?");
+        assert_eq!(content[1]["type"], "input_image");
+        assert_eq!(content[1]["image_url"], image_url);
+    }
+
+    #[test]
+    fn classifies_moderation_errors() {
+        let openrouter = r#"{"error": {"code": 403, "message": "Input was flagged", "metadata": {"reasons": ["violence"], "flagged_input": "...", "provider_name": "OpenAI"}}}"#;
+        match classify_error(reqwest::StatusCode::FORBIDDEN, openrouter) {
+            ApiError::ContentBlocked { reasons, message } => {
+                assert_eq!(reasons, vec!["violence".to_string()]);
+                assert_eq!(message, "Input was flagged");
+            }
+            other => panic!("expected a moderation error, got {other:?}"),
+        }
+
+        let provider = r#"{"error": {"code": "content_policy_violation", "message": "Blocked"}}"#;
+        assert!(matches!(
+            classify_error(reqwest::StatusCode::BAD_REQUEST, provider),
+            ApiError::ContentBlocked { reasons, .. } if reasons.is_empty()
+        ));
+
+        let rate_limited = r#"{"error": {"code": 429, "message": "Rate limit exceeded"}}"#;
+        assert!(matches!(
+            classify_error(reqwest::StatusCode::TOO_MANY_REQUESTS, rate_limited),
+            ApiError::Http { .. }
+        ));
+        assert!(matches!(
+            classify_error(reqwest::StatusCode::BAD_GATEWAY, "<html>bad gateway</html>"),
+            ApiError::Http { .. }
+        ));
+
+        let no_credit = r#"{"error": {"code": 402, "message": "Insufficient credits"}}"#;
+        assert!(classify_error(reqwest::StatusCode::PAYMENT_REQUIRED, no_credit).is_auth());
+        assert!(!classify_error(reqwest::StatusCode::TOO_MANY_REQUESTS, rate_limited).is_auth());
+    }
+
+    #[test]
+    fn estimates_text_tokens_by_character_class() {
+        assert_eq!(estimate_text_tokens(""), 0);
+        // English: about a token per word or punctuation mark (cl100k: 10).
+        assert_eq!(
+            estimate_text_tokens("The quick brown fox jumps over the lazy dog."),
+            10
+        );
+        assert_eq!(estimate_text_tokens("internationalization"), 4);
+        // CJK: a token per character, where bytes / 4 gave 12 for 16 characters.
+        assert_eq!(estimate_text_tokens("今日は良い天気ですね。散歩しよう"), 16);
+        assert_eq!(estimate_text_tokens("你好，世界"), 5);
+        // Other alphabets split more often than English.
+        assert_eq!(estimate_text_tokens("привет"), 3);
+        // Code: every bracket and operator counts, and so does indentation.
+        assert_eq!(
+            estimate_text_tokens("fn main() {\n    println!(\"hi\");\n}"),
+            16
+        );
+        assert_eq!(estimate_text_tokens("a b"), 2);
+        assert_eq!(estimate_text_tokens("a          b"), 4);
+    }
+
+    #[test]
+    fn reads_model_pricing() {
+        let body = r#"{"data":[
+            {"id":"openai/gpt-4o","name":"GPT-4o","context_length":128000,
+             "top_provider":{"max_completion_tokens":16384},
+             "pricing":{"prompt":"0.0000025","completion":"0.00001"}},
+            {"id":"meta/llama:free","name":"Llama","context_length":8000,"top_provider":{},
+             "pricing":{"prompt":"0","completion":"0"}},
+            {"id":"openrouter/auto","name":"Auto","context_length":2000000,"top_provider":{},
+             "pricing":{"prompt":"-1","completion":"-1"}},
+            {"id":"vendor/tiny","name":"Tiny","context_length":8000,"top_provider":{},
+             "pricing":{"prompt":"0.00000002","completion":"0.00000004"}},
+            {"id":"vendor/partial","name":"Partial","context_length":8000,"top_provider":{},
+             "pricing":{"prompt":"0.000001","completion":null}},
+            {"id":"vendor/odd","name":"Odd","context_length":8000,"top_provider":{},
+             "pricing":"contact sales"},
+            {"id":"vendor/numeric","name":"Numeric","context_length":8000,"top_provider":{},
+             "pricing":{"prompt":0.000001,"completion":"n/a"}}
+        ]}"#;
+        let models = parse_models(body, &ContextOverrides::default()).expect("models parse");
+
+        assert_eq!(
+            models[0].price_label().as_deref(),
+            Some("$2.50 in / $10.00 out per 1M tokens")
+        );
+        assert!(!models[0].is_free());
+        assert!(models[1].is_free());
+        assert_eq!(models[1].price_label().as_deref(), Some("free"));
+        // Variable pricing is shown as unknown rather than as a negative price.
+        assert_eq!(models[2].pricing, None);
+        assert_eq!(models[2].price_label(), None);
+        assert_eq!(
+            models[3].price_label().as_deref(),
+            Some("$0.0200 in / $0.0400 out per 1M tokens")
+        );
+        // Malformed pricing leaves that model's price unknown without failing the list.
+        assert_eq!(models.len(), 7);
+        assert!(models[4..].iter().all(|model| model.pricing.is_none()));
+    }
+
+    #[test]
+    fn detects_single_message_over_input_budget() {
+        let model = ModelSummary {
+            id: "test/small".to_string(),
+            name: "Small".to_string(),
+            context_length: 32_000,
+            max_completion_tokens: 4_000,
+            capabilities: ModelCapabilities::UNKNOWN,
+            pricing: None,
+        };
+        let system_prompts = ["You are a helpful assistant."];
+        let budget = model.input_budget(&system_prompts, &PayloadOptions::default());
+        assert!(budget > 0 && budget < model.token_budget());
+
+        // A pasted document of ~32k tokens can't fit even with all history pruned.
+        let document = "lorem ipsum ".repeat(16_000);
+        assert!(estimate_text_tokens(&document) > budget);
+
+        let truncated = truncate_to_tokens(&document, budget);
+        assert!(document.starts_with(truncated));
+        assert!(estimate_text_tokens(truncated) <= budget);
+        assert_eq!(truncate_to_tokens("short", budget), "short");
+
+        // Cuts never split a multi-byte character.
+        assert_eq!(truncate_to_tokens("ёжик", 1), "ёж");
+    }
+
+    fn tool_heavy_options() -> PayloadOptions {
+        let tools: Vec<serde_json::Value> = (0..20)
+            .map(|i| {
+                json!({
+                    "type": "function",
+                    "name": format!("tool_{i}"),
+                    "description": "Looks something up in a large external system. ".repeat(5),
+                    "parameters": {
+                        "type": "object",
+                        "properties": { "query": { "type": "string", "description": "Search terms" } },
+                    },
+                })
+            })
+            .collect();
+        PayloadOptions {
+            tools: Some(json!(tools)),
+            web_search: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tool_definitions_shrink_the_history_budget() {
+        let model = ModelSummary {
+            id: "test/small".to_string(),
+            name: "Small".to_string(),
+            context_length: 16_000,
+            max_completion_tokens: 2_000,
+            capabilities: ModelCapabilities::UNKNOWN,
+            pricing: None,
+        };
+        let reserved = ["You are a helpful assistant.", "What's the weather?"];
+        let with_tools = tool_heavy_options();
+        let plain = PayloadOptions::default();
+
+        let tools_tokens = with_tools.estimated_tokens();
+        assert!(tools_tokens > 1_000);
+        assert_eq!(
+            model.history_budget(&reserved, &plain) - model.history_budget(&reserved, &with_tools),
+            tools_tokens
+        );
+        assert_eq!(
+            model.input_budget(&reserved[..1], &plain)
+                - model.input_budget(&reserved[..1], &with_tools),
+            tools_tokens
+        );
+    }
+
+    #[test]
+    fn separates_reasoning_from_answer() {
+        let body = json!({
+            "output": [
+                {
+                    "type": "reasoning",
+                    "summary": [{ "type": "summary_text", "text": "User wants a number." }],
+                    "content": [{ "type": "reasoning_text", "text": "2 + 2 is 4." }]
+                },
+                {
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [{ "type": "output_text", "text": "4" }]
+                }
+            ],
+            "usage": {
+                "input_tokens": 5,
+                "output_tokens": 30,
+                "output_tokens_details": { "reasoning_tokens": 27 },
+                "total_tokens": 35
+            }
+        });
+
+        let response = extract_output_text(&body);
+        assert_eq!(response.completion_text, "4");
+        assert_eq!(response.reasoning_text, "User wants a number.\n2 + 2 is 4.");
+        assert_eq!(response.answer_message().text, "4");
+        assert_eq!(response.completion_tokens, 30);
+        assert_eq!(response.reasoning_tokens, 27);
+        // The provider left out `cost`.
+        assert_eq!(response.cost, 0.0);
+    }
+
+    #[tokio::test]
+    async fn classifies_failures_for_users() {
+        let http_error = |status: u16| {
+            anyhow::Error::from(ApiError::Http {
+                status: reqwest::StatusCode::from_u16(status).expect("valid status"),
+                body: String::new(),
+            })
+        };
+        assert_eq!(FailureKind::of(&http_error(401)), FailureKind::Auth);
+        assert_eq!(FailureKind::of(&http_error(402)), FailureKind::Auth);
+        assert_eq!(FailureKind::of(&http_error(502)), FailureKind::Provider);
+        assert_eq!(
+            FailureKind::of(&anyhow!("OpenRouter response missing text output")),
+            FailureKind::Provider
+        );
+
+        // Nothing listens on port 1, so the connection is refused.
+        let refused = Client::new()
+            .get("http://127.0.0.1:1/")
+            .send()
+            .await
+            .expect_err("nothing listens on port 1");
+        let refused = anyhow::Error::from(refused).context("failed to send request");
+        assert_eq!(FailureKind::of(&refused), FailureKind::Network);
+    }
+
+    #[test]
+    fn answers_without_usage_count_as_free() {
+        let body = json!({
+            "output": [
+                {
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [{ "type": "output_text", "text": "Hello!" }]
+                },
+                { "type": "function_call", "arguments": "{}" }
+            ]
+        });
+
+        let response = finish_response(&body).expect("the answer is still usable");
+        assert_eq!(response.completion_text, "Hello!");
+        assert!(response.tool_calls.is_empty());
+        assert_eq!(
+            (
+                response.prompt_tokens,
+                response.completion_tokens,
+                response.total_tokens
+            ),
+            (0, 0, 0)
+        );
+        assert_eq!(response.cost, 0.0);
+    }
+
+    #[test]
+    fn extracts_function_calls() {
+        let body = json!({
+            "output": [{
+                "type": "function_call",
+                "call_id": "call_9",
+                "name": "lookup",
+                "arguments": "{\"q\":\"rust\"}"
+            }],
+            "usage": { "input_tokens": 5, "output_tokens": 3, "total_tokens": 8, "cost": 0.0 }
+        });
+
+        let response = extract_output_text(&body);
+        assert!(response.completion_text.is_empty());
+        assert_eq!(
+            response.tool_calls,
+            vec![ToolCall {
+                call_id: "call_9".to_string(),
+                name: "lookup".to_string(),
+                arguments: "{\"q\":\"rust\"}".to_string(),
+            }]
+        );
     }
 
     // Integration test that calls the live OpenRouter models endpoint.
     #[tokio::test(flavor = "multi_thread")]
     async fn live_openrouter_models() {
         let http = reqwest::Client::new();
-        let models = list_models(&http).await.expect("live models fetch failed");
+        let body = fetch_models_json(&http, DEFAULT_BASE_URL)
+            .await
+            .expect("live models fetch failed");
+        let models = parse_models(&body, &ContextOverrides::default())
+            .expect("live models response did not parse");
 
         assert!(
             !models.is_empty(),
@@ -295,9 +1603,16 @@ mod tests {
             text: "hi".to_string(),
         };
 
-        let payload = prepare_payload(&model, std::iter::once(&user_message), false);
+        let payload = prepare_payload(
+            &model,
+            std::iter::once(&user_message),
+            false,
+            &PayloadOptions::default(),
+        );
 
-        let result = send(&http, &api_key, payload).await.expect("send failed");
+        let result = send(&http, DEFAULT_BASE_URL, &api_key, payload)
+            .await
+            .expect("send failed");
 
         assert!(
             result.completion_tokens > 0,
@@ -308,4 +1623,50 @@ mod tests {
             "response should contain content"
         );
     }
+
+    #[test]
+    fn streams_deltas_until_the_completed_response() {
+        let stream = concat!(
+            ": OPENROUTER PROCESSING\n\n",
+            "data: {\"type\":\"response.created\",\"response\":{}}\n\n",
+            "data: {\"type\":\"response.reasoning_text.delta\",\"delta\":\"Adding.\"}\n\n",
+            "data: {\"type\":\"response.output_text.delta\",\"delta\":\"2 + 2 \"}\r\n\r\n",
+            "data: {\"type\":\"response.output_text.delta\",\"delta\":\"= 4 ✓\"}\n\n",
+            "data: {\"type\":\"response.completed\",\"response\":{",
+            "\"output\":[{\"type\":\"message\",\"content\":[{\"type\":\"output_text\",\"text\":\"2 + 2 = 4 ✓\"}]}],",
+            "\"usage\":{\"input_tokens\":5,\"output_tokens\":7,\"total_tokens\":12,\"cost\":0.001}}}\n\n",
+            "data: [DONE]\n\n",
+        );
+
+        // Chunks split events, lines and even multi-byte characters.
+        let mut buffer = SseBuffer::default();
+        let mut state = StreamState::default();
+        let mut updates = 0;
+        for chunk in stream.as_bytes().chunks(7) {
+            for event in buffer.push(chunk) {
+                if state.apply(&event) {
+                    updates += 1;
+                }
+            }
+        }
+
+        assert_eq!(updates, 3);
+        assert_eq!(state.progress.text, "2 + 2 = 4 ✓");
+        assert_eq!(state.progress.reasoning, "Adding.");
+        assert!(state.error.is_none());
+        let response =
+            finish_response(state.completed.as_ref().expect("completed")).expect("valid response");
+        assert_eq!(response.completion_text, "2 + 2 = 4 ✓");
+        assert_eq!(response.total_tokens, 12);
+        assert!(!response.interrupted);
+
+        let mut state = StreamState::default();
+        for event in SseBuffer::default().push(
+            b"data: {\"type\":\"response.failed\",\"response\":{\"error\":{\"code\":\"server_error\"}}}\n\n",
+        ) {
+            assert!(!state.apply(&event));
+        }
+        assert!(state.completed.is_none());
+        assert!(state.error.expect("failed").contains("server_error"));
+    }
 }
@@ -0,0 +1,301 @@
+use regex::Regex;
+
+/// Cleanup rules for answers, enabled with `RESPONSE_STRIP_RULES`. They run on the model's
+/// text before it is sent and stored; the raw text stays in the `Response`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StripRule {
+    /// Chat-template tokens some providers leak, e.g. `<|im_end|>` or `</s>`.
+    SpecialTokens,
+    /// The system prompt repeated at the start of the answer.
+    PromptEcho,
+    /// One tag pair wrapping the whole answer, e.g. `<answer>…</answer>`.
+    WrapperTags,
+    /// Quotes wrapping the whole answer.
+    Quotes,
+}
+
+/// Quote pairs removed by `StripRule::Quotes`; single quotes are left alone since an answer
+/// may well start and end with an apostrophe.
+const QUOTE_PAIRS: [(char, char); 4] = [('"', '"'), ('“', '”'), ('„', '“'), ('«', '»')];
+
+/// Longest `<|...|>` token treated as a chat-template artifact rather than text.
+const MAX_SPECIAL_TOKEN_LEN: usize = 32;
+
+impl StripRule {
+    /// Every rule, in the order they are applied.
+    pub const ALL: [StripRule; 4] = [
+        StripRule::SpecialTokens,
+        StripRule::PromptEcho,
+        StripRule::WrapperTags,
+        StripRule::Quotes,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|rule| rule.name() == name)
+    }
+
+    /// Name used in `RESPONSE_STRIP_RULES`.
+    pub fn name(self) -> &'static str {
+        match self {
+            StripRule::SpecialTokens => "special_tokens",
+            StripRule::PromptEcho => "prompt_echo",
+            StripRule::WrapperTags => "wrapper_tags",
+            StripRule::Quotes => "quotes",
+        }
+    }
+
+    /// Apply this rule alone; `system_prompts` are the prompts the request was sent with.
+    pub fn apply(self, text: &str, system_prompts: &[&str]) -> String {
+        match self {
+            StripRule::SpecialTokens => strip_special_tokens(text),
+            StripRule::PromptEcho => strip_prompt_echo(text, system_prompts),
+            StripRule::WrapperTags => strip_wrapper_tags(text),
+            StripRule::Quotes => strip_quotes(text),
+        }
+    }
+}
+
+/// Apply `rules` in `StripRule::ALL` order. An answer that would end up empty is kept as is,
+/// since sending nothing is worse than sending an artifact.
+pub fn apply_rules(rules: &[StripRule], text: &str, system_prompts: &[&str]) -> String {
+    let mut cleaned = text.to_string();
+    for rule in StripRule::ALL {
+        if rules.contains(&rule) {
+            cleaned = rule.apply(&cleaned, system_prompts);
+        }
+    }
+
+    if cleaned.trim().is_empty() {
+        text.to_string()
+    } else {
+        cleaned
+    }
+}
+
+/// Markers that end a leaked reasoning preamble, used by chats with `/clean_thinking on`
+/// unless `CLEAN_THINKING_PATTERNS` replaces them.
+pub const DEFAULT_THINKING_PATTERNS: [&str; 2] = [
+    r"(?i)</think(ing)?>",
+    r"(?im)^[\s*_#]*final answer[\s*_]*:[\s*_]*",
+];
+
+/// Removes "thinking out loud" that models without a separate reasoning field put before
+/// their answer: everything up to the end of the last match of any pattern is dropped.
+#[derive(Debug, Clone)]
+pub struct ThinkingFilter {
+    patterns: Vec<Regex>,
+}
+
+impl Default for ThinkingFilter {
+    fn default() -> Self {
+        Self::new(&DEFAULT_THINKING_PATTERNS).expect("default thinking patterns are valid")
+    }
+}
+
+impl ThinkingFilter {
+    pub fn new(patterns: &[&str]) -> Result<Self, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|err| format!("invalid pattern `{pattern}`: {err}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Parse a JSON array of regexes, e.g. `["(?i)^.*?Answer:"]`.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let patterns: Vec<String> = serde_json::from_str(json)
+            .map_err(|err| format!("expected a JSON array of regexes: {err}"))?;
+        if patterns.is_empty() {
+            return Err("at least one pattern is required".to_string());
+        }
+        Self::new(&patterns.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    /// The answer after the last marker, or the text unchanged when no pattern matches or
+    /// nothing would be left.
+    pub fn strip(&self, text: &str) -> String {
+        let cut = self
+            .patterns
+            .iter()
+            .filter_map(|pattern| pattern.find_iter(text).last())
+            .map(|found| found.end())
+            .max();
+        match cut.map(|cut| text[cut..].trim()) {
+            Some(answer) if !answer.is_empty() => answer.to_string(),
+            _ => text.to_string(),
+        }
+    }
+}
+
+fn strip_special_tokens(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let token_len = if tail.starts_with("<s>") {
+            Some(3)
+        } else if tail.starts_with("</s>") {
+            Some(4)
+        } else if let Some(body) = tail.strip_prefix("<|") {
+            body.find("|>")
+                .filter(|&end| end + 4 <= MAX_SPECIAL_TOKEN_LEN)
+                .filter(|&end| !body[..end].contains(char::is_whitespace))
+                .map(|end| end + 4)
+        } else {
+            None
+        };
+
+        match token_len {
+            Some(len) => rest = &tail[len..],
+            None => {
+                result.push('<');
+                rest = &tail[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    if result.len() == text.len() {
+        result
+    } else {
+        result.trim().to_string()
+    }
+}
+
+fn strip_prompt_echo(text: &str, system_prompts: &[&str]) -> String {
+    let trimmed = text.trim_start();
+    for prompt in system_prompts.iter().map(|p| p.trim()) {
+        if !prompt.is_empty()
+            && let Some(rest) = trimmed.strip_prefix(prompt)
+        {
+            return rest.trim_start().to_string();
+        }
+    }
+
+    text.to_string()
+}
+
+fn strip_wrapper_tags(text: &str) -> String {
+    let trimmed = text.trim();
+    let Some(after_open) = trimmed.strip_prefix('<') else {
+        return text.to_string();
+    };
+    let Some(name_end) = after_open.find('>') else {
+        return text.to_string();
+    };
+    let name = &after_open[..name_end];
+    let is_tag_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !is_tag_name {
+        return text.to_string();
+    }
+
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    match after_open[name_end + 1..].strip_suffix(close.as_str()) {
+        // Several sibling blocks (`<a>x</a><a>y</a>`) aren't one wrapper.
+        Some(inner) if !inner.contains(&open) => inner.trim().to_string(),
+        _ => text.to_string(),
+    }
+}
+
+fn strip_quotes(text: &str) -> String {
+    let trimmed = text.trim();
+    for (open, close) in QUOTE_PAIRS {
+        if let Some(inner) = trimmed
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+            && !inner.contains(open)
+            && !inner.contains(close)
+        {
+            return inner.trim().to_string();
+        }
+    }
+
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_each_artifact_on_its_own() {
+        assert_eq!(
+            StripRule::SpecialTokens.apply("Hello!<|im_end|>\n</s>", &[]),
+            "Hello!"
+        );
+        assert_eq!(
+            StripRule::SpecialTokens.apply("a <b> and <| not a token |>", &[]),
+            "a <b> and <| not a token |>"
+        );
+        assert_eq!(
+            StripRule::PromptEcho.apply("You are terse.\n\nSure.", &["", "You are terse."]),
+            "Sure."
+        );
+        assert_eq!(
+            StripRule::WrapperTags.apply(" <answer>\n42\n</answer>\n", &[]),
+            "42"
+        );
+        assert_eq!(
+            StripRule::WrapperTags.apply("<b>bold</b> and <b>more</b>", &[]),
+            "<b>bold</b> and <b>more</b>"
+        );
+        assert_eq!(StripRule::Quotes.apply("\"Hi there\"", &[]), "Hi there");
+        assert_eq!(StripRule::Quotes.apply("«Salut»", &[]), "Salut");
+        assert_eq!(
+            StripRule::Quotes.apply("\"a\" and \"b\"", &[]),
+            "\"a\" and \"b\""
+        );
+    }
+
+    #[test]
+    fn applies_enabled_rules_only_and_never_empties_the_answer() {
+        let raw = "<response>\"Done.\"</response><|eot_id|>";
+        assert_eq!(apply_rules(&StripRule::ALL, raw, &[]), "Done.");
+        assert_eq!(
+            apply_rules(&[StripRule::SpecialTokens], raw, &[]),
+            "<response>\"Done.\"</response>"
+        );
+        assert_eq!(apply_rules(&[], raw, &[]), raw);
+        assert_eq!(
+            apply_rules(&StripRule::ALL, "<|im_end|>", &[]),
+            "<|im_end|>"
+        );
+
+        for rule in StripRule::ALL {
+            assert_eq!(StripRule::parse(rule.name()), Some(rule));
+        }
+    }
+
+    #[test]
+    fn strips_thinking_before_the_last_marker() {
+        let filter = ThinkingFilter::default();
+        assert_eq!(filter.strip("<think>The user wants 2+2.</think>\n\n4"), "4");
+        assert_eq!(
+            filter.strip("Let me see: 2+2 is 4.\n**Final Answer:** 4"),
+            "4"
+        );
+        // Only the last marker counts, so a draft answer in the reasoning goes too.
+        assert_eq!(
+            filter.strip("Final answer: 5?\nNo, recount.\nFinal answer: 4"),
+            "4"
+        );
+        // A marker mid-sentence or at the very end leaves the answer alone.
+        let plain = "The final answer: it depends.";
+        assert_eq!(filter.strip(plain), plain);
+        assert_eq!(filter.strip("Thinking...</think>"), "Thinking...</think>");
+        assert_eq!(filter.strip("Just 4."), "Just 4.");
+
+        let custom = ThinkingFilter::parse(r#"["(?m)^Answer:"]"#).expect("valid patterns");
+        assert_eq!(custom.strip("Reasoning...\nAnswer: 4"), "4");
+        assert!(ThinkingFilter::parse("[]").is_err());
+        assert!(ThinkingFilter::parse(r#"["("]"#).is_err());
+        assert!(ThinkingFilter::parse(r#""Answer:""#).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket for one chat: holds up to `per_minute` requests and refills at
+/// `per_minute` a minute, so short bursts pass while a steady flood is slowed down.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// A full bucket, so a chat's first burst is never limited.
+    pub fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(per_minute),
+            updated: now,
+        }
+    }
+
+    /// Take one request, or return how long until the next one is allowed.
+    pub fn try_take(&mut self, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        assert!(per_minute > 0, "a disabled limit has no buckets");
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        // The limit may have been lowered at runtime since the last request.
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(3, start);
+        for _ in 0..3 {
+            assert!(bucket.try_take(3, start).is_ok());
+        }
+        // One request is refilled every 20 seconds.
+        let wait = bucket.try_take(3, start).expect_err("bucket is empty");
+        assert_eq!(wait.as_secs_f64().ceil(), 20.0);
+        assert!(bucket.try_take(3, start + Duration::from_secs(10)).is_err());
+        assert!(bucket.try_take(3, start + Duration::from_secs(20)).is_ok());
+
+        // A long pause refills only up to the capacity.
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert!(bucket.try_take(3, later).is_ok());
+        }
+        assert!(bucket.try_take(3, later).is_err());
+    }
+}
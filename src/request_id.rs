@@ -0,0 +1,93 @@
+use flexi_logger::DeferredNow;
+use log::Record;
+use std::{
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+tokio::task_local! {
+    /// Correlation id of the update being handled by the current task.
+    static REQUEST_ID: String;
+}
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+/// Per-process salt so ids don't repeat across restarts in the same log file.
+static SALT: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// Short random-looking id, unique within the process.
+pub fn new_id() -> String {
+    let sequence = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}", SALT.hash_one(sequence) as u32)
+}
+
+/// Run `future` under a fresh correlation id; every log line it writes carries the id.
+pub async fn scope<F: Future>(future: F) -> F::Output {
+    REQUEST_ID.scope(new_id(), future).await
+}
+
+/// Carry the current id (if any) into a future that will run on another task.
+pub fn propagate<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let id = current();
+    async move {
+        match id {
+            Some(id) => REQUEST_ID.scope(id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// `flexi_logger::default_format` with the correlation id after the module path.
+pub fn log_format(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write!(
+        w,
+        "{} [{}] ",
+        record.level(),
+        record.module_path().unwrap_or("<unnamed>"),
+    )?;
+    if let Some(id) = current() {
+        write!(w, "[req {id}] ")?;
+    }
+
+    write!(w, "{}", record.args())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..1000).map(|_| new_id()).collect();
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.iter().all(|id| id.len() == 8));
+    }
+
+    #[tokio::test]
+    async fn scope_sets_and_propagates_the_id() {
+        assert_eq!(current(), None);
+
+        let (inner, spawned) = scope(async {
+            let inner = current().expect("id set inside the scope");
+            let spawned = tokio::spawn(propagate(async { current() }))
+                .await
+                .expect("spawned task panicked");
+            (inner, spawned)
+        })
+        .await;
+
+        assert_eq!(spawned.as_deref(), Some(inner.as_str()));
+        assert_eq!(current(), None);
+    }
+}
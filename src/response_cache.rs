@@ -0,0 +1,121 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Completions of recent deterministic requests, keyed by a hash of the full payload
+/// (model, messages, tools). Least recently used entries are evicted first.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<u64, (Instant, String)>,
+    /// Keys from least to most recently used.
+    order: VecDeque<u64>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        assert!(
+            capacity > 0,
+            "response cache needs room for at least one entry"
+        );
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Cache key for a request payload, or `None` when sampling may make answers vary: only
+    /// a temperature set to zero is deterministic, as providers sample when none is sent.
+    pub fn key(payload: &serde_json::Value) -> Option<u64> {
+        let temperature = payload.get("temperature").and_then(|t| t.as_f64());
+        if !temperature.is_some_and(|t| t <= 0.0) {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        payload.to_string().hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    pub fn get(&mut self, key: u64, now: Instant) -> Option<String> {
+        let (stored_at, text) = self.entries.get(&key)?;
+        if now.duration_since(*stored_at) > self.ttl {
+            self.entries.remove(&key);
+            self.order.retain(|k| *k != key);
+            return None;
+        }
+
+        let text = text.clone();
+        self.touch(key);
+        Some(text)
+    }
+
+    pub fn insert(&mut self, key: u64, text: String, now: Instant) {
+        if self.entries.insert(key, (now, text)).is_some() {
+            self.touch(key);
+            return;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            let evicted = self.order.pop_front().expect("order holds every key");
+            self.entries.remove(&evicted);
+        }
+        assert_eq!(self.entries.len(), self.order.len());
+    }
+
+    fn touch(&mut self, key: u64) {
+        let position = self
+            .order
+            .iter()
+            .position(|k| *k == key)
+            .expect("cached key missing from LRU order");
+        self.order.remove(position);
+        self.order.push_back(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn evicts_least_recently_used_and_expires() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(2, Duration::from_secs(60));
+        cache.insert(1, "one".to_string(), start);
+        cache.insert(2, "two".to_string(), start);
+        assert_eq!(cache.get(1, start).as_deref(), Some("one"));
+
+        cache.insert(3, "three".to_string(), start);
+        assert_eq!(cache.get(2, start), None);
+        assert_eq!(cache.get(1, start).as_deref(), Some("one"));
+        assert_eq!(cache.get(3, start).as_deref(), Some("three"));
+
+        assert_eq!(cache.get(1, start + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn skips_sampled_requests() {
+        let payload = json!({ "model": "m", "input": [{ "role": "user", "content": "hi" }] });
+        assert_eq!(ResponseCache::key(&payload), None);
+
+        let mut sampled = payload.clone();
+        sampled["temperature"] = json!(0.7);
+        assert_eq!(ResponseCache::key(&sampled), None);
+
+        let mut greedy = payload;
+        greedy["temperature"] = json!(0);
+        assert!(ResponseCache::key(&greedy).is_some());
+        assert_eq!(
+            ResponseCache::key(&greedy),
+            ResponseCache::key(&greedy.clone())
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, TimeZone, Utc, Weekday};
+
+/// Most schedules a single chat may keep.
+pub const MAX_SCHEDULES_PER_CHAT: usize = 10;
+
+/// Days a schedule runs on; the time of day is kept next to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Recurrence {
+    Daily,
+    /// Monday to Friday.
+    Weekdays,
+    Weekly(Weekday),
+}
+
+impl Recurrence {
+    /// Parse `daily`, `weekdays` or a weekday name such as `mon` or `monday`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "daily" => Some(Recurrence::Daily),
+            "weekdays" => Some(Recurrence::Weekdays),
+            day => day.parse::<Weekday>().ok().map(Recurrence::Weekly),
+        }
+    }
+
+    /// Stored form, read back with [`Recurrence::parse`].
+    pub fn to_db(self) -> String {
+        match self {
+            Recurrence::Daily => "daily".to_string(),
+            Recurrence::Weekdays => "weekdays".to_string(),
+            Recurrence::Weekly(day) => day.to_string().to_ascii_lowercase(),
+        }
+    }
+
+    fn runs_on(self, day: Weekday) -> bool {
+        match self {
+            Recurrence::Daily => true,
+            Recurrence::Weekdays => !matches!(day, Weekday::Sat | Weekday::Sun),
+            Recurrence::Weekly(weekday) => day == weekday,
+        }
+    }
+}
+
+impl std::fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recurrence::Daily => write!(f, "daily"),
+            Recurrence::Weekdays => write!(f, "on weekdays"),
+            Recurrence::Weekly(day) => write!(f, "every {day}"),
+        }
+    }
+}
+
+/// Parse a 24-hour `HH:MM` time of day.
+pub fn parse_time(text: &str) -> Option<NaiveTime> {
+    let (hours, minutes) = text.split_once(':')?;
+    if hours.is_empty() || hours.len() > 2 || minutes.len() != 2 {
+        return None;
+    }
+    NaiveTime::from_hms_opt(hours.parse().ok()?, minutes.parse().ok()?, 0)
+}
+
+/// Drop one pair of matching quotes around the prompt, as in `/schedule daily 09:00 "..."`.
+pub fn unquote(prompt: &str) -> &str {
+    let prompt = prompt.trim();
+    for (open, close) in [('"', '"'), ('“', '”'), ('\'', '\'')] {
+        if let Some(inner) = prompt
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+            && !inner.trim().is_empty()
+        {
+            return inner.trim();
+        }
+    }
+    prompt
+}
+
+/// First run strictly after `after`, with `time` read in the chat's offset.
+pub fn next_run(
+    recurrence: Recurrence,
+    time: NaiveTime,
+    offset: FixedOffset,
+    after: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let today = after.with_timezone(&offset).date_naive();
+    // Today is checked too, so a week and a day covers every recurrence.
+    (0..=7)
+        .map(|days| today + chrono::Days::new(days))
+        .filter(|date| recurrence.runs_on(date.weekday()))
+        .map(|date| {
+            offset
+                .from_local_datetime(&date.and_time(time))
+                .single()
+                .expect("fixed offsets map local times uniquely")
+                .with_timezone(&Utc)
+        })
+        .find(|run| *run > after)
+        .expect("every recurrence runs within a week")
+}
+
+/// A stored schedule, as listed by `/schedule list`.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub id: i64,
+    pub chat_id: i64,
+    pub recurrence: Recurrence,
+    /// Local time of day in the chat's `/timezone`.
+    pub time: NaiveTime,
+    pub prompt: String,
+    /// Unix timestamp (seconds) of the next run.
+    pub next_run: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text)
+            .expect("valid timestamp")
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_schedule_parts() {
+        assert_eq!(Recurrence::parse("Daily"), Some(Recurrence::Daily));
+        assert_eq!(
+            Recurrence::parse("monday"),
+            Some(Recurrence::Weekly(Weekday::Mon))
+        );
+        assert_eq!(Recurrence::parse("hourly"), None);
+        for recurrence in [
+            Recurrence::Daily,
+            Recurrence::Weekdays,
+            Recurrence::Weekly(Weekday::Fri),
+        ] {
+            assert_eq!(Recurrence::parse(&recurrence.to_db()), Some(recurrence));
+        }
+
+        assert_eq!(parse_time("9:05"), NaiveTime::from_hms_opt(9, 5, 0));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("09:5"), None);
+        assert_eq!(unquote(" \"Summarize the news\" "), "Summarize the news");
+        assert_eq!(unquote("say \"hi\""), "say \"hi\"");
+    }
+
+    #[test]
+    fn next_run_follows_the_chat_offset() {
+        let nine = NaiveTime::from_hms_opt(9, 0, 0).expect("valid time");
+        let berlin = FixedOffset::east_opt(3600).expect("valid offset");
+
+        // 07:30 UTC is 08:30 local: today's 09:00 is still ahead.
+        let now = utc("2024-03-08T07:30:00Z");
+        assert_eq!(
+            next_run(Recurrence::Daily, nine, berlin, now),
+            utc("2024-03-08T08:00:00Z")
+        );
+        // Exactly at the run time moves on to the next day.
+        assert_eq!(
+            next_run(Recurrence::Daily, nine, berlin, utc("2024-03-08T08:00:00Z")),
+            utc("2024-03-09T08:00:00Z")
+        );
+        // Friday after the run: weekdays skip to Monday.
+        assert_eq!(
+            next_run(
+                Recurrence::Weekdays,
+                nine,
+                berlin,
+                utc("2024-03-08T10:00:00Z")
+            ),
+            utc("2024-03-11T08:00:00Z")
+        );
+        assert_eq!(
+            next_run(
+                Recurrence::Weekly(Weekday::Fri),
+                nine,
+                berlin,
+                utc("2024-03-08T10:00:00Z")
+            ),
+            utc("2024-03-15T08:00:00Z")
+        );
+    }
+}
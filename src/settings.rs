@@ -0,0 +1,214 @@
+use serde_json::{Map, Value, json};
+
+use crate::conversation::{Conversation, ReplyMode};
+use crate::openrouter_api;
+use crate::timezone;
+
+/// Per-chat settings that `/settings export` dumps and `/settings import` may change.
+/// Authorization flags and the Telegram user name are deliberately not part of it.
+const KNOWN_FIELDS: [&str; 14] = [
+    "model_id",
+    "system_prompt",
+    "openrouter_api_key",
+    "tools",
+    "title",
+    "ephemeral",
+    "voice",
+    "web_search",
+    "cache",
+    "show_thinking",
+    "clean_thinking",
+    "stream",
+    "utc_offset",
+    "reply_mode",
+];
+
+/// Fields present in an import; the outer `Option` is "leave unchanged", the inner one clears.
+#[derive(Debug, Default, PartialEq)]
+pub struct SettingsPatch {
+    pub model_id: Option<Option<String>>,
+    pub system_prompt: Option<Option<String>>,
+    pub openrouter_api_key: Option<Option<String>>,
+    pub tools: Option<Option<Value>>,
+    pub title: Option<Option<String>>,
+    pub ephemeral: Option<bool>,
+    pub voice: Option<bool>,
+    pub web_search: Option<bool>,
+    pub cache: Option<bool>,
+    pub show_thinking: Option<bool>,
+    pub clean_thinking: Option<bool>,
+    pub stream: Option<Option<bool>>,
+    pub utc_offset: Option<chrono::FixedOffset>,
+    pub reply_mode: Option<ReplyMode>,
+}
+
+impl SettingsPatch {
+    pub fn is_empty(&self) -> bool {
+        *self == SettingsPatch::default()
+    }
+}
+
+/// Serialize the chat's settings; `api_key` is what to show for the key (masked or not).
+pub fn export(conv: &Conversation, api_key: Option<String>) -> Value {
+    let settings = json!({
+        "model_id": conv.model_id,
+        "system_prompt": conv.system_prompt.as_ref().map(|prompt| prompt.text.clone()),
+        "openrouter_api_key": api_key,
+        "tools": conv.tools,
+        "title": conv.title,
+        "ephemeral": conv.ephemeral,
+        "voice": conv.voice,
+        "web_search": conv.web_search,
+        "cache": conv.cache,
+        "show_thinking": conv.show_thinking,
+        "clean_thinking": conv.clean_thinking,
+        "stream": conv.stream,
+        "utc_offset": timezone::format_utc_offset(conv.utc_offset),
+        "reply_mode": conv.reply_mode.to_string(),
+    });
+    assert_eq!(
+        settings.as_object().expect("settings are an object").len(),
+        KNOWN_FIELDS.len(),
+        "export doesn't cover every known field"
+    );
+
+    settings
+}
+
+/// Parse and validate an import. Unknown fields and wrongly typed values are rejected as a
+/// whole so a bad import never half-applies.
+pub fn parse_import(json: &str) -> Result<SettingsPatch, String> {
+    let value: Value = serde_json::from_str(json).map_err(|err| format!("invalid JSON: {err}"))?;
+    let Value::Object(fields) = value else {
+        return Err("settings must be a JSON object".to_string());
+    };
+
+    if let Some(unknown) = fields
+        .keys()
+        .find(|key| !KNOWN_FIELDS.contains(&key.as_str()))
+    {
+        return Err(format!(
+            "unknown field `{unknown}` (known: {})",
+            KNOWN_FIELDS.join(", ")
+        ));
+    }
+
+    let openrouter_api_key = optional_string(&fields, "openrouter_api_key")?;
+    if let Some(Some(key)) = &openrouter_api_key
+        && (key.contains("...") || key.ends_with("***"))
+    {
+        return Err(
+            "openrouter_api_key is masked; remove the field or provide the full key".to_string(),
+        );
+    }
+
+    let tools = match fields.get("tools") {
+        None => None,
+        Some(Value::Null) => Some(None),
+        Some(tools) => {
+            openrouter_api::validate_tools(tools).map_err(|err| format!("invalid tools: {err}"))?;
+            Some(Some(tools.clone()))
+        }
+    };
+
+    let utc_offset = match fields.get("utc_offset") {
+        None => None,
+        Some(Value::String(text)) => Some(
+            timezone::parse_utc_offset(text)
+                .ok_or_else(|| format!("invalid utc_offset `{text}` (e.g. UTC+03:00)"))?,
+        ),
+        Some(_) => return Err("`utc_offset` must be a string such as UTC+03:00".to_string()),
+    };
+
+    let reply_mode = match fields.get("reply_mode") {
+        None => None,
+        Some(Value::String(text)) => Some(ReplyMode::parse(text).ok_or_else(|| {
+            format!("invalid reply_mode `{text}` (expected auto, thread or inline)")
+        })?),
+        Some(_) => return Err("`reply_mode` must be auto, thread or inline".to_string()),
+    };
+
+    Ok(SettingsPatch {
+        model_id: optional_string(&fields, "model_id")?,
+        system_prompt: optional_string(&fields, "system_prompt")?,
+        openrouter_api_key,
+        tools,
+        title: optional_string(&fields, "title")?,
+        ephemeral: optional_bool(&fields, "ephemeral")?,
+        voice: optional_bool(&fields, "voice")?,
+        web_search: optional_bool(&fields, "web_search")?,
+        cache: optional_bool(&fields, "cache")?,
+        show_thinking: optional_bool(&fields, "show_thinking")?,
+        clean_thinking: optional_bool(&fields, "clean_thinking")?,
+        stream: match fields.get("stream") {
+            None => None,
+            Some(Value::Null) => Some(None),
+            Some(Value::Bool(stream)) => Some(Some(*stream)),
+            Some(_) => return Err("`stream` must be true, false or null".to_string()),
+        },
+        utc_offset,
+        reply_mode,
+    })
+}
+
+/// A string-or-null field; blank strings count as null, like `none` for the setter commands.
+fn optional_string(
+    fields: &Map<String, Value>,
+    name: &str,
+) -> Result<Option<Option<String>>, String> {
+    match fields.get(name) {
+        None => Ok(None),
+        Some(Value::Null) => Ok(Some(None)),
+        Some(Value::String(text)) => {
+            let text = text.trim();
+            Ok(Some((!text.is_empty()).then(|| text.to_string())))
+        }
+        Some(_) => Err(format!("`{name}` must be a string or null")),
+    }
+}
+
+fn optional_bool(fields: &Map<String, Value>, name: &str) -> Result<Option<bool>, String> {
+    match fields.get(name) {
+        None => Ok(None),
+        Some(Value::Bool(value)) => Ok(Some(*value)),
+        Some(_) => Err(format!("`{name}` must be true or false")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_partial_import() {
+        let patch = parse_import(
+            r#"{"model_id": "openai/gpt-4o", "system_prompt": null, "title": "  ", "ephemeral": true}"#,
+        )
+        .expect("valid import");
+        assert_eq!(
+            patch,
+            SettingsPatch {
+                model_id: Some(Some("openai/gpt-4o".to_string())),
+                system_prompt: Some(None),
+                title: Some(None),
+                ephemeral: Some(true),
+                ..Default::default()
+            }
+        );
+        assert!(parse_import("{}").expect("empty object").is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_imports() {
+        assert!(parse_import("[]").is_err());
+        assert!(parse_import("not json").is_err());
+        assert!(parse_import(r#"{"is_admin": true}"#).is_err());
+        assert!(parse_import(r#"{"model_id": 5}"#).is_err());
+        assert!(parse_import(r#"{"ephemeral": "yes"}"#).is_err());
+        assert!(parse_import(r#"{"voice": 1}"#).is_err());
+        assert!(parse_import(r#"{"stream": "on"}"#).is_err());
+        assert!(parse_import(r#"{"utc_offset": "Mars/Olympus"}"#).is_err());
+        assert!(parse_import(r#"{"tools": [{"type": "function"}]}"#).is_err());
+        assert!(parse_import(r#"{"openrouter_api_key": "sk-or-v1-bab...68c"}"#).is_err());
+    }
+}
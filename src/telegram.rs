@@ -1,123 +1,1087 @@
-use crate::panic_handler::fatal_panic;
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 use teloxide::{
+    ApiError, RequestError,
+    net::Download,
+    payloads::SendDocumentSetters,
     payloads::SendMessageSetters,
     prelude::{Bot, Requester},
-    types::{ChatId, MessageId, ParseMode, ReplyParameters},
+    types::{
+        ChatId, InputFile, Message, MessageEntityKind, MessageId, ParseMode, PhotoSize,
+        ReplyParameters, UserId,
+    },
 };
 
 const TELEGRAM_MAX_MESSAGE_LENGTH: usize = 4096;
 
+static SEND_RETRIES: AtomicU32 = AtomicU32::new(2);
+static SEND_RETRY_DELAY_MS: AtomicU64 = AtomicU64::new(500);
+
+/// Set how often the send helpers retry network failures (`TELEGRAM_SEND_RETRIES`) and the
+/// first backoff delay. Called once at startup.
+pub fn configure_send_retries(retries: u32, delay: Duration) {
+    SEND_RETRIES.store(retries, Ordering::Relaxed);
+    SEND_RETRY_DELAY_MS.store(delay.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Whether a failed send is worth repeating: the request may never have reached Telegram.
+/// API errors (forbidden, bad request, ...) are Telegram's final answer and are not retried.
+pub fn is_retryable_send_error(err: &RequestError) -> bool {
+    matches!(err, RequestError::Network(_) | RequestError::Io(_))
+}
+
+/// How a failed message edit is handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EditFailure {
+    /// The message already shows this text (Telegram compares it trimmed); nothing to do.
+    NotModified,
+    /// Flood control: Telegram accepts no further edits for this long.
+    RetryAfter(Duration),
+    /// Anything else; reported to the caller.
+    Other,
+}
+
+/// Sort a failed `edit_message_text` into the cases `LiveReply` treats differently.
+pub fn classify_edit_error(err: &RequestError) -> EditFailure {
+    match err {
+        RequestError::Api(ApiError::MessageNotModified) => EditFailure::NotModified,
+        RequestError::RetryAfter(seconds) => EditFailure::RetryAfter(seconds.duration()),
+        _ => EditFailure::Other,
+    }
+}
+
+/// Run `send` until it succeeds, fails terminally, or `retries` extra attempts are used up,
+/// doubling `delay` after each retry.
+async fn retry_send<T, F, Fut>(
+    retries: u32,
+    delay: Duration,
+    mut send: F,
+) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match send().await {
+            Err(err) if attempt < retries && is_retryable_send_error(&err) => {
+                let wait = delay * 2u32.pow(attempt);
+                attempt += 1;
+                log::warn!(
+                    "Telegram send failed ({}); retry {}/{} in {:?}",
+                    err,
+                    attempt,
+                    retries,
+                    wait
+                );
+                tokio::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// `retry_send` with the limits from `configure_send_retries`.
+async fn send_with_retries<T, F, Fut>(send: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let retries = SEND_RETRIES.load(Ordering::Relaxed);
+    let delay = Duration::from_millis(SEND_RETRY_DELAY_MS.load(Ordering::Relaxed));
+    retry_send(retries, delay, send).await
+}
+
 /// Escape a string so it is safe to send with `ParseMode::MarkdownV2`.
 pub fn escape_markdown_v2(text: &str) -> String {
     teloxide::utils::markdown::escape(text)
 }
 
+/// Escape the contents of a MarkdownV2 code span or block, where only `` ` `` and `\\`
+/// are special.
+fn escape_markdown_v2_code(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if matches!(ch, '`' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+fn push_escaped(out: &mut String, ch: char) {
+    if "_*[]()~`>#+-=|{}.!\\".contains(ch) {
+        out.push('\\');
+    }
+    out.push(ch);
+}
+
+/// Convert the Markdown models write (fenced and inline code, bold, italic, strikethrough,
+/// links, headings, bullet and numbered lists, quotes) into MarkdownV2. Everything else is
+/// escaped, so the result always parses; a code block the model left open is closed.
+pub fn markdown_to_telegram(text: &str) -> String {
+    let mut lines = Vec::new();
+    // Fence character and length of the open code block.
+    let mut fence: Option<(char, usize)> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if let Some((fence_char, fence_len)) = fence {
+            let run = trimmed.chars().take_while(|&ch| ch == fence_char).count();
+            if run >= fence_len && trimmed[run..].trim().is_empty() {
+                lines.push("```".to_string());
+                fence = None;
+            } else {
+                lines.push(escape_markdown_v2_code(line));
+            }
+            continue;
+        }
+
+        if let Some(fence_char) = trimmed.chars().next().filter(|ch| matches!(ch, '`' | '~')) {
+            let run = trimmed.chars().take_while(|&ch| ch == fence_char).count();
+            let info = &trimmed[run..];
+            if run >= 3 && !(fence_char == '`' && info.contains('`')) {
+                let language: String = info
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '+' | '-' | '_' | '#'))
+                    .collect();
+                lines.push(format!("```{language}"));
+                fence = Some((fence_char, run));
+                continue;
+            }
+        }
+
+        lines.push(markdown_line_to_telegram(line));
+    }
+    if fence.is_some() {
+        lines.push("```".to_string());
+    }
+
+    lines.join("\n")
+}
+
+/// Convert one line outside code blocks: block markers first, then inline formatting.
+fn markdown_line_to_telegram(line: &str) -> String {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+
+    let hashes = trimmed.chars().take_while(|&ch| ch == '#').count();
+    if (1..=6).contains(&hashes)
+        && let Some(title) = trimmed[hashes..].strip_prefix(' ')
+    {
+        // Headings are bold already; bold inside them would nest.
+        let title = title.trim().trim_end_matches('#').trim();
+        let title = title.replace("**", "").replace("__", "");
+        return match title.as_str() {
+            "" => String::new(),
+            title => format!("*{}*", inline_to_telegram(title, Nesting::BOLD)),
+        };
+    }
+
+    if trimmed.len() >= 3
+        && let Some(first) = trimmed
+            .chars()
+            .next()
+            .filter(|ch| matches!(ch, '-' | '*' | '_'))
+        && trimmed.chars().all(|ch| ch == first || ch == ' ')
+    {
+        return "──────────".to_string();
+    }
+
+    if let Some(item) = ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|marker| trimmed.strip_prefix(marker))
+    {
+        return format!("{indent}• {}", inline_to_telegram(item, Nesting::NONE));
+    }
+
+    let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+    if (1..=9).contains(&digits)
+        && let Some(item) = trimmed[digits..]
+            .strip_prefix(". ")
+            .or_else(|| trimmed[digits..].strip_prefix(") "))
+    {
+        return format!(
+            "{indent}{}\\. {}",
+            &trimmed[..digits],
+            inline_to_telegram(item, Nesting::NONE)
+        );
+    }
+
+    if let Some(quoted) = trimmed.strip_prefix('>') {
+        return format!(">{}", markdown_line_to_telegram(quoted.trim_start()));
+    }
+
+    let mut out = String::from(indent);
+    out.push_str(&inline_to_telegram(trimmed, Nesting::NONE));
+    out
+}
+
+/// Entities enclosing the text being converted. Telegram rejects an entity nested in one of
+/// its own kind and code inside any other entity, so those are emitted as escaped text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Nesting {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    link: bool,
+}
+
+impl Nesting {
+    const NONE: Self = Self {
+        bold: false,
+        italic: false,
+        strike: false,
+        link: false,
+    };
+    const BOLD: Self = Self {
+        bold: true,
+        ..Self::NONE
+    };
+
+    fn any(self) -> bool {
+        self != Self::NONE
+    }
+}
+
+fn inline_to_telegram(text: &str, nesting: Nesting) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len() + text.len() / 4);
+    convert_inline(&chars, nesting, &mut out);
+    out
+}
+
+fn convert_inline(chars: &[char], nesting: Nesting, out: &mut String) {
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        let consumed = match ch {
+            '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => {
+                push_escaped(out, chars[i + 1]);
+                2
+            }
+            '`' => convert_code_span(chars, i, nesting, out),
+            '*' | '_' | '~' => convert_emphasis(chars, i, nesting, out),
+            '[' if !nesting.link => convert_link(chars, i, i + 1, nesting, out),
+            '!' if !nesting.link && chars.get(i + 1) == Some(&'[') => {
+                convert_link(chars, i, i + 2, nesting, out)
+            }
+            _ => 0,
+        };
+        if consumed > 0 {
+            i += consumed;
+        } else {
+            push_escaped(out, ch);
+            i += 1;
+        }
+    }
+}
+
+/// Number of `ch` in a row starting at `start`.
+fn run_length(chars: &[char], start: usize, ch: char) -> usize {
+    chars[start..].iter().take_while(|&&c| c == ch).count()
+}
+
+/// A `` `code` `` span starting at `start`; returns the chars consumed, 0 if unclosed.
+fn convert_code_span(chars: &[char], start: usize, nesting: Nesting, out: &mut String) -> usize {
+    let run = run_length(chars, start, '`');
+    let mut end = start + run;
+    while end < chars.len() {
+        let closing = run_length(chars, end, '`');
+        if closing == run {
+            break;
+        }
+        end += closing.max(1);
+    }
+    if end >= chars.len() {
+        // Unclosed: the whole run is literal.
+        for _ in 0..run {
+            push_escaped(out, '`');
+        }
+        return run;
+    }
+
+    let code: String = chars[start + run..end].iter().collect();
+    let code = match code
+        .strip_prefix(' ')
+        .and_then(|code| code.strip_suffix(' '))
+    {
+        Some(inner) if !inner.trim().is_empty() => inner.to_string(),
+        _ => code,
+    };
+    if code.is_empty() {
+        return 0;
+    }
+    if nesting.any() {
+        for ch in code.chars() {
+            push_escaped(out, ch);
+        }
+    } else {
+        out.push('`');
+        out.push_str(&escape_markdown_v2_code(&code));
+        out.push('`');
+    }
+    end + run - start
+}
+
+/// `*italic*`, `_italic_`, `**bold**`, `__bold__`, `***both***` or `~~struck~~` starting at
+/// `start`; returns the chars consumed, 0 if it isn't one.
+fn convert_emphasis(chars: &[char], start: usize, nesting: Nesting, out: &mut String) -> usize {
+    let marker = chars[start];
+    let run = run_length(chars, start, marker).min(3);
+    let word_char = |index: Option<usize>| {
+        index
+            .and_then(|index| chars.get(index))
+            .is_some_and(|ch| ch.is_alphanumeric())
+    };
+    // `snake_case` and `2*3*4` stay as they are.
+    if marker == '_' && word_char(start.checked_sub(1)) {
+        return 0;
+    }
+    if marker == '~' && run != 2 {
+        return 0;
+    }
+    let open_end = start + run;
+    if chars.get(open_end).is_none_or(|ch| ch.is_whitespace()) {
+        return 0;
+    }
+
+    // The first matching run that follows non-space text closes it. A longer run also
+    // closes something nested (`**bold *italic***`); its last `run` markers are ours.
+    let mut close = open_end + 1;
+    let mut found = false;
+    while close < chars.len() {
+        let closing = run_length(chars, close, marker);
+        if (closing == run || (closing > run && run > 1))
+            && !chars[close - 1].is_whitespace()
+            && !(marker == '_' && word_char(Some(close + closing)))
+        {
+            close += closing - run;
+            found = true;
+            break;
+        }
+        close += closing.max(1);
+    }
+    if !found {
+        return 0;
+    }
+
+    let inner = &chars[open_end..close];
+    let (tags, inner_nesting) = match (marker, run) {
+        ('~', _) => (
+            "~",
+            Nesting {
+                strike: true,
+                ..nesting
+            },
+        ),
+        (_, 1) => (
+            "_",
+            Nesting {
+                italic: true,
+                ..nesting
+            },
+        ),
+        (_, 2) => (
+            "*",
+            Nesting {
+                bold: true,
+                ..nesting
+            },
+        ),
+        _ => (
+            "*_",
+            Nesting {
+                bold: true,
+                italic: true,
+                ..nesting
+            },
+        ),
+    };
+    let already_open = match tags {
+        "~" => nesting.strike,
+        "_" => nesting.italic,
+        "*" => nesting.bold,
+        _ => nesting.bold || nesting.italic,
+    };
+    if already_open {
+        convert_inline(inner, nesting, out);
+    } else {
+        out.push_str(tags);
+        convert_inline(inner, inner_nesting, out);
+        out.extend(tags.chars().rev());
+    }
+    close + run - start
+}
+
+/// `[text](url)` (or `![alt](url)`, shown as a link) where `text_start` follows the `[`;
+/// returns the chars consumed, 0 if it isn't a link.
+fn convert_link(
+    chars: &[char],
+    start: usize,
+    text_start: usize,
+    nesting: Nesting,
+    out: &mut String,
+) -> usize {
+    let Some(text_end) = chars[text_start..]
+        .iter()
+        .position(|&ch| ch == ']')
+        .map(|offset| text_start + offset)
+    else {
+        return 0;
+    };
+    if chars.get(text_end + 1) != Some(&'(') {
+        return 0;
+    }
+
+    // URLs may contain balanced parentheses, as Wikipedia's do.
+    let mut depth = 0usize;
+    let mut url_end = None;
+    for (index, &ch) in chars.iter().enumerate().skip(text_end + 2) {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                url_end = Some(index);
+                break;
+            }
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    let Some(url_end) = url_end else {
+        return 0;
+    };
+    let target: String = chars[text_end + 2..url_end].iter().collect();
+    // Drop a `"title"` after the URL.
+    let Some(url) = target.split_whitespace().next() else {
+        return 0;
+    };
+
+    let text = &chars[text_start..text_end];
+    out.push('[');
+    if text.iter().all(|ch| ch.is_whitespace()) {
+        for ch in url.chars() {
+            push_escaped(out, ch);
+        }
+    } else {
+        convert_inline(
+            text,
+            Nesting {
+                link: true,
+                ..nesting
+            },
+            out,
+        );
+    }
+    out.push_str("](");
+    for ch in url.chars() {
+        if matches!(ch, ')' | '\\') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out.push(')');
+    url_end + 1 - start
+}
+
+/// Undo MarkdownV2 escaping so a rejected formatted chunk can be shown as plain text.
+pub fn unescape_markdown_v2(text: &str) -> String {
+    const ESCAPABLE: &str = "_*[]()~`>#+-=|{}.!\\";
+
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '\\'
+            && let Some(&next) = chars.peek()
+            && ESCAPABLE.contains(next)
+        {
+            result.push(next);
+            chars.next();
+            continue;
+        }
+        result.push(ch);
+    }
+
+    result
+}
+
 async fn send_formatted_checked(
     bot: &Bot,
     chat_id: ChatId,
     text: &str,
     reply_to: Option<MessageId>,
     parse_mode: ParseMode,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<MessageId> {
     assert!(
         text.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH,
         "message exceeds telegram max length"
     );
 
-    match reply_to {
-        Some(reply_id) => {
-            let reply = ReplyParameters {
+    let sent = send_with_retries(|| {
+        let request = bot.send_message(chat_id, text).parse_mode(parse_mode);
+        match reply_to {
+            Some(reply_id) => request.reply_parameters(ReplyParameters {
                 message_id: reply_id,
                 ..Default::default()
-            };
-            bot.send_message(chat_id, text)
-                .reply_parameters(reply)
-                .parse_mode(parse_mode)
-                .await?;
-        }
-        None => {
-            bot.send_message(chat_id, text)
-                .parse_mode(parse_mode)
-                .await?;
+            }),
+            None => request,
         }
-    }
+        .into_future()
+    })
+    .await?;
 
-    Ok(())
+    Ok(sent.id)
 }
 
+/// Send one plain message of at most the Telegram limit and return its id.
 pub async fn send_message_checked(
     bot: &Bot,
     chat_id: ChatId,
     text: &str,
     reply_to: Option<MessageId>,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<MessageId> {
     assert!(
         text.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH,
         "message exceeds telegram max length"
     );
 
-    match reply_to {
-        Some(reply_id) => {
-            let reply = ReplyParameters {
+    let sent = send_with_retries(|| {
+        let request = bot.send_message(chat_id, text);
+        match reply_to {
+            Some(reply_id) => request.reply_parameters(ReplyParameters {
                 message_id: reply_id,
                 ..Default::default()
-            };
-            bot.send_message(chat_id, text)
-                .reply_parameters(reply)
-                .await?;
+            }),
+            None => request,
         }
-        None => {
-            bot.send_message(chat_id, text).await?;
+        .into_future()
+    })
+    .await?;
+
+    Ok(sent.id)
+}
+
+/// What the chunk after `sent` replies to: a split answer that replies to a message is
+/// threaded, each chunk replying to the one before it, instead of every chunk replying to
+/// the same message. Answers that reply to nothing stay that way.
+fn next_reply_to(reply_to: Option<MessageId>, sent: MessageId) -> Option<MessageId> {
+    reply_to.map(|_| sent)
+}
+
+/// Whether the message addresses the bot via a `@username` mention or a text mention.
+/// Uses Telegram's entities when present and falls back to a word-boundary text search.
+pub fn mentions_bot(msg: &Message, bot_username: &str, bot_user_id: UserId) -> bool {
+    if let Some(entities) = msg.parse_entities() {
+        return entities.iter().any(|entity| match entity.kind() {
+            MessageEntityKind::Mention => entity
+                .text()
+                .trim_start_matches('@')
+                .eq_ignore_ascii_case(bot_username),
+            MessageEntityKind::TextMention { user } => user.id == bot_user_id,
+            _ => false,
+        });
+    }
+
+    msg.text()
+        .map(|text| contains_mention(text, bot_username))
+        .unwrap_or(false)
+}
+
+/// Whether `err` means the bot can't post in the chat at all (kicked, blocked, muted), so
+/// further sends for the same update are pointless.
+pub fn is_send_forbidden(err: &anyhow::Error) -> bool {
+    let Some(RequestError::Api(api_error)) = err.downcast_ref::<RequestError>() else {
+        return false;
+    };
+
+    match api_error {
+        ApiError::BotBlocked
+        | ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::BotKickedFromChannel
+        | ApiError::NotEnoughRightsToPostMessages
+        | ApiError::ChatNotFound
+        | ApiError::GroupDeactivated
+        | ApiError::UserDeactivated
+        | ApiError::CantInitiateConversation => true,
+        // Muted bots get errors teloxide doesn't name, e.g. "not enough rights to send text
+        // messages to the chat" or "CHAT_WRITE_FORBIDDEN".
+        ApiError::Unknown(text) => {
+            let text = text.to_ascii_lowercase();
+            text.contains("not enough rights to send")
+                || text.contains("have no rights to send")
+                || text.contains("chat_write_forbidden")
         }
+        _ => false,
     }
+}
 
-    Ok(())
+/// Whether the bot authored the message itself, from its own account or as an inline bot
+/// (`via_bot`). Such messages are never processed, so the bot can't end up answering itself.
+pub fn is_own_message(msg: &Message, bot_user_id: UserId) -> bool {
+    msg.from.as_ref().is_some_and(|user| user.id == bot_user_id)
+        || msg
+            .via_bot
+            .as_ref()
+            .is_some_and(|bot| bot.id == bot_user_id)
 }
 
-/// Send a formatted message (e.g., MarkdownV2), splitting only on newlines.
-/// Calls `fatal_panic` if any single line exceeds Telegram's maximum length.
-pub async fn bot_split_send_formatted(
+/// Whether the message comes from a bot or a channel rather than a person: bot accounts
+/// (including the anonymous group admin stand-in), the bot's own messages, channel posts
+/// auto-forwarded into a linked discussion group, and posts sent on behalf of a channel.
+/// `from` can't be relied on alone: for channel content it is absent or a service account.
+pub fn is_automated_message(msg: &Message, bot_user_id: UserId) -> bool {
+    msg.from.as_ref().is_some_and(|user| user.is_bot)
+        || is_own_message(msg, bot_user_id)
+        || msg.is_automatic_forward()
+        || msg
+            .sender_chat
+            .as_ref()
+            .is_some_and(|chat| chat.is_channel())
+}
+
+/// Display name of whoever wrote the message: the user's full name, or the title of the chat
+/// an anonymous admin or a channel posted it as.
+pub fn sender_name(msg: &Message) -> Option<String> {
+    if let Some(chat) = msg.sender_chat.as_ref() {
+        return chat.title().map(str::to_owned);
+    }
+    msg.from
+        .as_ref()
+        .map(|user| user.full_name())
+        .filter(|name| !name.trim().is_empty())
+}
+
+/// Download the largest size of a photo message.
+pub async fn download_photo(bot: &Bot, photo: &[PhotoSize]) -> anyhow::Result<Vec<u8>> {
+    let largest = photo
+        .iter()
+        .max_by_key(|size| size.width * size.height)
+        .expect("Telegram sends at least one photo size");
+    let file = bot.get_file(largest.file.id.clone()).await?;
+    let mut bytes = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Whether the message replies to one of the bot's own messages.
+pub fn is_reply_to_bot(msg: &Message, bot_user_id: UserId) -> bool {
+    msg.reply_to_message()
+        .and_then(|m| m.from.as_ref())
+        .map(|user| user.id == bot_user_id)
+        .unwrap_or(false)
+}
+
+/// Longest excerpt of the bot's earlier answer used to identify it when nothing was quoted.
+const REPLY_EXCERPT_CHARS: usize = 80;
+
+/// Build the prompt text for `msg`, prefixed with the message it replies to. A reply to one of
+/// the bot's answers only names that answer (repeating just the selected quote, if any) since
+/// the assistant's words must not be passed off as the user's; other replies quote the whole
+/// message.
+pub fn prompt_with_reply_context(msg: &Message, bot_user_id: UserId) -> String {
+    let text = msg.text().expect("Only text messages are supported.");
+    if text.starts_with('/') {
+        return text.to_owned();
+    }
+
+    let Some(replied_text) = msg
+        .reply_to_message()
+        .and_then(|reply| reply.text())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+    else {
+        return text.to_owned();
+    };
+
+    let selection = msg
+        .quote()
+        .map(|quote| quote.text.trim())
+        .filter(|text| !text.is_empty());
+
+    if is_reply_to_bot(msg, bot_user_id) {
+        let reference = match selection {
+            Some(selection) => format!(
+                "(Regarding this part of your earlier reply:)\n{}",
+                quote_lines(selection)
+            ),
+            None => {
+                let excerpt = match replied_text.char_indices().nth(REPLY_EXCERPT_CHARS) {
+                    Some((idx, _)) => format!("{}…", &replied_text[..idx]),
+                    None => replied_text.to_string(),
+                };
+                format!("(Regarding your earlier reply that starts with: \"{excerpt}\")")
+            }
+        };
+        return format!("{}\n\n{}", reference, text);
+    }
+
+    let quoted = match selection {
+        Some(selection) => format!(
+            "{}\n\n\n{}",
+            quote_lines(replied_text),
+            quote_lines(selection)
+        ),
+        None => quote_lines(replied_text),
+    };
+    format!("{}\n\n{}", quoted, text)
+}
+
+fn quote_lines(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("> {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Case-insensitive search for `@username` that isn't part of a longer word.
+fn contains_mention(text: &str, username: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let text = text.to_lowercase();
+    let needle = format!("@{}", username.to_lowercase());
+
+    text.match_indices(&needle).any(|(idx, _)| {
+        let before = text[..idx].chars().next_back();
+        let after = text[idx + needle.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+/// Send a formatted chunk; if Telegram can't parse its entities, resend it as plain text
+/// so the content still reaches the user. Returns the id of the message that was sent.
+async fn send_formatted_or_plain(
     bot: &Bot,
     chat_id: ChatId,
     text: &str,
     reply_to: Option<MessageId>,
     parse_mode: ParseMode,
+) -> anyhow::Result<MessageId> {
+    let err = match send_formatted_checked(bot, chat_id, text, reply_to, parse_mode).await {
+        Ok(sent) => return Ok(sent),
+        Err(err) => err,
+    };
+
+    let Some(RequestError::Api(ApiError::CantParseEntities(reason))) =
+        err.downcast_ref::<RequestError>()
+    else {
+        return Err(err);
+    };
+
+    log::warn!(
+        "Telegram rejected formatted chunk for chat {} ({}); resending as plain text. Chunk: {:?}",
+        chat_id,
+        reason,
+        text
+    );
+    let plain = match parse_mode {
+        ParseMode::MarkdownV2 => unescape_markdown_v2(text),
+        _ => text.to_string(),
+    };
+    send_message_checked(bot, chat_id, &plain, reply_to).await
+}
+
+/// Send a formatted message (e.g., MarkdownV2), splitting as [`split_formatted`] does.
+/// Chunks Telegram can't parse are resent as plain text.
+pub async fn bot_split_send_formatted(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    mut reply_to: Option<MessageId>,
+    parse_mode: ParseMode,
 ) -> anyhow::Result<()> {
+    for chunk in split_formatted(text, "") {
+        let sent = send_formatted_or_plain(bot, chat_id, &chunk, reply_to, parse_mode).await?;
+        reply_to = next_reply_to(reply_to, sent);
+    }
+
+    Ok(())
+}
+
+/// Like [`bot_split_send_formatted`] but surfaces parse errors instead of falling back to
+/// plain text; used by `/mdtest` to diagnose formatting bugs.
+pub async fn bot_split_send_formatted_strict(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    mut reply_to: Option<MessageId>,
+    parse_mode: ParseMode,
+) -> anyhow::Result<()> {
+    for chunk in split_formatted(text, "") {
+        let sent = send_formatted_checked(bot, chat_id, &chunk, reply_to, parse_mode).await?;
+        reply_to = next_reply_to(reply_to, sent);
+    }
+
+    Ok(())
+}
+
+/// Split formatted text into Telegram-sized chunks without breaking formatting entities.
+/// Paragraphs (blocks separated by a blank line) are kept together whenever they fit, so a
+/// block's first line (e.g. a section header) always travels with the lines that follow it.
+/// A code block cut by a split is closed at the end of the chunk and reopened, with its
+/// language, at the start of the next; a line too long for any chunk is cut, a prose line
+/// like plain text with `marker` ending every piece of a cut word, a code line anywhere.
+fn split_formatted(text: &str, marker: &str) -> Vec<String> {
     if text.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH {
-        return send_formatted_checked(bot, chat_id, text, reply_to, parse_mode).await;
+        return vec![text.to_string()];
     }
 
-    let mut buffer = String::new();
-    let mut buffer_len = 0usize;
+    split_lines(text, LineCut::Escaped(&escape_markdown_v2(marker)))
+}
+
+/// Split at paragraphs and code blocks, then lines, keeping code blocks whole where they fit
+/// and reopening them where they don't; see [`split_formatted`].
+fn split_lines(text: &str, cut: LineCut) -> Vec<String> {
+    let mut chunks = FencedChunks::new(cut);
+    for (separator, block) in text_blocks(text) {
+        if chunks.fits(&block, separator) {
+            chunks.push(&block, separator);
+            continue;
+        }
+
+        // The block doesn't fit behind the current buffer; start it on a fresh chunk.
+        chunks.flush();
+        if chunks.fits(&block, separator) {
+            chunks.push(&block, separator);
+            continue;
+        }
+
+        let mut separator = separator;
+        for line in block.split('\n') {
+            if !chunks.fits(line, separator) {
+                chunks.flush();
+            }
+            if chunks.fits(line, separator) {
+                chunks.push(line, separator);
+            } else {
+                chunks.push_cut(line, separator);
+            }
+            separator = "\n";
+        }
+    }
+
+    chunks.finish()
+}
+
+/// Paragraphs (ended by a blank line) and ```` ``` ```` code blocks (blank lines included),
+/// each with the separator that preceded it.
+fn text_blocks(text: &str) -> Vec<(&'static str, String)> {
+    let mut blocks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut separator = "";
+    let mut in_code = false;
 
     for line in text.split('\n') {
-        let line_len = line.chars().count();
-        let required = line_len + if buffer.is_empty() { 0 } else { 1 }; // include newline
+        let fence = line.trim_start().starts_with("```");
+        if in_code {
+            current.push(line);
+            if fence {
+                blocks.push((separator, current.join("\n")));
+                current.clear();
+                separator = "\n";
+                in_code = false;
+            }
+        } else if fence || line.is_empty() {
+            if !current.is_empty() {
+                blocks.push((separator, current.join("\n")));
+                current.clear();
+                separator = "\n";
+            }
+            if fence {
+                current.push(line);
+                in_code = true;
+            } else {
+                separator = "\n\n";
+            }
+        } else {
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        blocks.push((separator, current.join("\n")));
+    }
+
+    blocks
+}
+
+/// Closes a code block that continues in the next chunk.
+const CLOSING_FENCE: &str = "\n```";
+/// Longest code block opening (```` ```language ````) repeated in continuation chunks.
+const MAX_REOPENED_FENCE_LEN: usize = 64;
+/// Room for the pieces of a line cut at words: a chunk minus a reopened fence, the newline
+/// after it and the closing fence.
+const CUT_LINE_ROOM: usize =
+    TELEGRAM_MAX_MESSAGE_LENGTH - MAX_REOPENED_FENCE_LEN - 1 - CLOSING_FENCE.len();
+/// Longest `SPLIT_MARKER`, in characters: [`split_words`] needs the marker to take less than
+/// half of the room it cuts a word into, even with each character escaped for MarkdownV2.
+pub const MAX_SPLIT_MARKER_CHARS: usize = CUT_LINE_ROOM / 4 - 1;
 
-        if line_len > TELEGRAM_MAX_MESSAGE_LENGTH {
-            fatal_panic("Formatted message contains a line longer than Telegram allows");
+/// How [`split_lines`] cuts a line too long for any chunk.
+#[derive(Debug, Copy, Clone)]
+enum LineCut<'a> {
+    /// MarkdownV2: prose lines between words, or between grapheme clusters ending in the
+    /// (escaped) marker; code lines between characters. Never inside an escape.
+    Escaped(&'a str),
+    /// Between words, or grapheme clusters ending in the marker (plain text).
+    Words(&'a str),
+}
+
+/// Chunks being built by [`split_lines`], with the code block the buffer ends in.
+#[derive(Debug)]
+struct FencedChunks<'a> {
+    cut: LineCut<'a>,
+    chunks: Vec<String>,
+    buffer: String,
+    buffer_len: usize,
+    /// Length of the reopened fence the buffer starts with, if any.
+    reopened_len: usize,
+    /// Opening line of the code block the buffer ends inside.
+    open_fence: Option<String>,
+}
+
+impl<'a> FencedChunks<'a> {
+    fn new(cut: LineCut<'a>) -> Self {
+        Self {
+            cut,
+            chunks: Vec::new(),
+            buffer: String::new(),
+            buffer_len: 0,
+            reopened_len: 0,
+            open_fence: None,
         }
+    }
 
-        if buffer_len + required > TELEGRAM_MAX_MESSAGE_LENGTH {
-            send_formatted_checked(bot, chat_id, &buffer, reply_to, parse_mode).await?;
-            buffer.clear();
-            buffer_len = 0;
+    fn separator<'s>(&self, separator: &'s str) -> &'s str {
+        if self.buffer.is_empty() {
+            ""
+        } else if self.buffer_len == self.reopened_len {
+            "\n"
+        } else {
+            separator
         }
+    }
+
+    /// Whether `text` fits behind the buffer, leaving room to close a code block it leaves
+    /// open.
+    fn fits(&self, text: &str, separator: &str) -> bool {
+        let closing = match fence_after(self.open_fence.as_deref(), text) {
+            Some(_) => CLOSING_FENCE.len(),
+            None => 0,
+        };
+        self.buffer_len + self.separator(separator).len() + text.chars().count() + closing
+            <= TELEGRAM_MAX_MESSAGE_LENGTH
+    }
 
-        if !buffer.is_empty() {
-            buffer.push('\n');
-            buffer_len += 1;
+    fn push(&mut self, text: &str, separator: &str) {
+        let separator = self.separator(separator);
+        self.buffer.push_str(separator);
+        self.buffer.push_str(text);
+        self.buffer_len += separator.len() + text.chars().count();
+        self.open_fence = fence_after(self.open_fence.as_deref(), text);
+    }
+
+    /// Push a line longer than any chunk holds, one piece per chunk.
+    fn push_cut(&mut self, line: &str, separator: &str) {
+        let words = match self.cut {
+            LineCut::Words(marker) => Some((marker, grapheme_clusters as fn(&str) -> Vec<&str>)),
+            // A marker inside code would end up in whatever the reader copies.
+            LineCut::Escaped(marker) if self.open_fence.is_none() => {
+                Some((marker, escaped_clusters as fn(&str) -> Vec<&str>))
+            }
+            LineCut::Escaped(_) => None,
+        };
+        if let Some((marker, clusters)) = words {
+            let pieces = split_words_at(line, marker, CUT_LINE_ROOM, clusters);
+            let last = pieces.len() - 1;
+            for (index, piece) in pieces.iter().enumerate() {
+                self.push(piece, separator);
+                if index < last {
+                    self.flush();
+                }
+            }
+            return;
         }
 
-        buffer.push_str(line);
-        buffer_len += line_len;
+        // A piece never ends in the middle of an escape sequence.
+        let mut rest: Vec<char> = line.chars().collect();
+        let closing = if self.open_fence.is_some() {
+            CLOSING_FENCE.len()
+        } else {
+            0
+        };
+        while !rest.is_empty() {
+            let room = TELEGRAM_MAX_MESSAGE_LENGTH
+                - self.buffer_len
+                - self.separator(separator).len()
+                - closing;
+            let mut take = room.min(rest.len());
+            let trailing_backslashes = rest[..take]
+                .iter()
+                .rev()
+                .take_while(|&&ch| ch == '\\')
+                .count();
+            if take < rest.len() && trailing_backslashes % 2 == 1 {
+                take -= 1;
+            }
+            let piece: String = rest.drain(..take).collect();
+            self.push(&piece, separator);
+            if !rest.is_empty() {
+                self.flush();
+            }
+        }
     }
 
-    if !buffer.is_empty() {
-        send_formatted_checked(bot, chat_id, &buffer, reply_to, parse_mode).await?;
+    /// Move the buffer into a chunk, closing its code block and reopening it in the next.
+    fn flush(&mut self) {
+        if self.buffer_len == self.reopened_len {
+            return;
+        }
+        if self.open_fence.is_some() {
+            self.buffer.push_str(CLOSING_FENCE);
+        }
+        self.chunks.push(std::mem::take(&mut self.buffer));
+        self.buffer_len = 0;
+        self.reopened_len = 0;
+        if let Some(fence) = &self.open_fence {
+            let fence = if fence.chars().count() <= MAX_REOPENED_FENCE_LEN {
+                fence.as_str()
+            } else {
+                "```"
+            };
+            self.buffer.push_str(fence);
+            self.buffer_len = fence.chars().count();
+            self.reopened_len = self.buffer_len;
+        }
     }
 
-    Ok(())
+    fn finish(mut self) -> Vec<String> {
+        if self.buffer_len > self.reopened_len {
+            self.chunks.push(self.buffer);
+        }
+        self.chunks
+    }
+}
+
+/// The opening line of the code block still open after `text`, given the one open before it.
+fn fence_after<'a>(mut open: Option<&'a str>, text: &'a str) -> Option<String> {
+    if !text.contains("```") {
+        return open.map(str::to_string);
+    }
+    for line in text.split('\n') {
+        if line.trim_start().starts_with("```") {
+            open = match open {
+                Some(_) => None,
+                None => Some(line),
+            };
+        }
+    }
+    open.map(|line| line.trim().to_string())
 }
 
 pub async fn bot_split_send(
@@ -126,36 +1090,325 @@ pub async fn bot_split_send(
     text: &str,
     reply_to: Option<MessageId>,
 ) -> anyhow::Result<()> {
+    bot_split_send_marked(bot, chat_id, text, reply_to, "").await
+}
+
+/// Like [`bot_split_send`], ending every piece of a word cut in the middle with `marker`
+/// (e.g. `…`) so readers can tell it continues in the next message.
+pub async fn bot_split_send_marked(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    mut reply_to: Option<MessageId>,
+    marker: &str,
+) -> anyhow::Result<()> {
+    for chunk in split_plain(text, marker) {
+        let sent = send_message_checked(bot, chat_id, &chunk, reply_to).await?;
+        reply_to = next_reply_to(reply_to, sent);
+    }
+
+    Ok(())
+}
+
+/// Like [`bot_split_send_formatted`], but with a cap (`MAX_REPLY_CHUNKS`): once `max_chunks`
+/// messages are sent, the rest of the text follows as one `reply.txt` attachment instead,
+/// so a runaway answer can't flood the chat.
+pub async fn bot_split_send_formatted_capped(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    mut reply_to: Option<MessageId>,
+    parse_mode: ParseMode,
+    max_chunks: Option<usize>,
+    marker: &str,
+) -> anyhow::Result<()> {
+    let (chunks, rest) = cap_chunks(split_formatted(text, marker), parse_mode, max_chunks);
+    for chunk in &chunks {
+        let sent = send_formatted_or_plain(bot, chat_id, chunk, reply_to, parse_mode).await?;
+        reply_to = next_reply_to(reply_to, sent);
+    }
+
+    match rest {
+        Some(rest) => send_rest_as_file(bot, chat_id, rest, chunks.len(), reply_to).await,
+        None => Ok(()),
+    }
+}
+
+/// Send what didn't fit into `sent` messages as a `reply.txt` attachment.
+async fn send_rest_as_file(
+    bot: &Bot,
+    chat_id: ChatId,
+    rest: String,
+    sent: usize,
+    reply_to: Option<MessageId>,
+) -> anyhow::Result<()> {
+    log::info!(
+        "reply for chat {} exceeds {} message(s); sending {} more character(s) as a file",
+        chat_id,
+        sent,
+        rest.chars().count()
+    );
+    let caption = format!("The answer continues in this file (more than {sent} messages).");
+    send_with_retries(|| {
+        let request = bot
+            .send_document(
+                chat_id,
+                InputFile::memory(rest.clone().into_bytes()).file_name("reply.txt"),
+            )
+            .caption(caption.clone());
+        match reply_to {
+            Some(reply_id) => request.reply_parameters(ReplyParameters {
+                message_id: reply_id,
+                ..Default::default()
+            }),
+            None => request,
+        }
+        .into_future()
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// An answer shown while it streams in. Each [`LiveReply::show`] edits the messages whose
+/// part of the text changed and sends new ones once the text outgrows them, so every message
+/// stays within the Telegram limit. When Telegram answers an edit with flood control,
+/// [`LiveReply::preview`] stops editing until the requested time has passed, while
+/// [`LiveReply::show`] waits it out so the final text always lands.
+#[derive(Debug)]
+pub struct LiveReply {
+    bot: Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    marker: String,
+    /// Sent messages with the text each one shows.
+    sent: Vec<(MessageId, String)>,
+    /// No edits before this instant, as asked by a 429 `retry_after`.
+    paused_until: Option<Instant>,
+}
+
+impl LiveReply {
+    pub fn new(bot: Bot, chat_id: ChatId, reply_to: Option<MessageId>, marker: &str) -> Self {
+        Self {
+            bot,
+            chat_id,
+            reply_to,
+            marker: marker.to_string(),
+            sent: Vec::new(),
+            paused_until: None,
+        }
+    }
+
+    /// Whether flood control still holds back edits.
+    pub fn is_paused(&self) -> bool {
+        self.paused_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Show `text` as an intermediate state: skipped while flood control holds back edits,
+    /// and a new 429 only pauses further previews instead of failing.
+    pub async fn preview(&mut self, text: &str) -> anyhow::Result<()> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        self.update(text, false).await
+    }
+
+    /// Show `text` in place of what was shown before, waiting out flood control if needed.
+    /// Messages left over when the text got shorter (e.g. cleanup at the end) are deleted.
+    pub async fn show(&mut self, text: &str) -> anyhow::Result<()> {
+        self.update(text, true).await
+    }
+
+    async fn update(&mut self, text: &str, wait: bool) -> anyhow::Result<()> {
+        let chunks = if text.trim().is_empty() {
+            Vec::new()
+        } else {
+            split_plain(text, &self.marker)
+        };
+
+        for (idx, chunk) in chunks.iter().enumerate() {
+            match self.sent.get_mut(idx) {
+                Some((_, shown)) if shown == chunk => {}
+                Some((message_id, _)) => {
+                    let message_id = *message_id;
+                    if !self.edit(message_id, chunk, wait).await? {
+                        // Paused by flood control; the chunk is edited by a later update.
+                        continue;
+                    }
+                    self.sent[idx].1 = chunk.clone();
+                }
+                None => {
+                    let reply_to = match self.sent.last() {
+                        Some(&(previous, _)) => next_reply_to(self.reply_to, previous),
+                        None => self.reply_to,
+                    };
+                    let sent =
+                        send_message_checked(&self.bot, self.chat_id, chunk, reply_to).await?;
+                    self.sent.push((sent, chunk.clone()));
+                }
+            }
+        }
+
+        if self.sent.len() > chunks.len() {
+            for (message_id, _) in self.sent.split_off(chunks.len()) {
+                if let Err(err) = self.bot.delete_message(self.chat_id, message_id).await {
+                    log::warn!(
+                        "failed to delete leftover streamed message in chat {}: {}",
+                        self.chat_id,
+                        err
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Edit one message to show `chunk`. Returns false when flood control paused edits and
+    /// `wait` is off, leaving the message as it was.
+    async fn edit(
+        &mut self,
+        message_id: MessageId,
+        chunk: &str,
+        wait: bool,
+    ) -> anyhow::Result<bool> {
+        loop {
+            if let Some(until) = self.paused_until.take() {
+                if !wait && Instant::now() < until {
+                    self.paused_until = Some(until);
+                    return Ok(false);
+                }
+                tokio::time::sleep_until(until.into()).await;
+            }
+
+            let edited = send_with_retries(|| {
+                self.bot
+                    .edit_message_text(self.chat_id, message_id, chunk)
+                    .into_future()
+            })
+            .await;
+            let Err(err) = edited else {
+                return Ok(true);
+            };
+            match classify_edit_error(&err) {
+                EditFailure::NotModified => return Ok(true),
+                EditFailure::RetryAfter(retry_after) => {
+                    log::warn!(
+                        "Telegram flood control in chat {}; pausing edits for {:?}",
+                        self.chat_id,
+                        retry_after
+                    );
+                    self.paused_until = Some(Instant::now() + retry_after);
+                }
+                EditFailure::Other => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Keep at most `max_chunks` formatted chunks and rejoin the others, line by line and with
+/// their escaping undone, into the text of the attachment.
+fn cap_chunks(
+    mut chunks: Vec<String>,
+    parse_mode: ParseMode,
+    max_chunks: Option<usize>,
+) -> (Vec<String>, Option<String>) {
+    let Some(max_chunks) = max_chunks.filter(|&max| chunks.len() > max) else {
+        return (chunks, None);
+    };
+    assert!(max_chunks > 0, "reply chunk cap must be positive");
+
+    let rest = chunks
+        .split_off(max_chunks)
+        .iter()
+        .map(|chunk| match parse_mode {
+            ParseMode::MarkdownV2 => unescape_markdown_v2(chunk),
+            _ => chunk.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    (chunks, Some(rest))
+}
+
+/// Split plain text into Telegram-sized chunks at spaces and newlines. A word longer than a
+/// whole message is cut between grapheme clusters, never inside an emoji sequence or between
+/// a letter and its combining marks. Text with ```` ``` ```` code blocks is split at lines
+/// instead, so a split never lands inside a block that fits a message; a longer block is
+/// closed and reopened with its language in every chunk it spans.
+fn split_plain(text: &str, marker: &str) -> Vec<String> {
     if text.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH {
-        send_message_checked(bot, chat_id, text, reply_to).await?;
-        return Ok(());
+        return vec![text.to_string()];
     }
+    if text
+        .lines()
+        .any(|line| line.trim_start().starts_with("```"))
+    {
+        return split_lines(text, LineCut::Words(marker));
+    }
+
+    split_words(text, marker, TELEGRAM_MAX_MESSAGE_LENGTH)
+}
 
+/// Split at spaces and newlines into chunks of at most `limit` characters; the text is
+/// kept exactly, so the chunks concatenate back to it (apart from markers).
+fn split_words(text: &str, marker: &str, limit: usize) -> Vec<String> {
+    split_words_at(text, marker, limit, grapheme_clusters)
+}
+
+/// [`split_words`], cutting a long word between the `clusters` it is made of.
+fn split_words_at<'t>(
+    text: &'t str,
+    marker: &str,
+    limit: usize,
+    clusters: fn(&'t str) -> Vec<&'t str>,
+) -> Vec<String> {
+    if text.chars().count() <= limit {
+        return vec![text.to_string()];
+    }
+
+    let marker_len = marker.chars().count();
+    assert!(marker_len < limit / 2, "split marker is too long");
+
+    let mut chunks = Vec::new();
     let mut buffer = String::new();
     let mut buffer_len = 0usize;
 
     for token in text.split_inclusive([' ', '\n']) {
         let token_len = token.chars().count();
-        if token_len > TELEGRAM_MAX_MESSAGE_LENGTH {
+        if token_len > limit {
+            // The word starts on a fresh chunk; every cut piece leaves room for the marker.
             if !buffer.is_empty() {
-                send_message_checked(bot, chat_id, &buffer, reply_to).await?;
-                buffer.clear();
+                chunks.push(std::mem::take(&mut buffer));
                 buffer_len = 0;
             }
-            for ch in token.chars() {
-                buffer.push(ch);
-                buffer_len += 1;
-                if buffer_len == TELEGRAM_MAX_MESSAGE_LENGTH {
-                    send_message_checked(bot, chat_id, &buffer, reply_to).await?;
-                    buffer.clear();
+            for cluster in clusters(token) {
+                let cluster_len = cluster.chars().count();
+                if buffer_len + cluster_len + marker_len > limit && !buffer.is_empty() {
+                    buffer.push_str(marker);
+                    chunks.push(std::mem::take(&mut buffer));
                     buffer_len = 0;
                 }
+                if cluster_len + marker_len > limit {
+                    // A pathological cluster (thousands of combining marks) can't stay whole.
+                    for ch in cluster.chars() {
+                        if buffer_len + 1 + marker_len > limit {
+                            buffer.push_str(marker);
+                            chunks.push(std::mem::take(&mut buffer));
+                            buffer_len = 0;
+                        }
+                        buffer.push(ch);
+                        buffer_len += 1;
+                    }
+                    continue;
+                }
+                buffer.push_str(cluster);
+                buffer_len += cluster_len;
             }
             continue;
         }
-        if buffer_len + token_len > TELEGRAM_MAX_MESSAGE_LENGTH && !buffer.is_empty() {
-            send_message_checked(bot, chat_id, &buffer, reply_to).await?;
-            buffer.clear();
+        if buffer_len + token_len > limit && !buffer.is_empty() {
+            chunks.push(std::mem::take(&mut buffer));
             buffer_len = 0;
         }
 
@@ -164,8 +1417,648 @@ pub async fn bot_split_send(
     }
 
     if !buffer.is_empty() {
-        send_message_checked(bot, chat_id, &buffer, reply_to).await?;
+        chunks.push(buffer);
     }
 
-    Ok(())
+    chunks
+}
+
+/// [`grapheme_clusters`] of MarkdownV2 text, with each escaping backslash kept together
+/// with the cluster it escapes.
+fn escaped_clusters(text: &str) -> Vec<&str> {
+    let mut clusters = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for cluster in grapheme_clusters(text) {
+        end += cluster.len();
+        let trailing_backslashes = text[start..end]
+            .chars()
+            .rev()
+            .take_while(|&ch| ch == '\\')
+            .count();
+        if trailing_backslashes % 2 == 0 {
+            clusters.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < end {
+        clusters.push(&text[start..end]);
+    }
+    clusters
+}
+
+/// Approximate extended grapheme clusters (UAX #29) without the Unicode tables: a char starts
+/// a new cluster unless it is a combining mark, variation selector, emoji modifier or tag,
+/// a zero-width joiner or the char joined by it, or the second half of a flag.
+///
+/// Known gaps: conjoining Hangul jamo (U+1100–U+11FF) and Indic vowel signs and viramas,
+/// which [`extends_cluster`] doesn't list, start new clusters, so a cut can still land
+/// inside such a syllable. Precomposed Hangul and the common scripts are cut
+/// correctly; `unicode-segmentation` would close the gap if it becomes a dependency.
+fn grapheme_clusters(text: &str) -> Vec<&str> {
+    let mut clusters = Vec::new();
+    let mut start = 0;
+    let mut prev: Option<char> = None;
+    let mut regional_run = 0usize;
+
+    for (idx, ch) in text.char_indices() {
+        let is_regional = ('\u{1F1E6}'..='\u{1F1FF}').contains(&ch);
+        let joins_previous = match prev {
+            None => true,
+            Some(prev) => {
+                extends_cluster(ch) || prev == '\u{200D}' || (is_regional && regional_run % 2 == 1)
+            }
+        };
+        if !joins_previous {
+            clusters.push(&text[start..idx]);
+            start = idx;
+        }
+        regional_run = if is_regional { regional_run + 1 } else { 0 };
+        prev = Some(ch);
+    }
+    if start < text.len() {
+        clusters.push(&text[start..]);
+    }
+
+    clusters
+}
+
+fn extends_cluster(ch: char) -> bool {
+    matches!(ch,
+        '\u{0300}'..='\u{036F}'        // combining diacritical marks
+        | '\u{0483}'..='\u{0489}'      // Cyrillic combining marks
+        | '\u{0591}'..='\u{05BD}'      // Hebrew points
+        | '\u{064B}'..='\u{065F}'      // Arabic harakat
+        | '\u{0E31}' | '\u{0E34}'..='\u{0E3A}' | '\u{0E47}'..='\u{0E4E}' // Thai marks
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200C}' | '\u{200D}'      // ZWNJ, ZWJ
+        | '\u{20D0}'..='\u{20FF}'      // combining marks for symbols (keycaps)
+        | '\u{FE00}'..='\u{FE0F}'      // variation selectors
+        | '\u{FE20}'..='\u{FE2F}'
+        | '\u{1F3FB}'..='\u{1F3FF}'    // emoji skin tone modifiers
+        | '\u{E0020}'..='\u{E007F}'    // emoji tag sequences
+        | '\u{E0100}'..='\u{E01EF}'
+    )
+}
+
+/// Canned MarkdownV2 snippets, one per construct, used by `/mdtest` to exercise the
+/// formatted send path against Telegram's parser.
+pub fn markdown_v2_samples() -> Vec<(&'static str, String)> {
+    vec![
+        ("bold", "*bold text*".to_string()),
+        ("italic", "_italic text_".to_string()),
+        ("underline", "__underlined text__".to_string()),
+        ("strikethrough", "~struck text~".to_string()),
+        ("spoiler", "||hidden text||".to_string()),
+        ("inline code", "`let answer = 42;`".to_string()),
+        (
+            "fenced code",
+            "```rust\nfn main() {\n    println!(\"hello {}\", 1 + 2);\n}\n```".to_string(),
+        ),
+        (
+            "link",
+            "[OpenRouter](https://openrouter.ai/docs)".to_string(),
+        ),
+        (
+            "bullet list",
+            format!(
+                "{}\n{}",
+                escape_markdown_v2("- first item"),
+                escape_markdown_v2("- second item")
+            ),
+        ),
+        (
+            "numbered list",
+            format!(
+                "{}\n{}",
+                escape_markdown_v2("1. first step"),
+                escape_markdown_v2("2. second step")
+            ),
+        ),
+        ("blockquote", ">quoted line".to_string()),
+        (
+            "special chars",
+            escape_markdown_v2("_ * [ ] ( ) ~ ` > # + - = | { } . ! \\"),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rejoin chunks produced with `marker` (test texts never end in it themselves),
+    /// checking each respects the length limit.
+    fn rejoin(chunks: &[String], marker: &str) -> String {
+        let mut joined = String::new();
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+            let body = match marker {
+                "" => chunk.as_str(),
+                marker => chunk.strip_suffix(marker).unwrap_or(chunk),
+            };
+            joined.push_str(body);
+        }
+        joined
+    }
+
+    #[test]
+    fn splits_long_words_without_breaking_emoji_sequences() {
+        let family = "👨\u{200D}👩\u{200D}👧\u{200D}👦";
+        let text = family.repeat(1200);
+        let chunks = split_plain(&text, "…");
+        assert!(chunks.len() > 1);
+        assert_eq!(rejoin(&chunks, "…"), text);
+        for chunk in &chunks {
+            let body = chunk.trim_end_matches('…');
+            assert_eq!(body.matches(family).count() * family.len(), body.len());
+        }
+
+        let flags = "🇩🇪🇯🇵".repeat(2100);
+        let chunks = split_plain(&flags, "");
+        assert_eq!(rejoin(&chunks, ""), flags);
+        for chunk in &chunks {
+            assert_eq!(chunk.chars().count() % 2, 0, "flag split in half");
+        }
+    }
+
+    #[test]
+    fn caps_chunks_and_keeps_the_rest_intact() {
+        let lines = (0..1200)
+            .map(|i| format!("line {i}\\."))
+            .collect::<Vec<_>>();
+        let text = lines.join("\n");
+        let chunks = split_formatted(&text, "");
+        assert_eq!(chunks.len(), 4);
+
+        let (sent, rest) = cap_chunks(chunks.clone(), ParseMode::MarkdownV2, Some(2));
+        assert_eq!(sent, chunks[..2]);
+        assert_eq!(
+            unescape_markdown_v2(&sent.join("\n")) + "\n" + &rest.expect("text beyond the cap"),
+            unescape_markdown_v2(&text)
+        );
+        assert_eq!(
+            cap_chunks(chunks.clone(), ParseMode::MarkdownV2, Some(4)),
+            (chunks.clone(), None)
+        );
+        assert_eq!(
+            cap_chunks(chunks.clone(), ParseMode::MarkdownV2, None),
+            (chunks, None)
+        );
+    }
+
+    #[test]
+    fn keeps_combining_marks_with_their_letter() {
+        let text = format!("intro {}", "e\u{301}a\u{308}\u{304}".repeat(1500));
+        let chunks = split_plain(&text, "…");
+        assert_eq!(chunks[0], "intro ");
+        assert_eq!(rejoin(&chunks, "…"), text);
+        for chunk in &chunks[1..] {
+            let first = chunk.chars().next().expect("chunks aren't empty");
+            assert!(
+                !extends_cluster(first),
+                "chunk starts with a combining mark"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_short_text_and_splits_at_spaces() {
+        assert_eq!(split_plain("hello", "…"), ["hello"]);
+
+        let text = "word ".repeat(1000);
+        let chunks = split_plain(&text, "…");
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| !chunk.ends_with('…')));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    const BOT_ID: UserId = UserId(777);
+
+    fn group_message(text: &str, entities: serde_json::Value) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 10,
+            "date": 1_700_000_000,
+            "chat": { "id": -100_123, "type": "supergroup", "title": "Group" },
+            "from": { "id": 42, "is_bot": false, "first_name": "Alice" },
+            "text": text,
+            "entities": entities,
+        }))
+        .expect("valid test message")
+    }
+
+    fn reply_message(from_id: u64, replied: &str, quote: Option<&str>) -> Message {
+        let mut msg = serde_json::json!({
+            "message_id": 11,
+            "date": 1_700_000_000,
+            "chat": { "id": 42, "type": "private", "first_name": "Alice" },
+            "from": { "id": 42, "is_bot": false, "first_name": "Alice" },
+            "text": "why?",
+            "reply_to_message": {
+                "message_id": 9,
+                "date": 1_700_000_000,
+                "chat": { "id": 42, "type": "private", "first_name": "Alice" },
+                "from": { "id": from_id, "is_bot": from_id == BOT_ID.0, "first_name": "Someone" },
+                "text": replied,
+            },
+        });
+        if let Some(quote) = quote {
+            msg["quote"] = serde_json::json!({ "text": quote, "position": 0 });
+        }
+        serde_json::from_value(msg).expect("valid test message")
+    }
+
+    #[test]
+    fn channel_and_bot_content_is_automated() {
+        let mut human = serde_json::to_value(group_message("hi @tggpt", serde_json::json!([])))
+            .expect("message serializes");
+        let human_message: Message =
+            serde_json::from_value(human.clone()).expect("valid test message");
+        assert!(!is_automated_message(&human_message, BOT_ID));
+        assert!(!is_own_message(&human_message, BOT_ID));
+
+        // A channel post copied into the linked discussion group, sent by Telegram's service
+        // account on behalf of the channel.
+        let channel = serde_json::json!({ "id": -100_999, "type": "channel", "title": "News" });
+        let mut forwarded = human.clone();
+        forwarded["from"] =
+            serde_json::json!({ "id": 777_000, "is_bot": false, "first_name": "Telegram" });
+        forwarded["sender_chat"] = channel.clone();
+        forwarded["is_automatic_forward"] = serde_json::json!(true);
+        let forwarded: Message = serde_json::from_value(forwarded).expect("valid test message");
+        assert!(is_automated_message(&forwarded, BOT_ID));
+
+        // A user posting in the group as their channel.
+        let mut as_channel = human.clone();
+        as_channel["sender_chat"] = channel;
+        let as_channel: Message = serde_json::from_value(as_channel).expect("valid test message");
+        assert!(is_automated_message(&as_channel, BOT_ID));
+
+        // Inline results sent through this bot are its own content; other inline bots are
+        // used by people and stay eligible.
+        human["via_bot"] =
+            serde_json::json!({ "id": BOT_ID.0, "is_bot": true, "first_name": "Bot" });
+        let via_self: Message = serde_json::from_value(human.clone()).expect("valid test message");
+        assert!(is_own_message(&via_self, BOT_ID));
+        assert!(is_automated_message(&via_self, BOT_ID));
+        human["via_bot"] = serde_json::json!({ "id": 555, "is_bot": true, "first_name": "Gif" });
+        let via_other: Message = serde_json::from_value(human).expect("valid test message");
+        assert!(!is_automated_message(&via_other, BOT_ID));
+    }
+
+    #[test]
+    fn reply_to_bot_references_the_earlier_answer() {
+        let msg = reply_message(BOT_ID.0, "The sky is blue.\nMostly.", None);
+        assert_eq!(
+            prompt_with_reply_context(&msg, BOT_ID),
+            "(Regarding your earlier reply that starts with: \"The sky is blue.\nMostly.\")\n\nwhy?"
+        );
+
+        let msg = reply_message(BOT_ID.0, "The sky is blue. Mostly.", Some("Mostly."));
+        assert_eq!(
+            prompt_with_reply_context(&msg, BOT_ID),
+            "(Regarding this part of your earlier reply:)\n> Mostly.\n\nwhy?"
+        );
+    }
+
+    #[test]
+    fn reply_to_user_quotes_the_message() {
+        let msg = reply_message(5, "Cats are liquid.", None);
+        assert_eq!(
+            prompt_with_reply_context(&msg, BOT_ID),
+            "> Cats are liquid.\n\nwhy?"
+        );
+    }
+
+    #[test]
+    fn detects_send_forbidden_errors() {
+        let forbidden = |api_error| is_send_forbidden(&RequestError::Api(api_error).into());
+        assert!(forbidden(ApiError::BotKickedFromSupergroup));
+        assert!(forbidden(ApiError::BotBlocked));
+        assert!(forbidden(ApiError::Unknown(
+            "Bad Request: not enough rights to send text messages to the chat".to_string()
+        )));
+        assert!(!forbidden(ApiError::MessageNotModified));
+        assert!(!is_send_forbidden(&anyhow::anyhow!("network down")));
+    }
+
+    #[test]
+    fn threads_split_replies_only_when_replying() {
+        let (user_msg, first, second) = (MessageId(10), MessageId(11), MessageId(12));
+        let reply_to = next_reply_to(Some(user_msg), first);
+        assert_eq!(reply_to, Some(first));
+        assert_eq!(next_reply_to(reply_to, second), Some(second));
+        assert_eq!(next_reply_to(None, first), None);
+    }
+
+    #[test]
+    fn classifies_edit_errors() {
+        assert_eq!(
+            classify_edit_error(&RequestError::Api(ApiError::MessageNotModified)),
+            EditFailure::NotModified
+        );
+        assert_eq!(
+            classify_edit_error(&RequestError::RetryAfter(
+                teloxide::types::Seconds::from_seconds(7)
+            )),
+            EditFailure::RetryAfter(Duration::from_secs(7))
+        );
+        assert_eq!(
+            classify_edit_error(&RequestError::Api(ApiError::MessageToEditNotFound)),
+            EditFailure::Other
+        );
+        assert_eq!(
+            classify_edit_error(&RequestError::Io(std::sync::Arc::new(
+                std::io::Error::other("reset")
+            ))),
+            EditFailure::Other
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_network_errors_but_not_api_errors() {
+        let io_error = || RequestError::Io(std::sync::Arc::new(std::io::Error::other("reset")));
+        assert!(is_retryable_send_error(&io_error()));
+        assert!(!is_send_forbidden(&io_error().into()));
+        assert!(!is_retryable_send_error(&RequestError::Api(
+            ApiError::BotBlocked
+        )));
+
+        let calls = std::cell::Cell::new(0);
+        let result = retry_send(2, Duration::ZERO, || {
+            calls.set(calls.get() + 1);
+            let outcome = if calls.get() < 3 {
+                Err(io_error())
+            } else {
+                Ok(calls.get())
+            };
+            async move { outcome }
+        })
+        .await;
+        assert_eq!(result.expect("third attempt succeeds"), 3);
+
+        calls.set(0);
+        let result: Result<(), _> = retry_send(2, Duration::ZERO, || {
+            calls.set(calls.get() + 1);
+            async { Err(RequestError::Api(ApiError::BotBlocked)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        calls.set(0);
+        let result: Result<(), _> = retry_send(2, Duration::ZERO, || {
+            calls.set(calls.get() + 1);
+            async { Err(io_error()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn url_containing_bot_name_is_not_a_mention() {
+        let text = "see https://example.com/@gptbot/page";
+        let msg = group_message(
+            text,
+            serde_json::json!([{ "type": "url", "offset": 4, "length": 32 }]),
+        );
+        assert!(!mentions_bot(&msg, "gptbot", BOT_ID));
+    }
+
+    #[test]
+    fn mention_entity_is_detected() {
+        let msg = group_message(
+            "hey @GptBot what's up",
+            serde_json::json!([{ "type": "mention", "offset": 4, "length": 7 }]),
+        );
+        assert!(mentions_bot(&msg, "gptbot", BOT_ID));
+        assert!(!mentions_bot(&msg, "otherbot", BOT_ID));
+    }
+
+    #[test]
+    fn text_mention_entity_is_detected_by_user_id() {
+        let msg = group_message(
+            "hey Bot, help",
+            serde_json::json!([{
+                "type": "text_mention",
+                "offset": 4,
+                "length": 3,
+                "user": { "id": 777, "is_bot": true, "first_name": "Bot" }
+            }]),
+        );
+        assert!(mentions_bot(&msg, "gptbot", BOT_ID));
+        assert!(!mentions_bot(&msg, "gptbot", UserId(1)));
+    }
+
+    #[test]
+    fn fallback_text_search_respects_word_boundaries() {
+        assert!(contains_mention("@gptbot hi", "GptBot"));
+        assert!(contains_mention("hi, @gptbot!", "gptbot"));
+        assert!(!contains_mention("hi @gptbot_fan", "gptbot"));
+        assert!(!contains_mention("mail me at x@gptbot", "gptbot"));
+    }
+
+    #[test]
+    fn unescape_reverses_markdown_v2_escaping() {
+        let original = "Price: $5.00 (approx.) - see [docs] #1 > 2! a_b*c \\ d";
+        assert_eq!(
+            unescape_markdown_v2(&escape_markdown_v2(original)),
+            original
+        );
+        assert_eq!(unescape_markdown_v2("no escapes"), "no escapes");
+        assert_eq!(unescape_markdown_v2("trailing \\"), "trailing \\");
+    }
+
+    #[test]
+    fn converts_model_markdown_to_markdown_v2() {
+        let cases = [
+            (
+                "Done. Cost: $1.50 (approx)!",
+                "Done\\. Cost: $1\\.50 \\(approx\\)\\!",
+            ),
+            (
+                "**bold** and *italic* and _also_",
+                "*bold* and _italic_ and _also_",
+            ),
+            ("***both*** ~~gone~~", "*_both_* ~gone~"),
+            ("**bold *italic***", "*bold _italic_*"),
+            (
+                "snake_case_name and 2 * 3 * 4",
+                "snake\\_case\\_name and 2 \\* 3 \\* 4",
+            ),
+            ("use `a.b(c)` or ``x ` y``", "use `a.b(c)` or `x \\` y`"),
+            ("unclosed `tick and *star", "unclosed \\`tick and \\*star"),
+            ("**run `cargo test`**", "*run cargo test*"),
+            (
+                "[docs](https://en.wikipedia.org/wiki/Rust_(language)) [a.b](x)",
+                "[docs](https://en.wikipedia.org/wiki/Rust_(language\\)) [a\\.b](x)",
+            ),
+            ("## Step 1. **Setup**", "*Step 1\\. Setup*"),
+            ("- item one\n  * nested", "• item one\n  • nested"),
+            ("1. first\n2) second", "1\\. first\n2\\. second"),
+            ("> quoted *text*", ">quoted _text_"),
+            ("---", "──────────"),
+            ("\\*not italic\\*", "\\*not italic\\*"),
+        ];
+        for (markdown, expected) in cases {
+            assert_eq!(markdown_to_telegram(markdown), expected, "{markdown}");
+        }
+
+        assert_eq!(
+            markdown_to_telegram("Run:\n```rust title=x\nlet s = \"a.b\\\\`\";\n```\nok."),
+            "Run:\n```rust\nlet s = \"a.b\\\\\\\\\\`\";\n```\nok\\."
+        );
+        // A block the model didn't close is closed; tildes open blocks too.
+        assert_eq!(markdown_to_telegram("~~~\n*x* (1)"), "```\n*x* (1)\n```");
+    }
+
+    #[test]
+    fn code_blocks_are_reopened_after_a_split() {
+        let code = (0..600)
+            .map(|i| format!("let value_{i} = compute({i});"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = markdown_to_telegram(&format!("Here it is:\n\n```rust\n{code}\n```\n\nDone."));
+
+        let chunks = split_formatted(&text, "");
+        assert!(chunks.len() > 3);
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+            assert_eq!(
+                fence_after(None, chunk),
+                None,
+                "chunk {index} leaves a block open"
+            );
+            if index > 0 && index < chunks.len() - 1 {
+                assert!(
+                    chunk.starts_with("```rust\n"),
+                    "chunk {index} isn't reopened"
+                );
+            }
+        }
+        assert!(chunks.last().unwrap().ends_with("```\n\nDone\\."));
+
+        // A line longer than a message is cut without splitting an escape.
+        let long = "\\.".repeat(3000);
+        let chunks = split_formatted(&long, "");
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() % 2 == 0));
+        assert_eq!(chunks.concat(), long);
+
+        // Cut prose ends in the escaped marker; cut code doesn't.
+        let long = "a\\.".repeat(2000);
+        let chunks = split_formatted(&long, "...");
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+        let first = chunks[0]
+            .strip_suffix("\\.\\.\\.")
+            .expect("cut piece ends in the marker");
+        assert!(!first.ends_with('\\'));
+        assert_eq!(format!("{first}{}", chunks[1]), long);
+        let code = format!("```\n{}\n```", "x".repeat(6000));
+        let chunks = split_formatted(&code, "...");
+        assert!(chunks.iter().all(|chunk| !chunk.contains("\\.")));
+    }
+
+    /// Whether `chunk` opens and closes its code blocks, so it renders on its own.
+    fn fences_balanced(chunk: &str) -> bool {
+        chunk
+            .lines()
+            .filter(|line| line.trim_start().starts_with("```"))
+            .count()
+            % 2
+            == 0
+    }
+
+    #[test]
+    fn plain_split_keeps_code_blocks_whole() {
+        let snippet = (0..300)
+            .map(|i| format!("    let value_{i:03} = compute({i});"))
+            .collect::<Vec<_>>();
+        let snippet = format!("fn main() {{\n{}\n}}", snippet.join("\n"));
+        assert!(snippet.chars().count() > 9000);
+        let text = format!("Here is the program:\n\n```rust\n{snippet}\n```\n\nRun it with cargo.");
+
+        // The block doesn't fit behind the lead-in, so it starts the second chunk.
+        let chunks = split_plain(&text, "…");
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[0], "Here is the program:");
+        for (index, chunk) in chunks.iter().enumerate().skip(1) {
+            assert!(chunk.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+            assert!(fences_balanced(chunk), "chunk {index} leaves a block open");
+            assert!(
+                chunk.starts_with("```rust\n"),
+                "chunk {index} isn't reopened"
+            );
+        }
+        assert!(chunks[1].starts_with("```rust\nfn main() {"));
+        assert!(chunks[2].starts_with("```rust\n    let value_"));
+        assert!(chunks[3].ends_with("}\n```\n\nRun it with cargo."));
+        // Every line of the snippet arrives once, in order.
+        let code_lines = chunks
+            .iter()
+            .flat_map(|chunk| chunk.lines())
+            .filter(|line| line.starts_with("    let"))
+            .collect::<Vec<_>>();
+        assert_eq!(code_lines.len(), 300);
+        assert!(code_lines.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // A block that fits a message moves to the next one instead of being cut.
+        let prose = "word ".repeat(700);
+        let block = format!("```\n{}\n```", "x = 1;\n".repeat(100));
+        let chunks = split_plain(&format!("{prose}\n{block}"), "");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1], block);
+
+        // Without code blocks the text is split at words and kept exactly.
+        let words = "word ".repeat(3000);
+        assert_eq!(split_plain(&words, "").concat(), words);
+    }
+
+    #[test]
+    fn short_formatted_text_is_a_single_chunk() {
+        assert_eq!(split_formatted("*a*\nb", ""), vec!["*a*\nb".to_string()]);
+    }
+
+    #[test]
+    fn section_headers_stay_with_their_lines() {
+        let sections = (0..40)
+            .map(|section| {
+                let lines = (0..12)
+                    .map(|line| format!("`provider{section}/model-{line}` \\- Model {line}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!("*provider{section}*\n{lines}")
+            })
+            .collect::<Vec<_>>();
+        let text = sections.join("\n\n");
+
+        let chunks = split_formatted(&text, "");
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+            let last_line = chunk.lines().last().unwrap();
+            assert!(!last_line.starts_with('*'), "header orphaned: {last_line}");
+            assert!(chunk.starts_with('*'), "chunk doesn't start at a section");
+        }
+        assert_eq!(chunks.join("\n\n"), text);
+    }
+
+    #[test]
+    fn oversized_block_is_split_by_lines() {
+        let lines = (0..400)
+            .map(|i| format!("line number {i:04}"))
+            .collect::<Vec<_>>();
+        let text = format!("*header*\n{}", lines.join("\n"));
+
+        let chunks = split_formatted(&text, "");
+        assert!(chunks.len() > 1);
+        assert!(chunks[0].starts_with("*header*\nline number 0000"));
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+        }
+        assert_eq!(chunks.join("\n"), text);
+    }
 }
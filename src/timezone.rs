@@ -0,0 +1,109 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+
+/// Largest offsets in use worldwide (UTC-12:00 to UTC+14:00), in minutes.
+const MIN_OFFSET_MINUTES: i32 = -12 * 60;
+const MAX_OFFSET_MINUTES: i32 = 14 * 60;
+
+/// Parse a fixed UTC offset such as `+3`, `-05:30`, `UTC+5:45` or `UTC`.
+pub fn parse_utc_offset(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("UTC")
+        .or_else(|| text.strip_prefix("utc"))
+        .or_else(|| text.strip_prefix("GMT"))
+        .or_else(|| text.strip_prefix("gmt"))
+        .unwrap_or(text)
+        .trim();
+    if text.is_empty() {
+        return FixedOffset::east_opt(0);
+    }
+
+    let (sign, rest) = match text.as_bytes()[0] {
+        b'+' => (1, &text[1..]),
+        b'-' => (-1, &text[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) if minutes.len() == 2 => (hours, minutes),
+        Some(_) => return None,
+        None => (rest, "0"),
+    };
+    let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if hours.is_empty() || hours.len() > 2 || !digits(hours) || !digits(minutes) {
+        return None;
+    }
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+
+    let total = sign * (hours * 60 + minutes);
+    if !(MIN_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(&total) {
+        return None;
+    }
+    FixedOffset::east_opt(total * 60)
+}
+
+/// Build an offset from the stored minute count; out-of-range values are a logic error.
+pub fn offset_from_minutes(minutes: i32) -> FixedOffset {
+    assert!(
+        (MIN_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(&minutes),
+        "UTC offset out of range: {minutes} minutes"
+    );
+    FixedOffset::east_opt(minutes * 60).expect("UTC offset in range")
+}
+
+pub fn offset_minutes(offset: FixedOffset) -> i32 {
+    offset.local_minus_utc() / 60
+}
+
+/// Format as `UTC+03:00`.
+pub fn format_utc_offset(offset: FixedOffset) -> String {
+    format!("UTC{offset}")
+}
+
+/// The calendar day `now` falls on at the given offset; daily quotas reset when it changes.
+pub fn local_day(now: DateTime<Utc>, offset: FixedOffset) -> NaiveDate {
+    now.with_timezone(&offset).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_offsets() {
+        let minutes = |text| parse_utc_offset(text).map(offset_minutes);
+        assert_eq!(minutes("UTC"), Some(0));
+        assert_eq!(minutes("+3"), Some(180));
+        assert_eq!(minutes("UTC+5:45"), Some(345));
+        assert_eq!(minutes("-05:30"), Some(-330));
+        assert_eq!(minutes("gmt-12"), Some(-720));
+        assert_eq!(minutes("+14:00"), Some(840));
+
+        assert_eq!(minutes("+15"), None);
+        assert_eq!(minutes("3"), None);
+        assert_eq!(minutes("+3:5"), None);
+        assert_eq!(minutes("+03:60"), None);
+        assert_eq!(minutes("Europe/Berlin"), None);
+        assert_eq!(minutes("UTC--3"), None);
+        assert_eq!(minutes("+-3"), None);
+        assert_eq!(minutes("++3"), None);
+        assert_eq!(minutes("+3:-5"), None);
+        assert_eq!(minutes("+3:+5"), None);
+    }
+
+    #[test]
+    fn day_follows_local_midnight() {
+        let now = DateTime::parse_from_rfc3339("2024-03-10T22:30:00Z")
+            .expect("valid timestamp")
+            .with_timezone(&Utc);
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).expect("valid date");
+
+        assert_eq!(local_day(now, offset_from_minutes(0)), date(2024, 3, 10));
+        assert_eq!(local_day(now, offset_from_minutes(120)), date(2024, 3, 11));
+        assert_eq!(local_day(now, offset_from_minutes(-600)), date(2024, 3, 10));
+        assert_eq!(format_utc_offset(offset_from_minutes(-330)), "UTC-05:30");
+    }
+}
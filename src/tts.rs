@@ -0,0 +1,48 @@
+use anyhow::anyhow;
+use reqwest::Client;
+use serde_json::json;
+
+/// OpenAI-compatible speech endpoint used for `/voice` replies.
+#[derive(Debug, Clone)]
+pub struct TtsConfig {
+    pub api_url: String,
+    pub api_key: String,
+    pub model: String,
+    pub voice: String,
+    /// Longer replies are cut to this many characters before synthesis.
+    pub max_chars: usize,
+}
+
+/// Synthesize `text` to OGG/Opus, the format Telegram expects for voice messages.
+pub async fn synthesize(http: &Client, config: &TtsConfig, text: &str) -> anyhow::Result<Vec<u8>> {
+    assert!(!text.is_empty(), "nothing to synthesize");
+    assert!(
+        text.chars().count() <= config.max_chars,
+        "text exceeds the TTS length cap"
+    );
+
+    let response = http
+        .post(&config.api_url)
+        .bearer_auth(&config.api_key)
+        .json(&json!({
+            "model": config.model,
+            "voice": config.voice,
+            "input": text,
+            "response_format": "opus",
+        }))
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body_text = response.text().await?;
+        return Err(anyhow!("TTS API error {status}: {body_text}"));
+    }
+
+    let audio = response.bytes().await?;
+    if audio.is_empty() {
+        return Err(anyhow!("TTS API returned no audio"));
+    }
+
+    Ok(audio.to_vec())
+}
@@ -0,0 +1,88 @@
+use chrono::{Datelike, NaiveDate};
+
+use crate::db::DailyUsage;
+
+const HEADER: &str = "chat_id,date,model,requests,prompt_tokens,completion_tokens,cost";
+
+/// Inclusive date range of a `/usage_csv` export; missing bounds default to the month of `today`.
+pub fn resolve_range(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    today: NaiveDate,
+) -> Result<(NaiveDate, NaiveDate), String> {
+    let month_start = today.with_day(1).expect("every month has a first day");
+    let from = from.unwrap_or(month_start);
+    let to = to.unwrap_or(today);
+    if from > to {
+        return Err(format!("start date {from} is after end date {to}"));
+    }
+    Ok((from, to))
+}
+
+/// Render aggregated usage rows as CSV with a header line.
+pub fn render(rows: &[DailyUsage]) -> String {
+    let mut csv = String::from(HEADER);
+    csv.push('\n');
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{:.6}\n",
+            row.chat_id,
+            row.date,
+            field(&row.model_id),
+            row.requests,
+            row.prompt_tokens,
+            row.completion_tokens,
+            row.cost
+        ));
+    }
+    csv
+}
+
+/// Quote a text field when it contains separators or quotes (RFC 4180).
+fn field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).expect("valid date")
+    }
+
+    #[test]
+    fn defaults_to_current_month() {
+        let today = date(2024, 3, 17);
+        assert_eq!(
+            resolve_range(None, None, today),
+            Ok((date(2024, 3, 1), today))
+        );
+        assert_eq!(
+            resolve_range(Some(date(2024, 1, 1)), Some(date(2024, 1, 31)), today),
+            Ok((date(2024, 1, 1), date(2024, 1, 31)))
+        );
+        assert!(resolve_range(Some(date(2024, 4, 1)), None, today).is_err());
+    }
+
+    #[test]
+    fn renders_and_escapes_rows() {
+        let rows = [DailyUsage {
+            chat_id: -100,
+            date: "2024-03-01".to_string(),
+            model_id: "odd,\"model\"".to_string(),
+            requests: 2,
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            cost: 0.25,
+        }];
+        assert_eq!(
+            render(&rows),
+            "chat_id,date,model,requests,prompt_tokens,completion_tokens,cost\n-100,2024-03-01,\"odd,\"\"model\"\"\",2,10,5,0.250000\n"
+        );
+    }
+}